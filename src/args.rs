@@ -1,9 +1,13 @@
 // Copyright 2021 Benjamin Gordon
 // SPDX-License-Identifier: GPL-2.0-or-later
 
-use crate::commands::{backup, rsync, snapshots, ssh, sudo};
+use crate::commands::{
+    backup, check, generate_config, list_snapshots, orphans, prune, restore, rsync, sizes,
+    snapshots, ssh, sudo, test_ssh,
+};
 use crate::config;
 
+use clap::arg_enum;
 use std::env;
 use std::ffi::OsString;
 use std::fmt;
@@ -24,17 +28,44 @@ pub struct GlobalArgs {
     #[structopt(short, long)]
     pub verbose: bool,
 
+    /// Only print warnings and errors to the console.  --log still records info messages.
+    #[structopt(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
     #[structopt(short = "n", long)]
     pub dry_run: bool,
 
+    /// With --dry-run, print each command that would run to stdout, quoted for the shell.
+    #[structopt(long)]
+    pub print_commands: bool,
+
     #[structopt(short = "l", long)]
     pub log: Option<PathBuf>,
 
+    /// Rotate --log to `<log>.1` before opening it if it is larger than this many bytes.  Up to
+    /// 5 old logs are kept.
+    #[structopt(long)]
+    pub max_log_bytes: Option<u64>,
+
+    /// Also send a summary line for each host's backup to syslog, tagged `doppelback` with the
+    /// daemon facility.
+    #[structopt(long)]
+    pub syslog: bool,
+
+    /// Format of the records written to --log.  `json` writes one JSON object per line.
+    #[structopt(long, default_value = "text", case_insensitive = true)]
+    pub log_format: LogFormat,
+
+    /// Config file to load.  If this is a directory, all the `*.yaml` files in it are merged.
     #[structopt(short, long, parse(from_os_str))]
     pub config: PathBuf,
 
     #[structopt(long)]
     pub host: Option<String>,
+
+    /// Home directory to find ssh keys in, instead of $HOME or the passwd entry.
+    #[structopt(long, parse(from_os_str))]
+    pub home: Option<PathBuf>,
 }
 
 impl GlobalArgs {
@@ -43,6 +74,9 @@ impl GlobalArgs {
         if self.verbose {
             args.push(OsString::from("--verbose"));
         }
+        if self.quiet {
+            args.push(OsString::from("--quiet"));
+        }
         if self.dry_run {
             args.push(OsString::from("--dry_run"));
         }
@@ -55,6 +89,15 @@ impl GlobalArgs {
             }));
             args.push(log_arg);
         }
+        if let Some(max_log_bytes) = self.max_log_bytes {
+            args.push(OsString::from(format!("--max-log-bytes={}", max_log_bytes)));
+        }
+        if self.syslog {
+            args.push(OsString::from("--syslog"));
+        }
+        if self.log_format != LogFormat::Text {
+            args.push(OsString::from(format!("--log-format={}", self.log_format)));
+        }
         if !self.config.as_os_str().is_empty() {
             let mut cfg_arg = OsString::from("--config=");
             cfg_arg.push(self.config.canonicalize().unwrap_or_else(|_| {
@@ -69,10 +112,31 @@ impl GlobalArgs {
             host_arg.push(host);
             args.push(host_arg);
         }
+        if let Some(home) = &self.home {
+            let mut home_arg = OsString::from("--home=");
+            home_arg.push(home);
+            args.push(home_arg);
+        }
         args
     }
 }
 
+arg_enum! {
+    #[derive(Debug, PartialEq)]
+    pub enum LogFormat {
+        Text,
+        Json,
+    }
+}
+
+// arg_enum! has no way to mark a variant as the default.
+#[allow(clippy::derivable_impls)]
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub enum Command {
     /// Parse the config, check if contents are valid, and print the results.
@@ -108,8 +172,18 @@ pub enum Command {
     Rsync(rsync::RsyncCmd),
 
     /// Make a new dated snapshot of the live snapshots subdirectory.
+    ///
+    /// With --host, only that host's directory under `live` is snapshotted.  It must be a btrfs
+    /// subvolume of its own.
     MakeSnapshot(snapshots::MakeSnapshotCmd),
 
+    /// List the dated snapshots, newest first.
+    ///
+    /// Each source is listed with the snapshot name recorded in its `.snapshot` file inside that
+    /// snapshot, which identifies the backup run that produced its contents.  Use --host to limit
+    /// the output to one host and --since and --until to limit it to a range of dates.
+    ListSnapshots(list_snapshots::ListSnapshotsCmd),
+
     /// Run all the backups for a remote host
     ///
     /// This is equivalent to:
@@ -119,17 +193,82 @@ pub enum Command {
     ///     2a. Record the snapshot name in the host's live backup directory
     ///     2b. Run doppelback rsync for that backup source
     PullBackup(backup::PullBackupCmd),
+
+    /// Delete old snapshots according to a retention policy.
+    ///
+    /// Snapshots are grouped into daily, weekly, and monthly buckets by age, and only the newest
+    /// snapshot in each bucket is kept.  Snapshots older than the monthly buckets are deleted.
+    /// The newest snapshot is never deleted.  Use --since and --until to only delete snapshots from a
+    /// range of dates.  With --keep-last, only that many of the newest snapshots are kept instead.
+    Prune(prune::PruneCmd),
+
+    /// Copy a backup source from a snapshot back to its remote host.
+    ///
+    /// The snapshot copy of --source is pushed back to the same path on --host with rsync.
+    /// Files are never deleted from the host.  Because this overwrites files on the host, it
+    /// refuses to run unless --confirm or --dry-run is passed.
+    ///
+    /// The `doppelback ssh` forced command on the host only allows rsync to send files, so the
+    /// ssh key used for the restore must not be restricted to that command.
+    Restore(restore::RestoreCmd),
+
+    /// Show how much data each backup source of --host holds.
+    ///
+    /// The size of each source is listed for the live tree and for every dated snapshot.  Sources
+    /// that haven't been backed up yet are listed as 0.
+    Sizes(sizes::SizesCmd),
+
+    /// List what is left in --host's live directory by sources removed from the config.
+    ///
+    /// Backup directories and companion files that don't match any configured source are listed
+    /// by the source name they were created for.  Pass --delete to remove them from the live
+    /// tree.  Earlier snapshots keep their copies.
+    Orphans(orphans::OrphansCmd),
+
+    /// Check how recently --host was backed up, for use as a monitoring probe.
+    ///
+    /// Prints a single status line and exits with 0 (OK), 1 (WARNING), 2 (CRITICAL), or 3
+    /// (UNKNOWN) according to the age of the oldest source's last backup.  A source that has
+    /// never been backed up is critical.
+    Check(check::CheckCmd),
+
+    /// Check that --host can be reached over ssh the way pull-backup reaches it.
+    ///
+    /// Runs `doppelback config-test --type=source` on the host through the forced ssh command for
+    /// --source, or for every enabled source, and prints each result.  Exits with 1 if any source
+    /// fails.
+    TestSsh(test_ssh::TestSshCmd),
+
+    /// Print the loaded config as YAML.
+    ///
+    /// The output is the config doppelback actually uses, after merging a config directory and
+    /// filling in defaults.  It can be loaded back as a config file.
+    ShowConfig,
+
+    /// Print a commented starting config to stdout.
+    ///
+    /// The config named by --config is not read, so this works before any config exists.
+    GenerateConfig(generate_config::GenerateConfigCmd),
 }
 
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name = match self {
+            Command::Check(_) => "check",
             Command::ConfigTest(_) => "config-test",
+            Command::GenerateConfig(_) => "generate-config",
+            Command::ListSnapshots(_) => "list-snapshots",
             Command::MakeSnapshot(_) => "make-snapshot",
+            Command::Orphans(_) => "orphans",
+            Command::Prune(_) => "prune",
             Command::PullBackup(_) => "pull-backup",
+            Command::Restore(_) => "restore",
             Command::Rsync(_) => "rsync",
+            Command::ShowConfig => "show-config",
+            Command::Sizes(_) => "sizes",
             Command::Ssh(_) => "ssh",
             Command::Sudo(_) => "sudo",
+            Command::TestSsh(_) => "test-ssh",
         };
         write!(f, "{}", name)
     }
@@ -160,6 +299,30 @@ mod tests {
         assert_eq!(cli_args.len(), 1);
     }
 
+    #[test]
+    fn quiet_is_added() {
+        let args = GlobalArgs {
+            quiet: true,
+            ..GlobalArgs::default()
+        };
+        assert_eq!(args.as_cli_args(), vec![OsString::from("--quiet")]);
+    }
+
+    #[test]
+    fn quiet_conflicts_with_verbose() {
+        let parse = |args: &[&str]| CliArgs::from_iter_safe(args.iter());
+        assert!(parse(&["doppelback", "-c", "/etc/doppelback.yaml", "-q", "prune"]).is_ok());
+        assert!(parse(&[
+            "doppelback",
+            "-c",
+            "/etc/doppelback.yaml",
+            "--quiet",
+            "--verbose",
+            "prune"
+        ])
+        .is_err());
+    }
+
     #[test]
     fn dry_run_is_added() {
         let args = GlobalArgs {
@@ -194,6 +357,18 @@ mod tests {
         assert_eq!(cli_args.len(), 1);
     }
 
+    #[test]
+    fn log_format_is_added() {
+        let args = GlobalArgs {
+            log_format: LogFormat::Json,
+            ..GlobalArgs::default()
+        };
+        assert_eq!(
+            args.as_cli_args(),
+            vec![OsString::from("--log-format=Json")]
+        );
+    }
+
     #[test]
     fn config_is_expanded() {
         let args = GlobalArgs {
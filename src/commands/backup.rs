@@ -1,110 +1,846 @@
 // Copyright 2021 Benjamin Gordon
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::atomic_file;
 use crate::commands::{rsync, snapshots};
-use crate::config::{BackupDest, Config};
+use crate::config::{BackupDest, BackupHost, BackupSource, Config};
 use crate::doppelback_error::DoppelbackError;
-use log::{error, info};
-use std::ffi::OsStr;
+use crate::lock_file::LockSet;
+use crate::log_context;
+use crate::process_util;
+use crate::rsync_util::RsyncStats;
+use crate::signals;
+use crate::status_file::StatusFile;
+use crate::syslog;
+use log::{error, info, warn};
+use pathsearch::find_executable_in_path;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::time::{Duration, Instant};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
+/// Held while taking a snapshot.  Each snapshot gets the first free name, so hosts backed up in
+/// parallel could otherwise both pick the same one.
+static SNAPSHOT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Result of backing up all the sources of one host.
+#[derive(Debug, Serialize)]
+pub struct BackupReport {
+    pub host: String,
+
+    /// Name of the snapshot taken of the live tree before this backup started.
+    pub snapshot: String,
+    pub sources: Vec<SourceResult>,
+
+    /// Failure of the host itself after its sources were backed up, such as a snapshot with
+    /// `snapshot_only_on_change` that couldn't be taken.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceResult {
+    pub path: PathBuf,
+    pub outcome: SourceOutcome,
+    pub duration: Duration,
+
+    /// Bytes transferred according to rsync's stats, if it reported them.
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub enum SourceOutcome {
+    Succeeded,
+    Failed(String),
+    /// The host couldn't be reached over ssh, so nothing was transferred.
+    Unreachable(String),
+}
+
+/// The last backup attempt of one source, kept next to its backup directory in `<dest>.status`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DestStatus {
+    /// Unix time when the last attempt started.
+    pub last_attempt: u64,
+
+    /// Unix time when the last successful attempt started, if there has been one.
+    pub last_success: Option<u64>,
+
+    pub last_duration_secs: f64,
+    pub last_error: Option<String>,
+}
+
+/// Totals for a whole pull-backup run, as printed by `--json-summary`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RunTotals {
+    pub hosts: usize,
+
+    /// Hosts where every source was backed up.
+    pub hosts_succeeded: usize,
+
+    /// Hosts that couldn't be backed up at all or had a failed source.
+    pub hosts_failed: usize,
+
+    pub sources_succeeded: usize,
+    pub sources_failed: usize,
+
+    /// Bytes transferred according to rsync's stats, for the sources that reported them.
+    pub bytes: u64,
+
+    pub duration_secs: f64,
+}
+
+impl RunTotals {
+    /// Adds up `reports` and the `host_errors` hosts that didn't produce a report.
+    pub fn new(reports: &[BackupReport], host_errors: usize, duration: Duration) -> Self {
+        let mut totals = RunTotals {
+            hosts: reports.len() + host_errors,
+            hosts_failed: host_errors,
+            duration_secs: duration.as_secs_f64(),
+            ..RunTotals::default()
+        };
+        for report in reports {
+            if report.failed() == 0 && report.error.is_none() {
+                totals.hosts_succeeded += 1;
+            } else {
+                totals.hosts_failed += 1;
+            }
+            totals.sources_succeeded += report.succeeded();
+            totals.sources_failed += report.failed();
+            totals.bytes += report.sources.iter().filter_map(|s| s.bytes).sum::<u64>();
+        }
+        totals
+    }
+}
+
+impl BackupReport {
+    pub fn succeeded(&self) -> usize {
+        self.sources
+            .iter()
+            .filter(|s| s.outcome == SourceOutcome::Succeeded)
+            .count()
+    }
+
+    /// Returns the number of sources that didn't succeed, including unreachable ones.
+    pub fn failed(&self) -> usize {
+        self.sources.len() - self.succeeded()
+    }
+
+    pub fn unreachable(&self) -> usize {
+        self.sources
+            .iter()
+            .filter(|s| matches!(s.outcome, SourceOutcome::Unreachable(_)))
+            .count()
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct PullBackupCmd {
-    /// Back up all hosts in the config.
+    /// Back up all hosts in the config, except those with `auto: false`.
     ///
     /// If not passed, specify an individual host with --host.
     #[structopt(long)]
     pub all: bool,
+
+    /// Limit transfer bandwidth in KB/s.  Overrides `bwlimit` in the host config.
+    #[structopt(long)]
+    pub bwlimit: Option<u64>,
+
+    /// Number of sources to back up at the same time for each host.
+    #[structopt(long, default_value = "1")]
+    pub jobs: usize,
+
+    /// Number of hosts to back up at the same time with --all.
+    ///
+    /// Each host still takes its own snapshot before its sources are transferred.  When more than
+    /// one host runs at once, log lines are prefixed with the host they belong to.
+    #[structopt(long, default_value = "1")]
+    pub parallel_hosts: usize,
+
+    /// Log rsync's overall progress at debug level while each transfer runs.
+    #[structopt(long)]
+    pub progress: bool,
+
+    /// Only back up this source path of the host.
+    ///
+    /// Useful for re-running a single source that failed.  The path must match one of the host's
+    /// configured sources exactly.
+    #[structopt(long, parse(from_os_str), conflicts_with = "all")]
+    pub only_source: Option<PathBuf>,
+
+    /// Don't take a new snapshot first.
+    ///
+    /// Sources are rsynced straight into the live tree and keep the snapshot name recorded by
+    /// their last backup, so a re-run counts as part of the same backup as the run that took it.
+    /// Every source being backed up must already have a recorded snapshot.  Combine with
+    /// --only-source to re-run a single failed source without using more space.
+    #[structopt(long)]
+    pub no_snapshot: bool,
+
+    /// Back up into this directory instead of the configured snapshots directory.
+    ///
+    /// Each source goes into `live/<host>/<source>` below it, as with `rsync --dest-root`.  No
+    /// snapshot is taken and no snapshot names are recorded, so the real snapshots are left
+    /// untouched.
+    #[structopt(long, parse(from_os_str), conflicts_with = "no_snapshot")]
+    pub dest_root: Option<PathBuf>,
+
+    /// Skip this host with --all.  Can be repeated.
+    ///
+    /// Useful for leaving out a host that is down for maintenance.  The host must be in the config.
+    #[structopt(long, number_of_values = 1, requires = "all")]
+    pub except: Vec<String>,
+
+    /// Print one line of JSON with the totals for the whole run to stdout when it finishes.
+    ///
+    /// Combine with --quiet to keep the rest of stdout empty.
+    #[structopt(long)]
+    pub json_summary: bool,
 }
 
 impl PullBackupCmd {
+    /// Returns whether each host gets a new snapshot before its sources are transferred.
+    pub fn takes_snapshot(&self) -> bool {
+        !self.no_snapshot && self.dest_root.is_none()
+    }
+
+    /// Calls `f` on each of `hosts`, running up to `--parallel-hosts` of them at a time.
+    ///
+    /// Results are returned in the same order as `hosts`.  When hosts run in parallel, everything
+    /// logged while handling a host is tagged with its name.
+    pub fn for_each_host<R, F>(&self, hosts: &[&str], f: F) -> Vec<R>
+    where
+        R: Send,
+        F: Fn(&str) -> R + Sync,
+    {
+        let parallel = self.parallel_hosts > 1 && hosts.len() > 1;
+        run_parallel(hosts, self.parallel_hosts, |host| {
+            let _context = parallel.then(|| log_context::set_host(Some(host)));
+            f(host)
+        })
+    }
+
+    /// Returns the hosts from `config` that --all backs up, leaving out any passed to --except and
+    /// any with `auto: false`.
+    pub fn selected_hosts<'a>(&self, config: &'a Config) -> Result<Vec<&'a str>, DoppelbackError> {
+        if let Some(unknown) = self.except.iter().find(|h| !config.hosts.contains_key(*h)) {
+            return Err(DoppelbackError::InvalidConfig(format!(
+                "--except host {} not found in config",
+                unknown
+            )));
+        }
+        Ok(config
+            .hosts
+            .iter()
+            .filter(|(host, _)| !self.except.contains(host))
+            .filter(|(host, host_config)| {
+                if !host_config.auto() {
+                    info!("Skipping {}: auto is false", host);
+                }
+                host_config.auto()
+            })
+            .map(|(host, _)| host.as_str())
+            .collect())
+    }
+
+    /// Checks that `host`'s snapshots directory can be used, locks it in `locks`, and then backs
+    /// the host up.
+    ///
+    /// The checks and the lock are done per host so that a host with a broken destination fails
+    /// on its own instead of stopping the whole run.
+    pub fn run_host(
+        &self,
+        host: &str,
+        config: &Config,
+        dry_run: bool,
+        home_dir: &OsStr,
+        status: &StatusFile,
+        locks: &LockSet,
+    ) -> Result<BackupReport, DoppelbackError> {
+        config.host_snapshot_dir_valid(host)?;
+        // Overlapping runs (e.g. from cron) can't rsync into the same live directory at once.
+        if !dry_run {
+            locks.acquire(config.snapshot_root(host).join(".doppelback.lock"))?;
+        }
+        if self.takes_snapshot() {
+            snapshots::check_live_subvolume(config.snapshot_root(host).join("live"))?;
+        }
+        self.backup_host(host, config, dry_run, home_dir, status)
+    }
+
     pub fn backup_host(
         &self,
         host: &str,
         config: &Config,
         dry_run: bool,
         home_dir: &OsStr,
-    ) -> Result<usize, DoppelbackError> {
+        status: &StatusFile,
+    ) -> Result<BackupReport, DoppelbackError> {
         // The host passed into this function should have come from a config file key,
         // so we can assume that it will be found.
         let host_config = config.hosts.get(host).expect("host not found");
+        let root = config.snapshot_root(host);
+        let sources = self.selected_sources(host, host_config)?;
+        let recorded = if self.no_snapshot {
+            Some(recorded_snapshot(
+                root,
+                host,
+                &sources,
+                config.snapshot_name_format(),
+            )?)
+        } else {
+            None
+        };
         if host_config.find_ssh_key(home_dir).is_none() {
-            return Err(DoppelbackError::InvalidConfig(format!(
-                "ssh key {} not found",
-                host_config.key.display()
-            )));
+            return Err(host_config.missing_key_error());
+        }
+
+        host_config.check_options().map_err(|e| e.for_host(host))?;
+        config.check_free_space(host)?;
+
+        // A failed pre-hook means the host isn't ready to be backed up (e.g. a database dump
+        // didn't complete), so skip the whole host.
+        if let Some(hook) = &host_config.pre_hook {
+            run_hook(host, host_config, "pre", hook, home_dir, dry_run)?;
         }
 
-        let snapshot = snapshots::MakeSnapshotCmd::default();
-        let snapname = snapshot.make_snapshot(&config.snapshots, dry_run)?;
+        let snapshot_after = config.snapshot_only_on_change && self.takes_snapshot();
+        let snapname = match recorded {
+            Some(snapname) => {
+                info!("Skipping snapshot and reusing {}", snapname);
+                snapname
+            }
+            None if self.dest_root.is_some() => {
+                info!("Skipping snapshot for a backup outside the snapshots directory");
+                String::new()
+            }
+            None if snapshot_after => {
+                info!(
+                    "Taking the snapshot for {} after the backup if anything changes",
+                    host
+                );
+                String::new()
+            }
+            None => take_pending_snapshot(host_config, config, root, dry_run)?,
+        };
         info!(
             "Starting backup for {} with previous version {}",
             host, snapname
         );
+        status.start_host(host, &snapname);
 
         let host_start = Instant::now();
-        let mut errs = 0;
-        for source in &host_config.sources {
-            let dest = BackupDest::new(&config.snapshots, host, source);
+
+        // Record the snapshot name for every source before starting any transfers so that the
+        // companion file writes never overlap with each other or with running rsyncs.  Results
+        // are slotted back into config order afterwards.  With --no-snapshot, the names recorded
+        // by the previous run are left alone.
+        let mut results: Vec<Option<SourceResult>> = sources.iter().map(|_| None).collect();
+        let mut pending = Vec::with_capacity(sources.len());
+        let record_snapshot = self.takes_snapshot() && !snapshot_after;
+        for (i, source) in sources.iter().copied().enumerate() {
+            let dest = BackupDest::new(root, host, source);
 
             let snapshot_file = dest.get_companion_file("snapshot");
-            if !dry_run {
+            if dry_run && record_snapshot {
+                info!("Would write {} to {}", snapname, snapshot_file.display());
+            } else if record_snapshot {
+                // Recording the name is the first change to the live tree, ahead of any transfer.
+                // From here on the new snapshot holds the only copy of the previous backup and
+                // has to be kept even if the run is interrupted.
+                signals::clear_pending_snapshot(&root.join(&snapname));
                 if let Err(e) = fs::write(&snapshot_file, &snapname) {
                     error!(
                         "Failed to write snapshot name to {}: {}",
                         snapshot_file.display(),
                         e
                     );
-                    errs += 1;
+                    results[i] = Some(SourceResult {
+                        path: source.path.clone(),
+                        outcome: SourceOutcome::Failed(e.to_string()),
+                        duration: Duration::ZERO,
+                        bytes: None,
+                    });
                     continue;
                 }
             }
+            pending.push((i, source));
+        }
 
-            let source_start = Instant::now();
-            let rsync = rsync::RsyncCmd::new(host, &source.path);
-            match rsync.run_rsync(config, dry_run) {
-                Ok(()) => {
-                    info!(
-                        "{}:{}: {}",
-                        host,
-                        source.path.display(),
-                        fmt_duration(source_start.elapsed())
-                    );
-                }
+        let transferred = run_parallel(&pending, self.jobs, |(_, source)| {
+            self.backup_source(host, source, config, dry_run, status)
+        });
+        for ((i, _), result) in pending.iter().zip(transferred) {
+            results[*i] = Some(result);
+        }
+
+        // The post-hook runs even if sources failed so that it can clean up after the pre-hook.
+        if let Some(hook) = &host_config.post_hook {
+            if let Err(e) = run_hook(host, host_config, "post", hook, home_dir, dry_run) {
+                warn!("post-hook for {} failed: {}", host, e);
+            }
+        }
+
+        if !dry_run {
+            if let Some(control_path) = host_config.control_path(home_dir, host) {
+                close_control_master(host, host_config, &control_path);
+            }
+        }
+
+        let results: Vec<SourceResult> = results
+            .into_iter()
+            .map(|r| r.expect("missing source result"))
+            .collect();
 
+        // The live tree already holds the new backup, so the snapshot is a copy of it and there
+        // is nothing to delete if the run is interrupted.  The sources were transferred either
+        // way, so a failed snapshot fails the host but keeps their results.
+        let mut error = None;
+        let snapname = if snapshot_after && data_changed(&results) {
+            match take_snapshot(host_config, config, root, dry_run) {
+                Ok(snapname) => {
+                    for source in &sources {
+                        let snapshot_file =
+                            BackupDest::new(root, host, source).get_companion_file("snapshot");
+                        if dry_run {
+                            info!("Would write {} to {}", snapname, snapshot_file.display());
+                        } else if let Err(e) = fs::write(&snapshot_file, &snapname) {
+                            warn!(
+                                "Failed to write snapshot name to {}: {}",
+                                snapshot_file.display(),
+                                e
+                            );
+                        }
+                    }
+                    snapname
+                }
                 Err(e) => {
-                    error!(
-                        "Failed to back up {}:{}: {}",
-                        host,
-                        source.path.display(),
-                        e
-                    );
-                    errs += 1;
+                    error!("Failed to take the snapshot for {}: {}", host, e);
+                    error = Some(format!("couldn't take the snapshot: {}", e));
+                    snapname
                 }
             }
-        }
+        } else {
+            if snapshot_after {
+                info!("No data changed for {}; skipping the snapshot", host);
+            }
+            snapname
+        };
 
+        let report = BackupReport {
+            host: host.to_string(),
+            snapshot: snapname,
+            sources: results,
+            error,
+        };
         info!(
-            "Finished {} backup after {} with {} failed",
+            target: syslog::SUMMARY_TARGET,
+            "Finished {} backup after {} with {} failed ({} unreachable)",
             host,
             fmt_duration(host_start.elapsed()),
-            errs
+            report.failed(),
+            report.unreachable()
         );
-        Ok(host_config.sources.len() - errs)
+        Ok(report)
     }
+
+    /// Returns the sources of `host` that this run should back up.
+    ///
+    /// Disabled sources are left out, even if they were picked with `--only-source`.
+    fn selected_sources<'a>(
+        &self,
+        host: &str,
+        host_config: &'a BackupHost,
+    ) -> Result<Vec<&'a BackupSource>, DoppelbackError> {
+        let sources = match &self.only_source {
+            Some(path) => {
+                let source = host_config.get_source(path).ok_or_else(|| {
+                    DoppelbackError::InvalidConfig(format!(
+                        "source {} not found for {}",
+                        path.display(),
+                        host
+                    ))
+                })?;
+                vec![source]
+            }
+            None => host_config.sources.iter().collect(),
+        };
+
+        Ok(sources
+            .into_iter()
+            .filter(|source| {
+                if source.disabled {
+                    info!(
+                        "Skipping disabled source {}:{}",
+                        host,
+                        source.path.display()
+                    );
+                }
+                !source.disabled
+            })
+            .collect())
+    }
+
+    /// Runs rsync for a single source and logs the outcome.
+    fn backup_source(
+        &self,
+        host: &str,
+        source: &BackupSource,
+        config: &Config,
+        dry_run: bool,
+        status: &StatusFile,
+    ) -> SourceResult {
+        let source_start = Instant::now();
+        let attempt_time = SystemTime::now();
+        let mut rsync = rsync::RsyncCmd::new(host, &source.path);
+        rsync.bwlimit = self.bwlimit;
+        rsync.progress = self.progress;
+        rsync.dest_root = self.dest_root.clone();
+        status.start_source(host, &source.path);
+        let result = rsync.run_rsync(config, dry_run);
+        status.finish_source(host, &source.path);
+        let (outcome, bytes) = match result {
+            Ok(stats) => {
+                info!(
+                    "{}:{} {} in {}",
+                    host,
+                    source.path.display(),
+                    fmt_stats(&stats),
+                    fmt_duration(source_start.elapsed())
+                );
+                (SourceOutcome::Succeeded, stats.bytes_transferred)
+            }
+
+            Err(e @ DoppelbackError::HostUnreachable(_)) => {
+                error!(
+                    "Failed to back up {}:{}: {}",
+                    host,
+                    source.path.display(),
+                    e
+                );
+                (SourceOutcome::Unreachable(e.to_string()), None)
+            }
+
+            Err(e) => {
+                error!(
+                    "Failed to back up {}:{}: {}",
+                    host,
+                    source.path.display(),
+                    e
+                );
+                (SourceOutcome::Failed(e.to_string()), None)
+            }
+        };
+
+        let result = SourceResult {
+            path: source.path.clone(),
+            outcome,
+            duration: source_start.elapsed(),
+            bytes,
+        };
+        if !dry_run {
+            let root = match &self.dest_root {
+                Some(dest_root) => dest_root.as_path(),
+                None => config.snapshot_root(host),
+            };
+            let dest = BackupDest::new(root, host, source);
+            if let Err(e) = write_dest_status(&dest, attempt_time, &result) {
+                warn!(
+                    "Failed to write status for {}:{}: {}",
+                    host,
+                    source.path.display(),
+                    e
+                );
+            }
+        }
+        result
+    }
+}
+
+/// Takes a new snapshot of `root` for `host`.  Only one host takes a snapshot at a time.
+fn take_snapshot(
+    host_config: &BackupHost,
+    config: &Config,
+    root: &Path,
+    dry_run: bool,
+) -> Result<String, DoppelbackError> {
+    let _snapshot_lock = SNAPSHOT_LOCK.lock().expect("snapshot lock poisoned");
+    let snapshot = snapshots::MakeSnapshotCmd::with_timeout(host_config.timeout_secs);
+    let snapname = snapshot.make_snapshot(
+        root,
+        config.snapshot_sudo()?.as_deref(),
+        &config.btrfs_bin()?,
+        None,
+        config.snapshot_name_format(),
+        dry_run,
+    )?;
+    if dry_run {
+        info!("Would create snapshot {}", root.join(&snapname).display());
+    }
+    Ok(snapname)
+}
+
+/// Takes the snapshot before a backup into `root` and marks it pending, so that it is deleted again
+/// if the run is interrupted before the live tree changes.
+fn take_pending_snapshot(
+    host_config: &BackupHost,
+    config: &Config,
+    root: &Path,
+    dry_run: bool,
+) -> Result<String, DoppelbackError> {
+    let snapname = take_snapshot(host_config, config, root, dry_run)?;
+    if !dry_run {
+        signals::set_pending_snapshot(root.join(&snapname));
+    }
+    Ok(snapname)
+}
+
+/// Returns whether any of the transfers in `results` may have changed the live tree.
+///
+/// Only a source whose rsync reported zero transferred bytes, or that never reached the host, is
+/// known to be unchanged.  A failed transfer may have written part of its changes.
+fn data_changed(results: &[SourceResult]) -> bool {
+    results.iter().any(|result| match result.outcome {
+        SourceOutcome::Succeeded => result.bytes != Some(0),
+        SourceOutcome::Failed(_) => true,
+        SourceOutcome::Unreachable(_) => false,
+    })
+}
+
+/// Records `result` in the `.status` companion file of `dest`, keeping the time of the last
+/// success if this attempt failed.
+fn write_dest_status(
+    dest: &BackupDest,
+    attempt_time: SystemTime,
+    result: &SourceResult,
+) -> io::Result<()> {
+    let path = dest.get_companion_file("status");
+    // A missing or unreadable record only loses the time of the last success.
+    let mut status: DestStatus = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+
+    let attempt = attempt_time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    status.last_attempt = attempt;
+    status.last_duration_secs = result.duration.as_secs_f64();
+    status.last_error = match &result.outcome {
+        SourceOutcome::Succeeded => {
+            status.last_success = Some(attempt);
+            None
+        }
+        SourceOutcome::Failed(e) | SourceOutcome::Unreachable(e) => Some(e.clone()),
+    };
+
+    let json = serde_json::to_vec_pretty(&status)?;
+    atomic_file::write_atomic(path, &json)
 }
 
-fn fmt_duration(d: Duration) -> String {
+/// Stops the shared ssh connection to `host` and removes its control socket.
+///
+/// The master would exit on its own shortly after the last transfer, so failures are only logged.
+fn close_control_master(host: &str, host_config: &BackupHost, control_path: &Path) {
+    if !control_path.exists() {
+        return;
+    }
+    if let Some(ssh) = find_executable_in_path("ssh") {
+        let mut path_arg = OsString::from("-oControlPath=");
+        path_arg.push(control_path);
+        let result = process::Command::new(ssh)
+            .arg(path_arg)
+            .args(["-O", "exit"])
+            .arg(host_config.ssh_destination(host))
+            .current_dir("/")
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status();
+        if !matches!(result, Ok(status) if status.success()) {
+            warn!("Failed to stop the shared ssh connection to {}", host);
+        }
+    }
+    if let Err(e) = fs::remove_file(control_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", control_path.display(), e);
+        }
+    }
+}
+
+/// Returns the snapshot name recorded by the last backup of `sources`.
+///
+/// Fails if any of the sources has never been backed up.  If the sources were last backed up
+/// by different runs, the newest name is returned.  Names are compared by the time they were taken
+/// according to the snapshot name `format`, since the names themselves don't have to sort in
+/// order.  Names that don't parse with it, e.g. from before it was changed, count as the oldest.
+fn recorded_snapshot(
+    root: &Path,
+    host: &str,
+    sources: &[&BackupSource],
+    format: &str,
+) -> Result<String, DoppelbackError> {
+    let mut recorded_names = Vec::with_capacity(sources.len());
+    for source in sources {
+        let snapshot_file = BackupDest::new(root, host, source).get_companion_file("snapshot");
+        let recorded = match fs::read_to_string(&snapshot_file) {
+            Ok(recorded) => recorded.trim().to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if recorded.is_empty() {
+            return Err(DoppelbackError::InvalidConfig(format!(
+                "no snapshot recorded for {}:{}; run without --no-snapshot first",
+                host,
+                source.path.display()
+            )));
+        }
+        recorded_names.push(recorded);
+    }
+    recorded_names
+        .into_iter()
+        .max_by_key(|name| (snapshots::parse_snapshot_name(name, format), name.clone()))
+        .ok_or_else(|| DoppelbackError::InvalidConfig(format!("no sources for {}", host)))
+}
+
+/// Runs a pre- or post-hook command on `host` over ssh.
+///
+/// The remote doppelback only accepts the command if it exactly matches the hook in its own host
+/// config, the same way sources have to be listed on both sides.
+fn run_hook(
+    host: &str,
+    host_config: &BackupHost,
+    kind: &str,
+    hook: &str,
+    home_dir: &OsStr,
+    dry_run: bool,
+) -> Result<(), DoppelbackError> {
+    let ssh = find_executable_in_path("ssh")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Couldn't find ssh in PATH"))?;
+    let mut command = host_config
+        .ssh_args(&ssh, home_dir, host)
+        .map_err(|e| e.for_host(host))?;
+    command.push(OsString::from(host_config.ssh_destination(host)));
+    command.push(OsString::from(hook));
+
+    info!("Running {}-hook for {}: {:?}", kind, host, &command);
+    if dry_run {
+        process_util::print_dry_run(&command);
+        return Ok(());
+    }
+
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir("/")
+        .spawn()?;
+    let status =
+        process_util::wait_with_timeout(&mut child, host_config.timeout())?.ok_or_else(|| {
+            DoppelbackError::Timeout(
+                format!("{}-hook for {}", kind, host),
+                host_config.timeout().unwrap_or_default(),
+            )
+        })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DoppelbackError::CommandFailed(ssh, status))
+    }
+}
+
+/// Calls `f` on each of `items` using up to `jobs` threads at a time.
+///
+/// Results are returned in the same order as `items`.  With one job, everything runs on the
+/// calling thread.  Worker threads log with the calling thread's log context.
+pub fn run_parallel<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let host = log_context::host();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| {
+                let _context = log_context::set_host(host.as_deref());
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = f(&items[i]);
+                    results.lock().expect("results lock poisoned")[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .expect("results lock poisoned")
+        .into_iter()
+        .map(|r| r.expect("missing parallel result"))
+        .collect()
+}
+
+/// Formats transfer totals like "1.2 GiB in 342 files", leaving out anything rsync didn't report.
+fn fmt_stats(stats: &RsyncStats) -> String {
+    let bytes = stats
+        .bytes_transferred
+        .map_or_else(|| String::from("unknown size"), fmt_bytes);
+    match stats.files_transferred {
+        Some(1) => format!("{} in 1 file", bytes),
+        Some(files) => format!("{} in {} files", bytes, files),
+        None => bytes,
+    }
+}
+
+pub fn fmt_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats `d` as days, hours, minutes, and seconds, e.g. `1d06h02m01s`.
+///
+/// Leading units that are zero are left out, and every unit after the first is zero-padded.
+pub fn fmt_duration(d: Duration) -> String {
     let mut seconds = d.as_secs();
 
     let mut out = String::new();
     let mut first = true;
-    if seconds >= 3600 {
+    if seconds >= 86400 {
+        let days = seconds / 86400;
+        seconds %= 86400;
+        out.push_str(&format!("{}d", days));
+        first = false;
+    }
+    if seconds >= 3600 || !first {
         let hours = seconds / 3600;
         seconds %= 3600;
-        out.push_str(&format!("{}h", hours));
+        if first {
+            out.push_str(&format!("{}h", hours));
+        } else {
+            out.push_str(&format!("{:02}h", hours));
+        }
         first = false;
     }
     if seconds >= 60 || !first {
@@ -128,6 +864,28 @@ fn fmt_duration(d: Duration) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lock_file::LockFile;
+    use crate::test_util::{FakeCommand, ENV_LOCK};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    #[test]
+    fn fmt_duration_days() {
+        let d = Duration::from_secs(30 * 3600 + 121);
+        assert_eq!(fmt_duration(d), "1d06h02m01s");
+    }
+
+    #[test]
+    fn fmt_duration_days_exact() {
+        let d = Duration::from_secs(86400);
+        assert_eq!(fmt_duration(d), "1d00h00m00s");
+    }
+
+    #[test]
+    fn fmt_duration_hours_max() {
+        let d = Duration::from_secs(86399);
+        assert_eq!(fmt_duration(d), "23h59m59s");
+    }
 
     #[test]
     fn fmt_duration_hours() {
@@ -170,4 +928,1014 @@ mod tests {
         let d = Duration::from_secs(9);
         assert_eq!(fmt_duration(d), "9s");
     }
+
+    #[test]
+    fn fmt_bytes_units() {
+        assert_eq!(fmt_bytes(0), "0 B");
+        assert_eq!(fmt_bytes(1023), "1023 B");
+        assert_eq!(fmt_bytes(1024), "1.0 KiB");
+        assert_eq!(fmt_bytes(1_288_490_189), "1.2 GiB");
+        assert_eq!(fmt_bytes(u64::MAX), "16384.0 PiB");
+    }
+
+    #[test]
+    fn fmt_stats_partial() {
+        let stats = RsyncStats {
+            files_transferred: Some(342),
+            bytes_transferred: Some(1_288_490_189),
+        };
+        assert_eq!(fmt_stats(&stats), "1.2 GiB in 342 files");
+
+        let stats = RsyncStats {
+            files_transferred: None,
+            bytes_transferred: Some(100),
+        };
+        assert_eq!(fmt_stats(&stats), "100 B");
+
+        assert_eq!(fmt_stats(&RsyncStats::default()), "unknown size");
+    }
+
+    #[test]
+    fn backup_report_counts() {
+        let result = |outcome| SourceResult {
+            path: PathBuf::from("/etc"),
+            outcome,
+            duration: Duration::from_secs(1),
+            bytes: None,
+        };
+        let report = BackupReport {
+            host: String::from("host1"),
+            snapshot: String::from("20210704.00"),
+            sources: vec![
+                result(SourceOutcome::Succeeded),
+                result(SourceOutcome::Failed(String::from("rsync failed"))),
+                result(SourceOutcome::Succeeded),
+                result(SourceOutcome::Unreachable(String::from(
+                    "host1 is unreachable",
+                ))),
+            ],
+            error: None,
+        };
+        assert_eq!(report.succeeded(), 2);
+        assert_eq!(report.failed(), 2);
+        assert_eq!(report.unreachable(), 1);
+    }
+
+    #[test]
+    fn run_totals_add_up_reports() {
+        let result = |outcome, bytes| SourceResult {
+            path: PathBuf::from("/etc"),
+            outcome,
+            duration: Duration::from_secs(1),
+            bytes,
+        };
+        let reports = [
+            BackupReport {
+                host: String::from("host1"),
+                snapshot: String::from("20210704.00"),
+                sources: vec![
+                    result(SourceOutcome::Succeeded, Some(1000)),
+                    result(SourceOutcome::Succeeded, None),
+                ],
+                error: None,
+            },
+            BackupReport {
+                host: String::from("host2"),
+                snapshot: String::from("20210704.00"),
+                sources: vec![
+                    result(SourceOutcome::Succeeded, Some(24)),
+                    result(SourceOutcome::Failed(String::from("rsync failed")), None),
+                ],
+                error: None,
+            },
+        ];
+
+        let totals = RunTotals::new(&reports, 1, Duration::from_millis(90_500));
+        assert_eq!(
+            serde_json::to_string(&totals).unwrap(),
+            r#"{"hosts":3,"hosts_succeeded":1,"hosts_failed":2,"sources_succeeded":3,"sources_failed":1,"bytes":1024,"duration_secs":90.5}"#
+        );
+    }
+
+    #[test]
+    fn status_file_shows_running_source() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let snapshots = dir.path().join("snapshots");
+        fs::create_dir_all(snapshots.join("live")).unwrap();
+        let key = dir.path().join("id_backup");
+        fs::write(&key, "").unwrap();
+        let status_path = dir.path().join("status.json");
+        let seen_path = dir.path().join("seen.json");
+
+        // The stub rsync saves the status file as it was while rsync was running.
+        let _rsync = FakeCommand::script(
+            "rsync",
+            &format!("cp {} {}", status_path.display(), seen_path.display()),
+        )
+        .unwrap();
+        let _ssh = FakeCommand::new("ssh").unwrap();
+
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                user: String::from("backup"),
+                key,
+                sources: vec![source.clone()],
+                ..BackupHost::default()
+            },
+        );
+        let config = Config {
+            snapshots,
+            hosts,
+            ..Config::default()
+        };
+        let pull = pull_cmd();
+
+        let status = StatusFile::new(Some(status_path.clone()));
+        status.start_host("host1", "20210704.00");
+        let result = pull.backup_source("host1", &source, &config, false, &status);
+        assert_eq!(result.outcome, SourceOutcome::Succeeded);
+
+        let seen: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&seen_path).unwrap()).unwrap();
+        assert_eq!(seen["host"], "host1");
+        assert_eq!(seen["snapshot"], "20210704.00");
+        assert_eq!(seen["running"][0]["path"], "/etc");
+
+        let after: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&status_path).unwrap()).unwrap();
+        assert!(after["running"].as_array().unwrap().is_empty());
+    }
+
+    /// Returns the command `pull-backup <host>` parses to.
+    fn pull_cmd() -> PullBackupCmd {
+        PullBackupCmd {
+            all: false,
+            bwlimit: None,
+            jobs: 1,
+            parallel_hosts: 1,
+            progress: false,
+            only_source: None,
+            no_snapshot: false,
+            dest_root: None,
+            except: Vec::new(),
+            json_summary: false,
+        }
+    }
+
+    fn hook_config(dir: &TempDir) -> Config {
+        let snapshots = dir.path().join("snapshots");
+        fs::create_dir_all(snapshots.join("live/host1")).unwrap();
+        let key = dir.path().join("id_backup");
+        fs::write(&key, "").unwrap();
+
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                user: String::from("backup"),
+                key,
+                sources: vec![BackupSource {
+                    path: PathBuf::from("/etc"),
+                    ..BackupSource::default()
+                }],
+                pre_hook: Some(String::from("/usr/local/bin/dump-db")),
+                post_hook: Some(String::from("/usr/local/bin/cleanup")),
+                ..BackupHost::default()
+            },
+        );
+        Config {
+            snapshots,
+            hosts,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn dest_status_records_success_and_failure() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let source = config.hosts["host1"].sources[0].clone();
+        let _ssh = FakeCommand::new("ssh").unwrap();
+        let pull = pull_cmd();
+        let status = StatusFile::new(None);
+        let status_path = config.snapshots.join("live/host1/etc.status");
+        let read_status = || -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(&status_path).unwrap()).unwrap()
+        };
+
+        {
+            let _rsync = FakeCommand::script("rsync", "exit 0").unwrap();
+            let result = pull.backup_source("host1", &source, &config, false, &status);
+            assert_eq!(result.outcome, SourceOutcome::Succeeded);
+        }
+        let succeeded = read_status();
+        assert!(succeeded["last_attempt"].as_u64().unwrap() > 0);
+        assert_eq!(succeeded["last_success"], succeeded["last_attempt"]);
+        assert!(succeeded["last_duration_secs"].is_f64());
+        assert!(succeeded["last_error"].is_null());
+
+        {
+            let _rsync = FakeCommand::script("rsync", "exit 23").unwrap();
+            let result = pull.backup_source("host1", &source, &config, false, &status);
+            assert!(matches!(result.outcome, SourceOutcome::Failed(_)));
+        }
+        let failed = read_status();
+        assert_eq!(failed["last_success"], succeeded["last_success"]);
+        assert!(
+            failed["last_attempt"].as_u64().unwrap() >= succeeded["last_attempt"].as_u64().unwrap()
+        );
+        assert_eq!(
+            failed["last_error"],
+            "rsync failed with exit status 23 (partial transfer due to error)"
+        );
+    }
+
+    #[test]
+    fn only_source_selects_matching_source() {
+        let host_config = BackupHost {
+            sources: vec![
+                BackupSource {
+                    path: PathBuf::from("/etc"),
+                    ..BackupSource::default()
+                },
+                BackupSource {
+                    path: PathBuf::from("/home"),
+                    ..BackupSource::default()
+                },
+            ],
+            ..BackupHost::default()
+        };
+        let mut pull = pull_cmd();
+
+        let all = pull.selected_sources("host1", &host_config).unwrap();
+        assert_eq!(all.len(), 2);
+
+        pull.only_source = Some(PathBuf::from("/home"));
+        let selected = pull.selected_sources("host1", &host_config).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].path, Path::new("/home"));
+    }
+
+    #[test]
+    fn disabled_sources_are_skipped() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let mut config = hook_config(&dir);
+        let host_config = config.hosts.get_mut("host1").unwrap();
+        host_config.pre_hook = None;
+        host_config.post_hook = None;
+        host_config.sources.push(BackupSource {
+            path: PathBuf::from("/home"),
+            disabled: true,
+            ..BackupSource::default()
+        });
+        let transferred = dir.path().join("transferred");
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", "exit 0").unwrap();
+        let _rsync = FakeCommand::script(
+            "rsync",
+            &format!(
+                "for last; do :; done\necho \"$last\" >> {}",
+                transferred.display()
+            ),
+        )
+        .unwrap();
+
+        let pull = pull_cmd();
+        let report = pull
+            .backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.sources.len(), 1);
+        assert_eq!(report.succeeded(), 1);
+        assert_eq!(report.failed(), 0);
+        let lines = fs::read_to_string(&transferred).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.ends_with("/live/host1/etc\n"), "{}", lines);
+        assert!(!config.snapshots.join("live/host1/home.snapshot").exists());
+    }
+
+    #[test]
+    fn unknown_only_source_is_rejected() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let marker = dir.path().join("ran");
+        let _ssh = FakeCommand::script("ssh", &format!("touch {}", marker.display())).unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+
+        let pull = PullBackupCmd {
+            only_source: Some(PathBuf::from("/var")),
+            ..pull_cmd()
+        };
+        let result = pull.backup_host(
+            "host1",
+            &config,
+            false,
+            OsStr::new("/nonexistent"),
+            &StatusFile::new(None),
+        );
+        assert!(matches!(result, Err(DoppelbackError::InvalidConfig(_))));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn recorded_snapshot_requires_every_source() {
+        let dir = TempDir::new("backup").unwrap();
+        let etc = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        let home = BackupSource {
+            path: PathBuf::from("/home"),
+            ..BackupSource::default()
+        };
+        fs::create_dir_all(dir.path().join("live/host1")).unwrap();
+        fs::write(dir.path().join("live/host1/etc.snapshot"), "20210703.00").unwrap();
+
+        assert_eq!(
+            recorded_snapshot(
+                dir.path(),
+                "host1",
+                &[&etc],
+                snapshots::SNAPSHOT_DATE_FORMAT
+            )
+            .unwrap(),
+            "20210703.00"
+        );
+        assert!(matches!(
+            recorded_snapshot(
+                dir.path(),
+                "host1",
+                &[&etc, &home],
+                snapshots::SNAPSHOT_DATE_FORMAT
+            ),
+            Err(DoppelbackError::InvalidConfig(_))
+        ));
+
+        fs::write(dir.path().join("live/host1/home.snapshot"), "20210704.00\n").unwrap();
+        assert_eq!(
+            recorded_snapshot(
+                dir.path(),
+                "host1",
+                &[&etc, &home],
+                snapshots::SNAPSHOT_DATE_FORMAT
+            )
+            .unwrap(),
+            "20210704.00"
+        );
+    }
+
+    #[test]
+    fn recorded_snapshot_compares_times() {
+        let dir = TempDir::new("backup").unwrap();
+        let sources: Vec<BackupSource> = ["/etc", "/home", "/var"]
+            .iter()
+            .map(|path| BackupSource {
+                path: PathBuf::from(path),
+                ..BackupSource::default()
+            })
+            .collect();
+        let sources: Vec<&BackupSource> = sources.iter().collect();
+        fs::create_dir_all(dir.path().join("live/host1")).unwrap();
+        let record = |names: [&str; 3]| {
+            for (source, name) in ["etc", "home", "var"].iter().zip(names) {
+                fs::write(
+                    dir.path().join(format!("live/host1/{}.snapshot", source)),
+                    name,
+                )
+                .unwrap();
+            }
+            recorded_snapshot(dir.path(), "host1", &sources, "%d%m%Y").unwrap()
+        };
+
+        assert_eq!(
+            record(["05072021.00", "04082021.00", "old-name"]),
+            "04082021.00"
+        );
+        assert_eq!(
+            record(["04082021.09", "04082021.10", "04082021.02"]),
+            "04082021.10"
+        );
+    }
+
+    #[test]
+    fn no_snapshot_skips_snapshot_command() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let snapshot_file = config.snapshots.join("live/host1/etc.snapshot");
+        fs::write(&snapshot_file, "20210704.00\n").unwrap();
+        let marker = dir.path().join("ran");
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+        let _rsync = FakeCommand::script("rsync", "exit 0").unwrap();
+
+        let pull = PullBackupCmd {
+            only_source: Some(PathBuf::from("/etc")),
+            no_snapshot: true,
+            ..pull_cmd()
+        };
+        let report = pull
+            .backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.snapshot, "20210704.00");
+        assert_eq!(report.succeeded(), 1);
+        assert!(!marker.exists());
+        assert_eq!(fs::read_to_string(&snapshot_file).unwrap(), "20210704.00\n");
+    }
+
+    #[test]
+    fn dest_root_leaves_snapshots_alone() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let scratch = dir.path().join("scratch");
+        fs::create_dir(&scratch).unwrap();
+        let marker = dir.path().join("ran");
+        let dest_file = dir.path().join("dest");
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+        let _rsync = FakeCommand::script(
+            "rsync",
+            &format!(
+                "for arg; do dest=$arg; done; echo $dest > {}",
+                dest_file.display()
+            ),
+        )
+        .unwrap();
+
+        let pull = PullBackupCmd {
+            dest_root: Some(scratch.clone()),
+            ..pull_cmd()
+        };
+        let report = pull
+            .backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.succeeded(), 1);
+        assert!(!marker.exists());
+        assert_eq!(
+            fs::read_to_string(&dest_file).unwrap().trim(),
+            scratch.join("live/host1/etc").to_str().unwrap()
+        );
+        assert!(!config.snapshots.join("live/host1/etc.snapshot").exists());
+        assert!(!scratch.join("live/host1/etc.snapshot").exists());
+    }
+
+    fn tree(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                paths.extend(tree(&path));
+            }
+            paths.push(path);
+        }
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn data_changed_needs_transferred_bytes() {
+        let result = |outcome, bytes| SourceResult {
+            path: PathBuf::from("/etc"),
+            outcome,
+            duration: Duration::ZERO,
+            bytes,
+        };
+
+        assert!(!data_changed(&[]));
+        assert!(!data_changed(&[
+            result(SourceOutcome::Succeeded, Some(0)),
+            result(SourceOutcome::Unreachable(String::from("down")), None),
+        ]));
+        assert!(data_changed(&[
+            result(SourceOutcome::Succeeded, Some(0)),
+            result(SourceOutcome::Succeeded, Some(4096)),
+        ]));
+        // Without stats there is no telling what changed.
+        assert!(data_changed(&[result(SourceOutcome::Succeeded, None)]));
+        assert!(data_changed(&[result(
+            SourceOutcome::Failed(String::from("rsync failed")),
+            None
+        )]));
+    }
+
+    #[test]
+    fn snapshot_only_on_change_follows_rsync_stats() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = Config {
+            snapshot_only_on_change: true,
+            ..hook_config(&dir)
+        };
+        let marker = dir.path().join("ran");
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+        let pull = pull_cmd();
+        let backup = || {
+            pull.backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap()
+        };
+
+        let _rsync =
+            FakeCommand::script("rsync", "echo 'Total transferred file size: 0 bytes'").unwrap();
+        let report = backup();
+        assert_eq!(report.snapshot, "");
+        assert!(!marker.exists());
+
+        let _rsync =
+            FakeCommand::script("rsync", "echo 'Total transferred file size: 512 bytes'").unwrap();
+        let report = backup();
+        assert!(!report.snapshot.is_empty());
+        assert!(marker.exists());
+        assert_eq!(
+            fs::read_to_string(config.snapshots.join("live/host1/etc.snapshot")).unwrap(),
+            report.snapshot
+        );
+    }
+
+    /// A btrfs that creates and deletes snapshots as plain directories.
+    const SNAPSHOT_BTRFS: &str =
+        "case $2 in snapshot) mkdir \"$5\" ;; delete) rmdir \"$3\" ;; esac";
+
+    /// Returns the snapshots in `root`, leaving out the live tree.
+    fn snapshot_names(root: &Path) -> Vec<OsString> {
+        fs::read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != "live")
+            .collect()
+    }
+
+    #[test]
+    fn interrupt_before_transfer_deletes_snapshot() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let btrfs = FakeCommand::script("btrfs", SNAPSHOT_BTRFS).unwrap();
+
+        let snapname =
+            take_pending_snapshot(&config.hosts["host1"], &config, &config.snapshots, false)
+                .unwrap();
+        assert_eq!(
+            snapshot_names(&config.snapshots),
+            vec![OsString::from(snapname)]
+        );
+
+        signals::cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+        assert!(snapshot_names(&config.snapshots).is_empty());
+    }
+
+    #[test]
+    fn interrupt_during_transfer_keeps_snapshot() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let started = dir.path().join("started");
+        let finish = dir.path().join("finish");
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let btrfs = FakeCommand::script("btrfs", SNAPSHOT_BTRFS).unwrap();
+        let _rsync = FakeCommand::script(
+            "rsync",
+            &format!(
+                "touch {}; while [ ! -e {} ]; do sleep 0.01; done",
+                started.display(),
+                finish.display()
+            ),
+        )
+        .unwrap();
+
+        let pull = pull_cmd();
+        let status = StatusFile::new(None);
+        thread::scope(|scope| {
+            let backup = scope.spawn(|| {
+                pull.backup_host("host1", &config, false, OsStr::new("/nonexistent"), &status)
+            });
+            while !started.exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            signals::cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+            assert_eq!(snapshot_names(&config.snapshots).len(), 1);
+
+            fs::write(&finish, "").unwrap();
+            assert_eq!(backup.join().unwrap().unwrap().succeeded(), 1);
+        });
+    }
+
+    #[test]
+    fn failed_snapshot_after_backup_keeps_results() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = Config {
+            snapshot_only_on_change: true,
+            ..hook_config(&dir)
+        };
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", "exit 1").unwrap();
+        let _rsync =
+            FakeCommand::script("rsync", "echo 'Total transferred file size: 512 bytes'").unwrap();
+
+        let report = pull_cmd()
+            .backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.succeeded(), 1);
+        assert_eq!(report.sources[0].bytes, Some(512));
+        assert!(report
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("couldn't take the snapshot"));
+
+        let totals = RunTotals::new(&[report], 0, Duration::ZERO);
+        assert_eq!(totals.hosts_failed, 1);
+        assert_eq!(totals.sources_succeeded, 1);
+    }
+
+    #[test]
+    fn dry_run_leaves_snapshots_alone() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let before = tree(&config.snapshots);
+        let marker = dir.path().join("ran");
+        let _ssh = FakeCommand::script("ssh", &format!("touch {}", marker.display())).unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+        let _rsync = FakeCommand::script("rsync", &format!("touch {}", marker.display())).unwrap();
+
+        let pull = pull_cmd();
+        let report = pull
+            .backup_host(
+                "host1",
+                &config,
+                true,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.succeeded(), 1);
+        assert!(!report.snapshot.is_empty());
+        assert!(!marker.exists());
+        assert_eq!(tree(&config.snapshots), before);
+    }
+
+    #[test]
+    fn invalid_destination_only_fails_its_host() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let mut config = hook_config(&dir);
+        let mut host2 = config.hosts["host1"].clone();
+        host2.snapshots = Some(dir.path().join("missing"));
+        config.hosts.insert(String::from("host2"), host2);
+        fs::write(
+            config.snapshots.join("live/host1/etc.snapshot"),
+            "20210704.00\n",
+        )
+        .unwrap();
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _rsync = FakeCommand::script("rsync", "exit 0").unwrap();
+
+        // host1 has its own snapshots, so a missing global directory doesn't affect it.
+        let root = config.snapshots.clone();
+        config.hosts.get_mut("host1").unwrap().snapshots = Some(root.clone());
+        config.snapshots = dir.path().join("missing-global");
+
+        let pull = PullBackupCmd {
+            all: true,
+            no_snapshot: true,
+            ..pull_cmd()
+        };
+        let status = StatusFile::new(None);
+        let locks = LockSet::default();
+        let results = pull.for_each_host(&["host2", "host1"], |host| {
+            pull.run_host(
+                host,
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &status,
+                &locks,
+            )
+        });
+        assert!(matches!(
+            &results[0],
+            Err(DoppelbackError::MissingDir(p)) if p == &dir.path().join("missing")
+        ));
+        assert_eq!(results[1].as_ref().unwrap().succeeded(), 1);
+        assert!(root.join(".doppelback.lock").exists());
+        assert!(!config.snapshots.exists());
+    }
+
+    #[test]
+    fn locked_snapshots_dir_only_fails_its_hosts() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let mut config = hook_config(&dir);
+        let other_root = dir.path().join("other");
+        fs::create_dir_all(other_root.join("live/host2")).unwrap();
+        let mut host2 = config.hosts["host1"].clone();
+        host2.snapshots = Some(other_root.clone());
+        config.hosts.insert(String::from("host2"), host2);
+        fs::write(other_root.join("live/host2/etc.snapshot"), "20210704.00\n").unwrap();
+        let _ssh = FakeCommand::script("ssh", "exit 0").unwrap();
+        let _rsync = FakeCommand::script("rsync", "exit 0").unwrap();
+
+        // Another run is still backing up into host1's snapshots.
+        let _other_run = LockFile::acquire(config.snapshots.join(".doppelback.lock")).unwrap();
+
+        let pull = PullBackupCmd {
+            all: true,
+            no_snapshot: true,
+            ..pull_cmd()
+        };
+        let status = StatusFile::new(None);
+        let locks = LockSet::default();
+        let results = pull.for_each_host(&["host1", "host2"], |host| {
+            pull.run_host(
+                host,
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &status,
+                &locks,
+            )
+        });
+        assert!(matches!(&results[0], Err(DoppelbackError::Locked(_))));
+        assert_eq!(results[1].as_ref().unwrap().succeeded(), 1);
+    }
+
+    #[test]
+    fn failed_pre_hook_aborts_host() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let marker = dir.path().join("ran");
+        let _ssh = FakeCommand::script("ssh", "exit 1").unwrap();
+        let _btrfs = FakeCommand::script("btrfs", &format!("touch {}", marker.display())).unwrap();
+        let _rsync = FakeCommand::script("rsync", &format!("touch {}", marker.display())).unwrap();
+
+        let pull = pull_cmd();
+        let result = pull.backup_host(
+            "host1",
+            &config,
+            false,
+            OsStr::new("/nonexistent"),
+            &StatusFile::new(None),
+        );
+        assert!(matches!(result, Err(DoppelbackError::CommandFailed(_, _))));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn failed_post_hook_is_not_fatal() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let hooks = dir.path().join("hooks");
+        let _ssh = FakeCommand::script(
+            "ssh",
+            &format!(
+                "for last; do :; done\necho \"$last\" >> {}\n\
+                 [ \"$last\" != /usr/local/bin/cleanup ]",
+                hooks.display()
+            ),
+        )
+        .unwrap();
+        let _btrfs = FakeCommand::script("btrfs", "exit 0").unwrap();
+        let _rsync = FakeCommand::script("rsync", "exit 0").unwrap();
+
+        let pull = pull_cmd();
+        let report = pull
+            .backup_host(
+                "host1",
+                &config,
+                false,
+                OsStr::new("/nonexistent"),
+                &StatusFile::new(None),
+            )
+            .unwrap();
+        assert_eq!(report.succeeded(), 1);
+        assert_eq!(
+            fs::read_to_string(&hooks).unwrap(),
+            "/usr/local/bin/dump-db\n/usr/local/bin/cleanup\n"
+        );
+    }
+
+    #[test]
+    fn hooks_are_skipped_in_dry_run() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("backup").unwrap();
+        let config = hook_config(&dir);
+        let _ssh = FakeCommand::script("ssh", "exit 1").unwrap();
+
+        let host_config = config.hosts.get("host1").unwrap();
+        assert!(run_hook(
+            "host1",
+            host_config,
+            "pre",
+            "/usr/local/bin/dump-db",
+            OsStr::new("/nonexistent"),
+            true
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn run_parallel_preserves_order() {
+        let items: Vec<u32> = (0..10).collect();
+        let results = run_parallel(&items, 3, |i| i * 2);
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn run_parallel_limits_concurrency() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let items: Vec<u32> = (0..8).collect();
+
+        let results = run_parallel(&items, 2, |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            true
+        });
+
+        assert_eq!(results.len(), 8);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    fn parallel_pull(parallel_hosts: usize) -> PullBackupCmd {
+        PullBackupCmd {
+            all: true,
+            parallel_hosts,
+            ..pull_cmd()
+        }
+    }
+
+    #[test]
+    fn for_each_host_runs_n_at_a_time() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let hosts = ["host1", "host2", "host3", "host4", "host5"];
+
+        let results = parallel_pull(2).for_each_host(&hosts, |host| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            if host == "host3" {
+                Err(format!("{} failed", host))
+            } else {
+                Ok(log_context::host())
+            }
+        });
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Some(String::from("host1"))),
+                Ok(Some(String::from("host2"))),
+                Err(String::from("host3 failed")),
+                Ok(Some(String::from("host4"))),
+                Ok(Some(String::from("host5"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn except_removes_hosts() {
+        let mut hosts = HashMap::new();
+        for host in ["host1", "host2", "host3"] {
+            hosts.insert(String::from(host), BackupHost::default());
+        }
+        let config = Config {
+            hosts,
+            ..Config::default()
+        };
+
+        let mut pull = parallel_pull(1);
+        let mut selected = pull.selected_hosts(&config).unwrap();
+        selected.sort_unstable();
+        assert_eq!(selected, vec!["host1", "host2", "host3"]);
+
+        pull.except = vec![String::from("host1"), String::from("host3")];
+        assert_eq!(pull.selected_hosts(&config).unwrap(), vec!["host2"]);
+
+        pull.except = vec![String::from("host2"), String::from("host4")];
+        let err = pull.selected_hosts(&config).unwrap_err();
+        assert!(err.to_string().contains("host4"), "{}", err);
+    }
+
+    #[test]
+    fn all_skips_manual_hosts() {
+        let mut hosts = HashMap::new();
+        for (host, auto) in [
+            ("host1", None),
+            ("host2", Some(false)),
+            ("host3", Some(true)),
+        ] {
+            hosts.insert(
+                String::from(host),
+                BackupHost {
+                    auto,
+                    ..BackupHost::default()
+                },
+            );
+        }
+        let config = Config {
+            hosts,
+            ..Config::default()
+        };
+
+        let mut pull = parallel_pull(1);
+        let mut selected = pull.selected_hosts(&config).unwrap();
+        selected.sort_unstable();
+        assert_eq!(selected, vec!["host1", "host3"]);
+
+        pull.except = vec![String::from("host1")];
+        assert_eq!(pull.selected_hosts(&config).unwrap(), vec!["host3"]);
+    }
+
+    #[test]
+    fn for_each_host_serial_has_no_log_context() {
+        let hosts = ["host1", "host2"];
+        let results = parallel_pull(1).for_each_host(&hosts, |_| log_context::host());
+        assert_eq!(results, vec![None, None]);
+    }
+
+    #[test]
+    fn run_parallel_single_job_is_serial() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let items: Vec<u32> = (0..4).collect();
+
+        let failed = run_parallel(&items, 1, |i| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            running.fetch_sub(1, Ordering::SeqCst);
+            i % 2 == 0
+        })
+        .iter()
+        .filter(|ok| !**ok)
+        .count();
+
+        assert_eq!(failed, 2);
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
 }
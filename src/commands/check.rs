@@ -0,0 +1,230 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::backup::fmt_duration;
+use crate::config::{BackupDest, Config};
+use crate::doppelback_error::DoppelbackError;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct CheckCmd {
+    /// Only check this source of the host.  All sources are checked if omitted.
+    #[structopt(long, parse(from_os_str))]
+    source: Option<PathBuf>,
+
+    /// Report a warning if the last backup started more than this many hours ago.
+    #[structopt(long, default_value = "26")]
+    warn: u64,
+
+    /// Report a critical problem if the last backup started more than this many hours ago.
+    #[structopt(long, default_value = "50")]
+    crit: u64,
+}
+
+/// Result of a freshness check, ordered from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl CheckStatus {
+    /// Returns the exit code monitoring plugins use for this status.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            CheckStatus::Ok => 0,
+            CheckStatus::Warning => 1,
+            CheckStatus::Critical => 2,
+            CheckStatus::Unknown => 3,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "OK",
+            CheckStatus::Warning => "WARNING",
+            CheckStatus::Critical => "CRITICAL",
+            CheckStatus::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+impl CheckCmd {
+    /// Checks how long ago `host` was last backed up.
+    ///
+    /// The age of each source is taken from the mtime of its `.snapshot` companion file in the
+    /// live tree, which pull-backup writes just before transferring it.  The oldest source
+    /// determines the result, and a source that has never been backed up is critical.  Disabled
+    /// sources are only checked if they are picked with `--source`.  Returns
+    /// the status and a one-line description.
+    pub fn check(
+        &self,
+        config: &Config,
+        host: &str,
+    ) -> Result<(CheckStatus, String), DoppelbackError> {
+        self.check_at(config, host, SystemTime::now())
+    }
+
+    fn check_at(
+        &self,
+        config: &Config,
+        host: &str,
+        now: SystemTime,
+    ) -> Result<(CheckStatus, String), DoppelbackError> {
+        let host_config = config.hosts.get(host).expect("host already checked");
+        let sources: Vec<_> = match &self.source {
+            Some(path) => vec![host_config.get_source(path).ok_or_else(|| {
+                DoppelbackError::InvalidConfig(format!(
+                    "source {} not found for {}",
+                    path.display(),
+                    host
+                ))
+            })?],
+            None => host_config.sources.iter().filter(|s| !s.disabled).collect(),
+        };
+        if sources.is_empty() {
+            return Err(DoppelbackError::InvalidConfig(format!(
+                "no sources for {}",
+                host
+            )));
+        }
+
+        let mut oldest: Option<(Option<Duration>, PathBuf)> = None;
+        for source in sources {
+            let companion = BackupDest::new(config.snapshot_root(host), host, source)
+                .get_companion_file("snapshot");
+            let age = match fs::metadata(&companion) {
+                Ok(metadata) => Some(
+                    now.duration_since(metadata.modified()?)
+                        .unwrap_or(Duration::ZERO),
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            let is_older = match &oldest {
+                None => true,
+                Some((oldest_age, _)) => older_than(age, *oldest_age),
+            };
+            if is_older {
+                oldest = Some((age, source.path.clone()));
+            }
+        }
+
+        let (age, path) = oldest.expect("at least one source");
+        let status = self.status_for_age(age);
+        let message = match age {
+            Some(age) => format!(
+                "{}:{} last backed up {} ago",
+                host,
+                path.display(),
+                fmt_duration(age)
+            ),
+            None => format!("{}:{} has never been backed up", host, path.display()),
+        };
+        Ok((status, message))
+    }
+
+    fn status_for_age(&self, age: Option<Duration>) -> CheckStatus {
+        match age {
+            None => CheckStatus::Critical,
+            Some(age) if age > Duration::from_secs(self.crit * 3600) => CheckStatus::Critical,
+            Some(age) if age > Duration::from_secs(self.warn * 3600) => CheckStatus::Warning,
+            Some(_) => CheckStatus::Ok,
+        }
+    }
+}
+
+/// Returns whether `a` is older than `b`, where `None` means never backed up.
+fn older_than(a: Option<Duration>, b: Option<Duration>) -> bool {
+    match (a, b) {
+        (None, _) => b.is_some(),
+        (Some(_), None) => false,
+        (Some(a), Some(b)) => a > b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackupHost, BackupSource};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    fn check_cmd(source: Option<&str>) -> CheckCmd {
+        CheckCmd {
+            source: source.map(PathBuf::from),
+            warn: 26,
+            crit: 50,
+        }
+    }
+
+    #[test]
+    fn thresholds_map_to_exit_codes() {
+        let cmd = check_cmd(None);
+        let hours = |h: u64| Some(Duration::from_secs(h * 3600));
+
+        assert_eq!(cmd.status_for_age(hours(1)).exit_code(), 0);
+        assert_eq!(cmd.status_for_age(hours(26)).exit_code(), 0);
+        assert_eq!(cmd.status_for_age(hours(27)).exit_code(), 1);
+        assert_eq!(cmd.status_for_age(hours(50)).exit_code(), 1);
+        assert_eq!(cmd.status_for_age(hours(51)).exit_code(), 2);
+        assert_eq!(cmd.status_for_age(None).exit_code(), 2);
+        assert_eq!(CheckStatus::Unknown.exit_code(), 3);
+    }
+
+    #[test]
+    fn oldest_source_decides() {
+        let root = TempDir::new("check").unwrap();
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                sources: vec![
+                    BackupSource {
+                        path: PathBuf::from("/etc"),
+                        ..BackupSource::default()
+                    },
+                    BackupSource {
+                        path: PathBuf::from("/home"),
+                        ..BackupSource::default()
+                    },
+                ],
+                ..BackupHost::default()
+            },
+        );
+        let config = Config {
+            snapshots: root.path().to_path_buf(),
+            hosts,
+            ..Config::default()
+        };
+        fs::create_dir_all(root.path().join("live/host1")).unwrap();
+        fs::write(root.path().join("live/host1/etc.snapshot"), "20210704.00").unwrap();
+
+        let (status, message) = check_cmd(Some("/etc")).check(&config, "host1").unwrap();
+        assert_eq!(status, CheckStatus::Ok);
+        assert!(
+            message.starts_with("host1:/etc last backed up"),
+            "{}",
+            message
+        );
+
+        let (status, message) = check_cmd(None).check(&config, "host1").unwrap();
+        assert_eq!(status, CheckStatus::Critical);
+        assert_eq!(message, "host1:/home has never been backed up");
+
+        fs::write(root.path().join("live/host1/home.snapshot"), "20210701.00").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(30 * 3600);
+        let (status, message) = check_cmd(None).check_at(&config, "host1", later).unwrap();
+        assert_eq!(status, CheckStatus::Warning);
+        assert!(message.contains("last backed up 1d06h"), "{}", message);
+
+        assert!(check_cmd(Some("/var")).check(&config, "host1").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use structopt::StructOpt;
+
+/// Starting config printed by `generate-config`.  See sample.yaml for every available setting.
+const SAMPLE_CONFIG: &str = r#"# doppelback config.  See sample.yaml in the doppelback source for every
+# available setting.
+
+# `snapshots` must be a path on the backup server where snapshots will be
+# stored.  Must contain a "live" subdirectory.
+snapshots: /srv/backups/snapshots
+
+# `hosts` is a set of machines to back up.  The key is the name of the machine,
+# and the value is the configuration for that particular host.
+hosts:
+  host1.example.com:
+    # `user` is the user on the remote machine.
+    user: backup
+
+    # `port` is the remote port to use for ssh connections.  Defaults to
+    # 22 if omitted.
+    port: 22
+
+    # `key` is the ssh key used to connect to this host.  It can be an
+    # absolute path or the name of a file under ~/.ssh.
+    key: id_ed25519_host1_backup
+
+    # `sources` is a list of backup sources on this machine.
+    #   * path: Absolute path to be backed up.
+    #   * root: Doppelback will run rsync as root to access `path` if this is
+    #           true.
+    sources:
+      - path: /etc
+        root: true
+      - path: /home/user
+        root: false
+"#;
+
+#[derive(Debug, StructOpt)]
+pub struct GenerateConfigCmd {}
+
+impl GenerateConfigCmd {
+    /// Prints a commented starting config to stdout.
+    pub fn print_sample(&self) {
+        print!("{}", SAMPLE_CONFIG);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn sample_config_parses() {
+        let dir = TempDir::new("config").unwrap();
+        let file = dir.path().join("doppelback.yaml");
+        fs::write(&file, SAMPLE_CONFIG).unwrap();
+
+        let config = Config::load(&file).unwrap();
+        assert_eq!(config.snapshots, Path::new("/srv/backups/snapshots"));
+        let host = &config.hosts["host1.example.com"];
+        assert_eq!(host.user, "backup");
+        assert_eq!(host.port, Some(22));
+        assert_eq!(host.key, Path::new("id_ed25519_host1_backup"));
+        assert!(host.check_options().is_ok());
+        assert_eq!(host.sources.len(), 2);
+        assert!(host.sources[0].root);
+        assert!(!host.sources[1].root);
+        for source in &host.sources {
+            assert!(source.check_options().is_ok());
+        }
+        assert!(host.find_overlapping_sources().is_none());
+    }
+}
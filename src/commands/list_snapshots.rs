@@ -0,0 +1,289 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::snapshots;
+use crate::config::{BackupDest, Config};
+use crate::doppelback_error::DoppelbackError;
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ListSnapshotsCmd {
+    /// Print the results as JSON instead of columns.
+    #[structopt(long)]
+    json: bool,
+
+    #[structopt(flatten)]
+    range: snapshots::DateRange,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SnapshotListing {
+    date: String,
+    index: u32,
+    name: String,
+    sources: Vec<SourceListing>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SourceListing {
+    host: String,
+    path: PathBuf,
+
+    /// Snapshot name recorded in the source's `.snapshot` companion file, if present.
+    recorded: Option<String>,
+}
+
+impl ListSnapshotsCmd {
+    /// Prints the snapshots under each snapshots directory in the config, newest first.
+    ///
+    /// Hosts with their own `snapshots` are listed with the snapshots in that directory.  If
+    /// `host` is given, only sources for that host are included.  The text output has one line
+    /// per snapshot and source with the snapshot name, the recorded name (or `-`), and
+    /// `host:path`, so it can be split on whitespace by scripts.
+    pub fn list_snapshots(
+        &self,
+        config: &Config,
+        host: Option<&str>,
+    ) -> Result<(), DoppelbackError> {
+        let listings = get_listings(config, host, &self.range)?;
+
+        if self.json {
+            let json = serde_json::to_string_pretty(&listings).map_err(io::Error::from)?;
+            println!("{}", json);
+            return Ok(());
+        }
+
+        for snapshot in &listings {
+            for source in &snapshot.sources {
+                println!(
+                    "{:<11} {:<11} {}:{}",
+                    snapshot.name,
+                    source.recorded.as_deref().unwrap_or("-"),
+                    source.host,
+                    source.path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_listings(
+    config: &Config,
+    host: Option<&str>,
+    range: &snapshots::DateRange,
+) -> Result<Vec<SnapshotListing>, DoppelbackError> {
+    let mut hosts: Vec<_> = config
+        .hosts
+        .iter()
+        .filter(|(name, _)| host.is_none() || host == Some(name.as_str()))
+        .collect();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut listings = Vec::new();
+    for root in config.snapshot_roots() {
+        let root_hosts: Vec<_> = hosts
+            .iter()
+            .filter(|(host_name, _)| config.snapshot_root(host_name) == root)
+            .collect();
+        if root_hosts.is_empty() {
+            continue;
+        }
+
+        for snapshot in snapshots::find_snapshots(root, config.snapshot_name_format())? {
+            if !range.contains(snapshot.date()) {
+                continue;
+            }
+            let mut sources = Vec::new();
+            for (host_name, host_config) in &root_hosts {
+                for source in &host_config.sources {
+                    let dest = BackupDest::for_snapshot(root, &snapshot.name, host_name, source);
+                    let recorded = fs::read_to_string(dest.get_companion_file("snapshot"))
+                        .ok()
+                        .map(|s| s.trim().to_string());
+                    sources.push(SourceListing {
+                        host: host_name.to_string(),
+                        path: source.path.clone(),
+                        recorded,
+                    });
+                }
+            }
+
+            listings.push(SnapshotListing {
+                date: snapshot.date().format("%Y-%m-%d").to_string(),
+                index: snapshot.index,
+                name: snapshot.name,
+                sources,
+            });
+        }
+    }
+    Ok(listings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackupHost, BackupSource};
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    fn test_config(root: &TempDir) -> Config {
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                sources: vec![source.clone()],
+                ..BackupHost::default()
+            },
+        );
+        hosts.insert(
+            String::from("host2"),
+            BackupHost {
+                sources: vec![source],
+                ..BackupHost::default()
+            },
+        );
+        Config {
+            snapshots: root.path().to_path_buf(),
+            hosts,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn listings_are_newest_first_with_recorded_names() {
+        let root = TempDir::new("snapshots").unwrap();
+        let config = test_config(&root);
+        fs::create_dir_all(root.path().join("live")).unwrap();
+        fs::create_dir_all(root.path().join("20210703.00")).unwrap();
+        fs::create_dir_all(root.path().join("20210704.00/host1")).unwrap();
+        fs::write(
+            root.path().join("20210704.00/host1/etc.snapshot"),
+            "20210703.00\n",
+        )
+        .unwrap();
+
+        let listings =
+            get_listings(&config, Some("host1"), &snapshots::DateRange::default()).unwrap();
+        assert_eq!(
+            listings,
+            vec![
+                SnapshotListing {
+                    date: String::from("2021-07-04"),
+                    index: 0,
+                    name: String::from("20210704.00"),
+                    sources: vec![SourceListing {
+                        host: String::from("host1"),
+                        path: PathBuf::from("/etc"),
+                        recorded: Some(String::from("20210703.00")),
+                    }],
+                },
+                SnapshotListing {
+                    date: String::from("2021-07-03"),
+                    index: 0,
+                    name: String::from("20210703.00"),
+                    sources: vec![SourceListing {
+                        host: String::from("host1"),
+                        path: PathBuf::from("/etc"),
+                        recorded: None,
+                    }],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn listings_include_all_hosts_in_order() {
+        let root = TempDir::new("snapshots").unwrap();
+        let config = test_config(&root);
+        fs::create_dir_all(root.path().join("20210704.00")).unwrap();
+
+        let listings = get_listings(&config, None, &snapshots::DateRange::default()).unwrap();
+        let hosts: Vec<_> = listings[0].sources.iter().map(|s| s.host.clone()).collect();
+        assert_eq!(hosts, vec!["host1", "host2"]);
+    }
+
+    #[test]
+    fn listings_use_each_hosts_snapshots() {
+        let root = TempDir::new("snapshots").unwrap();
+        let other = TempDir::new("snapshots").unwrap();
+        let mut config = test_config(&root);
+        config.hosts.get_mut("host2").unwrap().snapshots = Some(other.path().to_path_buf());
+        fs::create_dir_all(root.path().join("20210704.00")).unwrap();
+        fs::create_dir_all(other.path().join("20210705.00")).unwrap();
+
+        let listings = get_listings(&config, None, &snapshots::DateRange::default()).unwrap();
+        let found: Vec<_> = listings
+            .iter()
+            .map(|l| (l.name.as_str(), l.sources[0].host.as_str(), l.sources.len()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![("20210704.00", "host1", 1), ("20210705.00", "host2", 1)]
+        );
+
+        let listings =
+            get_listings(&config, Some("host2"), &snapshots::DateRange::default()).unwrap();
+        let names: Vec<_> = listings.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["20210705.00"]);
+    }
+
+    #[test]
+    fn listings_are_filtered_by_date() {
+        let root = TempDir::new("snapshots").unwrap();
+        let config = test_config(&root);
+        for name in ["20210702.00", "20210703.00", "20210704.00", "20210705.00"] {
+            fs::create_dir_all(root.path().join(name)).unwrap();
+        }
+
+        let range = snapshots::DateRange {
+            since: NaiveDate::from_ymd_opt(2021, 7, 3),
+            until: NaiveDate::from_ymd_opt(2021, 7, 4),
+        };
+        let listings = get_listings(&config, Some("host1"), &range).unwrap();
+        let names: Vec<_> = listings.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["20210704.00", "20210703.00"]);
+    }
+
+    #[test]
+    fn listings_use_name_format() {
+        let root = TempDir::new("snapshots").unwrap();
+        let config = Config {
+            snapshot_name_format: Some(String::from("%Y%m%d%H")),
+            ..test_config(&root)
+        };
+        for name in ["live", "2021070409.00", "2021070413.00", "20210703.00"] {
+            fs::create_dir_all(root.path().join(name)).unwrap();
+        }
+
+        let listings =
+            get_listings(&config, Some("host1"), &snapshots::DateRange::default()).unwrap();
+        let names: Vec<_> = listings.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["2021070413.00", "2021070409.00"]);
+        assert_eq!(listings[0].date, "2021-07-04");
+    }
+
+    #[test]
+    fn listings_serialize_to_json() {
+        let listing = SnapshotListing {
+            date: String::from("2021-07-04"),
+            index: 1,
+            name: String::from("20210704.01"),
+            sources: vec![],
+        };
+        assert_eq!(
+            serde_json::to_string(&vec![listing]).unwrap(),
+            r#"[{"date":"2021-07-04","index":1,"name":"20210704.01","sources":[]}]"#
+        );
+    }
+}
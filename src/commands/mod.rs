@@ -2,7 +2,15 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 pub mod backup;
+pub mod check;
+pub mod generate_config;
+pub mod list_snapshots;
+pub mod orphans;
+pub mod prune;
+pub mod restore;
 pub mod rsync;
+pub mod sizes;
 pub mod snapshots;
 pub mod ssh;
 pub mod sudo;
+pub mod test_ssh;
@@ -0,0 +1,191 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::config::{BackupDest, BackupHost, Config};
+use crate::doppelback_error::DoppelbackError;
+
+use log::info;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct OrphansCmd {
+    /// Delete the orphaned entries instead of only listing them.  Respects --dry-run.
+    #[structopt(long)]
+    delete: bool,
+}
+
+impl OrphansCmd {
+    /// Lists the entries in `host`'s live directory that don't belong to any configured source.
+    ///
+    /// Each source's backup directory and companion files are named after the source's path, so
+    /// anything left behind by a source that was removed from the config can be found by name.
+    /// Disabled sources still count as configured.  With --delete, the orphans are removed from
+    /// the live tree.  Earlier snapshots still hold their last backup.  Returns the number of
+    /// orphaned sources found.
+    pub fn find_orphans(
+        &self,
+        config: &Config,
+        host: &str,
+        dry_run: bool,
+    ) -> Result<usize, DoppelbackError> {
+        let host_config = config.hosts.get(host).expect("host already checked");
+        let host_dir = config.snapshot_root(host).join("live").join(host);
+
+        let mut entries = Vec::new();
+        match fs::read_dir(&host_dir) {
+            Ok(dir) => {
+                for entry in dir {
+                    entries.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+            }
+            // Nothing has been backed up for this host yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let orphans = orphaned_entries(host_config, entries);
+        for (name, entries) in &orphans {
+            println!("{}:{}", host, name);
+            for entry in entries {
+                let path = host_dir.join(entry);
+                println!("    {}", path.display());
+                if !self.delete {
+                    continue;
+                }
+                if dry_run {
+                    info!("Would delete {}", path.display());
+                    continue;
+                }
+                info!("Deleting {}", path.display());
+                remove_entry(&path)?;
+            }
+        }
+        Ok(orphans.len())
+    }
+}
+
+/// Groups the `entries` of a host's live directory that don't belong to any of its sources by the
+/// source name they were created for.
+///
+/// Source names never contain a `.`, so an entry's source name is everything before its first
+/// `.`.  That covers both the backup directory and companion files like `etc.snapshot`.
+fn orphaned_entries<I>(host_config: &BackupHost, entries: I) -> BTreeMap<String, Vec<String>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let configured: HashSet<String> = host_config
+        .sources
+        .iter()
+        .map(|source| BackupDest::get_safe_name(&source.path))
+        .collect();
+
+    let mut orphans: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for entry in entries {
+        let name = entry.split('.').next().unwrap_or_default();
+        if !configured.contains(name) {
+            orphans.entry(name.to_string()).or_default().push(entry);
+        }
+    }
+    for entries in orphans.values_mut() {
+        entries.sort();
+    }
+    orphans
+}
+
+fn remove_entry(path: &Path) -> Result<(), DoppelbackError> {
+    // Don't follow a symlink into a directory that doesn't belong to the backup.
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupSource;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    fn host_with_sources(paths: &[&str]) -> BackupHost {
+        BackupHost {
+            sources: paths
+                .iter()
+                .map(|path| BackupSource {
+                    path: PathBuf::from(path),
+                    disabled: *path == "/srv",
+                    ..BackupSource::default()
+                })
+                .collect(),
+            ..BackupHost::default()
+        }
+    }
+
+    #[test]
+    fn orphans_are_grouped_by_source_name() {
+        let host_config = host_with_sources(&["/etc", "/home/user", "/", "/srv"]);
+        let entries = [
+            "etc",
+            "etc.snapshot",
+            "home_user",
+            "home_user.exclude",
+            "rootfs",
+            "srv",
+            "var_lib",
+            "var_lib.snapshot",
+            "opt.exclude",
+        ]
+        .iter()
+        .map(|e| e.to_string());
+
+        let orphans = orphaned_entries(&host_config, entries);
+        let expected: BTreeMap<_, _> = [
+            ("opt", vec!["opt.exclude"]),
+            ("var_lib", vec!["var_lib", "var_lib.snapshot"]),
+        ]
+        .iter()
+        .map(|(name, entries)| {
+            (
+                name.to_string(),
+                entries.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            )
+        })
+        .collect();
+        assert_eq!(orphans, expected);
+    }
+
+    #[test]
+    fn delete_removes_orphans_unless_dry_run() {
+        let root = TempDir::new("orphans").unwrap();
+        let host_dir = root.path().join("live/host1");
+        fs::create_dir_all(host_dir.join("etc")).unwrap();
+        fs::create_dir_all(host_dir.join("var_lib/data")).unwrap();
+        fs::write(host_dir.join("var_lib.snapshot"), "20210704.00").unwrap();
+
+        let mut hosts = HashMap::new();
+        hosts.insert(String::from("host1"), host_with_sources(&["/etc"]));
+        let config = Config {
+            snapshots: root.path().to_path_buf(),
+            hosts,
+            ..Config::default()
+        };
+
+        let cmd = OrphansCmd { delete: true };
+        assert_eq!(cmd.find_orphans(&config, "host1", true).unwrap(), 1);
+        assert!(host_dir.join("var_lib").exists());
+
+        assert_eq!(cmd.find_orphans(&config, "host1", false).unwrap(), 1);
+        assert!(!host_dir.join("var_lib").exists());
+        assert!(!host_dir.join("var_lib.snapshot").exists());
+        assert!(host_dir.join("etc").exists());
+
+        assert_eq!(cmd.find_orphans(&config, "host1", false).unwrap(), 0);
+    }
+}
@@ -0,0 +1,450 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::snapshots::{self, Snapshot};
+use crate::doppelback_error::DoppelbackError;
+use crate::process_util;
+
+use chrono::{Datelike, Local, NaiveDate};
+use log::{debug, error, info};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct PruneCmd {
+    /// Keep the newest snapshot from each of this many days.
+    #[structopt(long, default_value = "7")]
+    keep_daily: u32,
+
+    /// After the daily snapshots, keep the newest snapshot from each of this many weeks.
+    #[structopt(long, default_value = "4")]
+    keep_weekly: u32,
+
+    /// After the weekly snapshots, keep the newest snapshot from each of this many months.
+    #[structopt(long, default_value = "12")]
+    keep_monthly: u32,
+
+    /// Keep only this many of the newest snapshots and ignore the daily, weekly, and monthly
+    /// settings.  Must be at least 1.
+    #[structopt(long)]
+    keep_last: Option<usize>,
+
+    /// Only delete snapshots within this range.  Snapshots outside it are still counted by the
+    /// retention policy.
+    #[structopt(flatten)]
+    range: snapshots::DateRange,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Bucket {
+    Day(NaiveDate),
+    Week(i32, u32),
+    Month(i32, u32),
+}
+
+impl PruneCmd {
+    /// Deletes the snapshots in `snapshots` that aren't needed by the retention policy.
+    ///
+    /// Returns the number of snapshots deleted (or that would have been deleted for a dry run).
+    /// Snapshots are recognized by names in the chrono `format` used to create them.
+    pub fn prune<P: AsRef<Path>>(
+        &self,
+        snapshots: P,
+        format: &str,
+        btrfs: &Path,
+        dry_run: bool,
+    ) -> Result<usize, DoppelbackError> {
+        if self.keep_last == Some(0) {
+            return Err(DoppelbackError::IoError(Error::new(
+                ErrorKind::InvalidInput,
+                "--keep-last 0 would delete every snapshot",
+            )));
+        }
+
+        let found = snapshots::find_snapshots(snapshots.as_ref(), format)?;
+        let today = Local::now().date_naive();
+        let expired = match self.keep_last {
+            Some(keep) => self.select_beyond_last(&found, keep),
+            None => self.select_expired(&found, today),
+        };
+        if expired.is_empty() {
+            info!("No snapshots to prune");
+            return Ok(0);
+        }
+
+        for snap in &expired {
+            let snap_dir = snapshots.as_ref().join(&snap.name);
+            let subvolumes = snapshot_subvolumes(&snap_dir)?;
+            if dry_run {
+                info!("Would delete snapshot {}", snap.name);
+            } else {
+                info!("Deleting snapshot {}", snap.name);
+            }
+            for subvolume in &subvolumes {
+                let command = get_delete_command(btrfs, subvolume);
+                debug!("Prune command: {:?}", &command);
+                if dry_run {
+                    process_util::print_dry_run(&command);
+                    continue;
+                }
+
+                let child = process::Command::new(&command[0])
+                    .args(&command[1..])
+                    .current_dir("/")
+                    .output()?;
+                if !child.status.success() {
+                    error!(
+                        "{:?} failed: {}",
+                        btrfs,
+                        String::from_utf8_lossy(&child.stderr)
+                    );
+                    return Err(DoppelbackError::CommandFailed(
+                        btrfs.to_path_buf(),
+                        child.status,
+                    ));
+                }
+            }
+            // A per-host snapshot leaves the directory that held the host subvolumes.
+            if !dry_run && subvolumes != [snap_dir.clone()] {
+                fs::remove_dir(&snap_dir)?;
+            }
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Picks which of `found` (sorted newest first) fall outside the retention policy.
+    ///
+    /// Each snapshot is assigned to a daily, weekly, or monthly bucket depending on its age, and
+    /// only the newest snapshot in each bucket is kept.  The newest snapshot overall is always
+    /// kept, even if it has aged out of every bucket.  Snapshots outside `range` are never
+    /// selected, but still fill their buckets.
+    fn select_expired<'a>(&self, found: &'a [Snapshot], today: NaiveDate) -> Vec<&'a Snapshot> {
+        let mut seen = HashSet::new();
+        let mut expired = Vec::new();
+        for (i, snap) in found.iter().enumerate() {
+            let date = snap.date();
+            let age_days = (today - date).num_days();
+            let age_months = (today.year() * 12 + today.month() as i32)
+                - (date.year() * 12 + date.month() as i32);
+
+            let bucket = if age_days < self.keep_daily as i64 {
+                Some(Bucket::Day(date))
+            } else if age_days < (self.keep_daily as i64 + self.keep_weekly as i64 * 7) {
+                let week = date.iso_week();
+                Some(Bucket::Week(week.year(), week.week()))
+            } else if age_months < self.keep_monthly as i32 {
+                Some(Bucket::Month(date.year(), date.month()))
+            } else {
+                None
+            };
+
+            let keep = match bucket {
+                Some(b) => seen.insert(b),
+                None => false,
+            };
+            if !keep && i > 0 && self.range.contains(date) {
+                expired.push(snap);
+            }
+        }
+        expired
+    }
+
+    /// Picks which of `found` (sorted newest first) come after the newest `keep`.
+    ///
+    /// Snapshots outside `range` are never selected, but still count towards `keep`.
+    fn select_beyond_last<'a>(&self, found: &'a [Snapshot], keep: usize) -> Vec<&'a Snapshot> {
+        found
+            .iter()
+            .skip(keep)
+            .filter(|snap| self.range.contains(snap.date()))
+            .collect()
+    }
+}
+
+/// Returns the subvolumes to delete to remove the snapshot at `dir`.
+///
+/// A snapshot taken with `make-snapshot --host` is a plain directory holding a subvolume for each
+/// host, which have to be deleted one by one.  Anything else is deleted as a whole, so btrfs
+/// reports it if it isn't a subvolume after all.
+fn snapshot_subvolumes(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if snapshots::is_btrfs_subvolume(dir)? {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+
+    let mut subvolumes = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && snapshots::is_btrfs_subvolume(entry.path())? {
+            subvolumes.push(entry.path());
+        }
+    }
+    if subvolumes.is_empty() {
+        subvolumes.push(dir.to_path_buf());
+    }
+    subvolumes.sort();
+    Ok(subvolumes)
+}
+
+pub fn get_delete_command(btrfs: &Path, snapshot: &Path) -> Vec<OsString> {
+    vec![
+        btrfs.as_os_str().to_os_string(),
+        OsString::from("subvolume"),
+        OsString::from("delete"),
+        snapshot.as_os_str().to_os_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn snapshot(name: &str) -> Snapshot {
+        let (time, index) =
+            snapshots::parse_snapshot_name(name, snapshots::SNAPSHOT_DATE_FORMAT).unwrap();
+        Snapshot {
+            time,
+            index,
+            name: name.to_string(),
+        }
+    }
+
+    fn expired_names(cmd: &PruneCmd, names: &[&str], today: NaiveDate) -> Vec<String> {
+        let mut found: Vec<_> = names.iter().map(|n| snapshot(n)).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        cmd.select_expired(&found, today)
+            .iter()
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn daily_keeps_newest_per_day() {
+        let cmd = PruneCmd {
+            keep_daily: 7,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_last: None,
+            range: snapshots::DateRange::default(),
+        };
+        let today = NaiveDate::from_ymd_opt(2021, 7, 10).unwrap();
+        let expired = expired_names(
+            &cmd,
+            &["20210710.00", "20210709.00", "20210709.01", "20210701.00"],
+            today,
+        );
+        assert_eq!(expired, vec!["20210709.00", "20210701.00"]);
+    }
+
+    #[test]
+    fn weekly_and_monthly_buckets() {
+        let cmd = PruneCmd {
+            keep_daily: 1,
+            keep_weekly: 2,
+            keep_monthly: 3,
+            keep_last: None,
+            range: snapshots::DateRange::default(),
+        };
+        let today = NaiveDate::from_ymd_opt(2021, 7, 31).unwrap();
+        let expired = expired_names(
+            &cmd,
+            &[
+                "20210731.00",
+                // Same ISO week (Mon 2021-07-26).
+                "20210729.00",
+                "20210727.00",
+                // Previous week.
+                "20210721.00",
+                // Monthly range.
+                "20210615.00",
+                "20210601.00",
+                "20210520.00",
+                // Too old.
+                "20210410.00",
+            ],
+            today,
+        );
+        assert_eq!(expired, vec!["20210727.00", "20210601.00", "20210410.00"]);
+    }
+
+    #[test]
+    fn newest_is_always_kept() {
+        let cmd = PruneCmd {
+            keep_daily: 0,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_last: None,
+            range: snapshots::DateRange::default(),
+        };
+        let today = NaiveDate::from_ymd_opt(2021, 7, 31).unwrap();
+        let expired = expired_names(&cmd, &["20200101.00", "20190101.00"], today);
+        assert_eq!(expired, vec!["20190101.00"]);
+    }
+
+    #[test]
+    fn range_limits_deletions_but_not_buckets() {
+        let cmd = PruneCmd {
+            keep_daily: 7,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_last: None,
+            range: snapshots::DateRange {
+                since: NaiveDate::from_ymd_opt(2021, 7, 5),
+                until: None,
+            },
+        };
+        let today = NaiveDate::from_ymd_opt(2021, 7, 10).unwrap();
+        let expired = expired_names(
+            &cmd,
+            &[
+                "20210710.00",
+                "20210709.00",
+                "20210709.01",
+                "20210701.00",
+                "20210625.00",
+            ],
+            today,
+        );
+        assert_eq!(expired, vec!["20210709.00"]);
+    }
+
+    fn keep_last_cmd(keep: usize) -> PruneCmd {
+        PruneCmd {
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 12,
+            keep_last: Some(keep),
+            range: snapshots::DateRange::default(),
+        }
+    }
+
+    fn beyond_last_names(cmd: &PruneCmd, names: &[&str]) -> Vec<String> {
+        let mut found: Vec<_> = names.iter().map(|n| snapshot(n)).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        cmd.select_beyond_last(&found, cmd.keep_last.unwrap())
+            .iter()
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn keep_last_sorts_by_date_and_index() {
+        let names = [
+            "20210701.00",
+            "20210709.01",
+            "20210710.00",
+            "20210709.00",
+            "20210625.00",
+        ];
+        assert_eq!(
+            beyond_last_names(&keep_last_cmd(2), &names),
+            vec!["20210709.00", "20210701.00", "20210625.00"]
+        );
+        assert_eq!(
+            beyond_last_names(&keep_last_cmd(4), &names),
+            vec!["20210625.00"]
+        );
+        assert!(beyond_last_names(&keep_last_cmd(5), &names).is_empty());
+        assert!(beyond_last_names(&keep_last_cmd(10), &names).is_empty());
+    }
+
+    #[test]
+    fn keep_last_respects_range() {
+        let cmd = PruneCmd {
+            range: snapshots::DateRange {
+                since: None,
+                until: NaiveDate::from_ymd_opt(2021, 7, 1),
+            },
+            ..keep_last_cmd(1)
+        };
+        assert_eq!(
+            beyond_last_names(&cmd, &["20210710.00", "20210705.00", "20210701.00"]),
+            vec!["20210701.00"]
+        );
+    }
+
+    #[test]
+    fn keep_last_zero_is_refused() {
+        let dir = TempDir::new("prune").unwrap();
+        fs::create_dir(dir.path().join("20210710.00")).unwrap();
+        assert!(keep_last_cmd(0)
+            .prune(
+                dir.path(),
+                snapshots::SNAPSHOT_DATE_FORMAT,
+                Path::new("/bin/false"),
+                true
+            )
+            .is_err());
+        assert!(dir.path().join("20210710.00").exists());
+    }
+
+    #[test]
+    fn prune_uses_name_format() {
+        let dir = TempDir::new("prune").unwrap();
+        for name in ["2021071013.00", "2021071014.00", "2021071015.00", "live"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        let count = keep_last_cmd(1)
+            .prune(dir.path(), "%Y%m%d%H", Path::new("/bin/false"), true)
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn hourly_snapshots_share_daily_bucket() {
+        let cmd = PruneCmd {
+            keep_daily: 7,
+            keep_weekly: 0,
+            keep_monthly: 0,
+            keep_last: None,
+            range: snapshots::DateRange::default(),
+        };
+        let mut found: Vec<_> = ["2021071013.00", "2021071009.00", "2021070923.00"]
+            .iter()
+            .map(|name| {
+                let (time, index) = snapshots::parse_snapshot_name(name, "%Y%m%d%H").unwrap();
+                Snapshot {
+                    time,
+                    index,
+                    name: name.to_string(),
+                }
+            })
+            .collect();
+        found.sort_by(|a, b| b.cmp(a));
+        let today = NaiveDate::from_ymd_opt(2021, 7, 10).unwrap();
+        let expired: Vec<_> = cmd
+            .select_expired(&found, today)
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(expired, vec!["2021071009.00"]);
+    }
+
+    #[test]
+    fn plain_snapshot_dir_is_deleted_whole() {
+        let dir = TempDir::new("prune").unwrap();
+        let snap = dir.path().join("20210710.00");
+        fs::create_dir_all(snap.join("host1")).unwrap();
+        assert_eq!(snapshot_subvolumes(&snap).unwrap(), vec![snap]);
+    }
+
+    #[test]
+    fn delete_command() {
+        let command = get_delete_command(Path::new("/sbin/btrfs"), Path::new("/snaps/20210704.00"));
+        assert_eq!(
+            command,
+            vec![
+                OsString::from("/sbin/btrfs"),
+                OsString::from("subvolume"),
+                OsString::from("delete"),
+                OsString::from("/snaps/20210704.00"),
+            ]
+        );
+    }
+}
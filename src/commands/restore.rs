@@ -0,0 +1,201 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::snapshots;
+use crate::config::{BackupDest, BackupHost, BackupSource, Config};
+use crate::doppelback_error::DoppelbackError;
+use crate::process_util;
+
+use itertools::Itertools;
+use log::info;
+use pathsearch::find_executable_in_path;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct RestoreCmd {
+    /// Path of the backup source to restore.  Must match an entry in the host config.
+    #[structopt(long)]
+    source: String,
+
+    /// Actually run the restore.  Required unless --dry-run is passed.
+    #[structopt(long)]
+    confirm: bool,
+
+    /// Name of the snapshot to restore from (YYYYMMDD.NN).
+    snapshot: String,
+}
+
+impl RestoreCmd {
+    pub fn restore(
+        &self,
+        config: &Config,
+        host: &str,
+        dry_run: bool,
+    ) -> Result<(), DoppelbackError> {
+        if !dry_run && !self.confirm {
+            return Err(DoppelbackError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "restore overwrites files on the remote host; pass --confirm to run it",
+            )));
+        }
+
+        // Only accept real snapshot names so the snapshot can't be used to point outside the
+        // snapshots directory.
+        if snapshots::parse_snapshot_name(&self.snapshot, config.snapshot_name_format()).is_none() {
+            return Err(DoppelbackError::InvalidPath(PathBuf::from(&self.snapshot)));
+        }
+
+        let host_config = config
+            .hosts
+            .get(host)
+            .ok_or_else(|| DoppelbackError::InvalidConfig(format!("host {} not found", host)))?;
+        let source = host_config.get_source(&self.source).ok_or_else(|| {
+            DoppelbackError::InvalidConfig(format!("path {} not found", self.source))
+        })?;
+        host_config.check_options().map_err(|e| e.for_host(host))?;
+
+        let dest =
+            BackupDest::for_snapshot(config.snapshot_root(host), &self.snapshot, host, source);
+        if !dest.backup_dir().is_dir() {
+            return Err(DoppelbackError::MissingDir(dest.backup_dir().to_path_buf()));
+        }
+
+        let home_dir = process_util::home_dir()?;
+        let ssh = find_executable_in_path("ssh")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Couldn't find ssh in PATH"))?;
+        let ssh_args = host_config
+            .source_ssh_args(ssh, home_dir, host, source)
+            .map_err(|e| e.for_host(host))?;
+        let rsync = config.rsync_bin()?;
+
+        let command = get_command(&rsync, host, host_config, &ssh_args, source, &dest);
+        info!("Restore command: {:?}", &command);
+        if dry_run {
+            process_util::print_dry_run(&command);
+            return Ok(());
+        }
+
+        let status = process::Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir("/")
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(DoppelbackError::CommandFailed(rsync, status))
+        }
+    }
+}
+
+/// Builds the rsync command to push `dest` back to the path of `source` on `host`.
+///
+/// `--delete` is never passed, so files created on the host since the snapshot are left alone.
+/// `--fake-super` reads back the ownership that was stored in xattrs during the backup, and
+/// `--numeric-ids` is passed if the backup used it so that the ids are restored unchanged.
+fn get_command(
+    rsync: &Path,
+    host: &str,
+    host_config: &BackupHost,
+    ssh_args: &[OsString],
+    source: &BackupSource,
+    dest: &BackupDest,
+) -> Vec<OsString> {
+    let mut command = vec![rsync.as_os_str().to_os_string()];
+
+    let ssh_args = ssh_args.iter().map(|s| s.to_string_lossy()).join(" ");
+    command.push(OsString::from(format!("--rsh={}", ssh_args)));
+    command.extend(
+        [
+            "--archive",
+            "--hard-links",
+            "--acls",
+            "--xattrs",
+            "--sparse",
+            "--fake-super",
+        ]
+        .iter()
+        .map(OsString::from),
+    );
+    if host_config.numeric_ids {
+        command.push(OsString::from("--numeric-ids"));
+    }
+
+    let mut local = dest.backup_dir().as_os_str().to_os_string();
+    local.push("/");
+    command.push(local);
+    command.push(OsString::from(format!(
+        "{}:{}/",
+        host_config.source_rsync_destination(host, source),
+        source.path.to_string_lossy().trim_end_matches('/')
+    )));
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_requires_confirm() {
+        let cmd = RestoreCmd {
+            source: String::from("/etc"),
+            confirm: false,
+            snapshot: String::from("20210704.00"),
+        };
+        assert!(cmd.restore(&Config::default(), "host1", false).is_err());
+    }
+
+    #[test]
+    fn restore_rejects_bad_snapshot_name() {
+        let cmd = RestoreCmd {
+            source: String::from("/etc"),
+            confirm: true,
+            snapshot: String::from("../live"),
+        };
+        assert!(matches!(
+            cmd.restore(&Config::default(), "host1", true),
+            Err(DoppelbackError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn restore_command_is_additive() {
+        let host_config = BackupHost {
+            user: String::from("backupuser"),
+            ..BackupHost::default()
+        };
+        let source = BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..BackupSource::default()
+        };
+        let dest = BackupDest::for_snapshot("/snapshots", "20210704.00", "host1", &source);
+        let ssh_args = vec![OsString::from("/usr/bin/ssh"), OsString::from("-a")];
+
+        let command = get_command(
+            Path::new("/usr/bin/rsync"),
+            "host1",
+            &host_config,
+            &ssh_args,
+            &source,
+            &dest,
+        );
+
+        assert_eq!(command[0], "/usr/bin/rsync");
+        assert!(command.contains(&OsString::from("--rsh=/usr/bin/ssh -a")));
+        assert!(!command.contains(&OsString::from("--numeric-ids")));
+        assert!(!command
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--delete")));
+        assert_eq!(
+            command[command.len() - 2..],
+            [
+                OsString::from("/snapshots/20210704.00/host1/opt_backups/"),
+                OsString::from("backupuser@host1:/opt/backups/"),
+            ]
+        );
+    }
+}
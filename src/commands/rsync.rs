@@ -3,18 +3,27 @@
 
 use crate::config;
 use crate::doppelback_error::DoppelbackError;
+use crate::log_context;
+use crate::process_util;
+use crate::rsync_util;
+use chrono::Local;
 use itertools::Itertools;
-use log::debug;
+use log::{debug, info, log, warn};
 use pathsearch::find_executable_in_path;
-use std::env;
 use std::ffi::OsString;
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-
-use std::process;
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+/// Files larger than this are skipped unless the source sets its own `max_size`.
+const DEFAULT_MAX_SIZE: &str = "10G";
+
 #[derive(Debug, StructOpt)]
 pub struct RsyncCmd {
     /// Name of the remote host.  Must match an entry in the config.
@@ -22,6 +31,23 @@ pub struct RsyncCmd {
 
     /// Path on the host specified by `host`.  Must match an entry in the host config.
     source: String,
+
+    /// Limit transfer bandwidth in KB/s.  Overrides `bwlimit` from the host config.
+    #[structopt(long)]
+    pub bwlimit: Option<u64>,
+
+    /// Log rsync's overall progress at debug level while the transfer runs.
+    #[structopt(long)]
+    pub progress: bool,
+
+    /// Write the backup under this directory instead of the configured snapshots directory.
+    ///
+    /// Useful for testing a config against a scratch location without touching the real live
+    /// tree.  The path must be absolute and already exist.  The backup goes into
+    /// `live/<host>/<source>` below it, which is created as needed, and the `.exclude` file for
+    /// the source is also read from there.
+    #[structopt(long, parse(from_os_str))]
+    pub dest_root: Option<PathBuf>,
 }
 
 impl RsyncCmd {
@@ -29,61 +55,324 @@ impl RsyncCmd {
         RsyncCmd {
             host: host.to_string(),
             source: source.as_ref().to_string_lossy().to_string(),
+            bwlimit: None,
+            progress: false,
+            dest_root: None,
         }
     }
 
-    pub fn run_rsync(&self, config: &config::Config, dry_run: bool) -> Result<(), DoppelbackError> {
+    /// Runs rsync for this host and source, retrying transient failures if the host config allows.
+    ///
+    /// Returns the transfer totals from rsync's `--stats` output, which are empty for a dry run.
+    pub fn run_rsync(
+        &self,
+        config: &config::Config,
+        dry_run: bool,
+    ) -> Result<rsync_util::RsyncStats, DoppelbackError> {
         debug!("rsync host=<{}> path=<{}>", self.host, self.source,);
 
+        let root = self.dest_root(config)?;
         let (host_config, source) = self.check_config(config)?;
 
-        let home_dir = env::var_os("HOME")
-            .ok_or_else(|| DoppelbackError::MissingDir(PathBuf::from("HOME")))?;
+        let home_dir = process_util::home_dir()?;
         let ssh = find_executable_in_path("ssh")
             .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Couldn't find ssh in PATH"))?;
         let ssh_args = host_config
-            .ssh_args(ssh, home_dir)
-            .ok_or_else(|| DoppelbackError::InvalidPath(PathBuf::from(&host_config.key)))?;
+            .source_ssh_args(ssh, &home_dir, &self.host, source)
+            .map_err(|e| e.for_host(&self.host))?;
 
-        let rsync = find_executable_in_path("rsync").ok_or_else(|| {
-            io::Error::new(io::ErrorKind::NotFound, "Couldn't find rsync in PATH")
-        })?;
+        let rsync = config.rsync_bin()?;
 
-        let dest = config::BackupDest::new(&config.snapshots, &self.host, source);
-        fs::create_dir_all(dest.backup_dir())?;
+        let dest = config::BackupDest::new(root, &self.host, source);
+        if !dry_run {
+            dest.create_backup_dir(root, config.dest_mode)?;
+        } else if !dest.backup_dir().is_dir() {
+            info!("Would create {}", dest.backup_dir().display());
+        }
 
-        let command = self.get_command(rsync, &host_config.user, &ssh_args, &dest)?;
+        if is_initial_sync(dest.backup_dir())? {
+            info!(
+                "{}:{} has no earlier backup; this is an initial full sync and may take much longer than usual",
+                self.host, self.source
+            );
+        }
+
+        // A dry run reuses the cache directories found by the last real run.
+        if source.exclude_caches && !dry_run {
+            if let Err(e) =
+                self.update_cache_excludes(&rsync, host_config, &ssh_args, source, &dest)
+            {
+                warn!(
+                    "Failed to look for cache directories in {}:{}: {}",
+                    self.host, self.source, e
+                );
+            }
+        }
+
+        let global_excludes = config.global_excludes.as_deref();
+        let command = self.get_command(
+            rsync,
+            host_config,
+            &ssh_args,
+            source,
+            global_excludes,
+            &dest,
+        )?;
+        let command = priority_command(source, command)?;
 
         debug!(
             "Final rsync command: {}",
-            &command
-                .iter()
-                .map(|arg| {
-                    let s = arg.to_string_lossy();
-                    if s.contains(' ') {
-                        format!(r#""{}""#, s)
-                    } else {
-                        s.to_string()
-                    }
-                })
-                .join(" ")
+            process_util::shell_quote(&command)
         );
         if dry_run {
-            return Ok(());
+            process_util::print_dry_run(&command);
+            return Ok(rsync_util::RsyncStats::default());
+        }
+
+        let changelog_run = source.changelog.then(|| changelog_run_name(config, &dest));
+        let changelog_failed = |e: io::Error| {
+            warn!(
+                "Failed to write changelog for {}:{}: {}",
+                self.host, self.source, e
+            );
+        };
+
+        let retries = host_config.retries.unwrap_or(0);
+        let mut attempt = 0;
+        loop {
+            // Changes from a failed attempt are still in the live tree, so they are recorded too.
+            let changelog = changelog_run
+                .as_ref()
+                .and_then(|run| Changelog::open(&dest, run).map_err(changelog_failed).ok());
+            let stdout = RsyncStdout {
+                changelog,
+                ..RsyncStdout::default()
+            };
+            let output = self.run_once(
+                &command,
+                host_config.timeout(),
+                host_config.min_rate(),
+                stdout,
+            )?;
+            let status = output.status;
+            if let Some(changelog) = output.stdout.changelog {
+                if let Err(e) = changelog.finish() {
+                    changelog_failed(e);
+                }
+            }
+
+            let err = match self.check_exit_status(status) {
+                Ok(()) => return Ok(output.stdout.stats),
+                Err(e) => e,
+            };
+
+            let retryable = status
+                .code()
+                .is_some_and(rsync_util::is_retryable_exit_code);
+            if !retryable || attempt >= retries {
+                return Err(err);
+            }
+
+            let delay = rsync_util::retry_delay(attempt);
+            attempt += 1;
+            info!(
+                "rsync for {}:{} exited with status {}; retry {} of {} in {}s",
+                self.host,
+                self.source,
+                status.code().unwrap_or(-1),
+                attempt,
+                retries,
+                delay.as_secs()
+            );
+            thread::sleep(delay);
         }
+    }
 
-        let status = process::Command::new(&command[0])
+    /// Runs `command` once and waits for it to exit.
+    ///
+    /// stdout and stderr are both captured so that unattended runs keep a full record in the log.
+    /// Each line of stdout is logged at debug level and added to `stdout`, and each line of stderr
+    /// is logged as a warning.  If `min_rate` is given, rsync is stopped once its progress output
+    /// stays below that many bytes per second for the given time.
+    fn run_once(
+        &self,
+        command: &[OsString],
+        timeout: Option<Duration>,
+        min_rate: Option<(u64, Duration)>,
+        stdout: RsyncStdout,
+    ) -> Result<RsyncOutput, DoppelbackError> {
+        let mut child = process::Command::new(&command[0])
             .args(&command[1..])
             .current_dir("/")
-            .status()?;
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain both pipes on other threads so rsync can't block on a full pipe while we wait
+        // for it to exit.
+        let label = format!("{}:{}", self.host, self.source);
+        let stdout_pipe = child.stdout.take().expect("rsync stdout not piped");
+        let stderr_pipe = child.stderr.take().expect("rsync stderr not piped");
+        let stalled = Arc::new(AtomicBool::new(false));
+        let mut watchdog =
+            min_rate.map(|(rate, window)| rsync_util::StallWatchdog::new(rate, window));
+        let pid = child.id() as libc::pid_t;
+        let stdout_stalled = Arc::clone(&stalled);
+        let mut check_rate = move |line: &str| {
+            let (watchdog, rate) = match (&mut watchdog, rsync_util::parse_progress_rate(line)) {
+                (Some(watchdog), Some(rate)) => (watchdog, rate),
+                _ => return,
+            };
+            if watchdog.sample(rate, Instant::now()) && !stdout_stalled.swap(true, Ordering::SeqCst)
+            {
+                unsafe { libc::kill(pid, libc::SIGTERM) };
+            }
+        };
+        let stdout_reader = spawn_logger(
+            stdout_pipe,
+            log::Level::Debug,
+            label.clone(),
+            stdout,
+            move |stdout: &mut RsyncStdout, line: &str| {
+                check_rate(line);
+                stdout.add_line(line);
+            },
+        );
+        let stderr_reader = spawn_logger(stderr_pipe, log::Level::Warn, label, (), |_, _| {});
+
+        let status = process_util::wait_with_timeout(&mut child, timeout)?.ok_or_else(|| {
+            DoppelbackError::Timeout(
+                format!("rsync for {}:{}", self.host, self.source),
+                timeout.unwrap_or_default(),
+            )
+        })?;
+        let stdout = stdout_reader
+            .join()
+            .expect("rsync stdout reader panicked")?;
+        stderr_reader
+            .join()
+            .expect("rsync stderr reader panicked")?;
 
-        if status.success() {
-            Ok(())
-        } else {
-            Err(DoppelbackError::CommandFailed(
-                PathBuf::from(&command[0]),
-                status,
-            ))
+        if let (true, Some((min_rate, duration))) = (stalled.load(Ordering::SeqCst), min_rate) {
+            return Err(DoppelbackError::Stalled {
+                what: format!("rsync for {}:{}", self.host, self.source),
+                min_rate,
+                duration,
+            });
+        }
+        Ok(RsyncOutput { status, stdout })
+    }
+
+    /// Lists the `CACHEDIR.TAG` files under the source on the host and writes an exclude rule for
+    /// each directory holding one to the source's `.caches` companion file.
+    ///
+    /// rsync can't exclude a directory based on a file inside it, so this takes a separate
+    /// listing pass over the source before the transfer.  The tag's signature isn't checked,
+    /// since that would mean downloading every tag.  If the listing fails, the previous file is
+    /// kept.
+    fn update_cache_excludes(
+        &self,
+        rsync: &Path,
+        host_config: &config::BackupHost,
+        ssh_args: &[OsString],
+        source_config: &config::BackupSource,
+        dest: &config::BackupDest,
+    ) -> Result<(), DoppelbackError> {
+        let command = self.get_cache_scan_command(rsync, host_config, ssh_args, source_config);
+        debug!(
+            "Cache scan command: {}",
+            process_util::shell_quote(&command)
+        );
+        let stdout = RsyncStdout {
+            cache_dirs: Some(Vec::new()),
+            ..RsyncStdout::default()
+        };
+        let output = self.run_once(&command, host_config.timeout(), None, stdout)?;
+        self.check_exit_status(output.status)?;
+
+        let rules: String = output
+            .stdout
+            .cache_dirs
+            .unwrap_or_default()
+            .iter()
+            .map(|dir| format!("/{}/\n", rsync_util::escape_pattern(dir)))
+            .collect();
+        fs::write(dest.get_companion_file("caches"), rules)?;
+        Ok(())
+    }
+
+    /// Returns the rsync command that lists only the `CACHEDIR.TAG` files under the source.
+    fn get_cache_scan_command(
+        &self,
+        rsync: &Path,
+        host_config: &config::BackupHost,
+        ssh_args: &[OsString],
+        source_config: &config::BackupSource,
+    ) -> Vec<OsString> {
+        let mut command = vec![
+            rsync.as_os_str().to_os_string(),
+            OsString::from(format!(
+                "--rsh={}",
+                ssh_args.iter().map(|s| s.to_string_lossy()).join(" ")
+            )),
+            OsString::from("--recursive"),
+            OsString::from("--one-file-system"),
+            OsString::from("--list-only"),
+            OsString::from("--include=*/"),
+            OsString::from("--include=CACHEDIR.TAG"),
+            OsString::from("--exclude=*"),
+        ];
+        if let Some(rsync_path) = &source_config.rsync_path {
+            command.push(OsString::from(format!("--rsync-path={}", rsync_path)));
+        }
+        command.push(OsString::from(format!(
+            "{}:{}/",
+            host_config.source_rsync_destination(&self.host, source_config),
+            self.source
+        )));
+        command
+    }
+
+    /// Converts rsync's exit status into a result.
+    ///
+    /// Files vanishing during the transfer are expected when backing up a live filesystem, so that
+    /// exit status is logged as a warning instead of being treated as a failure.
+    fn check_exit_status(&self, status: process::ExitStatus) -> Result<(), DoppelbackError> {
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(rsync_util::EXIT_VANISHED) => {
+                warn!(
+                    "{}:{}: {}",
+                    self.host,
+                    self.source,
+                    rsync_util::describe_exit_code(rsync_util::EXIT_VANISHED)
+                );
+                Ok(())
+            }
+            Some(rsync_util::EXIT_SSH_FAILED) => {
+                Err(DoppelbackError::HostUnreachable(self.host.clone()))
+            }
+            _ => Err(DoppelbackError::RsyncFailed(status)),
+        }
+    }
+
+    /// Returns the directory the backup is written under: `--dest-root` if it was passed, or
+    /// else the host's snapshots directory.
+    fn dest_root<'a>(&'a self, config: &'a config::Config) -> Result<&'a Path, DoppelbackError> {
+        match &self.dest_root {
+            Some(root) => {
+                if !root.is_absolute() {
+                    return Err(DoppelbackError::InvalidPath(root.clone()));
+                }
+                if !root.is_dir() {
+                    return Err(DoppelbackError::MissingDir(root.clone()));
+                }
+                Ok(root)
+            }
+            None => {
+                config.host_snapshot_dir_valid(&self.host)?;
+                Ok(config.snapshot_root(&self.host))
+            }
         }
     }
 
@@ -91,14 +380,16 @@ impl RsyncCmd {
         &self,
         config: &'a config::Config,
     ) -> Result<(&'a config::BackupHost, &'a config::BackupSource), DoppelbackError> {
-        config.snapshot_dir_valid()?;
-
         let host = config.hosts.get(&self.host).ok_or_else(|| {
-            DoppelbackError::InvalidConfig(format!("host {} not found", self.host))
+            DoppelbackError::config_field("hosts", "has no entry for this host")
+                .for_host(&self.host)
         })?;
         let source = host.get_source(&self.source).ok_or_else(|| {
-            DoppelbackError::InvalidConfig(format!("path {} not found", self.source))
+            DoppelbackError::config_field("sources", format!("has no entry for {}", self.source))
+                .for_host(&self.host)
         })?;
+        host.check_options().map_err(|e| e.for_host(&self.host))?;
+        source.check_options().map_err(|e| e.for_host(&self.host))?;
 
         Ok((host, source))
     }
@@ -106,15 +397,28 @@ impl RsyncCmd {
     fn get_command(
         &self,
         rsync: PathBuf,
-        user: &str,
+        host_config: &config::BackupHost,
         ssh_args: &[OsString],
+        source_config: &config::BackupSource,
+        global_excludes: Option<&Path>,
         dest: &config::BackupDest,
     ) -> Result<Vec<OsString>, DoppelbackError> {
         let mut command = vec![rsync.into_os_string()];
 
-        let source = format!("{}@{}:{}/", user, self.host, self.source);
+        let source = format!(
+            "{}:{}/",
+            host_config.source_rsync_destination(&self.host, source_config),
+            self.source
+        );
         let ssh_args = ssh_args.iter().map(|s| s.to_string_lossy()).join(" ");
         let ssh = format!("--rsh={}", ssh_args);
+        let max_size = format!(
+            "--max-size={}",
+            source_config
+                .max_size
+                .as_deref()
+                .unwrap_or(DEFAULT_MAX_SIZE)
+        );
 
         command.extend(
             vec![
@@ -124,24 +428,143 @@ impl RsyncCmd {
                 "--acls",
                 "--xattrs",
                 "--one-file-system",
-                "--max-size=10G",
+                &max_size[..],
                 "--delete",
-                "--delete-excluded",
                 "--inplace",
                 "--sparse",
                 "--no-W",
                 "-M--no-W",
                 "--preallocate",
                 "--fake-super",
+                "--stats",
                 "--exclude=lost+found",
                 "--exclude=**/.cache",
                 "--exclude=.*.swp",
                 "--exclude=.viminfo",
             ]
             .iter()
+            // Updating files in place rewrites blocks shared with earlier snapshots, which some
+            // sources don't want.  rsync also refuses --inplace together with --partial-dir.
+            .filter(|arg| {
+                !(source_config.no_inplace
+                    || source_config.atomic
+                    || host_config.partial_dir.is_some())
+                    || !matches!(**arg, "--inplace" | "--preallocate")
+            })
+            .flat_map(|arg| match *arg {
+                "--delete" => source_config.delete_args(),
+                _ => vec![*arg],
+            })
             .map(OsString::from),
         );
 
+        // Changed files are written to temporary copies and only renamed into place once the
+        // whole transfer is done, so an interrupted rsync doesn't leave half-written files.
+        if source_config.atomic {
+            command.push(OsString::from("--delay-updates"));
+        }
+
+        // Interrupted transfers are kept in the partial dir and resumed by the next run.  rsync
+        // already excludes it, but only after all the other rules.  An exclude of our own would be
+        // deleted by --delete-excluded, so it is protected ahead of the source's rules instead.
+        if let Some(partial_dir) = &host_config.partial_dir {
+            command.push(OsString::from("--partial"));
+            command.push(OsString::from(format!("--partial-dir={}", partial_dir)));
+            command.push(OsString::from(format!("--filter=P {}/", partial_dir)));
+        }
+
+        // The schedule is only checked when rsync starts, so a transfer that runs past the end of
+        // a window keeps its limit.
+        let bwlimit = self
+            .bwlimit
+            .or_else(|| host_config.bwlimit_at(Local::now().time()));
+        if let Some(bwlimit) = bwlimit {
+            command.push(OsString::from(format!("--bwlimit={}", bwlimit)));
+        }
+
+        command.extend(host_config.compress_args().into_iter().map(OsString::from));
+
+        // --fake-super stores ownership in xattrs as uid/gid numbers either way, but without
+        // --numeric-ids they are first mapped through the user and group names on this server.
+        if host_config.numeric_ids {
+            command.push(OsString::from("--numeric-ids"));
+        }
+
+        // The progress output is also where the transfer rate for `min_rate_bytes` comes from.
+        if self.progress || host_config.min_rate().is_some() {
+            command.push(OsString::from("--info=progress2"));
+        }
+
+        if source_config.changelog {
+            command.push(OsString::from("--itemize-changes"));
+        }
+
+        // Compare full file checksums instead of size and mtime, so files that were corrupted
+        // without their mtime changing are transferred again.
+        if source_config.checksum {
+            command.push(OsString::from("--checksum"));
+        }
+
+        if source_config.crtimes {
+            command.push(OsString::from("--crtimes"));
+        }
+
+        // rsync's own I/O timeout catches a stalled transfer on a connection that is still up,
+        // independently of the host's timeout_secs on the whole process.
+        if let Some(io_timeout) = source_config.io_timeout_secs {
+            command.push(OsString::from(format!("--timeout={}", io_timeout)));
+        }
+
+        // With --fake-super the changed permissions are also what gets recorded in the xattrs, so
+        // a restore writes them back instead of the originals.
+        if let Some(chmod) = &source_config.chmod {
+            command.push(OsString::from(format!("--chmod={}", chmod)));
+        }
+
+        if let Some(rsync_path) = &source_config.rsync_path {
+            command.push(OsString::from(format!("--rsync-path={}", rsync_path)));
+        }
+
+        for arg in &source_config.extra_rsync_args {
+            if rsync_util::is_allowed_client_arg(arg) {
+                command.push(OsString::from(arg));
+            } else {
+                warn!("Removed unsafe rsync argument {}", arg);
+            }
+        }
+
+        // rsync uses the first rule that matches.  Includes come after the built-in excludes
+        // above, so they can't bring back lost+found or caches, but ahead of everything else so
+        // that they carve exceptions out of the source's own excludes and exclude files.
+        command.extend(
+            source_config
+                .includes
+                .iter()
+                .map(|i| OsString::from(format!("--include={}", i))),
+        );
+
+        command.extend(
+            source_config
+                .excludes
+                .iter()
+                .map(|e| OsString::from(format!("--exclude={}", e))),
+        );
+
+        // The global file goes first so that rules in the per-destination file can refine it.
+        if let Some(global_excludes) = global_excludes {
+            if fs::File::open(global_excludes).is_ok() {
+                command.push(OsString::from(format!(
+                    "--exclude-from={}",
+                    global_excludes.display()
+                )));
+            } else {
+                warn!(
+                    "Skipping unreadable global exclude file {}",
+                    global_excludes.display()
+                );
+            }
+        }
+
         let exclude_from = dest.get_companion_file("exclude");
         if exclude_from.is_file() {
             command.push(OsString::from(format!(
@@ -149,6 +572,34 @@ impl RsyncCmd {
                 exclude_from.display()
             )));
         }
+
+        // Directories tagged with CACHEDIR.TAG, as found by `update_cache_excludes`.
+        let caches = dest.get_companion_file("caches");
+        if source_config.exclude_caches && caches.is_file() {
+            command.push(OsString::from(format!(
+                "--exclude-from={}",
+                caches.display()
+            )));
+        }
+
+        // A merge rule reads the file's include and exclude rules in place.  rsync uses the first
+        // rule that matches, so everything excluded above stays excluded.
+        if let Some(filter_file) = &source_config.filter_file {
+            command.push(OsString::from(format!(
+                "--filter=. {}",
+                filter_file.display()
+            )));
+        }
+
+        // Anything that no rule above included is left out.  This has to be the very last rule.
+        // rsync doesn't look inside an excluded directory, so directories that weren't excluded
+        // above are still walked for nested includes such as `Documents/**/*.pdf`, and the ones
+        // that end up with nothing included aren't created in the backup.
+        if source_config.include_only {
+            command.push(OsString::from("--prune-empty-dirs"));
+            command.push(OsString::from("--include=*/"));
+            command.push(OsString::from("--exclude=*"));
+        }
         command.push(OsString::from(source));
         command.push(OsString::from(dest.backup_dir()));
 
@@ -156,37 +607,343 @@ impl RsyncCmd {
     }
 }
 
+/// Largest size a single changelog is allowed to grow to.
+const MAX_CHANGELOG_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Prefixes `command` with `nice -n` and `ionice -c` for the priorities set on `source`.  Both
+/// programs are looked up in PATH, and only when they are needed.
+fn priority_command(
+    source: &config::BackupSource,
+    command: Vec<OsString>,
+) -> Result<Vec<OsString>, DoppelbackError> {
+    let find = |program: &str| {
+        find_executable_in_path(program).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Couldn't find {} in PATH", program),
+            )
+        })
+    };
+
+    let mut wrapped = Vec::new();
+    if let Some(nice) = source.nice {
+        wrapped.push(find("nice")?.into_os_string());
+        wrapped.push(OsString::from("-n"));
+        wrapped.push(OsString::from(nice.to_string()));
+    }
+    if let Some(class) = source.ionice_class {
+        wrapped.push(find("ionice")?.into_os_string());
+        wrapped.push(OsString::from("-c"));
+        wrapped.push(OsString::from(class.to_string()));
+    }
+    wrapped.extend(command);
+    Ok(wrapped)
+}
+
+/// Returns whether `backup_dir` is empty, meaning rsync will have to transfer the whole source.
+fn is_initial_sync(backup_dir: &Path) -> io::Result<bool> {
+    match fs::read_dir(backup_dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        // Only a dry run gets here without creating the directory first.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the name of the changelog for this run: the snapshot name that pull-backup recorded
+/// for the source, or the current time if rsync is run by itself.
+fn changelog_run_name(config: &config::Config, dest: &config::BackupDest) -> String {
+    match fs::read_to_string(dest.get_companion_file("snapshot")) {
+        Ok(name) if !name.trim().is_empty() => name.trim().to_string(),
+        _ => Local::now()
+            .format(config.snapshot_name_format())
+            .to_string(),
+    }
+}
+
+/// Appends the itemized changes from rsync's stdout to `<dest>.changes/<run>.log` as they arrive.
+///
+/// Once the log reaches `MAX_CHANGELOG_BYTES`, the remaining changes are only counted in a final
+/// line written by `finish`.
+struct Changelog {
+    log: io::BufWriter<fs::File>,
+    size: u64,
+    skipped: usize,
+
+    /// The first write that failed.  Nothing more is written after it.
+    error: Option<io::Error>,
+}
+
+impl Changelog {
+    fn open(dest: &config::BackupDest, run: &str) -> io::Result<Self> {
+        let dir = dest.get_companion_file("changes");
+        fs::create_dir_all(&dir)?;
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.log", run)))?;
+        Ok(Changelog {
+            size: log.metadata()?.len(),
+            log: io::BufWriter::new(log),
+            skipped: 0,
+            error: None,
+        })
+    }
+
+    fn add_line(&mut self, line: &str) {
+        if self.error.is_some() || !rsync_util::is_itemized_change(line) {
+            return;
+        }
+        let line_size = line.len() as u64 + 1;
+        if self.skipped > 0 || self.size + line_size > MAX_CHANGELOG_BYTES {
+            self.skipped += 1;
+            return;
+        }
+        match writeln!(self.log, "{}", line) {
+            Ok(()) => self.size += line_size,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Writes the count of changes that didn't fit and flushes the log.
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if self.skipped > 0 {
+            writeln!(self.log, "... {} more changes not recorded", self.skipped)?;
+        }
+        self.log.flush()
+    }
+}
+
+/// What is kept of a single rsync run's stdout.  Lines are handled as they arrive, so the output
+/// of a large transfer is never held in memory.
+#[derive(Default)]
+struct RsyncStdout {
+    stats: rsync_util::RsyncStats,
+
+    /// Where the itemized changes are recorded, if the source keeps a changelog.
+    changelog: Option<Changelog>,
+
+    /// Directories holding a `CACHEDIR.TAG`, if this is a cache scan.
+    cache_dirs: Option<Vec<String>>,
+}
+
+impl RsyncStdout {
+    fn add_line(&mut self, line: &str) {
+        self.stats.add_line(line);
+        if let Some(changelog) = &mut self.changelog {
+            changelog.add_line(line);
+        }
+        if let Some(cache_dirs) = &mut self.cache_dirs {
+            cache_dirs.extend(rsync_util::cache_dir(line));
+        }
+    }
+}
+
+/// What a single rsync run printed to stdout, along with how it exited.  stderr is only logged.
+struct RsyncOutput {
+    status: process::ExitStatus,
+    stdout: RsyncStdout,
+}
+
+/// Starts a thread that logs the lines of `reader` with `log_lines`, passes each one to `on_line`
+/// along with `state`, and returns `state` at the end.
+///
+/// The thread logs with the caller's log context so that its lines stay attributed to the right
+/// host.
+fn spawn_logger<R, S, F>(
+    reader: R,
+    level: log::Level,
+    label: String,
+    mut state: S,
+    mut on_line: F,
+) -> thread::JoinHandle<io::Result<S>>
+where
+    R: Read + Send + 'static,
+    S: Send + 'static,
+    F: FnMut(&mut S, &str) + Send + 'static,
+{
+    let host = log_context::host();
+    thread::spawn(move || {
+        let _context = log_context::set_host(host.as_deref());
+        log_lines(reader, level, &label, |line| on_line(&mut state, line))?;
+        Ok(state)
+    })
+}
+
+/// Logs each line of rsync's output at `level` as it arrives and returns the lines.
+#[cfg(test)]
+fn log_output<R: Read>(reader: R, level: log::Level, label: &str) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    log_lines(reader, level, label, |line| lines.push(line.to_string()))?;
+    Ok(lines)
+}
+
+/// Logs each line of rsync's output at `level` and passes it to `on_line` as it arrives.  Only
+/// the current line is kept in memory.
+///
+/// `--info=progress2` redraws its line with carriage returns instead of newlines, so both end a
+/// line here.  Splitting on newlines alone would hold back the progress that `min_rate` watches
+/// until a whole file was done.
+fn log_lines<R, F>(reader: R, level: log::Level, label: &str, mut on_line: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&str),
+{
+    let mut emit = |line: &[u8]| {
+        if !line.is_empty() {
+            let text = String::from_utf8_lossy(line);
+            log!(level, "{}: {}", label, text.trim());
+            on_line(&text);
+        }
+    };
+
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let used = match buf.iter().position(|&b| b == b'\r' || b == b'\n') {
+            Some(end) => {
+                line.extend_from_slice(&buf[..end]);
+                emit(&line);
+                line.clear();
+                end + 1
+            }
+            None => {
+                line.extend_from_slice(buf);
+                buf.len()
+            }
+        };
+        reader.consume(used);
+    }
+    emit(&line);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use std::os::unix::process::ExitStatusExt;
     use tempdir::TempDir;
 
+    fn backup_host() -> config::BackupHost {
+        config::BackupHost {
+            user: String::from("backupuser"),
+            ..config::BackupHost::default()
+        }
+    }
+
+    fn backup_source() -> config::BackupSource {
+        config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..config::BackupSource::default()
+        }
+    }
+
+    /// Returns the command `rsync` runs to back up `source` into `/backups/snapshots` with
+    /// `host_config`, without ssh arguments or global excludes.
+    fn rsync_command(
+        rsync: &RsyncCmd,
+        host_config: &config::BackupHost,
+        source: &config::BackupSource,
+    ) -> Vec<OsString> {
+        let dest = config::BackupDest::new("/backups/snapshots", "host1.example.com", source);
+        rsync
+            .get_command(
+                PathBuf::from("/opt/bin/rsync"),
+                host_config,
+                &[],
+                source,
+                None,
+                &dest,
+            )
+            .unwrap()
+    }
+
+    /// Returns the command for backing up `source` from host1.example.com with `host_config`.
+    fn command_with_host(
+        host_config: &config::BackupHost,
+        source: &config::BackupSource,
+    ) -> Vec<OsString> {
+        let rsync = RsyncCmd::new("host1.example.com", &source.path);
+        rsync_command(&rsync, host_config, source)
+    }
+
+    /// Returns the command for backing up `source` from host1.example.com with `backup_host()`.
+    fn command_for(source: &config::BackupSource) -> Vec<OsString> {
+        command_with_host(&backup_host(), source)
+    }
+
+    #[test]
+    fn check_config_errors_name_host_and_field() {
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            config::BackupHost {
+                compress: Some(String::from("maybe")),
+                sources: vec![config::BackupSource {
+                    path: PathBuf::from("/etc"),
+                    ..config::BackupSource::default()
+                }],
+                ..backup_host()
+            },
+        );
+        let config = config::Config {
+            hosts,
+            ..config::Config::default()
+        };
+
+        let message = |host: &str, source: &str| {
+            RsyncCmd::new(host, source)
+                .check_config(&config)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            message("host2", "/etc"),
+            "invalid config for host2: hosts has no entry for this host"
+        );
+        assert_eq!(
+            message("host1", "/home"),
+            "invalid config for host1: sources has no entry for /home"
+        );
+        let compress = message("host1", "/etc");
+        assert!(
+            compress.starts_with("invalid config for host1: compress maybe"),
+            "{}",
+            compress
+        );
+    }
+
     #[test]
     fn get_command_no_exclude() {
         let dir = PathBuf::from("/backups/snapshots/live/host1.example.com/opt_backups");
 
-        let rsync = RsyncCmd {
-            host: String::from("host1.example.com"),
-            source: String::from("/opt/backups"),
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let source = config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..config::BackupSource::default()
         };
-        let dest = config::BackupDest::new(
-            "/backups/snapshots",
-            "host1.example.com",
-            &config::BackupSource {
-                path: PathBuf::from("/opt/backups"),
-                ..config::BackupSource::default()
-            },
-        );
+        let dest = config::BackupDest::new("/backups/snapshots", "host1.example.com", &source);
         let ssh_args: Vec<_> = vec!["/usr/bin/ssh", "-i", "/opt/sshkey"]
             .iter()
             .map(OsString::from)
             .collect();
 
+        let host_config = backup_host();
         let command = rsync
             .get_command(
                 PathBuf::from("/opt/bin/rsync"),
-                "backupuser",
+                &host_config,
                 &ssh_args,
+                &source,
+                None,
                 &dest,
             )
             .unwrap();
@@ -199,6 +956,35 @@ mod tests {
         assert_eq!(command.last().unwrap(), &dir.into_os_string());
     }
 
+    #[test]
+    fn get_command_ipv6_address() {
+        let host_config = config::BackupHost {
+            address: Some(String::from("2001:db8::5")),
+            ..backup_host()
+        };
+        let command = command_with_host(&host_config, &backup_source());
+
+        // The address is only used for the connection.  Backups still go under the host's name.
+        let dir = PathBuf::from("/backups/snapshots/live/host1.example.com/opt_backups");
+        assert!(command.contains(&OsString::from("backupuser@[2001:db8::5]:/opt/backups/")));
+        assert_eq!(command.last().unwrap(), &dir.into_os_string());
+    }
+
+    #[test]
+    fn get_command_source_host() {
+        let command = command_for(&config::BackupSource {
+            path: PathBuf::from("/srv/db"),
+            source_host: Some(String::from("db1.example.com")),
+            source_user: Some(String::from("dbbackup")),
+            ..config::BackupSource::default()
+        });
+
+        // Only the connection changes.  The backup is still kept under the host's name.
+        let dir = PathBuf::from("/backups/snapshots/live/host1.example.com/srv_db");
+        assert!(command.contains(&OsString::from("dbbackup@db1.example.com:/srv/db/")));
+        assert_eq!(command.last().unwrap(), &dir.into_os_string());
+    }
+
     #[test]
     fn get_command_with_exclude() {
         let snapshots = TempDir::new("snapshots").unwrap();
@@ -216,28 +1002,25 @@ mod tests {
             .write(true)
             .open(&exclude_file);
 
-        let rsync = RsyncCmd {
-            host: String::from("host1.example.com"),
-            source: String::from("/opt/backups"),
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let source = config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..config::BackupSource::default()
         };
-        let dest = config::BackupDest::new(
-            snapshots.path(),
-            "host1.example.com",
-            &config::BackupSource {
-                path: PathBuf::from("/opt/backups"),
-                ..config::BackupSource::default()
-            },
-        );
+        let dest = config::BackupDest::new(snapshots.path(), "host1.example.com", &source);
         let ssh_args: Vec<_> = vec!["/usr/bin/ssh", "-i", "/opt/sshkey"]
             .iter()
             .map(OsString::from)
             .collect();
 
+        let host_config = backup_host();
         let command = rsync
             .get_command(
                 PathBuf::from("/opt/bin/rsync"),
-                "backupuser",
+                &host_config,
                 &ssh_args,
+                &source,
+                None,
                 &dest,
             )
             .unwrap();
@@ -251,4 +1034,875 @@ mod tests {
         assert!(command.contains(&exclude_arg));
         assert_eq!(command.last().unwrap(), &dir.into_os_string());
     }
+
+    #[test]
+    fn get_command_global_excludes_before_local() {
+        let snapshots = TempDir::new("snapshots").unwrap();
+        let dir = snapshots.path().join("live/host1.example.com");
+        fs::create_dir_all(&dir).unwrap();
+        let exclude_file = dir.join("opt_backups.exclude");
+        fs::write(&exclude_file, "*.tmp\n").unwrap();
+        let global_file = snapshots.path().join("global.exclude");
+        fs::write(&global_file, "*.o\n").unwrap();
+
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let source = config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..config::BackupSource::default()
+        };
+        let dest = config::BackupDest::new(snapshots.path(), "host1.example.com", &source);
+
+        let host_config = backup_host();
+        let command = rsync
+            .get_command(
+                PathBuf::from("/opt/bin/rsync"),
+                &host_config,
+                &[],
+                &source,
+                Some(&global_file),
+                &dest,
+            )
+            .unwrap();
+
+        let exclude_from: Vec<_> = command
+            .iter()
+            .filter(|a| a.to_string_lossy().starts_with("--exclude-from="))
+            .collect();
+        assert_eq!(
+            exclude_from,
+            vec![
+                &OsString::from(format!("--exclude-from={}", global_file.display())),
+                &OsString::from(format!("--exclude-from={}", exclude_file.display())),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_command_skips_missing_global_excludes() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let source = config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..config::BackupSource::default()
+        };
+        let dest = config::BackupDest::new("/backups/snapshots", "host1.example.com", &source);
+
+        let host_config = backup_host();
+        let command = rsync
+            .get_command(
+                PathBuf::from("/opt/bin/rsync"),
+                &host_config,
+                &[],
+                &source,
+                Some(Path::new("/nonexistent/global.exclude")),
+                &dest,
+            )
+            .unwrap();
+
+        assert!(!command
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--exclude-from=")));
+    }
+
+    #[test]
+    fn get_command_no_inplace() {
+        let mut source = backup_source();
+        let command = command_for(&source);
+        assert!(command.contains(&OsString::from("--inplace")));
+        assert!(command.contains(&OsString::from("--preallocate")));
+
+        source.no_inplace = true;
+        let command = command_for(&source);
+        assert!(!command.contains(&OsString::from("--inplace")));
+        assert!(!command.contains(&OsString::from("--preallocate")));
+        assert!(command.contains(&OsString::from("--sparse")));
+    }
+
+    #[test]
+    fn get_command_atomic() {
+        let mut source = backup_source();
+        let normal = command_for(&source);
+        assert!(!normal.contains(&OsString::from("--delay-updates")));
+
+        source.atomic = true;
+        let atomic = command_for(&source);
+        assert!(atomic.contains(&OsString::from("--delay-updates")));
+
+        // Only the in-place arguments are swapped out.
+        let expected: Vec<_> = normal
+            .into_iter()
+            .filter(|arg| arg != "--inplace" && arg != "--preallocate")
+            .collect();
+        let without_delay: Vec<_> = atomic
+            .into_iter()
+            .filter(|arg| arg != "--delay-updates")
+            .collect();
+        assert_eq!(without_delay, expected);
+    }
+
+    #[test]
+    fn get_command_partial_dir() {
+        let source = config::BackupSource {
+            excludes: vec![String::from("*.tmp")],
+            ..backup_source()
+        };
+
+        let normal = command_for(&source);
+        assert!(!normal.contains(&OsString::from("--partial")));
+        assert!(normal.contains(&OsString::from("--inplace")));
+
+        let host_config = config::BackupHost {
+            partial_dir: Some(String::from(".rsync-partial")),
+            ..backup_host()
+        };
+        let command = command_with_host(&host_config, &source);
+        let position = |arg: &str| command.iter().position(|a| a == arg);
+        let partial = position("--partial").expect("--partial missing");
+        let partial_dir = position("--partial-dir=.rsync-partial").expect("--partial-dir missing");
+        let protect = position("--filter=P .rsync-partial/").expect("protect missing");
+        assert_eq!(partial_dir, partial + 1);
+        assert_eq!(protect, partial + 2);
+        assert!(protect < position("--exclude=*.tmp").unwrap());
+        assert!(position("--exclude=.rsync-partial/").is_none());
+        assert!(command.contains(&OsString::from("--delete-excluded")));
+        assert!(position("--inplace").is_none());
+        assert!(position("--preallocate").is_none());
+    }
+
+    #[test]
+    fn get_command_exclude_caches() {
+        let root = TempDir::new("rsync").unwrap();
+        let rsync = RsyncCmd::new("host1.example.com", "/home");
+        let mut source = config::BackupSource {
+            path: PathBuf::from("/home"),
+            ..config::BackupSource::default()
+        };
+        let dest = config::BackupDest::new(root.path(), "host1.example.com", &source);
+        dest.create_backup_dir(root.path(), None).unwrap();
+        let caches = dest.get_companion_file("caches");
+        fs::write(&caches, "/user/.cache/\n").unwrap();
+        let caches_arg = OsString::from(format!("--exclude-from={}", caches.display()));
+
+        let get_command = |source: &config::BackupSource| {
+            rsync
+                .get_command(
+                    PathBuf::from("/opt/bin/rsync"),
+                    &backup_host(),
+                    &[],
+                    source,
+                    None,
+                    &dest,
+                )
+                .unwrap()
+        };
+        assert!(!get_command(&source).contains(&caches_arg));
+
+        source.exclude_caches = true;
+        let command = get_command(&source);
+        assert!(command.contains(&caches_arg));
+        assert_eq!(command[command.len() - 3], caches_arg);
+    }
+
+    #[test]
+    fn cache_scan_lists_only_tags() {
+        let rsync = RsyncCmd::new("host1.example.com", "/home");
+        let source = config::BackupSource {
+            path: PathBuf::from("/home"),
+            rsync_path: Some(String::from("sudo doppelback sudo -- rsync")),
+            ..config::BackupSource::default()
+        };
+        let ssh_args = vec![OsString::from("/usr/bin/ssh"), OsString::from("-x")];
+        let command = rsync.get_cache_scan_command(
+            Path::new("/opt/bin/rsync"),
+            &backup_host(),
+            &ssh_args,
+            &source,
+        );
+        let expected: Vec<_> = [
+            "/opt/bin/rsync",
+            "--rsh=/usr/bin/ssh -x",
+            "--recursive",
+            "--one-file-system",
+            "--list-only",
+            "--include=*/",
+            "--include=CACHEDIR.TAG",
+            "--exclude=*",
+            "--rsync-path=sudo doppelback sudo -- rsync",
+            "backupuser@host1.example.com:/home/",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(command, expected);
+    }
+
+    #[test]
+    fn get_command_filters_extra_args() {
+        let command = command_for(&config::BackupSource {
+            extra_rsync_args: vec![
+                String::from("--checksum"),
+                String::from("--remove-source-files"),
+                String::from("--rsync-path=sudo rm -rf /"),
+                String::from("--compress"),
+            ],
+            ..backup_source()
+        });
+
+        assert!(command.contains(&OsString::from("--checksum")));
+        assert!(command.contains(&OsString::from("--compress")));
+        assert!(!command
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--remove-")
+                || a.to_string_lossy().starts_with("--rsync-path")));
+    }
+
+    #[test]
+    fn get_command_progress() {
+        let mut rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let progress_arg = OsString::from("--info=progress2");
+
+        let command = rsync_command(&rsync, &backup_host(), &backup_source());
+        assert!(!command.contains(&progress_arg));
+
+        rsync.progress = true;
+        let command = rsync_command(&rsync, &backup_host(), &backup_source());
+        assert!(command.contains(&progress_arg));
+    }
+
+    #[test]
+    fn stderr_is_captured_separately() {
+        let _lock = crate::test_util::ENV_LOCK.lock().unwrap();
+        let fake = crate::test_util::FakeCommand::script(
+            "rsync",
+            "echo 'Number of regular files transferred: 3'\n\
+             echo 'rsync: send_files failed to open \"/etc/shadow\": Permission denied (13)' >&2\n\
+             echo 'rsync error: some files could not be transferred (code 23)' >&2\n\
+             exit 23",
+        )
+        .unwrap();
+
+        let rsync = RsyncCmd::new("host1.example.com", "/etc");
+        let output = rsync
+            .run_once(
+                &[fake.cmd.clone().into_os_string()],
+                None,
+                None,
+                RsyncStdout::default(),
+            )
+            .unwrap();
+        assert_eq!(output.status.code(), Some(23));
+        assert_eq!(output.stdout.stats.files_transferred, Some(3));
+        assert!(matches!(
+            rsync.check_exit_status(output.status),
+            Err(DoppelbackError::RsyncFailed(_))
+        ));
+    }
+
+    #[test]
+    fn slow_transfer_is_stopped() {
+        let _lock = crate::test_util::ENV_LOCK.lock().unwrap();
+        let fake = crate::test_util::FakeCommand::script(
+            "rsync",
+            "printf '      32,768   0%%    0.01kB/s    0:00:01\\r'\n\
+             exec sleep 30",
+        )
+        .unwrap();
+
+        let rsync = RsyncCmd::new("host1.example.com", "/etc");
+        let err = rsync
+            .run_once(
+                &[fake.cmd.clone().into_os_string()],
+                None,
+                Some((1024, Duration::ZERO)),
+                RsyncStdout::default(),
+            )
+            .err()
+            .expect("stalled rsync succeeded");
+        assert_eq!(
+            err.to_string(),
+            "rsync for host1.example.com:/etc stalled below 1024 bytes/s for 0s"
+        );
+    }
+
+    #[test]
+    fn changelog_records_itemized_lines() {
+        let _lock = crate::test_util::ENV_LOCK.lock().unwrap();
+        let fake = crate::test_util::FakeCommand::script(
+            "rsync",
+            "echo '>f+++++++++ new.conf'\n\
+             echo '*deleting   old.conf'\n\
+             echo 'Number of regular files transferred: 1'",
+        )
+        .unwrap();
+
+        let root = TempDir::new("rsync").unwrap();
+        let source = config::BackupSource {
+            path: PathBuf::from("/etc"),
+            changelog: true,
+            ..config::BackupSource::default()
+        };
+        let dest = config::BackupDest::new(root.path(), "host1.example.com", &source);
+        dest.create_backup_dir(root.path(), None).unwrap();
+        fs::write(dest.get_companion_file("snapshot"), "20210704.00").unwrap();
+        let config = config::Config {
+            snapshots: root.path().to_path_buf(),
+            ..config::Config::default()
+        };
+
+        let run = changelog_run_name(&config, &dest);
+        assert_eq!(run, "20210704.00");
+        let stdout = RsyncStdout {
+            changelog: Some(Changelog::open(&dest, &run).unwrap()),
+            ..RsyncStdout::default()
+        };
+        let rsync = RsyncCmd::new("host1.example.com", "/etc");
+        let output = rsync
+            .run_once(&[fake.cmd.clone().into_os_string()], None, None, stdout)
+            .unwrap();
+        output.stdout.changelog.unwrap().finish().unwrap();
+
+        let log = root
+            .path()
+            .join("live/host1.example.com/etc.changes/20210704.00.log");
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            ">f+++++++++ new.conf\n*deleting   old.conf\n"
+        );
+
+        // A retry appends to the same log.
+        let mut changelog = Changelog::open(&dest, &run).unwrap();
+        changelog.add_line(">f..t...... new.conf");
+        changelog.finish().unwrap();
+        assert!(fs::read_to_string(&log)
+            .unwrap()
+            .ends_with("old.conf\n>f..t...... new.conf\n"));
+    }
+
+    #[test]
+    fn log_output_returns_stderr_lines() {
+        let stderr = "rsync: send_files failed to open \"/etc/shadow\": Permission denied (13)\n\
+                      rsync error: some files could not be transferred (code 23)\n";
+        let logged = log_output(stderr.as_bytes(), log::Level::Warn, "host1:/etc").unwrap();
+        assert_eq!(logged, stderr.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn log_output_joins_long_lines() {
+        let long = "a".repeat(20_000);
+        let output = format!("{}\nb", long);
+        let logged = log_output(output.as_bytes(), log::Level::Debug, "host1:/opt").unwrap();
+        assert_eq!(logged, [long, String::from("b")]);
+    }
+
+    #[test]
+    fn log_progress_keeps_output_for_stats() {
+        let output = "      32,768   0%    1.00MB/s    0:00:01\r\
+                      1,048,576  50%    2.00MB/s    0:00:02\r\
+                      2,097,152 100%    2.00MB/s    0:00:03 (xfr#1, to-chk=0/1)\n\
+                      Number of regular files transferred: 1\n\
+                      Total transferred file size: 2,097,152 bytes\n";
+        let logged = log_output(output.as_bytes(), log::Level::Debug, "host1:/opt").unwrap();
+        assert_eq!(logged.len(), 5);
+        assert_eq!(logged[1], "1,048,576  50%    2.00MB/s    0:00:02");
+
+        let mut stats = rsync_util::RsyncStats::default();
+        logged.iter().for_each(|line| stats.add_line(line));
+        assert_eq!(
+            stats,
+            rsync_util::RsyncStats {
+                files_transferred: Some(1),
+                bytes_transferred: Some(2_097_152),
+            }
+        );
+    }
+
+    #[test]
+    fn get_command_io_timeout() {
+        let timeout_args = |source: &config::BackupSource| -> Vec<OsString> {
+            command_for(source)
+                .into_iter()
+                .filter(|a| a.to_string_lossy().starts_with("--timeout"))
+                .collect()
+        };
+
+        let mut source = backup_source();
+        assert!(timeout_args(&source).is_empty());
+
+        source.io_timeout_secs = Some(300);
+        assert_eq!(timeout_args(&source), vec![OsString::from("--timeout=300")]);
+    }
+
+    #[test]
+    fn get_command_rsync_path() {
+        let rsync_path_args = |source: &config::BackupSource| -> Vec<OsString> {
+            command_for(source)
+                .into_iter()
+                .filter(|a| a.to_string_lossy().starts_with("--rsync-path"))
+                .collect()
+        };
+
+        let mut source = backup_source();
+        assert!(rsync_path_args(&source).is_empty());
+
+        source.rsync_path = Some(String::from("sudo doppelback sudo -- rsync"));
+        assert_eq!(
+            rsync_path_args(&source),
+            vec![OsString::from("--rsync-path=sudo doppelback sudo -- rsync")]
+        );
+    }
+
+    #[test]
+    fn priority_command_wraps_rsync() {
+        let _lock = crate::test_util::ENV_LOCK.lock().unwrap();
+        let nice = crate::test_util::FakeCommand::new("nice").unwrap();
+        let ionice = crate::test_util::FakeCommand::new("ionice").unwrap();
+
+        let command = vec![OsString::from("/usr/bin/rsync"), OsString::from("--stats")];
+        let wrapped = |nice, ionice_class| {
+            let source = config::BackupSource {
+                nice,
+                ionice_class,
+                ..config::BackupSource::default()
+            };
+            priority_command(&source, command.clone()).unwrap()
+        };
+
+        assert_eq!(wrapped(None, None), command);
+
+        let mut expected = vec![nice.cmd.clone().into_os_string(), "-n".into(), "10".into()];
+        expected.extend(command.clone());
+        assert_eq!(wrapped(Some(10), None), expected);
+
+        let mut expected = vec![ionice.cmd.clone().into_os_string(), "-c".into(), "3".into()];
+        expected.extend(command.clone());
+        assert_eq!(wrapped(None, Some(3)), expected);
+
+        let mut expected = vec![
+            nice.cmd.clone().into_os_string(),
+            "-n".into(),
+            "-5".into(),
+            ionice.cmd.clone().into_os_string(),
+            "-c".into(),
+            "2".into(),
+        ];
+        expected.extend(command.clone());
+        assert_eq!(wrapped(Some(-5), Some(2)), expected);
+    }
+
+    #[test]
+    fn empty_backup_dir_is_initial_sync() {
+        let dir = TempDir::new("backup").unwrap();
+        assert!(is_initial_sync(dir.path()).unwrap());
+
+        fs::write(dir.path().join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        assert!(!is_initial_sync(dir.path()).unwrap());
+
+        // A dry run doesn't create the backup directory.
+        assert!(is_initial_sync(&dir.path().join("missing")).unwrap());
+    }
+
+    #[test]
+    fn dest_root_defaults_to_snapshots() {
+        let dir = TempDir::new("snapshots").unwrap();
+        fs::create_dir(dir.path().join("live")).unwrap();
+        let config = config::Config {
+            snapshots: dir.path().to_path_buf(),
+            ..config::Config::default()
+        };
+
+        let rsync = RsyncCmd::new("host1.example.com", "/etc");
+        assert_eq!(rsync.dest_root(&config).unwrap(), dir.path());
+    }
+
+    #[test]
+    fn dest_root_override() {
+        let scratch = TempDir::new("scratch").unwrap();
+        // The configured snapshots directory isn't needed when writing somewhere else.
+        let config = config::Config {
+            snapshots: PathBuf::from("/nonexistent"),
+            ..config::Config::default()
+        };
+
+        let mut rsync = RsyncCmd::new("host1.example.com", "/etc");
+        rsync.dest_root = Some(scratch.path().to_path_buf());
+        assert_eq!(rsync.dest_root(&config).unwrap(), scratch.path());
+
+        rsync.dest_root = Some(PathBuf::from("scratch"));
+        assert!(matches!(
+            rsync.dest_root(&config),
+            Err(DoppelbackError::InvalidPath(_))
+        ));
+
+        rsync.dest_root = Some(scratch.path().join("missing"));
+        assert!(matches!(
+            rsync.dest_root(&config),
+            Err(DoppelbackError::MissingDir(_))
+        ));
+    }
+
+    #[test]
+    fn get_command_chmod() {
+        let mut source = backup_source();
+        assert!(!command_for(&source)
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--chmod")));
+
+        source.chmod = Some(String::from("D0755,F0644"));
+        let args = command_for(&source);
+        assert!(args.contains(&OsString::from("--chmod=D0755,F0644")));
+        assert!(args.contains(&OsString::from("--fake-super")));
+    }
+
+    #[test]
+    fn get_command_checksum() {
+        let mut source = backup_source();
+        assert!(!command_for(&source).contains(&OsString::from("--checksum")));
+
+        source.checksum = true;
+        assert!(command_for(&source).contains(&OsString::from("--checksum")));
+    }
+
+    #[test]
+    fn get_command_crtimes() {
+        let mut source = backup_source();
+        assert!(!command_for(&source).contains(&OsString::from("--crtimes")));
+
+        source.crtimes = true;
+        assert!(command_for(&source).contains(&OsString::from("--crtimes")));
+    }
+
+    #[test]
+    fn get_command_filter_file() {
+        let mut source = backup_source();
+        assert!(!command_for(&source)
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--filter")));
+
+        source.excludes = vec![String::from("*.iso")];
+        source.filter_file = Some(PathBuf::from("/etc/doppelback/opt.filter"));
+        let args = command_for(&source);
+        let filter = args
+            .iter()
+            .position(|a| a == "--filter=. /etc/doppelback/opt.filter")
+            .expect("filter rule missing");
+        let last_exclude = args
+            .iter()
+            .rposition(|a| a.to_string_lossy().starts_with("--exclude"))
+            .unwrap();
+        assert!(filter > last_exclude);
+    }
+
+    #[test]
+    fn get_command_default_max_size() {
+        let command = command_for(&backup_source());
+        assert!(command.contains(&OsString::from("--max-size=10G")));
+    }
+
+    #[test]
+    fn get_command_custom_max_size() {
+        let command = command_for(&config::BackupSource {
+            max_size: Some(String::from("200G")),
+            ..backup_source()
+        });
+
+        let max_size: Vec<_> = command
+            .iter()
+            .filter(|a| a.to_string_lossy().starts_with("--max-size="))
+            .collect();
+        assert_eq!(max_size, vec![&OsString::from("--max-size=200G")]);
+    }
+
+    #[test]
+    fn get_command_no_config_excludes() {
+        let command = command_for(&backup_source());
+
+        let excludes: Vec<_> = command
+            .iter()
+            .filter(|a| a.to_string_lossy().starts_with("--exclude"))
+            .collect();
+        assert_eq!(
+            excludes,
+            vec![
+                &OsString::from("--exclude=lost+found"),
+                &OsString::from("--exclude=**/.cache"),
+                &OsString::from("--exclude=.*.swp"),
+                &OsString::from("--exclude=.viminfo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_command_config_excludes_are_ordered() {
+        let snapshots = TempDir::new("snapshots").unwrap();
+        let mut exclude_file = snapshots.path().join("live");
+        exclude_file.push("host1.example.com");
+        let _ = fs::create_dir_all(&exclude_file);
+        exclude_file.push("opt_backups.exclude");
+        fs::write(&exclude_file, "*.tmp\n").unwrap();
+
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let source = config::BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            excludes: vec![String::from("*.iso"), String::from("/scratch")],
+            ..config::BackupSource::default()
+        };
+        let dest = config::BackupDest::new(snapshots.path(), "host1.example.com", &source);
+
+        let host_config = backup_host();
+        let command = rsync
+            .get_command(
+                PathBuf::from("/opt/bin/rsync"),
+                &host_config,
+                &[],
+                &source,
+                None,
+                &dest,
+            )
+            .unwrap();
+
+        let excludes: Vec<_> = command
+            .iter()
+            .filter(|a| a.to_string_lossy().starts_with("--exclude"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            excludes[excludes.len() - 4..],
+            [
+                OsString::from("--exclude=.viminfo"),
+                OsString::from("--exclude=*.iso"),
+                OsString::from("--exclude=/scratch"),
+                OsString::from(format!("--exclude-from={}", exclude_file.display())),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_command_includes_are_ordered() {
+        let rules = |source: &config::BackupSource| -> Vec<OsString> {
+            command_for(source)
+                .into_iter()
+                .filter(|a| {
+                    let a = a.to_string_lossy();
+                    a.starts_with("--include")
+                        || a.starts_with("--exclude=")
+                        || a.starts_with("--filter")
+                })
+                .collect()
+        };
+        let mut source = config::BackupSource {
+            path: PathBuf::from("/home"),
+            includes: vec![String::from("keep.iso")],
+            excludes: vec![String::from("*.iso")],
+            filter_file: Some(PathBuf::from("/etc/doppelback/home.filter")),
+            ..config::BackupSource::default()
+        };
+
+        // The include is an exception to the source's excludes, but not to the built-in ones.
+        let found = rules(&source);
+        assert_eq!(
+            found[found.len() - 4..],
+            [
+                OsString::from("--exclude=.viminfo"),
+                OsString::from("--include=keep.iso"),
+                OsString::from("--exclude=*.iso"),
+                OsString::from("--filter=. /etc/doppelback/home.filter"),
+            ]
+        );
+
+        source.include_only = true;
+        source.includes = vec![String::from("/user/"), String::from("/user/docs/***")];
+        let found = rules(&source);
+        assert_eq!(
+            found[found.len() - 7..],
+            [
+                OsString::from("--exclude=.viminfo"),
+                OsString::from("--include=/user/"),
+                OsString::from("--include=/user/docs/***"),
+                OsString::from("--exclude=*.iso"),
+                OsString::from("--filter=. /etc/doppelback/home.filter"),
+                OsString::from("--include=*/"),
+                OsString::from("--exclude=*"),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_command_include_only_nested() {
+        let command = command_for(&config::BackupSource {
+            path: PathBuf::from("/home/user"),
+            includes: vec![String::from("Documents/**/*.pdf")],
+            include_only: true,
+            ..config::BackupSource::default()
+        });
+
+        // Every directory has to be walked to reach the PDFs below Documents, but only the
+        // directories that lead to one end up in the backup.
+        let position = |arg: &str| command.iter().position(|a| a == arg);
+        let include = position("--include=Documents/**/*.pdf").expect("include missing");
+        let dirs = position("--include=*/").expect("directory include missing");
+        let exclude = position("--exclude=*").expect("exclude missing");
+        assert!(include < dirs);
+        assert_eq!(exclude, dirs + 1);
+        assert!(position("--prune-empty-dirs").is_some());
+        assert!(position("--exclude=**/.cache").unwrap() < dirs);
+    }
+
+    fn bwlimit_args(rsync: &RsyncCmd, host_config: &config::BackupHost) -> Vec<OsString> {
+        rsync_command(rsync, host_config, &backup_source())
+            .into_iter()
+            .filter(|a| a.to_string_lossy().starts_with("--bwlimit"))
+            .collect()
+    }
+
+    #[test]
+    fn get_command_no_bwlimit() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        assert!(bwlimit_args(&rsync, &backup_host()).is_empty());
+    }
+
+    #[test]
+    fn get_command_config_bwlimit() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let host_config = config::BackupHost {
+            bwlimit: Some(500),
+            ..backup_host()
+        };
+        assert_eq!(
+            bwlimit_args(&rsync, &host_config),
+            vec![OsString::from("--bwlimit=500")]
+        );
+    }
+
+    #[test]
+    fn get_command_cli_bwlimit_overrides_config() {
+        let mut rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        rsync.bwlimit = Some(100);
+        let host_config = config::BackupHost {
+            bwlimit: Some(500),
+            ..backup_host()
+        };
+        assert_eq!(
+            bwlimit_args(&rsync, &host_config),
+            vec![OsString::from("--bwlimit=100")]
+        );
+    }
+
+    #[test]
+    fn get_command_numeric_ids() {
+        for numeric_ids in [false, true] {
+            let host_config = config::BackupHost {
+                numeric_ids,
+                ..backup_host()
+            };
+            let command = command_with_host(&host_config, &backup_source());
+            assert_eq!(
+                command.contains(&OsString::from("--numeric-ids")),
+                numeric_ids
+            );
+        }
+    }
+
+    fn compress_args(host_config: &config::BackupHost) -> Vec<OsString> {
+        command_with_host(host_config, &backup_source())
+            .into_iter()
+            .filter(|a| a.to_string_lossy().starts_with("--compress") || a == "-z")
+            .collect()
+    }
+
+    fn delete_args(delete_mode: Option<&str>) -> Vec<OsString> {
+        let source = config::BackupSource {
+            delete_mode: delete_mode.map(String::from),
+            ..backup_source()
+        };
+        command_for(&source)
+            .into_iter()
+            .filter(|a| a.to_string_lossy().starts_with("--delete"))
+            .collect()
+    }
+
+    #[test]
+    fn get_command_delete_mode() {
+        for (mode, expected) in [
+            (None, vec!["--delete", "--delete-excluded"]),
+            (Some("during"), vec!["--delete", "--delete-excluded"]),
+            (Some("after"), vec!["--delete-after", "--delete-excluded"]),
+            (Some("before"), vec!["--delete-before", "--delete-excluded"]),
+            (Some("none"), vec![]),
+        ] {
+            assert_eq!(delete_args(mode), expected, "delete_mode {:?}", mode);
+        }
+    }
+
+    #[test]
+    fn get_command_no_compress() {
+        assert!(compress_args(&backup_host()).is_empty());
+
+        let host_config = config::BackupHost {
+            compress: Some(String::from("none")),
+            ..backup_host()
+        };
+        assert!(compress_args(&host_config).is_empty());
+    }
+
+    #[test]
+    fn get_command_compress_choice() {
+        for algorithm in ["zlib", "zstd", "lz4"] {
+            let host_config = config::BackupHost {
+                compress: Some(String::from(algorithm)),
+                ..backup_host()
+            };
+            assert_eq!(
+                compress_args(&host_config),
+                vec![
+                    OsString::from("--compress"),
+                    OsString::from(format!("--compress-choice={}", algorithm)),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn vanished_files_are_not_failures() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        assert!(rsync
+            .check_exit_status(process::ExitStatus::from_raw(0))
+            .is_ok());
+        assert!(rsync
+            .check_exit_status(process::ExitStatus::from_raw(24 << 8))
+            .is_ok());
+    }
+
+    #[test]
+    fn failed_exit_status_is_described() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let err = rsync
+            .check_exit_status(process::ExitStatus::from_raw(23 << 8))
+            .unwrap_err();
+        assert!(matches!(err, DoppelbackError::RsyncFailed(_)));
+        assert_eq!(
+            err.to_string(),
+            "rsync failed with exit status 23 (partial transfer due to error)"
+        );
+    }
+
+    #[test]
+    fn ssh_failure_is_host_unreachable() {
+        let rsync = RsyncCmd::new("host1.example.com", "/opt/backups");
+        let err = rsync
+            .check_exit_status(process::ExitStatus::from_raw(255 << 8))
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            DoppelbackError::HostUnreachable(host) if host == "host1.example.com"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "host1.example.com is unreachable: ssh connection failed"
+        );
+
+        // Other connection problems are still reported as rsync failures.
+        let err = rsync
+            .check_exit_status(process::ExitStatus::from_raw(12 << 8))
+            .unwrap_err();
+        assert!(matches!(err, DoppelbackError::RsyncFailed(_)));
+    }
 }
@@ -0,0 +1,219 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::backup::fmt_bytes;
+use crate::commands::snapshots;
+use crate::config::{BackupDest, BackupHost, Config};
+use crate::doppelback_error::DoppelbackError;
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct SizesCmd {
+    /// Print sizes as a plain number of bytes instead of KiB, MiB, etc.
+    #[structopt(long)]
+    bytes: bool,
+
+    /// Print the results as JSON instead of columns.
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SnapshotSizes {
+    /// Snapshot name, or `live` for the live tree.
+    snapshot: String,
+    sources: Vec<SourceSize>,
+    total: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SourceSize {
+    path: PathBuf,
+    bytes: u64,
+}
+
+impl SizesCmd {
+    /// Prints the size of each of `host`'s sources in the live tree and in every snapshot.
+    ///
+    /// Sizes are the total length of the regular files under each source's directory.  Files
+    /// that are shared between snapshots are counted in each one, so the sizes show how much
+    /// data each snapshot holds rather than how much disk space deleting it would free.
+    pub fn print_sizes(&self, config: &Config, host: &str) -> Result<(), DoppelbackError> {
+        let host_config = config.hosts.get(host).expect("host already checked");
+        let root = config.snapshot_root(host);
+
+        let mut names = vec![String::from("live")];
+        names.extend(
+            snapshots::find_snapshots(root, config.snapshot_name_format())?
+                .into_iter()
+                .map(|s| s.name),
+        );
+        let sizes = names
+            .into_iter()
+            .map(|name| get_sizes(root, name, host, host_config))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if self.json {
+            let json = serde_json::to_string_pretty(&sizes).map_err(io::Error::from)?;
+            println!("{}", json);
+            return Ok(());
+        }
+
+        for snapshot in &sizes {
+            for source in &snapshot.sources {
+                println!(
+                    "{:<11} {:>12} {}",
+                    snapshot.snapshot,
+                    self.format_size(source.bytes),
+                    source.path.display()
+                );
+            }
+            println!(
+                "{:<11} {:>12} total",
+                snapshot.snapshot,
+                self.format_size(snapshot.total)
+            );
+        }
+        Ok(())
+    }
+
+    fn format_size(&self, bytes: u64) -> String {
+        if self.bytes {
+            bytes.to_string()
+        } else {
+            fmt_bytes(bytes)
+        }
+    }
+}
+
+fn get_sizes(
+    root: &Path,
+    snapshot: String,
+    host: &str,
+    host_config: &BackupHost,
+) -> Result<SnapshotSizes, DoppelbackError> {
+    let mut sources = Vec::new();
+    for source in &host_config.sources {
+        let dest = BackupDest::for_snapshot(root, &snapshot, host, source);
+        sources.push(SourceSize {
+            path: source.path.clone(),
+            bytes: tree_size(dest.backup_dir())?,
+        });
+    }
+    let total = sources.iter().map(|s| s.bytes).sum();
+
+    Ok(SnapshotSizes {
+        snapshot,
+        sources,
+        total,
+    })
+}
+
+/// Returns the total size of the regular files under `dir`, or 0 if `dir` doesn't exist.
+///
+/// Symlinks are not followed.
+fn tree_size(dir: &Path) -> io::Result<u64> {
+    let metadata = match fs::symlink_metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        return Ok(if metadata.is_file() {
+            metadata.len()
+        } else {
+            0
+        });
+    }
+
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        total += tree_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupSource;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    fn test_host() -> BackupHost {
+        BackupHost {
+            sources: vec![
+                BackupSource {
+                    path: PathBuf::from("/etc"),
+                    ..BackupSource::default()
+                },
+                BackupSource {
+                    path: PathBuf::from("/home"),
+                    ..BackupSource::default()
+                },
+            ],
+            ..BackupHost::default()
+        }
+    }
+
+    #[test]
+    fn tree_size_counts_nested_files() {
+        let dir = TempDir::new("sizes").unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("top"), vec![0; 10]).unwrap();
+        fs::write(dir.path().join("a/b/nested"), vec![0; 100]).unwrap();
+        symlink("/etc/passwd", dir.path().join("a/link")).unwrap();
+
+        assert_eq!(tree_size(dir.path()).unwrap(), 110);
+        assert_eq!(tree_size(&dir.path().join("nosuch")).unwrap(), 0);
+    }
+
+    #[test]
+    fn missing_source_dir_is_zero() {
+        let root = TempDir::new("sizes").unwrap();
+        let host = test_host();
+        let dest = BackupDest::new(root.path(), "host1", &host.sources[0]);
+        fs::create_dir_all(dest.backup_dir()).unwrap();
+        fs::write(dest.backup_dir().join("hosts"), vec![0; 42]).unwrap();
+
+        let sizes = get_sizes(root.path(), String::from("live"), "host1", &host).unwrap();
+        assert_eq!(
+            sizes,
+            SnapshotSizes {
+                snapshot: String::from("live"),
+                sources: vec![
+                    SourceSize {
+                        path: PathBuf::from("/etc"),
+                        bytes: 42,
+                    },
+                    SourceSize {
+                        path: PathBuf::from("/home"),
+                        bytes: 0,
+                    },
+                ],
+                total: 42,
+            }
+        );
+    }
+
+    #[test]
+    fn sizes_serialize_to_json() {
+        let sizes = SnapshotSizes {
+            snapshot: String::from("20210704.00"),
+            sources: vec![SourceSize {
+                path: PathBuf::from("/etc"),
+                bytes: 42,
+            }],
+            total: 42,
+        };
+        assert_eq!(
+            serde_json::to_string(&sizes).unwrap(),
+            r#"{"snapshot":"20210704.00","sources":[{"path":"/etc","bytes":42}],"total":42}"#
+        );
+    }
+}
@@ -1,59 +1,115 @@
 // Copyright 2021 Benjamin Gordon
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::commands::prune;
 use crate::doppelback_error::DoppelbackError;
+use crate::process_util;
 
-use chrono::{Local, NaiveDate};
-use log::{debug, error};
-use pathsearch::find_executable_in_path;
-use std::ffi::OsString;
-use std::io::{Error, ErrorKind};
-use std::path::{Path, PathBuf};
-use std::process;
-use std::time::{self, SystemTime};
+use chrono::format::{self, Item, Parsed, StrftimeItems};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime};
+use log::{debug, error, info};
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Stdio};
+use std::time::{self, Duration, SystemTime};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt, Default)]
 pub struct MakeSnapshotCmd {
     /// Date of the new snapshot (YYYY-MM-DD).  Defaults to today if not specified.
     date: Option<NaiveDate>,
+
+    /// Kill btrfs if taking the snapshot takes longer than this many seconds.
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Name the snapshot instead of picking the next free `.NN` for the date.
+    #[structopt(long, conflicts_with = "date")]
+    name: Option<String>,
+
+    /// Delete an existing snapshot named with --name and take it again.
+    #[structopt(long, requires = "name")]
+    force: bool,
 }
 
 impl MakeSnapshotCmd {
+    /// Creates a command for today's snapshot that gives up after `timeout_secs`, if set.
+    pub fn with_timeout(timeout_secs: Option<u64>) -> Self {
+        MakeSnapshotCmd {
+            timeout: timeout_secs,
+            ..MakeSnapshotCmd::default()
+        }
+    }
+
+    /// Takes a read-only snapshot of `live`, named with `name_format` and a `.NN` suffix.
+    ///
+    /// If a date was passed on the command line, the time of day in the name is midnight.  A name
+    /// passed with `--name` is used as is, and with `--force` an existing snapshot of that name is
+    /// deleted and taken again.
+    ///
+    /// If `host` is given, only `live/<host>` is snapshotted, into `<name>/<host>` so that the
+    /// backups inside keep the same paths as in a whole-tree snapshot.  btrfs snapshots don't
+    /// include nested subvolumes, so this is how hosts that live on their own subvolume get
+    /// snapshotted.  `live/<host>` must be a subvolume.
     pub fn make_snapshot<P: AsRef<Path>>(
         &self,
         snapshots: P,
+        sudo: Option<&Path>,
+        btrfs: &Path,
+        host: Option<&str>,
+        name_format: &str,
         dry_run: bool,
     ) -> Result<String, DoppelbackError> {
-        let date = self.date.unwrap_or_else(|| Local::now().date_naive());
+        let time = match self.date {
+            Some(date) => date.and_time(NaiveTime::MIN),
+            None => Local::now().naive_local(),
+        };
 
-        let snapname = next_available_name(snapshots.as_ref(), date);
-        let livedir = snapshots.as_ref().join("live");
+        let snapname = self.snapshot_path(snapshots.as_ref(), time, name_format)?;
+        let (livedir, snapdir) = snapshot_paths(snapshots.as_ref(), &snapname, host);
+        if host.is_some() {
+            check_live_subvolume(&livedir)?;
+        }
 
-        let btrfs = find_executable_in_path("btrfs")
-            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Couldn't find btrfs in PATH"))?;
+        let replace = snapdir.exists();
+        if replace && !self.force {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "{} already exists; pass --force to replace it",
+                    snapdir.display()
+                ),
+            )
+            .into());
+        }
 
-        let command = self.get_command(&btrfs, &livedir, &snapname);
+        let delete_command = replace.then(|| self.get_delete_command(sudo, btrfs, &snapdir));
+        let command = self.get_command(sudo, btrfs, &livedir, &snapdir);
         debug!("Snapshot command: {:?}", &command);
-        if !dry_run {
-            let timestamp = SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .map_err(|_| Error::new(ErrorKind::InvalidData, "Couldn't get system time"))?
-                .as_secs();
-            utime::set_file_times(livedir, timestamp, timestamp)?;
-
-            let child = process::Command::new(&command[0])
-                .args(&command[1..])
-                .current_dir("/")
-                .output()?;
-            if !child.status.success() {
-                error!(
-                    "{:?} failed: {}",
-                    btrfs,
-                    String::from_utf8_lossy(&child.stderr)
-                );
-                return Err(DoppelbackError::CommandFailed(btrfs, child.status));
+        if dry_run {
+            if let Some(delete_command) = &delete_command {
+                process_util::print_dry_run(delete_command);
+            }
+            process_util::print_dry_run(&command);
+        } else {
+            if let Some(delete_command) = &delete_command {
+                info!("Replacing {}", snapdir.display());
+                run_delete(btrfs, delete_command)?;
+            }
+            // A named per-host snapshot can share its directory with other hosts.
+            let created = host.is_some() && !snapname.exists();
+            if created {
+                fs::create_dir(&snapname)?;
             }
+            let result = self.run_snapshot(btrfs, &command, &livedir);
+            if result.is_err() && created {
+                let _ = fs::remove_dir(&snapname);
+            }
+            result?;
         }
 
         Ok(snapname
@@ -63,34 +119,295 @@ impl MakeSnapshotCmd {
             .to_string())
     }
 
-    fn get_command(&self, btrfs: &Path, old: &Path, new: &Path) -> Vec<OsString> {
-        vec![
+    /// Returns the path of the new snapshot: `--name` if it was passed, or else the next free
+    /// name for `time`.  `--force` is refused without `--name` so that it can never replace a
+    /// snapshot that was picked automatically.
+    fn snapshot_path(
+        &self,
+        snapshots: &Path,
+        time: NaiveDateTime,
+        name_format: &str,
+    ) -> Result<PathBuf, DoppelbackError> {
+        match &self.name {
+            Some(name) => {
+                let mut components = Path::new(name).components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) {
+                    return Err(DoppelbackError::InvalidPath(PathBuf::from(name)));
+                }
+                Ok(snapshots.join(name))
+            }
+            None if self.force => {
+                Err(Error::new(ErrorKind::InvalidInput, "--force needs an explicit --name").into())
+            }
+            None => Ok(next_available_name(snapshots, time, name_format)),
+        }
+    }
+
+    /// Runs the btrfs `command` that snapshots `livedir`, after touching `livedir` so that the
+    /// snapshot's mtime records when it was taken.
+    fn run_snapshot(
+        &self,
+        btrfs: &Path,
+        command: &[OsString],
+        livedir: &Path,
+    ) -> Result<(), DoppelbackError> {
+        let timestamp = SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Couldn't get system time"))?
+            .as_secs();
+        utime::set_file_times(livedir, timestamp, timestamp)?;
+
+        let timeout = self.timeout.map(Duration::from_secs);
+        let mut child = process::Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir("/")
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let status = process_util::wait_with_timeout(&mut child, timeout)?.ok_or_else(|| {
+            DoppelbackError::Timeout(
+                format!("btrfs snapshot of {}", livedir.display()),
+                timeout.unwrap_or_default(),
+            )
+        })?;
+        if !status.success() {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = child.stderr.take() {
+                pipe.read_to_string(&mut stderr)?;
+            }
+            error!("{:?} failed: {}", btrfs, stderr);
+            return Err(DoppelbackError::CommandFailed(btrfs.to_path_buf(), status));
+        }
+        Ok(())
+    }
+
+    /// Returns the btrfs command that snapshots `old` into `new`, run through `sudo --` if `sudo`
+    /// is given.
+    fn get_command(
+        &self,
+        sudo: Option<&Path>,
+        btrfs: &Path,
+        old: &Path,
+        new: &Path,
+    ) -> Vec<OsString> {
+        let sudo = sudo.map(|sudo| [sudo.as_os_str().to_os_string(), OsString::from("--")]);
+        let btrfs_args = [
             btrfs.as_os_str().to_os_string(),
             OsString::from("subvolume"),
             OsString::from("snapshot"),
             OsString::from("-r"),
             old.as_os_str().to_os_string(),
             new.as_os_str().to_os_string(),
-        ]
+        ];
+        sudo.into_iter().flatten().chain(btrfs_args).collect()
+    }
+
+    /// Returns the btrfs command that deletes the snapshot at `snapdir`, run through `sudo --` if
+    /// `sudo` is given.
+    fn get_delete_command(
+        &self,
+        sudo: Option<&Path>,
+        btrfs: &Path,
+        snapdir: &Path,
+    ) -> Vec<OsString> {
+        let sudo = sudo.map(|sudo| [sudo.as_os_str().to_os_string(), OsString::from("--")]);
+        sudo.into_iter()
+            .flatten()
+            .chain(prune::get_delete_command(btrfs, snapdir))
+            .collect()
+    }
+}
+
+fn run_delete(btrfs: &Path, command: &[OsString]) -> Result<(), DoppelbackError> {
+    let status = process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir("/")
+        .status()?;
+    if !status.success() {
+        return Err(DoppelbackError::CommandFailed(btrfs.to_path_buf(), status));
+    }
+    Ok(())
+}
+
+/// Returns the subvolume to snapshot and where the snapshot goes for a new snapshot at `snapname`.
+///
+/// Without a host, `live` itself becomes `snapname`.  With a host, only `live/<host>` is
+/// snapshotted, into `snapname/<host>`.
+fn snapshot_paths(snapshots: &Path, snapname: &Path, host: Option<&str>) -> (PathBuf, PathBuf) {
+    let livedir = snapshots.join("live");
+    match host {
+        Some(host) => (livedir.join(host), snapname.join(host)),
+        None => (livedir, snapname.to_path_buf()),
+    }
+}
+
+/// chrono format string for the date part of snapshot directory names.
+pub const SNAPSHOT_DATE_FORMAT: &str = "%Y%m%d";
+
+/// A dated snapshot directory found under the snapshots root.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Snapshot {
+    pub time: NaiveDateTime,
+    pub index: u32,
+    pub name: String,
+}
+
+impl Snapshot {
+    /// Returns the day the snapshot was taken.
+    pub fn date(&self) -> NaiveDate {
+        self.time.date()
+    }
+}
+
+/// Parses a snapshot directory name made by `make_snapshot` with the chrono `format`, i.e. the
+/// formatted time followed by `.NN`.
+///
+/// Formats without minutes or hours parse as the start of the hour or day.  Returns `None` for
+/// anything that doesn't round-trip through `format`, so unrelated directories such as `live`
+/// are ignored.
+pub fn parse_snapshot_name(name: &str, format: &str) -> Option<(NaiveDateTime, u32)> {
+    let (time_str, index_str) = name.rsplit_once('.')?;
+    if index_str.len() < 2 || !index_str.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let index = index_str.parse().ok()?;
+
+    let mut parsed = Parsed::new();
+    format::parse(&mut parsed, time_str, StrftimeItems::new(format)).ok()?;
+    if parsed.hour_div_12().is_none() && parsed.hour_mod_12().is_none() {
+        parsed.set_hour(0).ok()?;
+    }
+    if parsed.minute().is_none() {
+        parsed.set_minute(0).ok()?;
+    }
+    let time = parsed.to_naive_datetime_with_offset(0).ok()?;
+    if time.format(format).to_string() != time_str {
+        return None;
+    }
+    Some((time, index))
 }
 
-fn next_available_name(snapshots: &Path, date: NaiveDate) -> PathBuf {
+/// Snapshot dates selected with `--since` and `--until`.  Both ends are inclusive.
+#[derive(Debug, Default, StructOpt)]
+pub struct DateRange {
+    /// Only include snapshots taken on or after this date (YYYY-MM-DD).
+    #[structopt(long, alias = "newer-than")]
+    pub since: Option<NaiveDate>,
+
+    /// Only include snapshots taken on or before this date (YYYY-MM-DD).
+    #[structopt(long)]
+    pub until: Option<NaiveDate>,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        let after_since = !matches!(self.since, Some(since) if date < since);
+        let before_until = !matches!(self.until, Some(until) if date > until);
+        after_since && before_until
+    }
+}
+
+/// Returns all the snapshots in `snapshots` named with the chrono `format`, newest first.
+pub fn find_snapshots<P: AsRef<Path>>(snapshots: P, format: &str) -> io::Result<Vec<Snapshot>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(snapshots)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some((time, index)) = parse_snapshot_name(&name, format) {
+            found.push(Snapshot { time, index, name });
+        }
+    }
+    found.sort_by(|a, b| b.cmp(a));
+    Ok(found)
+}
+
+/// Inode number btrfs always assigns to the root directory of a subvolume.
+const BTRFS_SUBVOLUME_INO: u64 = 256;
+
+/// `f_type` that statfs(2) reports for btrfs.
+const BTRFS_SUPER_MAGIC: u32 = 0x9123_683E;
+
+/// Checks whether `path` looks like the root of a btrfs subvolume.
+///
+/// This uses the inode number heuristic instead of `btrfs subvolume show` so that it works without
+/// root privileges.  A plain directory on btrfs will never have inode 256, but one on another
+/// filesystem can, so the filesystem type is checked with statfs(2) first.
+pub fn is_btrfs_subvolume<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let path = path.as_ref();
+    let meta = fs::metadata(path)?;
+    if !meta.is_dir() || meta.ino() != BTRFS_SUBVOLUME_INO {
+        return Ok(false);
+    }
+    is_on_btrfs(path)
+}
+
+/// Checks whether `path` is on a btrfs filesystem.
+fn is_on_btrfs(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // f_type is signed on some targets, so compare only the low 32 bits the magic uses.
+    Ok(stat.f_type as u32 == BTRFS_SUPER_MAGIC)
+}
+
+/// Checks that `live_dir` is a btrfs subvolume that can be snapshotted.
+///
+/// A plain directory makes `btrfs subvolume snapshot` fail with a confusing error, so this is
+/// checked up front whenever a snapshot is about to be taken.  btrfs is the only snapshot backend,
+/// so there is nothing to check for runs that don't take a snapshot.
+pub fn check_live_subvolume<P: AsRef<Path>>(live_dir: P) -> Result<(), DoppelbackError> {
+    let live_dir = live_dir.as_ref();
+    match is_btrfs_subvolume(live_dir) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(DoppelbackError::NotSubvolume(live_dir.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(DoppelbackError::MissingDir(live_dir.to_path_buf()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn next_available_name(snapshots: &Path, time: NaiveDateTime, format: &str) -> PathBuf {
+    let prefix = time.format(format).to_string();
     let mut i = 0;
-    let mut candidate = format!("{}.{:02}", date.format("%Y%m%d"), i);
-    let mut dir = snapshots.join(candidate);
+    let mut dir = snapshots.join(format!("{}.{:02}", prefix, i));
     while dir.exists() {
         i += 1;
-        candidate = format!("{}.{:02}", date.format("%Y%m%d"), i);
-        dir = snapshots.join(candidate);
+        dir = snapshots.join(format!("{}.{:02}", prefix, i));
     }
     dir
 }
 
+/// Checks that `format` is a valid chrono format string that produces a usable directory name.
+pub fn check_name_format(format: &str) -> Result<(), DoppelbackError> {
+    let invalid =
+        |reason: &str| DoppelbackError::InvalidConfig(format!("snapshot_name_format {}", reason));
+
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(invalid("is not a valid date format"));
+    }
+    let sample = NaiveDate::from_ymd_opt(2021, 7, 4)
+        .and_then(|d| d.and_hms_opt(13, 5, 9))
+        .expect("invalid sample date")
+        .format(format)
+        .to_string();
+    if sample.is_empty() || sample.contains(['/', '\0']) || sample.starts_with('.') {
+        return Err(invalid("must produce a plain file name"));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
     use tempdir::TempDir;
 
     #[test]
@@ -98,12 +415,181 @@ mod tests {
         let dir = TempDir::new("names").unwrap();
         let date = NaiveDate::from_ymd_opt(2021, 07, 04).unwrap();
 
-        let name = next_available_name(dir.path(), date);
+        let name = next_available_name(dir.path(), date.into(), SNAPSHOT_DATE_FORMAT);
 
         let expected = dir.path().join("20210704.00");
         assert_eq!(name, expected);
     }
 
+    #[test]
+    fn command_snapshots_whole_live_tree() {
+        let cmd = MakeSnapshotCmd::default();
+        let (live, snap) = snapshot_paths(
+            Path::new("/snapshots"),
+            Path::new("/snapshots/20210704.00"),
+            None,
+        );
+        assert_eq!(
+            cmd.get_command(None, Path::new("/sbin/btrfs"), &live, &snap),
+            vec![
+                "/sbin/btrfs",
+                "subvolume",
+                "snapshot",
+                "-r",
+                "/snapshots/live",
+                "/snapshots/20210704.00"
+            ]
+        );
+    }
+
+    #[test]
+    fn command_runs_through_sudo() {
+        let cmd = MakeSnapshotCmd::default();
+        let (live, snap) = snapshot_paths(
+            Path::new("/snapshots"),
+            Path::new("/snapshots/20210704.00"),
+            None,
+        );
+        assert_eq!(
+            cmd.get_command(
+                Some(Path::new("/usr/bin/sudo")),
+                Path::new("/sbin/btrfs"),
+                &live,
+                &snap
+            ),
+            vec![
+                "/usr/bin/sudo",
+                "--",
+                "/sbin/btrfs",
+                "subvolume",
+                "snapshot",
+                "-r",
+                "/snapshots/live",
+                "/snapshots/20210704.00"
+            ]
+        );
+    }
+
+    #[test]
+    fn command_snapshots_one_host() {
+        let cmd = MakeSnapshotCmd::default();
+        let (live, snap) = snapshot_paths(
+            Path::new("/snapshots"),
+            Path::new("/snapshots/20210704.00"),
+            Some("host1"),
+        );
+        assert_eq!(
+            cmd.get_command(None, Path::new("/sbin/btrfs"), &live, &snap),
+            vec![
+                "/sbin/btrfs",
+                "subvolume",
+                "snapshot",
+                "-r",
+                "/snapshots/live/host1",
+                "/snapshots/20210704.00/host1"
+            ]
+        );
+    }
+
+    #[test]
+    fn host_snapshot_requires_subvolume() {
+        let dir = TempDir::new("snapshots").unwrap();
+        fs::create_dir_all(dir.path().join("live/host1")).unwrap();
+
+        let result = MakeSnapshotCmd::default().make_snapshot(
+            dir.path(),
+            None,
+            Path::new("/bin/false"),
+            Some("host1"),
+            SNAPSHOT_DATE_FORMAT,
+            true,
+        );
+        assert!(matches!(
+            result,
+            Err(DoppelbackError::NotSubvolume(p)) if p == dir.path().join("live/host1")
+        ));
+    }
+
+    #[test]
+    fn force_replaces_named_snapshot() {
+        let dir = TempDir::new("snapshots").unwrap();
+        fs::create_dir_all(dir.path().join("20210704.00")).unwrap();
+        let make = |force| {
+            let cmd = MakeSnapshotCmd {
+                name: Some(String::from("20210704.00")),
+                force,
+                ..MakeSnapshotCmd::default()
+            };
+            cmd.make_snapshot(
+                dir.path(),
+                None,
+                Path::new("/bin/false"),
+                None,
+                SNAPSHOT_DATE_FORMAT,
+                true,
+            )
+        };
+
+        let err = make(false).unwrap_err();
+        assert!(err.to_string().contains("pass --force"), "{}", err);
+        assert_eq!(make(true).unwrap(), "20210704.00");
+
+        let cmd = MakeSnapshotCmd::default();
+        let snap = dir.path().join("20210704.00");
+        assert_eq!(
+            cmd.get_delete_command(
+                Some(Path::new("/usr/bin/sudo")),
+                Path::new("/sbin/btrfs"),
+                &snap
+            ),
+            vec![
+                OsString::from("/usr/bin/sudo"),
+                OsString::from("--"),
+                OsString::from("/sbin/btrfs"),
+                OsString::from("subvolume"),
+                OsString::from("delete"),
+                snap.into_os_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn force_requires_name() {
+        let parse = |args: &[&str]| MakeSnapshotCmd::from_iter_safe(args.iter());
+        assert!(parse(&["make-snapshot", "--force"]).is_err());
+        assert!(parse(&["make-snapshot", "--force", "--name", "20210704.00"]).is_ok());
+        assert!(parse(&["make-snapshot", "--name", "20210704.00", "2021-07-04"]).is_err());
+
+        let dir = TempDir::new("snapshots").unwrap();
+        let cmd = MakeSnapshotCmd {
+            force: true,
+            ..MakeSnapshotCmd::default()
+        };
+        assert!(cmd
+            .make_snapshot(
+                dir.path(),
+                None,
+                Path::new("/bin/false"),
+                None,
+                SNAPSHOT_DATE_FORMAT,
+                true,
+            )
+            .is_err());
+
+        for name in ["../live", "a/b", "/20210704.00", ""] {
+            let cmd = MakeSnapshotCmd {
+                name: Some(name.to_string()),
+                ..MakeSnapshotCmd::default()
+            };
+            assert!(
+                cmd.snapshot_path(dir.path(), NaiveDateTime::MIN, SNAPSHOT_DATE_FORMAT)
+                    .is_err(),
+                "{} accepted",
+                name
+            );
+        }
+    }
+
     #[test]
     fn name_skips_existing() {
         let dir = TempDir::new("names").unwrap();
@@ -111,9 +597,188 @@ mod tests {
         fs::create_dir(dir.path().join("20210704.00")).unwrap();
         fs::create_dir(dir.path().join("20210704.01")).unwrap();
 
-        let name = next_available_name(dir.path(), date);
+        let name = next_available_name(dir.path(), date.into(), SNAPSHOT_DATE_FORMAT);
 
         let expected = dir.path().join("20210704.02");
         assert_eq!(name, expected);
     }
+
+    #[test]
+    fn name_custom_hourly_format() {
+        let dir = TempDir::new("names").unwrap();
+        let time = NaiveDate::from_ymd_opt(2021, 7, 4)
+            .unwrap()
+            .and_hms_opt(13, 45, 0)
+            .unwrap();
+        fs::create_dir(dir.path().join("20210704-13.00")).unwrap();
+
+        let name = next_available_name(dir.path(), time, "%Y%m%d-%H");
+
+        assert_eq!(name, dir.path().join("20210704-13.01"));
+    }
+
+    #[test]
+    fn name_format_must_be_file_name() {
+        assert!(check_name_format(SNAPSHOT_DATE_FORMAT).is_ok());
+        assert!(check_name_format("%Y%m%d-%H%M").is_ok());
+        assert!(check_name_format("%Y/%m/%d").is_err());
+        assert!(check_name_format("%F/").is_err());
+        assert!(check_name_format("").is_err());
+        assert!(check_name_format(".%Y").is_err());
+        assert!(check_name_format("%Q").is_err());
+    }
+
+    #[test]
+    fn parse_snapshot_name_round_trips() {
+        let time: NaiveDateTime = NaiveDate::from_ymd_opt(2021, 7, 4).unwrap().into();
+        let dir = TempDir::new("names").unwrap();
+        let name = next_available_name(dir.path(), time, SNAPSHOT_DATE_FORMAT);
+        let name = name.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(
+            parse_snapshot_name(name, SNAPSHOT_DATE_FORMAT),
+            Some((time, 0))
+        );
+        assert_eq!(
+            parse_snapshot_name("20210704.13", SNAPSHOT_DATE_FORMAT),
+            Some((time, 13))
+        );
+    }
+
+    #[test]
+    fn parse_snapshot_name_uses_format() {
+        let time = |h, m| {
+            NaiveDate::from_ymd_opt(2021, 7, 4)
+                .and_then(|d| d.and_hms_opt(h, m, 0))
+                .unwrap()
+        };
+        assert_eq!(
+            parse_snapshot_name("04-07-2021_13.02", "%d-%m-%Y_%H"),
+            Some((time(13, 0), 2))
+        );
+        assert_eq!(
+            parse_snapshot_name("2021-07-04T13:05.00", "%Y-%m-%dT%H:%M"),
+            Some((time(13, 5), 0))
+        );
+        assert_eq!(
+            parse_snapshot_name("20210704.01", SNAPSHOT_DATE_FORMAT),
+            Some((time(0, 0), 1))
+        );
+        assert_eq!(parse_snapshot_name("20210704.01", "%d-%m-%Y_%H"), None);
+        assert_eq!(parse_snapshot_name("4-7-2021_13.01", "%d-%m-%Y_%H"), None);
+    }
+
+    #[test]
+    fn parse_snapshot_name_ignores_garbage() {
+        for name in [
+            "live",
+            "20210704",
+            "20210704.1",
+            "20210704.ab",
+            "2021074.01",
+            "20211304.01",
+        ] {
+            assert_eq!(parse_snapshot_name(name, SNAPSHOT_DATE_FORMAT), None);
+        }
+    }
+
+    #[test]
+    fn date_range_is_inclusive() {
+        let date = |d| NaiveDate::from_ymd_opt(2021, 7, d).unwrap();
+        let range = DateRange {
+            since: Some(date(3)),
+            until: Some(date(5)),
+        };
+        assert!(!range.contains(date(2)));
+        assert!(range.contains(date(3)));
+        assert!(range.contains(date(5)));
+        assert!(!range.contains(date(6)));
+
+        let open = DateRange::default();
+        assert!(open.contains(date(1)));
+
+        let since_only = DateRange {
+            since: Some(date(3)),
+            until: None,
+        };
+        assert!(since_only.contains(date(31)));
+        assert!(!since_only.contains(date(1)));
+    }
+
+    #[test]
+    fn find_snapshots_sorts_newest_first() {
+        let dir = TempDir::new("names").unwrap();
+        for name in [
+            "20210704.00",
+            "20210704.01",
+            "20210601.00",
+            "live",
+            "junk.00",
+        ] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+
+        let names: Vec<_> = find_snapshots(dir.path(), SNAPSHOT_DATE_FORMAT)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["20210704.01", "20210704.00", "20210601.00"]);
+    }
+
+    #[test]
+    fn find_snapshots_uses_format() {
+        let dir = TempDir::new("names").unwrap();
+        for name in [
+            "2021070409.00",
+            "2021070413.00",
+            "2021070322.01",
+            "20210704.00",
+        ] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+
+        let names: Vec<_> = find_snapshots(dir.path(), "%Y%m%d%H")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["2021070413.00", "2021070409.00", "2021070322.01"]
+        );
+    }
+
+    #[test]
+    fn plain_dir_is_not_subvolume() {
+        let dir = TempDir::new("subvol").unwrap();
+        let plain = dir.path().join("plain");
+        fs::create_dir(&plain).unwrap();
+
+        assert!(!is_btrfs_subvolume(&plain).unwrap());
+        assert!(is_btrfs_subvolume(dir.path().join("nosuch")).is_err());
+    }
+
+    #[test]
+    fn proc_is_not_btrfs() {
+        // /proc is never btrfs, so an inode number of 256 there can't make it a subvolume.
+        assert!(!is_on_btrfs(Path::new("/proc")).unwrap());
+        assert!(is_on_btrfs(Path::new("/nosuch")).is_err());
+    }
+
+    #[test]
+    fn check_live_subvolume_explains_plain_dir() {
+        let dir = TempDir::new("subvol").unwrap();
+        let live = dir.path().join("live");
+        fs::create_dir(&live).unwrap();
+
+        let err = check_live_subvolume(&live).unwrap_err();
+        assert!(matches!(&err, DoppelbackError::NotSubvolume(p) if p == &live));
+        assert!(err.to_string().contains("btrfs subvolume create"));
+
+        assert!(matches!(
+            check_live_subvolume(dir.path().join("nosuch")),
+            Err(DoppelbackError::MissingDir(_))
+        ));
+    }
 }
@@ -3,7 +3,7 @@
 
 use crate::args::GlobalArgs;
 use crate::config::{BackupHost, BackupSource, ConfigTestCmd, ConfigTestType, Inhibit};
-use crate::rsync_util;
+use crate::rsync_util::RsyncServerInvocation;
 use log::{error, info};
 use pathsearch::find_executable_in_path;
 use std::ffi::OsString;
@@ -61,7 +61,28 @@ impl SshCmd {
     }
 
     fn get_command<'a>(&self, host_config: &'a BackupHost) -> Result<ParsedCmd<'a>, Error> {
-        let args: Vec<&str> = self.original_cmd.split_ascii_whitespace().collect();
+        // Hooks are arbitrary shell commands, so only run them if they exactly match one
+        // configured for this host.
+        let hooks = [&host_config.pre_hook, &host_config.post_hook];
+        if hooks
+            .iter()
+            .any(|hook| hook.as_deref() == Some(self.original_cmd.trim()))
+        {
+            info!("Running backup hook");
+            return Ok(ParsedCmd {
+                command: "sh".into(),
+                args: vec![
+                    OsString::from("-c"),
+                    OsString::from(self.original_cmd.trim()),
+                ],
+                source: None,
+                sudo: false,
+                inhibit: Inhibit::None,
+            });
+        }
+
+        let words = split_command(&self.original_cmd)?;
+        let args: Vec<&str> = words.iter().map(String::as_str).collect();
         if args.is_empty() {
             error!("Missing arguments to ssh subcommand");
             return Err(Error::new(ErrorKind::InvalidInput, "Missing arguments"));
@@ -69,21 +90,10 @@ impl SshCmd {
 
         match args[0] {
             "rsync" => {
-                let path = args.last().ok_or_else(|| {
-                    Error::new(
-                        ErrorKind::InvalidInput,
-                        "rsync path not found in SSH_ORIGINAL_COMMAND",
-                    )
-                })?;
-                let path = PathBuf::from(path);
-                if !path.is_absolute() {
-                    return Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        format!("rsync path is not absolute: {}", path.display()),
-                    ));
-                }
+                let invocation = RsyncServerInvocation::parse(&args[1..])?;
+                let path = &invocation.source;
                 info!("Looking for {} in host backup config", path.display());
-                let source_config = host_config.get_source(&path).ok_or_else(|| {
+                let source_config = host_config.get_source(path).ok_or_else(|| {
                     Error::new(
                         ErrorKind::NotFound,
                         format!("Backup source {} not found in config", path.display()),
@@ -92,7 +102,7 @@ impl SshCmd {
 
                 Ok(ParsedCmd {
                     command: "rsync".into(),
-                    args: rsync_util::filter_args(&args[1..])?,
+                    args: invocation.filtered_args()?,
                     source: Some(source_config),
                     sudo: source_config.root,
                     inhibit: host_config
@@ -207,88 +217,180 @@ impl SshCmd {
     }
 }
 
+/// Splits an ssh command line into words the way a POSIX shell would, without expanding anything.
+///
+/// Single quotes, double quotes and backslashes are honored so that a path containing spaces
+/// arrives as one argument.  The words are passed straight to exec, so other shell syntax has no
+/// meaning.  Control characters other than tab are rejected anywhere in the command: no real rsync
+/// or doppelback command contains them, and a shell would take a newline as the start of another
+/// command.
+fn split_command(command: &str) -> Result<Vec<String>, Error> {
+    if command.chars().any(|c| c.is_control() && c != '\t') {
+        error!("Control character in ssh command {:?}", command);
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Control character in SSH_ORIGINAL_COMMAND",
+        ));
+    }
+    let unterminated = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "Unterminated quote or escape in SSH_ORIGINAL_COMMAND",
+        )
+    };
+
+    let mut words = Vec::new();
+    // `None` between words, so that an empty quoted string still counts as a word.
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or_else(unterminated)? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars.next().ok_or_else(unterminated)?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
-    use std::io::Result;
-    use std::os::unix::fs::symlink;
-    use std::path::{Path, PathBuf};
-    use std::sync::Mutex;
+    use crate::test_util::{FakeCommand, ENV_LOCK};
     use tempdir::TempDir;
 
-    lazy_static! {
-        // Tests should lock this before manipulating PATH.  Otherwise FakeCommand
-        // instances in separate threads can end up overwriting each other's changes.
-        static ref ENV_LOCK: Mutex<()> = Mutex::new(());
-    }
-
-    struct FakeCommand {
-        dir: tempdir::TempDir,
-        cmd: PathBuf,
-    }
-
-    impl FakeCommand {
-        fn new<P: AsRef<Path>>(command: P) -> Result<FakeCommand> {
-            let dir = TempDir::new("test")?;
-
-            let file_path = dir.path().join(command);
-            symlink("/bin/false", &file_path)?;
-
-            if let Some(path) = env::var_os("PATH") {
-                let mut paths = env::split_paths(&path).collect::<Vec<_>>();
-                paths.insert(0, dir.path().to_path_buf());
-                let new_path = env::join_paths(paths)
-                    .or(Err(Error::new(ErrorKind::Other, "Failed to join paths")))?;
-                env::set_var("PATH", &new_path);
-            }
+    #[test]
+    fn configured_hook_is_accepted() {
+        let host_config = BackupHost {
+            pre_hook: Some(String::from("/usr/local/bin/dump-db --all")),
+            post_hook: Some(String::from("rm -f /var/backups/db.sql")),
+            ..BackupHost::default()
+        };
 
-            Ok(FakeCommand {
-                dir: dir,
-                cmd: file_path,
-            })
+        for hook in ["/usr/local/bin/dump-db --all", "rm -f /var/backups/db.sql"] {
+            let ssh = SshCmd {
+                original_cmd: String::from(hook),
+            };
+            let parsed = ssh.get_command(&host_config).unwrap();
+            assert_eq!(parsed.command, "sh");
+            assert_eq!(
+                parsed.args,
+                vec![OsString::from("-c"), OsString::from(hook)]
+            );
+            assert!(!parsed.sudo);
         }
     }
 
-    impl Drop for FakeCommand {
-        fn drop(&mut self) {
-            if let Some(path) = env::var_os("PATH") {
-                let paths = env::split_paths(&path)
-                    .filter(|p| p != self.dir.path())
-                    .collect::<Vec<_>>();
-                if let Ok(new_path) = env::join_paths(paths) {
-                    env::set_var("PATH", &new_path);
-                } else {
-                    error!("Failed to remove {} from PATH", self.dir.path().display());
-                }
-            }
-        }
+    #[test]
+    fn unconfigured_hook_is_rejected() {
+        let host_config = BackupHost {
+            pre_hook: Some(String::from("/usr/local/bin/dump-db")),
+            ..BackupHost::default()
+        };
+        let ssh = SshCmd {
+            original_cmd: String::from("/usr/local/bin/dump-db; rm -rf /"),
+        };
+        let err = ssh.get_command(&host_config).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
     }
 
     #[test]
-    fn fakecommand_cleans_path() {
-        let _lock = ENV_LOCK.lock().unwrap();
+    fn split_command_handles_quotes() {
+        let words =
+            split_command("rsync  --server\t'/srv/my dir/' \"a \\\"b\\\" \\x\" c\\ d '' x\"y\"'z'")
+                .unwrap();
+        assert_eq!(
+            words,
+            vec![
+                "rsync",
+                "--server",
+                "/srv/my dir/",
+                "a \"b\" \\x",
+                "c d",
+                "",
+                "xyz"
+            ]
+        );
 
-        let mytest = FakeCommand::new("mytest").unwrap();
-        let path = env::var_os("PATH").unwrap();
-        let dir = mytest.dir.path().to_str().unwrap().to_string();
-        assert!(path.to_str().unwrap().contains(&dir));
-        drop(mytest);
-        let path = env::var_os("PATH").unwrap();
-        assert!(!path.to_str().unwrap().contains(&dir));
+        assert!(split_command("rsync '/srv").is_err());
+        assert!(split_command("rsync \"/srv").is_err());
+        assert!(split_command("rsync /srv\\").is_err());
     }
 
     #[test]
-    fn fakecommand_is_found() {
-        let _lock = ENV_LOCK.lock().unwrap();
+    fn control_characters_are_rejected() {
+        for cmd in [
+            "rsync --server --sender . /etc\nrm -rf /",
+            "rsync --server --sender . '/etc\n'",
+            "rsync --server --sender . /etc\0",
+            "rsync --server --sender . /etc\x1b[2J",
+        ] {
+            let err = split_command(cmd).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", cmd);
+        }
 
-        let mytest = FakeCommand::new("mytest").unwrap();
+        let ssh = SshCmd {
+            original_cmd: String::from("doppelback config-test\n--type=host"),
+        };
+        assert!(ssh.get_command(&BackupHost::default()).is_err());
+    }
 
-        assert_eq!(mytest.cmd.file_name().unwrap(), "mytest");
-        assert!(mytest.cmd.exists());
+    #[test]
+    fn get_rsync_quoted_path_with_space() {
+        let dir = TempDir::new("ssh").unwrap();
+        let source = dir.path().join("my dir");
+        std::fs::create_dir(&source).unwrap();
+        let host_config = BackupHost {
+            sources: vec![BackupSource {
+                path: source.clone(),
+                ..BackupSource::default()
+            }],
+            ..BackupHost::default()
+        };
 
-        let found = find_executable_in_path("mytest").unwrap();
-        assert_eq!(found, mytest.cmd);
+        for quoted in [
+            format!("'{}/'", source.display()),
+            format!("{}/", source.display()).replace(' ', "\\ "),
+        ] {
+            let ssh = SshCmd {
+                original_cmd: format!("rsync --server --sender -logDtpre.iLsfxC . {}", quoted),
+            };
+            let parsed = ssh.get_command(&host_config).unwrap();
+            assert_eq!(parsed.source.unwrap().path, source);
+            assert_eq!(
+                parsed.args.last().unwrap(),
+                &OsString::from(format!("{}/", source.display()))
+            );
+        }
     }
 
     #[test]
@@ -322,7 +424,7 @@ mod tests {
     fn get_rsync_requires_existing_directory() {
         // Directory doesn't exist.
         let cmd = SshCmd {
-            original_cmd: String::from("rsync --server --sender 3 4 /no/such/"),
+            original_cmd: String::from("rsync --server --sender -logDtpre.iLsfxC . /no/such/"),
         };
         let host_config = BackupHost::default();
         let result = cmd.get_command(&host_config);
@@ -334,7 +436,10 @@ mod tests {
         // Directory exists but isn't in config.
         let dir = TempDir::new("test").unwrap();
         let cmd = SshCmd {
-            original_cmd: format!("rsync --server --sender 3 4 {}/", dir.path().display()),
+            original_cmd: format!(
+                "rsync --server --sender -logDtpre.iLsfxC . {}/",
+                dir.path().display()
+            ),
         };
         let host_config = BackupHost::default();
         let result = cmd.get_command(&host_config);
@@ -354,6 +459,7 @@ mod tests {
         let source = BackupSource {
             path: dir.path().to_path_buf(),
             root: false,
+            ..BackupSource::default()
         };
         let host_config = BackupHost {
             sources: vec![source],
@@ -3,15 +3,22 @@
 
 use crate::args;
 use crate::doppelback_error::DoppelbackError;
-use crate::rsync_util;
+use crate::rsync_util::{self, RsyncServerInvocation};
 use log::{error, info};
+use std::env;
 use std::ffi::OsString;
 use std::io::{Error, ErrorKind};
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process;
+use std::ptr;
 use structopt::StructOpt;
 
+/// Environment variables passed on to the command.  Everything else is cleared so that nothing
+/// from the caller's environment can change how the command runs as root.  rsync needs the
+/// locale to convert file names, and doppelback itself needs HOME.
+const ENV_ALLOWLIST: &[&str] = &["PATH", "HOME", "LANG", "LC_ALL", "LC_CTYPE"];
+
 #[derive(Debug, StructOpt)]
 pub struct SudoCmd {
     #[structopt(last = true)]
@@ -23,11 +30,14 @@ impl SudoCmd {
         info!("sudo cmd=<{:?}>", self.args);
 
         let command = self.get_command()?;
+        drop_supplementary_groups()?;
 
         Err(DoppelbackError::IoError(
             process::Command::new(&command[0])
                 .args(&command[1..])
                 .current_dir("/")
+                .env_clear()
+                .envs(filter_env(env::vars_os()))
                 .exec(),
         ))
     }
@@ -52,8 +62,9 @@ impl SudoCmd {
 
         let args = match &*cmd_name {
             "rsync" => {
-                rsync_util::check_source_path(&self.args[1..])?;
-                rsync_util::filter_args(&self.args[1..])
+                let invocation = RsyncServerInvocation::parse(&self.args[1..])?;
+                rsync_util::check_source_path(&invocation.source)?;
+                invocation.filtered_args()
             }
             .map_err(DoppelbackError::IoError),
 
@@ -81,6 +92,28 @@ impl SudoCmd {
     }
 }
 
+/// Returns the variables from `vars` that are in `ENV_ALLOWLIST`.
+fn filter_env<I>(vars: I) -> Vec<(OsString, OsString)>
+where
+    I: IntoIterator<Item = (OsString, OsString)>,
+{
+    vars.into_iter()
+        .filter(|(name, _)| ENV_ALLOWLIST.iter().any(|allowed| name == allowed))
+        .collect()
+}
+
+/// Drops the supplementary groups inherited from sudo so the command only runs with root's own
+/// uid and gid.  Does nothing when not running as root, since only root can change them.
+fn drop_supplementary_groups() -> Result<(), DoppelbackError> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    if unsafe { libc::setgroups(0, ptr::null()) } != 0 {
+        return Err(DoppelbackError::IoError(Error::last_os_error()));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -133,6 +166,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rsync_source_is_checked() {
+        let rsync = |paths: &[&str]| SudoCmd {
+            args: ["/usr/bin/rsync", "--server", "--sender", "-r", "."]
+                .iter()
+                .chain(paths)
+                .map(|arg| arg.to_string())
+                .collect(),
+        };
+        assert!(rsync(&["/tmp"]).get_command().is_ok());
+        assert!(rsync(&["/tmp/../etc"]).get_command().is_err());
+        assert!(rsync(&["/tmp", "/etc"]).get_command().is_err());
+        assert!(rsync(&[]).get_command().is_err());
+    }
+
+    #[test]
+    fn env_is_filtered_to_allowlist() {
+        let vars = [
+            ("PATH", "/usr/bin:/bin"),
+            ("HOME", "/root"),
+            ("LANG", "en_US.UTF-8"),
+            ("LC_ALL", "C.UTF-8"),
+            ("LD_PRELOAD", "/tmp/evil.so"),
+            ("RSYNC_RSH", "sh"),
+            ("SUDO_USER", "backup"),
+            ("PATHEXT", "x"),
+        ]
+        .iter()
+        .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+
+        assert_eq!(
+            filter_env(vars),
+            vec![
+                (OsString::from("PATH"), OsString::from("/usr/bin:/bin")),
+                (OsString::from("HOME"), OsString::from("/root")),
+                (OsString::from("LANG"), OsString::from("en_US.UTF-8")),
+                (OsString::from("LC_ALL"), OsString::from("C.UTF-8")),
+            ]
+        );
+    }
+
     #[test]
     fn doppelback_invalid_args_rejected() {
         let doppelback = SudoCmd {
@@ -0,0 +1,136 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::config::{BackupHost, BackupSource, Config};
+use crate::config_test;
+use crate::doppelback_error::DoppelbackError;
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct TestSshCmd {
+    /// Only test this source of the host.  Every enabled source is tested if omitted.
+    #[structopt(long, parse(from_os_str))]
+    source: Option<PathBuf>,
+}
+
+impl TestSshCmd {
+    /// Runs `doppelback config-test --type=source` on `host` over ssh for each selected source and
+    /// prints the result.
+    ///
+    /// This goes through the same ssh key and forced command as a backup, so a source that passes
+    /// here can be reached by pull-backup.  Returns the number of sources that failed.
+    pub fn test_ssh(
+        &self,
+        config: &Config,
+        host: &str,
+        ssh: &Path,
+        home_dir: &OsStr,
+    ) -> Result<usize, DoppelbackError> {
+        let host_config = config.hosts.get(host).expect("host already checked");
+        host_config.check_options().map_err(|e| e.for_host(host))?;
+
+        let mut failed = 0;
+        for source in self.selected_sources(host, host_config)? {
+            let result =
+                config_test::remote_source_command(ssh, home_dir, host, host_config, source)
+                    .and_then(|command| config_test::run_remote_command(&command));
+            match result {
+                Ok(output) => {
+                    println!("{}:{}: OK", host, source.path.display());
+                    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                        println!("    {}", line);
+                    }
+                }
+                Err(message) => {
+                    println!("{}:{}: FAILED", host, source.path.display());
+                    for line in message.lines() {
+                        println!("    {}", line);
+                    }
+                    failed += 1;
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Returns --source, or every enabled source of the host if it wasn't passed.  A disabled
+    /// source can still be tested by naming it.
+    fn selected_sources<'a>(
+        &self,
+        host: &str,
+        host_config: &'a BackupHost,
+    ) -> Result<Vec<&'a BackupSource>, DoppelbackError> {
+        match &self.source {
+            Some(path) => {
+                let source = host_config.get_source(path).ok_or_else(|| {
+                    DoppelbackError::config_field(
+                        "sources",
+                        format!("has no entry for {}", path.display()),
+                    )
+                    .for_host(host)
+                })?;
+                Ok(vec![source])
+            }
+            None => Ok(host_config
+                .sources
+                .iter()
+                .filter(|source| !source.disabled)
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_config() -> BackupHost {
+        let source = |path: &str, disabled: bool| BackupSource {
+            path: PathBuf::from(path),
+            disabled,
+            ..BackupSource::default()
+        };
+        BackupHost {
+            sources: vec![
+                source("/etc", false),
+                source("/srv", true),
+                source("/home", false),
+            ],
+            ..BackupHost::default()
+        }
+    }
+
+    fn paths(sources: Vec<&BackupSource>) -> Vec<&Path> {
+        sources.iter().map(|source| source.path.as_path()).collect()
+    }
+
+    #[test]
+    fn enabled_sources_are_tested_by_default() {
+        let host_config = host_config();
+        let cmd = TestSshCmd { source: None };
+        assert_eq!(
+            paths(cmd.selected_sources("host1", &host_config).unwrap()),
+            vec![Path::new("/etc"), Path::new("/home")]
+        );
+
+        let cmd = TestSshCmd {
+            source: Some(PathBuf::from("/srv")),
+        };
+        assert_eq!(
+            paths(cmd.selected_sources("host1", &host_config).unwrap()),
+            vec![Path::new("/srv")]
+        );
+
+        let cmd = TestSshCmd {
+            source: Some(PathBuf::from("/var")),
+        };
+        let err = cmd.selected_sources("host1", &host_config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config for host1: sources has no entry for /var"
+        );
+    }
+}
@@ -1,23 +1,91 @@
 // Copyright 2021 Benjamin Gordon
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::commands::snapshots;
 use crate::doppelback_error::DoppelbackError;
+use crate::rsync_util;
+use chrono::NaiveTime;
 use clap::arg_enum;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::ffi::OsString;
+use log::info;
+use pathsearch::find_executable_in_path;
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::iter;
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::time::Duration;
 use structopt::StructOpt;
 
-#[derive(Default, Deserialize, Debug)]
+#[derive(Default, Deserialize, Serialize, Debug)]
 pub struct Config {
     pub snapshots: PathBuf,
 
+    #[serde(serialize_with = "serialize_sorted")]
     pub hosts: HashMap<String, BackupHost>,
+
+    pub notify: Option<Notify>,
+
+    /// Minimum free space required on the snapshots filesystem before a backup starts.
+    pub min_free_bytes: Option<u64>,
+
+    /// rsync exclude file applied to every source before its own `.exclude` file.
+    pub global_excludes: Option<PathBuf>,
+
+    /// Prometheus textfile written after each pull-backup run.
+    pub metrics_file: Option<PathBuf>,
+
+    /// JSON file describing what a running pull-backup is doing.  Removed when the run ends.
+    pub status_file: Option<PathBuf>,
+
+    /// chrono format for new snapshot names, before the `.NN` suffix.
+    pub snapshot_name_format: Option<String>,
+
+    /// rsync to run instead of the first one in PATH.
+    pub rsync_bin: Option<PathBuf>,
+
+    /// btrfs to run instead of the first one in PATH.
+    pub btrfs_bin: Option<PathBuf>,
+
+    /// Run the btrfs snapshot command through sudo when not running as root.
+    #[serde(default)]
+    pub use_sudo_for_snapshot: bool,
+
+    /// Take pull-backup's snapshot after the transfers, and only if they changed any data.
+    #[serde(default)]
+    pub snapshot_only_on_change: bool,
+
+    /// Permissions for the destination directories doppelback creates, instead of the umask.
+    pub dest_mode: Option<u32>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub enum NotifyMode {
+    #[serde(rename = "command")]
+    Command,
+    #[serde(rename = "webhook")]
+    Webhook,
+}
+
+/// Where to report the result of a pull-backup run.
+///
+/// In `command` mode, `target` is a program that receives a text summary on stdin.  In `webhook`
+/// mode, `target` is a URL that receives the summary as a JSON POST.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Notify {
+    pub mode: NotifyMode,
+    pub target: String,
+    #[serde(default)]
+    pub notify_on_success: bool,
 }
 
-#[derive(Clone, Deserialize, Debug, Default)]
+#[derive(Clone, Deserialize, Serialize, Debug, Default)]
 pub enum Inhibit {
     #[default]
     None,
@@ -27,19 +95,160 @@ pub enum Inhibit {
     Caffeinate,
 }
 
-#[derive(Clone, Default, Deserialize, Debug)]
+/// A daily time window with its own bandwidth limit.
+///
+/// `start` and `end` are local times written as `HH:MM`.  The window includes `start` but not
+/// `end`, and wraps around midnight if `end` is earlier than `start`.  Equal times cover the whole
+/// day.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct BwlimitWindow {
+    pub start: String,
+    pub end: String,
+    pub bwlimit: u64,
+}
+
+impl BwlimitWindow {
+    /// Returns the parsed start and end times, or `None` if either is malformed.
+    fn times(&self) -> Option<(NaiveTime, NaiveTime)> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").ok();
+        Some((parse(&self.start)?, parse(&self.end)?))
+    }
+
+    /// Returns whether `time` falls inside this window.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.times() {
+            Some((start, end)) if start < end => start <= time && time < end,
+            Some((start, end)) if start > end => time >= start || time < end,
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// A snapshots directory on another machine, written as `[user@]host:/path` like an rsync or scp
+/// destination.
+///
+/// Backing up to one isn't supported: it is only recognized so that `snapshots` can be rejected
+/// with a clear error.  rsync can't copy between two remote machines, so pushing to a remote
+/// target needs the transfer itself to run there, which is left for later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteTarget {
+    pub user: Option<String>,
+    pub host: String,
+    pub path: PathBuf,
+}
+
+impl RemoteTarget {
+    /// Parses `path` as a remote target.  Returns `None` for anything that isn't one, including
+    /// every absolute local path.
+    ///
+    /// An IPv6 address must be in brackets, e.g. `backup@[2001:db8::1]:/snapshots`.
+    pub fn parse(path: &Path) -> Option<RemoteTarget> {
+        let spec = path.to_str()?;
+        let (host_spec, remote_path) = match spec.find("]:") {
+            Some(end) => (&spec[..end + 1], &spec[end + 2..]),
+            None => spec.split_once(':')?,
+        };
+        if host_spec.contains('/') || !remote_path.starts_with('/') {
+            return None;
+        }
+
+        let (user, host) = match host_spec.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, host_spec),
+        };
+        let host = host
+            .strip_prefix('[')
+            .map_or(host, |h| h.trim_end_matches(']'));
+        if host.is_empty() || user.as_deref() == Some("") {
+            return None;
+        }
+        Some(RemoteTarget {
+            user,
+            host: host.to_string(),
+            path: PathBuf::from(remote_path),
+        })
+    }
+
+    /// Returns the `[user@]host` argument for ssh.
+    pub fn ssh_destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, Debug)]
 pub struct BackupHost {
     pub user: String,
+    pub address: Option<String>,
+    pub snapshots: Option<PathBuf>,
+    pub auto: Option<bool>,
     pub port: Option<u16>,
+    #[serde(default)]
     pub key: PathBuf,
+    #[serde(default)]
+    pub keys: Vec<PathBuf>,
     pub sources: Vec<BackupSource>,
     pub inhibit_shutdown: Option<Inhibit>,
+    pub bwlimit: Option<u64>,
+    #[serde(default)]
+    pub bwlimit_schedule: Vec<BwlimitWindow>,
+    pub compress: Option<String>,
+    #[serde(default)]
+    pub numeric_ids: bool,
+    pub retries: Option<u32>,
+    pub partial_dir: Option<String>,
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    #[serde(default)]
+    pub ssh_multiplex: bool,
+    pub rsh: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub min_rate_bytes: Option<u64>,
+    pub min_rate_secs: Option<u64>,
+    pub known_hosts: Option<PathBuf>,
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
 }
 
-#[derive(Clone, Default, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, Serialize, Debug)]
 pub struct BackupSource {
     pub path: PathBuf,
     pub root: bool,
+    pub max_size: Option<String>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    #[serde(default)]
+    pub includes: Vec<String>,
+    #[serde(default)]
+    pub include_only: bool,
+    #[serde(default)]
+    pub extra_rsync_args: Vec<String>,
+    #[serde(default)]
+    pub no_inplace: bool,
+    #[serde(default)]
+    pub atomic: bool,
+    #[serde(default)]
+    pub checksum: bool,
+    #[serde(default)]
+    pub crtimes: bool,
+    #[serde(default)]
+    pub changelog: bool,
+    #[serde(default)]
+    pub exclude_caches: bool,
+    pub io_timeout_secs: Option<u64>,
+    pub rsync_path: Option<String>,
+    pub chmod: Option<String>,
+    pub filter_file: Option<PathBuf>,
+    pub delete_mode: Option<String>,
+    pub source_host: Option<String>,
+    pub source_user: Option<String>,
+    pub nice: Option<i32>,
+    pub ionice_class: Option<u8>,
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 pub struct BackupDest {
@@ -53,6 +262,14 @@ pub struct ConfigTestCmd {
 
     #[structopt(long = "type", default_value = "host")]
     pub test_type: ConfigTestType,
+
+    /// Also report likely mistakes such as duplicate sources and fail if any are found.
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Print the results of `--type host` as a single JSON object instead of text.
+    #[structopt(long)]
+    pub json: bool,
 }
 
 arg_enum! {
@@ -61,35 +278,408 @@ arg_enum! {
         Host,
         Source,
         Remote,
+        Parse,
     }
 }
 
 impl Config {
+    /// Loads the config from a YAML file, or from every `*.yaml` file in a directory.
     pub fn load<P: AsRef<Path>>(file: P) -> Result<Self, DoppelbackError> {
-        let yaml = fs::read_to_string(file)?;
-        serde_yaml::from_str(&yaml).map_err(DoppelbackError::ParseError)
+        let config: Config = if file.as_ref().is_dir() {
+            serde_yaml::from_value(merge_config_dir(file.as_ref())?)
+                .map_err(DoppelbackError::ParseError)?
+        } else {
+            let yaml = fs::read_to_string(file)?;
+            serde_yaml::from_str(&yaml).map_err(DoppelbackError::ParseError)?
+        };
+        if let Some(format) = &config.snapshot_name_format {
+            snapshots::check_name_format(format)?;
+        }
+        if let Some(mode) = config.dest_mode {
+            if mode > 0o7777 {
+                return Err(DoppelbackError::config_field(
+                    "dest_mode",
+                    format!("{:o} is not a valid permission mode", mode),
+                ));
+            }
+        }
+        Ok(config)
     }
 
-    pub fn snapshot_dir_valid(&self) -> Result<(), DoppelbackError> {
-        // serde_yaml parses an empty PathBuf as ~.  Check for this explicitly
-        // so callers don't have to be surprised by it.
-        if self.snapshots == Path::new("~").to_path_buf() {
-            return Err(DoppelbackError::InvalidPath(self.snapshots.clone()));
+    /// Returns the loaded config as YAML, with every default filled in.
+    ///
+    /// Hosts are sorted by name so the output is stable.  Loading the output gives back the same
+    /// config.
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("config can always be serialized")
+    }
+
+    /// Returns the rsync to run: `rsync_bin` if it is set, or else the first rsync in PATH.
+    pub fn rsync_bin(&self) -> Result<PathBuf, DoppelbackError> {
+        find_program("rsync", "rsync_bin", self.rsync_bin.as_deref())
+    }
+
+    /// Returns the btrfs to run: `btrfs_bin` if it is set, or else the first btrfs in PATH.
+    pub fn btrfs_bin(&self) -> Result<PathBuf, DoppelbackError> {
+        find_program("btrfs", "btrfs_bin", self.btrfs_bin.as_deref())
+    }
+
+    /// Returns the sudo to take snapshots with, if `use_sudo_for_snapshot` is set and this process
+    /// isn't already root.
+    pub fn snapshot_sudo(&self) -> Result<Option<PathBuf>, DoppelbackError> {
+        if !self.use_sudo_for_snapshot || unsafe { libc::geteuid() } == 0 {
+            return Ok(None);
         }
-        if !self.snapshots.is_absolute() {
-            return Err(DoppelbackError::InvalidPath(self.snapshots.clone()));
+        let sudo = find_executable_in_path("sudo")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Couldn't find sudo in PATH"))?;
+        Ok(Some(sudo))
+    }
+
+    pub fn snapshot_name_format(&self) -> &str {
+        self.snapshot_name_format
+            .as_deref()
+            .unwrap_or(snapshots::SNAPSHOT_DATE_FORMAT)
+    }
+
+    /// Returns the snapshots directory for `host`: its own `snapshots` if set, or else the
+    /// global one.
+    pub fn snapshot_root(&self, host: &str) -> &Path {
+        self.hosts
+            .get(host)
+            .and_then(|host_config| host_config.snapshots.as_deref())
+            .unwrap_or(&self.snapshots)
+    }
+
+    /// Returns every distinct snapshots directory in the config, starting with the global one.
+    pub fn snapshot_roots(&self) -> Vec<&Path> {
+        let mut overrides: Vec<&Path> = self
+            .hosts
+            .values()
+            .filter_map(|host_config| host_config.snapshots.as_deref())
+            .filter(|root| *root != self.snapshots)
+            .collect();
+        overrides.sort_unstable();
+        overrides.dedup();
+
+        let mut roots = vec![self.snapshots.as_path()];
+        roots.extend(overrides);
+        roots
+    }
+
+    /// Checks the global snapshots directory and every host's override.
+    pub fn snapshot_dir_valid(&self) -> Result<(), DoppelbackError> {
+        self.snapshot_roots()
+            .into_iter()
+            .try_for_each(check_snapshot_root)
+    }
+
+    /// Checks the snapshots directory that `host` is backed up into.
+    pub fn host_snapshot_dir_valid(&self, host: &str) -> Result<(), DoppelbackError> {
+        check_snapshot_root(self.snapshot_root(host))
+    }
+
+    /// Returns every problem found by the checks that only need the parsed config.
+    ///
+    /// This is what `config-test --type=parse` runs: users, source paths, overlapping sources and
+    /// the option checks, without touching ssh keys, the snapshots directory or any host.
+    /// Problems are sorted by host so the output is stable.
+    pub fn parse_problems(&self) -> Vec<DoppelbackError> {
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut problems = Vec::new();
+        for (host, host_config) in hosts {
+            if !host_config.is_user_valid() {
+                problems.push(
+                    DoppelbackError::config_field(
+                        "user",
+                        format!("{} is not a valid user", host_config.user),
+                    )
+                    .for_host(host),
+                );
+            }
+            if let Err(e) = host_config.check_options() {
+                problems.push(e.for_host(host));
+            }
+            for source in &host_config.sources {
+                if !source.path.is_absolute() {
+                    problems.push(
+                        DoppelbackError::config_field(
+                            "sources",
+                            format!("path {} is not absolute", source.path.display()),
+                        )
+                        .for_host(host),
+                    );
+                }
+                if let Err(e) = source.check_options() {
+                    problems.push(e.for_host(host));
+                }
+            }
+            if let Some((outer, inner)) = host_config.find_overlapping_sources() {
+                problems.push(
+                    DoppelbackError::config_field(
+                        "sources",
+                        format!(
+                            "path {} overlaps source {}",
+                            inner.display(),
+                            outer.display()
+                        ),
+                    )
+                    .for_host(host),
+                );
+            }
         }
-        if !self.snapshots.is_dir() {
-            return Err(DoppelbackError::MissingDir(self.snapshots.clone()));
+        problems
+    }
+
+    /// Returns every problem found by the `config-test --strict` rules.
+    ///
+    /// These are things that don't stop a backup from running but are almost certainly mistakes.
+    /// Problems are sorted by host so the output is stable.
+    pub fn strict_problems(&self) -> Vec<DoppelbackError> {
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut problems = Vec::new();
+        for (host, host_config) in hosts {
+            let mut problem = |field: &str, message: String| {
+                problems.push(DoppelbackError::config_field(field, message).for_host(host));
+            };
+            if host_config.sources.is_empty() {
+                problem("sources", String::from("is empty"));
+            }
+            if host_config.port == Some(0) {
+                problem("port", String::from("is set to 0"));
+            }
+
+            let mut seen = Vec::with_capacity(host_config.sources.len());
+            for source in &host_config.sources {
+                if !source.path.is_absolute() {
+                    problem(
+                        "sources",
+                        format!("path {} is not absolute", source.path.display()),
+                    );
+                }
+                if seen.contains(&&source.path) {
+                    problem(
+                        "sources",
+                        format!("path {} is listed more than once", source.path.display()),
+                    );
+                }
+                seen.push(&source.path);
+            }
         }
-        let live_dir = self.snapshots.join("live");
-        if !live_dir.is_dir() {
-            return Err(DoppelbackError::MissingDir(live_dir));
+        problems
+    }
+
+    /// Returns an error if the snapshots filesystem has less than `min_free_bytes` available.
+    ///
+    /// Running out of space partway through leaves a partially updated live tree, so it's better
+    /// to refuse to start.
+    pub fn check_free_space(&self, host: &str) -> Result<(), DoppelbackError> {
+        let min_free = match self.min_free_bytes {
+            Some(min_free) => min_free,
+            None => return Ok(()),
+        };
+
+        let root = self.snapshot_root(host);
+        let path = CString::new(root.as_os_str().as_bytes())
+            .map_err(|_| DoppelbackError::InvalidPath(root.to_path_buf()))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(DoppelbackError::IoError(io::Error::last_os_error()));
         }
+
+        #[allow(clippy::unnecessary_cast)]
+        let available =
+            check_available_space(stat.f_frsize as u64, stat.f_bavail as u64, min_free)?;
+        info!(
+            "{} bytes available in {} ({} required)",
+            available,
+            root.display(),
+            min_free
+        );
         Ok(())
     }
 }
 
+/// Returns `configured` after checking that it is an absolute path to an executable file, or
+/// searches PATH for `name` if nothing is configured.  `field` names the config setting.
+fn find_program(
+    name: &str,
+    field: &str,
+    configured: Option<&Path>,
+) -> Result<PathBuf, DoppelbackError> {
+    let path = match configured {
+        Some(path) => path,
+        None => {
+            return find_executable_in_path(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Couldn't find {} in PATH", name),
+                )
+                .into()
+            })
+        }
+    };
+    if !path.is_absolute() {
+        return Err(DoppelbackError::config_field(
+            field,
+            format!("{} is not an absolute path", path.display()),
+        ));
+    }
+    let executable = fs::metadata(path)
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0);
+    if !executable {
+        return Err(DoppelbackError::config_field(
+            field,
+            format!("{} is not an executable file", path.display()),
+        ));
+    }
+    Ok(path.to_path_buf())
+}
+
+fn serialize_sorted<S: Serializer>(
+    hosts: &HashMap<String, BackupHost>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    hosts
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+/// Checks that `root` is an absolute path to a directory containing `live`.
+fn check_snapshot_root(root: &Path) -> Result<(), DoppelbackError> {
+    // serde_yaml parses an empty PathBuf as ~.  Check for this explicitly
+    // so callers don't have to be surprised by it.
+    if root == Path::new("~") {
+        return Err(DoppelbackError::InvalidPath(root.to_path_buf()));
+    }
+    if let Some(target) = RemoteTarget::parse(root) {
+        return Err(DoppelbackError::config_field(
+            "snapshots",
+            format!(
+                "{} is on the remote host {}, but only a local directory is supported",
+                target.path.display(),
+                target.ssh_destination()
+            ),
+        ));
+    }
+    if !root.is_absolute() {
+        return Err(DoppelbackError::InvalidPath(root.to_path_buf()));
+    }
+    if !root.is_dir() {
+        return Err(DoppelbackError::MissingDir(root.to_path_buf()));
+    }
+    let live_dir = root.join("live");
+    if !live_dir.is_dir() {
+        return Err(DoppelbackError::MissingDir(live_dir));
+    }
+    Ok(())
+}
+
+/// Computes the bytes available to unprivileged users from statvfs's fragment size and available
+/// block count, and returns `InsufficientSpace` if it is below `required`.
+fn check_available_space(
+    fragment_size: u64,
+    blocks_available: u64,
+    required: u64,
+) -> Result<u64, DoppelbackError> {
+    let available = fragment_size.saturating_mul(blocks_available);
+    if available < required {
+        return Err(DoppelbackError::InsufficientSpace {
+            available,
+            required,
+        });
+    }
+    Ok(available)
+}
+
+/// Reads every `*.yaml` file in `dir` and merges them into a single config document.
+///
+/// The `hosts` maps of all the files are combined, and a host defined in more than one file is an
+/// error.  Any other top-level setting can be given in one file, or repeated with the same value
+/// in several.
+fn merge_config_dir(dir: &Path) -> Result<serde_yaml::Value, DoppelbackError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("yaml")) && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut merged = serde_yaml::Mapping::new();
+    let mut hosts = serde_yaml::Mapping::new();
+    // Which file each setting and host came from, for reporting conflicts.
+    let mut origins: HashMap<String, PathBuf> = HashMap::new();
+    for file in files {
+        let yaml = fs::read_to_string(&file)?;
+        let mapping = match serde_yaml::from_str(&yaml).map_err(DoppelbackError::ParseError)? {
+            serde_yaml::Value::Mapping(mapping) => mapping,
+            serde_yaml::Value::Null => continue,
+            _ => {
+                return Err(DoppelbackError::InvalidConfig(format!(
+                    "{} is not a YAML mapping",
+                    file.display()
+                )))
+            }
+        };
+
+        for (key, value) in mapping {
+            let name = key.as_str().unwrap_or_default().to_string();
+            if name == "hosts" {
+                let file_hosts = match value {
+                    serde_yaml::Value::Mapping(file_hosts) => file_hosts,
+                    serde_yaml::Value::Null => continue,
+                    _ => {
+                        return Err(DoppelbackError::InvalidConfig(format!(
+                            "hosts in {} is not a YAML mapping",
+                            file.display()
+                        )))
+                    }
+                };
+                for (host, host_config) in file_hosts {
+                    let host_name = host.as_str().unwrap_or_default().to_string();
+                    if let Some(other) = origins.get(&format!("hosts.{}", host_name)) {
+                        return Err(DoppelbackError::InvalidConfig(format!(
+                            "host {} is defined in both {} and {}",
+                            host_name,
+                            other.display(),
+                            file.display()
+                        )));
+                    }
+                    origins.insert(format!("hosts.{}", host_name), file.clone());
+                    hosts.insert(host, host_config);
+                }
+                continue;
+            }
+
+            if let Some(existing) = merged.get(&key) {
+                if *existing != value {
+                    return Err(DoppelbackError::InvalidConfig(format!(
+                        "{} is defined differently in {} and {}",
+                        name,
+                        origins[&name].display(),
+                        file.display()
+                    )));
+                }
+                continue;
+            }
+            origins.insert(name, file.clone());
+            merged.insert(key, value);
+        }
+    }
+
+    merged.insert(
+        serde_yaml::Value::from("hosts"),
+        serde_yaml::Value::Mapping(hosts),
+    );
+    Ok(serde_yaml::Value::Mapping(merged))
+}
+
 impl BackupHost {
     pub fn is_user_valid(&self) -> bool {
         // serde_yaml parses empty string values as ~.  Wrap this up in a function
@@ -98,307 +688,2118 @@ impl BackupHost {
         !self.user.is_empty() && self.user != "~" && self.user != "root"
     }
 
+    /// Returns the configured ssh keys in the order they should be tried.
+    ///
+    /// The single `key` field from older configs comes first, followed by everything in `keys`.
+    pub fn configured_keys(&self) -> Vec<&Path> {
+        iter::once(self.key.as_path())
+            .chain(self.keys.iter().map(PathBuf::as_path))
+            .filter(|key| !key.as_os_str().is_empty() && *key != Path::new("~"))
+            .collect()
+    }
+
+    /// Resolves the configured keys against `home_dir` and returns the ones that exist.
+    pub fn find_ssh_keys<P: AsRef<Path>>(&self, home_dir: P) -> Vec<PathBuf> {
+        self.configured_keys()
+            .into_iter()
+            .map(|key| {
+                if key.is_absolute() {
+                    key.to_path_buf()
+                } else {
+                    let mut path = home_dir.as_ref().join(".ssh");
+                    path.push(key);
+                    path
+                }
+            })
+            .filter(|key_path| key_path.is_file())
+            .collect()
+    }
+
     pub fn find_ssh_key<P: AsRef<Path>>(&self, home_dir: P) -> Option<PathBuf> {
-        if self.key.as_os_str().is_empty() || self.key == Path::new("~") {
-            return None;
-        }
+        self.find_ssh_keys(home_dir).into_iter().next()
+    }
 
-        let key_path = if self.key.is_absolute() {
-            self.key.to_path_buf()
+    /// The error to report when none of the configured keys can be found.
+    pub fn missing_key_error(&self) -> DoppelbackError {
+        let keys = self.configured_keys();
+        let names = if keys.is_empty() {
+            String::from("(none configured)")
         } else {
-            let mut path = home_dir.as_ref().join(".ssh");
-            path.push(&self.key);
-            path
+            keys.iter()
+                .map(|key| key.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
         };
-
-        if key_path.is_file() {
-            Some(key_path)
-        } else {
-            None
-        }
+        DoppelbackError::InvalidConfig(format!("ssh key {} not found", names))
     }
 
     pub fn get_source<P: AsRef<Path>>(&self, path: P) -> Option<&BackupSource> {
         return self.sources.iter().find(|&src| src.path == path.as_ref());
     }
 
+    /// Returns `user@address` for connecting to `host` with ssh.
+    ///
+    /// `host` is the key of this host in the config, and is used as the address unless `address`
+    /// is set.
+    pub fn ssh_destination(&self, host: &str) -> String {
+        format!("{}@{}", self.user, self.address.as_deref().unwrap_or(host))
+    }
+
+    /// Returns the user and address to connect to for `source`: its own `source_user` and
+    /// `source_host` if set, or else the host's.
+    fn source_user_address<'a>(
+        &'a self,
+        host: &'a str,
+        source: &'a BackupSource,
+    ) -> (&'a str, &'a str) {
+        let user = source.source_user.as_deref().unwrap_or(&self.user);
+        let address = source
+            .source_host
+            .as_deref()
+            .or(self.address.as_deref())
+            .unwrap_or(host);
+        (user, address)
+    }
+
+    /// Like `ssh_destination`, but for connecting to `source`.
+    pub fn source_ssh_destination(&self, host: &str, source: &BackupSource) -> String {
+        let (user, address) = self.source_user_address(host, source);
+        format!("{}@{}", user, address)
+    }
+
+    /// Returns `user@address` for the remote side of an rsync command that transfers `source`.
+    ///
+    /// IPv6 literals are wrapped in brackets so that rsync doesn't take their colons as the start
+    /// of the path.
+    pub fn source_rsync_destination(&self, host: &str, source: &BackupSource) -> String {
+        let (user, address) = self.source_user_address(host, source);
+        rsync_destination(user, address)
+    }
+
+    /// Returns the ssh command line, without the destination, for connecting to `host`.  With
+    /// `ssh_multiplex`, it shares the host's connection with the transfers, hooks and checks.
+    ///
+    /// Fails if none of the keys can be found or if `rsh` or `ssh_options` is invalid.
     pub fn ssh_args<P1: AsRef<Path>, P2: AsRef<Path>>(
         &self,
         ssh: P1,
         home: P2,
-    ) -> Option<Vec<OsString>> {
-        let key = self.find_ssh_key(home)?;
+        host: &str,
+    ) -> Result<Vec<OsString>, DoppelbackError> {
+        let mut args = self.unshared_ssh_args(ssh, &home)?;
+        args.extend(self.multiplex_args(home, host));
+        Ok(args)
+    }
 
-        let mut args = vec![
-            ssh.as_ref().as_os_str().to_os_string(),
-            OsString::from("-a"),
-            OsString::from("-x"),
-            OsString::from("-oIdentitiesOnly=true"),
-            OsString::from("-i"),
-            key.into_os_string(),
-        ];
+    /// Returns the ssh command line for connecting to `host` to fetch `source`.  The control
+    /// socket is named after the host, so a source that connects elsewhere doesn't share it.
+    pub fn source_ssh_args<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        ssh: P1,
+        home: P2,
+        host: &str,
+        source: &BackupSource,
+    ) -> Result<Vec<OsString>, DoppelbackError> {
+        if source.overrides_destination() {
+            self.unshared_ssh_args(ssh, home)
+        } else {
+            self.ssh_args(ssh, home, host)
+        }
+    }
+
+    /// Returns the ssh command line without the `ssh_multiplex` options.
+    fn unshared_ssh_args<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        ssh: P1,
+        home: P2,
+    ) -> Result<Vec<OsString>, DoppelbackError> {
+        let keys = self.find_ssh_keys(home);
+        if keys.is_empty() {
+            return Err(self.missing_key_error());
+        }
+
+        // A custom rsh replaces the ssh command and its default options.  Keys and the port are
+        // still added unless it already sets them.
+        let (mut args, has_key, has_port) = match &self.rsh {
+            Some(rsh) => {
+                check_rsh(rsh).map_err(|reason| {
+                    DoppelbackError::config_field("rsh", format!("{} {}", rsh, reason))
+                })?;
+                let words: Vec<&str> = rsh.split(' ').collect();
+                let has_key = words.iter().any(|w| w.starts_with("-i"));
+                let has_port = words.iter().any(|w| w.starts_with("-p"));
+                (
+                    words.into_iter().map(OsString::from).collect(),
+                    has_key,
+                    has_port,
+                )
+            }
+            None => (
+                vec![
+                    ssh.as_ref().as_os_str().to_os_string(),
+                    OsString::from("-a"),
+                    OsString::from("-x"),
+                    OsString::from("-oIdentitiesOnly=true"),
+                ],
+                false,
+                false,
+            ),
+        };
+        if !has_key {
+            for key in keys {
+                args.push(OsString::from("-i"));
+                args.push(key.into_os_string());
+            }
+        }
 
         if let Some(port) = self.port {
-            if port > 0 {
+            if port > 0 && !has_port {
                 args.push(OsString::from("-p"));
                 args.push(OsString::from(port.to_string()));
             }
         }
 
-        Some(args)
-    }
-}
+        if let Some(known_hosts) = &self.known_hosts {
+            let mut option = OsString::from("-oUserKnownHostsFile=");
+            option.push(known_hosts);
+            args.push(option);
+            args.push(OsString::from("-oStrictHostKeyChecking=yes"));
+        }
 
-impl BackupDest {
-    pub fn new<P: AsRef<Path>>(root: P, host: &str, source: &BackupSource) -> Self {
-        let dest_name = BackupDest::get_safe_name(&source.path);
-        let mut dest_dir = root.as_ref().join("live");
-        dest_dir.push(host);
-        dest_dir.push(dest_name);
+        for option in &self.ssh_options {
+            args.push(OsString::from(parse_ssh_option(option)?));
+        }
 
-        Self { dest_dir }
+        Ok(args)
     }
 
-    pub fn backup_dir(&self) -> &Path {
-        self.dest_dir.as_path()
+    /// Returns the control socket shared by the ssh connections to `host`, if `ssh_multiplex` is
+    /// set.
+    pub fn control_path<P: AsRef<Path>>(&self, home: P, host: &str) -> Option<PathBuf> {
+        if !self.ssh_multiplex {
+            return None;
+        }
+        let name = format!("doppelback-{}.sock", host.replace('/', "_"));
+        Some(home.as_ref().join(".ssh").join(name))
     }
 
-    pub fn get_companion_file(&self, name: &str) -> PathBuf {
-        self.dest_dir.with_extension(name)
+    /// Returns the ssh options that let the connections to `host` share one connection through
+    /// `control_path`.  Empty unless `ssh_multiplex` is set.
+    ///
+    /// The first connection becomes the master and stays open for a minute after the last one
+    /// using it exits, so the next source doesn't have to connect again.
+    pub fn multiplex_args<P: AsRef<Path>>(&self, home: P, host: &str) -> Vec<OsString> {
+        let control_path = match self.control_path(home, host) {
+            Some(control_path) => control_path,
+            None => return Vec::new(),
+        };
+        let mut path_arg = OsString::from("-oControlPath=");
+        path_arg.push(control_path);
+        vec![
+            OsString::from("-oControlMaster=auto"),
+            path_arg,
+            OsString::from("-oControlPersist=60"),
+        ]
     }
 
-    fn get_safe_name<P: AsRef<Path>>(original: P) -> String {
-        let path = original.as_ref().to_string_lossy();
-        let name = path.trim_matches('/');
-
-        if name.is_empty() {
-            return "rootfs".to_string();
-        }
-
-        name.replace(['/', '.'], "_")
+    /// Returns the bandwidth limit in KB/s for a transfer starting at `time`.
+    ///
+    /// The first window in `bwlimit_schedule` that contains `time` wins.  Outside every window,
+    /// the plain `bwlimit` applies.
+    pub fn bwlimit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.bwlimit_schedule
+            .iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.bwlimit)
+            .or(self.bwlimit)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempdir::TempDir;
+    /// Returns whether `pull-backup --all` includes this host.  Defaults to true.
+    pub fn auto(&self) -> bool {
+        self.auto.unwrap_or(true)
+    }
 
-    #[test]
-    fn snapshots_must_be_present() {
-        let cfg = Config {
-            ..Config::default()
-        };
-        assert!(cfg.snapshot_dir_valid().is_err());
+    /// Returns how long a single command for this host may run before it is killed.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
     }
 
-    #[test]
-    fn snapshots_must_be_present_yaml() {
-        let cfg = Config {
-            snapshots: Path::new("~").to_path_buf(),
-            ..Config::default()
-        };
-        assert!(cfg.snapshot_dir_valid().is_err());
+    /// Returns the minimum transfer rate in bytes per second and how long an rsync may stay below
+    /// it before it is stopped, if `min_rate_bytes` is set.  The time defaults to 5 minutes.
+    pub fn min_rate(&self) -> Option<(u64, Duration)> {
+        let min_rate = self.min_rate_bytes?;
+        Some((
+            min_rate,
+            Duration::from_secs(self.min_rate_secs.unwrap_or(DEFAULT_MIN_RATE_SECS)),
+        ))
     }
 
-    #[test]
-    fn snapshots_must_be_dir() {
-        let cfg = Config {
-            snapshots: Path::new("/dev/null").to_path_buf(),
-            ..Config::default()
+    /// Checks that the configured `known_hosts` file has a key for `host`.
+    ///
+    /// Does nothing if `known_hosts` isn't set.  The lookup uses `ssh-keygen -F` so that hashed
+    /// entries are found too.
+    pub fn check_known_hosts(&self, host: &str) -> Result<(), DoppelbackError> {
+        let known_hosts = match &self.known_hosts {
+            Some(known_hosts) => known_hosts,
+            None => return Ok(()),
         };
-        assert!(cfg.snapshot_dir_valid().is_err());
+        if !known_hosts.is_file() {
+            return Err(DoppelbackError::InvalidConfig(format!(
+                "known_hosts file {} not found",
+                known_hosts.display()
+            )));
+        }
 
-        let dir = TempDir::new("snapshots").unwrap();
-        let cfg = Config {
-            snapshots: dir.path().to_path_buf(),
-            ..Config::default()
+        let ssh_keygen = find_executable_in_path("ssh-keygen").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Couldn't find ssh-keygen in PATH")
+        })?;
+        let host_spec = match self.port {
+            Some(port) if port > 0 && port != 22 => format!("[{}]:{}", host, port),
+            _ => host.to_string(),
         };
-        let err = cfg.snapshot_dir_valid();
-        assert!(err.is_err());
-        match err {
-            Err(DoppelbackError::MissingDir(d)) => {
-                assert!(format!("{}", d.display()).contains("live"))
-            }
-            _ => assert!(false),
+        let output = process::Command::new(&ssh_keygen)
+            .arg("-F")
+            .arg(&host_spec)
+            .arg("-f")
+            .arg(known_hosts)
+            .current_dir("/")
+            .output()?;
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(1) => Err(DoppelbackError::InvalidConfig(format!(
+                "no entry for {} in {}",
+                host_spec,
+                known_hosts.display()
+            ))),
+            _ => Err(DoppelbackError::CommandFailed(ssh_keygen, output.status)),
         }
     }
 
-    #[test]
-    fn snapshots_must_contain_live() {
-        let dir = TempDir::new("snapshots").unwrap();
-        let live_dir = dir.path().join("live");
-        fs::create_dir(live_dir).unwrap();
+    /// Returns the first pair of sources where one path contains the other, outer path first.
+    ///
+    /// A nested source would be copied twice, once on its own and once as part of the outer
+    /// source.  Identical paths count as overlapping.
+    pub fn find_overlapping_sources(&self) -> Option<(PathBuf, PathBuf)> {
+        for (i, a) in self.sources.iter().enumerate() {
+            for b in &self.sources[i + 1..] {
+                if b.path.starts_with(&a.path) {
+                    return Some((a.path.clone(), b.path.clone()));
+                }
+                if a.path.starts_with(&b.path) {
+                    return Some((b.path.clone(), a.path.clone()));
+                }
+            }
+        }
+        None
+    }
 
-        let cfg = Config {
-            snapshots: dir.path().to_path_buf(),
-            ..Config::default()
-        };
-        assert!(cfg.snapshot_dir_valid().is_ok());
+    /// Checks that every entry in `ssh_options` is an allowed `-o key=value` option, that `rsh`
+    /// starts with a known transport, that `compress` names a known algorithm, and that
+    /// `partial_dir` is a plain directory name.
+    pub fn check_options(&self) -> Result<(), DoppelbackError> {
+        if let Some(address) = &self.address {
+            if !is_valid_address(address) {
+                return Err(DoppelbackError::config_field(
+                    "address",
+                    format!("{} is not a hostname or IP address", address),
+                ));
+            }
+        }
+        for option in &self.ssh_options {
+            parse_ssh_option(option)?;
+        }
+        if let Some(rsh) = &self.rsh {
+            check_rsh(rsh).map_err(|reason| {
+                DoppelbackError::config_field("rsh", format!("{} {}", rsh, reason))
+            })?;
+        }
+        if let Some(compress) = &self.compress {
+            if !COMPRESS_CHOICES.contains(&compress.as_str()) {
+                return Err(DoppelbackError::config_field(
+                    "compress",
+                    format!("{} is not one of {}", compress, COMPRESS_CHOICES.join(", ")),
+                ));
+            }
+        }
+        if let Some(window) = self.bwlimit_schedule.iter().find(|w| w.times().is_none()) {
+            return Err(DoppelbackError::config_field(
+                "bwlimit_schedule",
+                format!(
+                    "window {}-{} doesn't use HH:MM times",
+                    window.start, window.end
+                ),
+            ));
+        }
+        if let Some(partial_dir) = &self.partial_dir {
+            // The directory is created inside each destination directory, so it has to be a
+            // single name that stays there.
+            if partial_dir.is_empty()
+                || partial_dir.contains('/')
+                || partial_dir == "."
+                || partial_dir == ".."
+            {
+                return Err(DoppelbackError::config_field(
+                    "partial_dir",
+                    format!("{} is not a plain directory name", partial_dir),
+                ));
+            }
+        }
+        Ok(())
     }
 
-    #[test]
-    fn backuphost_user_is_nonempty() {
-        let cfg = BackupHost {
-            user: String::from(""),
-            ..BackupHost::default()
-        };
-        assert!(!cfg.is_user_valid());
+    /// Returns the rsync arguments that enable the configured compression, if any.
+    pub fn compress_args(&self) -> Vec<String> {
+        match self.compress.as_deref() {
+            None | Some("none") => Vec::new(),
+            Some(algorithm) => vec![
+                String::from("--compress"),
+                format!("--compress-choice={}", algorithm),
+            ],
+        }
     }
+}
 
-    #[test]
-    fn backuphost_user_is_nonempty_yaml() {
-        let cfg = BackupHost {
-            user: String::from("~"),
+/// How long a transfer may stay below `min_rate_bytes` if `min_rate_secs` isn't set.
+const DEFAULT_MIN_RATE_SECS: u64 = 300;
+
+/// Values accepted for a host's `compress`.  `none` turns compression off.
+const COMPRESS_CHOICES: &[&str] = &["none", "zlib", "zstd", "lz4"];
+
+/// Programs that may start `rsync_path` besides an absolute path.
+const KNOWN_RSYNC_WRAPPERS: &[&str] = &["sudo", "doppelback"];
+
+/// Programs that a host's `rsh` may run, either by name or as the last component of an absolute
+/// path.
+const KNOWN_RSH_TRANSPORTS: &[&str] = &["ssh", "autossh"];
+
+/// Characters allowed in each word of `rsync_path` and `rsh`.  None of them need quoting.
+const SAFE_WORD_PATTERN: &str = r"^[A-Za-z0-9_@%+=:,./-]+$";
+
+/// ssh options that run local commands.  These would let the config execute arbitrary programs
+/// on the backup server.  `Include` reads another config file, which could set any of the others.
+const FORBIDDEN_SSH_OPTIONS: &[&str] = &[
+    "include",
+    "knownhostscommand",
+    "localcommand",
+    "permitlocalcommand",
+    "proxycommand",
+];
+
+/// ssh flags that take a value, either in the rest of the same word or in the next one.
+const SSH_VALUE_FLAGS: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// Normalizes an ssh option from the config to the single-argument `-oKey=value` form.
+///
+/// Both `-oKey=value` and `-o Key=value` are accepted.  The value can't contain whitespace because
+/// the ssh command line is joined with spaces when passed to rsync's `--rsh`.
+fn parse_ssh_option(option: &str) -> Result<String, DoppelbackError> {
+    let option_re =
+        Regex::new(r"^-o ?([A-Za-z][A-Za-z0-9]*)=(\S+)$").expect("invalid ssh option regex");
+    let invalid = || {
+        DoppelbackError::config_field(
+            "ssh_options",
+            format!("{} is not a valid ssh option", option),
+        )
+    };
+
+    let caps = option_re.captures(option).ok_or_else(invalid)?;
+    let (key, value) = (&caps[1], &caps[2]);
+    if FORBIDDEN_SSH_OPTIONS.contains(&key.to_lowercase().as_str()) {
+        return Err(invalid());
+    }
+    Ok(format!("-o{}={}", key, value))
+}
+
+/// Returns whether `address` is a hostname, IPv4 address or unbracketed IPv6 address (with an
+/// optional zone).  A leading - would be taken as an ssh option.
+fn is_valid_address(address: &str) -> bool {
+    let address_re =
+        Regex::new(r"^[A-Za-z0-9_.:%][A-Za-z0-9_.:%-]*$").expect("invalid address regex");
+    address_re.is_match(address)
+}
+
+/// Returns `user@address` for rsync, with IPv6 literals in brackets.
+fn rsync_destination(user: &str, address: &str) -> String {
+    if address.contains(':') {
+        format!("{}@[{}]", user, address)
+    } else {
+        format!("{}@{}", user, address)
+    }
+}
+
+impl BackupSource {
+    /// Returns whether this source is fetched from somewhere other than its host's default user
+    /// and address.
+    pub fn overrides_destination(&self) -> bool {
+        self.source_host.is_some() || self.source_user.is_some()
+    }
+
+    /// Checks the rsync-related options of this source for values rsync would reject.
+    pub fn check_options(&self) -> Result<(), DoppelbackError> {
+        if let Some(source_host) = &self.source_host {
+            if !is_valid_address(source_host) {
+                return Err(DoppelbackError::config_field(
+                    "source_host",
+                    format!(
+                        "{} for {} is not a hostname or IP address",
+                        source_host,
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(source_user) = &self.source_user {
+            // Same rules as the host's user, and nothing that ssh could take as an option or
+            // that would change the destination.
+            let user_re = Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9_.-]*$").expect("invalid user regex");
+            if !user_re.is_match(source_user) || source_user == "root" {
+                return Err(DoppelbackError::config_field(
+                    "source_user",
+                    format!(
+                        "{} for {} is not a valid user",
+                        source_user,
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(max_size) = &self.max_size {
+            let size_re = Regex::new(r"^[0-9]+[KMGTkmgt]?$").expect("invalid size regex");
+            if !size_re.is_match(max_size) {
+                return Err(DoppelbackError::config_field(
+                    "max_size",
+                    format!(
+                        "{} for {} is not a valid rsync size",
+                        max_size,
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(chmod) = &self.chmod {
+            // Each comma-separated item is an octal mode or a single symbolic change, optionally
+            // limited to directories (D) or files (F).
+            let item_re = Regex::new(r"^[DF]?([0-7]{1,4}|[ugoa]*[-+=][rwxXst]*)$")
+                .expect("invalid chmod regex");
+            if !chmod.split(',').all(|item| item_re.is_match(item)) {
+                return Err(DoppelbackError::config_field(
+                    "chmod",
+                    format!(
+                        "{} for {} is not a valid rsync chmod spec",
+                        chmod,
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(filter_file) = &self.filter_file {
+            // rsync reads merge files relative to its working directory, which isn't the
+            // directory the config is in.
+            if !filter_file.is_absolute() {
+                return Err(DoppelbackError::config_field(
+                    "filter_file",
+                    format!(
+                        "{} for {} is not an absolute path",
+                        filter_file.display(),
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+        if let Some(rsync_path) = &self.rsync_path {
+            check_rsync_path(rsync_path).map_err(|reason| {
+                DoppelbackError::config_field(
+                    "rsync_path",
+                    format!("{} for {} {}", rsync_path, self.path.display(), reason),
+                )
+            })?;
+        }
+        if self.include_only && self.includes.is_empty() {
+            return Err(DoppelbackError::config_field(
+                "include_only",
+                format!(
+                    "for {} needs at least one pattern in includes",
+                    self.path.display()
+                ),
+            ));
+        }
+        if let Some(nice) = self.nice {
+            if !NICE_RANGE.contains(&nice) {
+                return Err(DoppelbackError::config_field(
+                    "nice",
+                    format!(
+                        "{} for {} is not between {} and {}",
+                        nice,
+                        self.path.display(),
+                        NICE_RANGE.start(),
+                        NICE_RANGE.end()
+                    ),
+                ));
+            }
+        }
+        if let Some(class) = self.ionice_class {
+            if !IONICE_CLASSES.contains(&class) {
+                return Err(DoppelbackError::config_field(
+                    "ionice_class",
+                    format!(
+                        "{} for {} is not between {} and {}",
+                        class,
+                        self.path.display(),
+                        IONICE_CLASSES.start(),
+                        IONICE_CLASSES.end()
+                    ),
+                ));
+            }
+        }
+        if let Some(delete_mode) = &self.delete_mode {
+            if !DELETE_MODES.contains(&delete_mode.as_str()) {
+                return Err(DoppelbackError::config_field(
+                    "delete_mode",
+                    format!(
+                        "{} for {} is not one of {}",
+                        delete_mode,
+                        self.path.display(),
+                        DELETE_MODES.join(", ")
+                    ),
+                ));
+            }
+        }
+        for arg in &self.extra_rsync_args {
+            if !rsync_util::is_allowed_client_arg(arg) {
+                return Err(DoppelbackError::config_field(
+                    "extra_rsync_args",
+                    format!("{} for {} is not allowed", arg, self.path.display()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the rsync arguments that remove files from the backup that are gone from the
+    /// source, according to `delete_mode`.
+    pub fn delete_args(&self) -> Vec<&'static str> {
+        match self.delete_mode.as_deref() {
+            Some("none") => Vec::new(),
+            Some("after") => vec!["--delete-after", "--delete-excluded"],
+            Some("before") => vec!["--delete-before", "--delete-excluded"],
+            _ => vec!["--delete", "--delete-excluded"],
+        }
+    }
+}
+
+/// Values accepted for a source's `delete_mode`.  `none` keeps files that were removed from the
+/// source.
+const DELETE_MODES: &[&str] = &["during", "after", "before", "none"];
+
+/// Niceness values accepted by `nice -n`.
+const NICE_RANGE: RangeInclusive<i32> = -20..=19;
+
+/// Scheduling classes accepted by `ionice -c`: none, realtime, best-effort, and idle.
+const IONICE_CLASSES: RangeInclusive<u8> = 0..=3;
+
+/// Checks that `rsync_path` is a plain command line without shell syntax.
+///
+/// The remote shell runs the value, so every word is limited to characters that need no quoting,
+/// and the first word must be an absolute path or one of `KNOWN_RSYNC_WRAPPERS`.
+fn check_rsync_path(rsync_path: &str) -> Result<(), &'static str> {
+    let word_re = Regex::new(SAFE_WORD_PATTERN).expect("invalid rsync_path regex");
+    let words: Vec<&str> = rsync_path.split(' ').collect();
+    if words.iter().any(|word| !word_re.is_match(word)) {
+        return Err("contains characters that aren't allowed");
+    }
+    if !words[0].starts_with('/') && !KNOWN_RSYNC_WRAPPERS.contains(&words[0]) {
+        return Err("must start with an absolute path or a known wrapper");
+    }
+    Ok(())
+}
+
+/// Checks that `rsh` is a plain command line that runs one of `KNOWN_RSH_TRANSPORTS`.
+///
+/// Like `rsync_path`, every word is limited to characters that need no quoting.  ssh options that
+/// run local commands are rejected the same way as in `ssh_options`.
+fn check_rsh(rsh: &str) -> Result<(), &'static str> {
+    let word_re = Regex::new(SAFE_WORD_PATTERN).expect("invalid rsh regex");
+    let words: Vec<&str> = rsh.split(' ').collect();
+    if words.iter().any(|word| !word_re.is_match(word)) {
+        return Err("contains characters that aren't allowed");
+    }
+    let program = if words[0].starts_with('/') {
+        Path::new(words[0])
+            .file_name()
+            .map_or("", |name| name.to_str().unwrap_or(""))
+    } else {
+        words[0]
+    };
+    if !KNOWN_RSH_TRANSPORTS.contains(&program) {
+        return Err("must start with a known transport such as ssh");
+    }
+
+    // Flags are split the way getopt does, so bundled flags such as `-qF file` are caught too.
+    let mut pending_flag = None;
+    for word in &words[1..] {
+        let (flag, value) = match pending_flag.take() {
+            Some(flag) => (flag, *word),
+            None => {
+                let flags = match word.strip_prefix('-') {
+                    Some(flags) if !flags.starts_with('-') => flags,
+                    _ => continue,
+                };
+                match flags
+                    .char_indices()
+                    .find(|(_, c)| SSH_VALUE_FLAGS.contains(*c))
+                {
+                    Some((i, flag)) if i + 1 == flags.len() => {
+                        pending_flag = Some(flag);
+                        continue;
+                    }
+                    Some((i, flag)) => (flag, &flags[i + 1..]),
+                    None => continue,
+                }
+            }
+        };
+        // A config file can set any option, including the ones that run commands.
+        if flag == 'F' {
+            return Err("can't read an ssh config file with -F");
+        }
+        let runs_command = flag == 'o'
+            && value.split_once('=').is_some_and(|(key, _)| {
+                FORBIDDEN_SSH_OPTIONS.contains(&key.to_lowercase().as_str())
+            });
+        if runs_command {
+            return Err("contains an ssh option that runs local commands");
+        }
+    }
+    Ok(())
+}
+
+impl BackupDest {
+    pub fn new<P: AsRef<Path>>(root: P, host: &str, source: &BackupSource) -> Self {
+        BackupDest::for_snapshot(root, "live", host, source)
+    }
+
+    /// Returns the destination for `source` as preserved in the named snapshot under `root`.
+    pub fn for_snapshot<P: AsRef<Path>>(
+        root: P,
+        snapshot: &str,
+        host: &str,
+        source: &BackupSource,
+    ) -> Self {
+        let dest_name = BackupDest::get_safe_name(&source.path);
+        let mut dest_dir = root.as_ref().join(snapshot);
+        dest_dir.push(host);
+        dest_dir.push(dest_name);
+
+        Self { dest_dir }
+    }
+
+    pub fn backup_dir(&self) -> &Path {
+        self.dest_dir.as_path()
+    }
+
+    pub fn get_companion_file(&self, name: &str) -> PathBuf {
+        self.dest_dir.with_extension(name)
+    }
+
+    /// Creates the backup directory and any missing parents below `root`.
+    ///
+    /// Every component between `root` and the backup directory must be a real directory.  A
+    /// symlink anywhere along the way could redirect rsync's writes outside the snapshots, so it
+    /// is rejected instead of followed.  Directories created here are set to `mode` if given;
+    /// existing ones are left alone.
+    pub fn create_backup_dir<P: AsRef<Path>>(
+        &self,
+        root: P,
+        mode: Option<u32>,
+    ) -> Result<(), DoppelbackError> {
+        let relative = self
+            .dest_dir
+            .strip_prefix(root.as_ref())
+            .map_err(|_| DoppelbackError::InvalidPath(self.dest_dir.clone()))?;
+
+        let mut dir = root.as_ref().to_path_buf();
+        for component in relative.components() {
+            if !matches!(component, Component::Normal(_)) {
+                return Err(DoppelbackError::InvalidPath(self.dest_dir.clone()));
+            }
+            dir.push(component);
+
+            let created = match fs::create_dir(&dir) {
+                Ok(()) => true,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+                Err(e) => return Err(e.into()),
+            };
+            // Check after creating so that a directory swapped for a symlink by someone else in
+            // the meantime is still caught.
+            let metadata = fs::symlink_metadata(&dir)?;
+            if !metadata.is_dir() {
+                return Err(DoppelbackError::InvalidPath(dir));
+            }
+            if let (true, Some(mode)) = (created, mode) {
+                fs::set_permissions(&dir, fs::Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_writable(&self) -> Result<(), DoppelbackError> {
+        if !self.dest_dir.is_dir() {
+            return Err(DoppelbackError::MissingDir(self.dest_dir.clone()));
+        }
+
+        // Use access(2) instead of checking the mode bits so that ACLs and the effective uid are
+        // taken into account the same way they will be when rsync writes into the directory.
+        let path = CString::new(self.dest_dir.as_os_str().as_bytes())
+            .map_err(|_| DoppelbackError::InvalidPath(self.dest_dir.clone()))?;
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(DoppelbackError::IoError(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Returns the name used for `original`'s backup directory and companion files.
+    pub fn get_safe_name<P: AsRef<Path>>(original: P) -> String {
+        let path = original.as_ref().to_string_lossy();
+        let name = path.trim_matches('/');
+
+        if name.is_empty() {
+            return "rootfs".to_string();
+        }
+
+        name.replace(['/', '.'], "_")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn snapshots_must_be_present() {
+        let cfg = Config {
+            ..Config::default()
+        };
+        assert!(cfg.snapshot_dir_valid().is_err());
+    }
+
+    #[test]
+    fn remote_targets_are_parsed() {
+        let parse = |spec: &str| RemoteTarget::parse(Path::new(spec));
+        assert_eq!(
+            parse("backup@nas.example.com:/volume1/snapshots"),
+            Some(RemoteTarget {
+                user: Some(String::from("backup")),
+                host: String::from("nas.example.com"),
+                path: PathBuf::from("/volume1/snapshots"),
+            })
+        );
+        assert_eq!(
+            parse("nas:/snapshots"),
+            Some(RemoteTarget {
+                user: None,
+                host: String::from("nas"),
+                path: PathBuf::from("/snapshots"),
+            })
+        );
+        assert_eq!(
+            parse("backup@[2001:db8::1]:/snapshots"),
+            Some(RemoteTarget {
+                user: Some(String::from("backup")),
+                host: String::from("2001:db8::1"),
+                path: PathBuf::from("/snapshots"),
+            })
+        );
+
+        for local in [
+            "/snapshots",
+            "/mnt/nas:/snapshots",
+            "snapshots",
+            "nas:snapshots",
+            ":/snapshots",
+            "@nas:/snapshots",
+            "~",
+        ] {
+            assert_eq!(parse(local), None, "{} parsed as remote", local);
+        }
+    }
+
+    #[test]
+    fn remote_target_ssh_destination() {
+        let mut target = RemoteTarget::parse(Path::new("backup@nas:/snapshots")).unwrap();
+        assert_eq!(target.ssh_destination(), "backup@nas");
+        target.user = None;
+        assert_eq!(target.ssh_destination(), "nas");
+    }
+
+    #[test]
+    fn remote_snapshots_are_rejected() {
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                snapshots: Some(PathBuf::from("backup@nas:/volume1/snapshots")),
+                ..BackupHost::default()
+            },
+        );
+        let dir = TempDir::new("snapshots").unwrap();
+        fs::create_dir(dir.path().join("live")).unwrap();
+        let cfg = Config {
+            snapshots: dir.path().to_path_buf(),
+            hosts,
+            ..Config::default()
+        };
+        assert_eq!(
+            cfg.snapshot_dir_valid().unwrap_err().to_string(),
+            "invalid config: snapshots /volume1/snapshots is on the remote host backup@nas, but \
+             only a local directory is supported"
+        );
+    }
+
+    #[test]
+    fn snapshots_must_be_present_yaml() {
+        let cfg = Config {
+            snapshots: Path::new("~").to_path_buf(),
+            ..Config::default()
+        };
+        assert!(cfg.snapshot_dir_valid().is_err());
+    }
+
+    #[test]
+    fn snapshots_must_be_dir() {
+        let cfg = Config {
+            snapshots: Path::new("/dev/null").to_path_buf(),
+            ..Config::default()
+        };
+        assert!(cfg.snapshot_dir_valid().is_err());
+
+        let dir = TempDir::new("snapshots").unwrap();
+        let cfg = Config {
+            snapshots: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let err = cfg.snapshot_dir_valid();
+        assert!(err.is_err());
+        match err {
+            Err(DoppelbackError::MissingDir(d)) => {
+                assert!(format!("{}", d.display()).contains("live"))
+            }
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn snapshots_must_contain_live() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let live_dir = dir.path().join("live");
+        fs::create_dir(live_dir).unwrap();
+
+        let cfg = Config {
+            snapshots: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        assert!(cfg.snapshot_dir_valid().is_ok());
+    }
+
+    #[test]
+    fn host_snapshots_override_global() {
+        let global = TempDir::new("snapshots").unwrap();
+        let other = TempDir::new("snapshots").unwrap();
+        fs::create_dir(global.path().join("live")).unwrap();
+
+        let mut hosts = HashMap::new();
+        hosts.insert(String::from("host1"), BackupHost::default());
+        hosts.insert(
+            String::from("host2"),
+            BackupHost {
+                snapshots: Some(other.path().to_path_buf()),
+                ..BackupHost::default()
+            },
+        );
+        let mut cfg = Config {
+            snapshots: global.path().to_path_buf(),
+            hosts,
+            ..Config::default()
+        };
+
+        assert_eq!(cfg.snapshot_root("host1"), global.path());
+        assert_eq!(cfg.snapshot_root("host2"), other.path());
+        assert_eq!(cfg.snapshot_roots(), vec![global.path(), other.path()]);
+
+        // The override is checked as well as the global directory.
+        match cfg.snapshot_dir_valid() {
+            Err(DoppelbackError::MissingDir(d)) => assert_eq!(d, other.path().join("live")),
+            other => panic!("expected MissingDir, got {:?}", other),
+        }
+        fs::create_dir(other.path().join("live")).unwrap();
+        assert!(cfg.snapshot_dir_valid().is_ok());
+
+        cfg.hosts.get_mut("host2").unwrap().snapshots = Some(PathBuf::from("relative"));
+        assert!(matches!(
+            cfg.snapshot_dir_valid(),
+            Err(DoppelbackError::InvalidPath(_))
+        ));
+    }
+
+    fn strict_config(host_config: BackupHost) -> Config {
+        let mut hosts = HashMap::new();
+        hosts.insert(String::from("host1"), host_config);
+        Config {
+            hosts,
+            ..Config::default()
+        }
+    }
+
+    fn source(path: &str) -> BackupSource {
+        BackupSource {
+            path: PathBuf::from(path),
+            ..BackupSource::default()
+        }
+    }
+
+    fn strict_messages(cfg: &Config) -> Vec<String> {
+        cfg.strict_problems()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn strict_accepts_valid_host() {
+        let cfg = strict_config(BackupHost {
+            port: Some(2222),
+            sources: vec![source("/etc"), source("/home")],
+            ..BackupHost::default()
+        });
+        assert!(cfg.strict_problems().is_empty());
+    }
+
+    #[test]
+    fn strict_rejects_empty_sources() {
+        let cfg = strict_config(BackupHost::default());
+        assert_eq!(
+            strict_messages(&cfg),
+            vec!["invalid config for host1: sources is empty"]
+        );
+    }
+
+    #[test]
+    fn strict_rejects_duplicate_sources() {
+        let cfg = strict_config(BackupHost {
+            sources: vec![source("/etc"), source("/home"), source("/etc")],
+            ..BackupHost::default()
+        });
+        assert_eq!(
+            strict_messages(&cfg),
+            vec!["invalid config for host1: sources path /etc is listed more than once"]
+        );
+    }
+
+    #[test]
+    fn strict_rejects_relative_sources() {
+        let cfg = strict_config(BackupHost {
+            sources: vec![source("etc")],
+            ..BackupHost::default()
+        });
+        assert_eq!(
+            strict_messages(&cfg),
+            vec!["invalid config for host1: sources path etc is not absolute"]
+        );
+    }
+
+    #[test]
+    fn strict_rejects_zero_port() {
+        let cfg = strict_config(BackupHost {
+            port: Some(0),
+            sources: vec![source("/etc")],
+            ..BackupHost::default()
+        });
+        match &cfg.strict_problems()[..] {
+            [DoppelbackError::ConfigError {
+                host: Some(host),
+                field,
+                ..
+            }] => {
+                assert_eq!(host, "host1");
+                assert_eq!(field, "port");
+            }
+            other => panic!("unexpected problems {:?}", other),
+        }
+        assert_eq!(
+            strict_messages(&cfg),
+            vec!["invalid config for host1: port is set to 0"]
+        );
+    }
+
+    #[test]
+    fn strict_collects_all_problems() {
+        let mut cfg = strict_config(BackupHost {
+            port: Some(0),
+            ..BackupHost::default()
+        });
+        cfg.hosts.insert(
+            String::from("host0"),
+            BackupHost {
+                sources: vec![source("/etc"), source("/etc")],
+                ..BackupHost::default()
+            },
+        );
+        assert_eq!(
+            strict_messages(&cfg),
+            vec![
+                "invalid config for host0: sources path /etc is listed more than once",
+                "invalid config for host1: sources is empty",
+                "invalid config for host1: port is set to 0",
+            ]
+        );
+    }
+
+    fn parse_messages(cfg: &Config) -> Vec<String> {
+        cfg.parse_problems()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn parse_accepts_valid_host() {
+        let cfg = strict_config(BackupHost {
+            user: String::from("backup"),
+            sources: vec![source("/etc"), source("/home")],
+            ..BackupHost::default()
+        });
+        assert!(cfg.parse_problems().is_empty());
+    }
+
+    #[test]
+    fn parse_collects_all_problems() {
+        let mut cfg = strict_config(BackupHost {
+            user: String::from("root"),
+            compress: Some(String::from("gzip")),
+            sources: vec![
+                source("etc"),
+                BackupSource {
+                    max_size: Some(String::from("lots")),
+                    ..source("/srv")
+                },
+            ],
+            ..BackupHost::default()
+        });
+        cfg.hosts.insert(
+            String::from("host0"),
+            BackupHost {
+                user: String::from("backup"),
+                sources: vec![source("/home"), source("/home/user")],
+                ..BackupHost::default()
+            },
+        );
+        assert_eq!(
+            parse_messages(&cfg),
+            vec![
+                "invalid config for host0: sources path /home/user overlaps source /home",
+                "invalid config for host1: user root is not a valid user",
+                "invalid config for host1: compress gzip is not one of none, zlib, zstd, lz4",
+                "invalid config for host1: sources path etc is not absolute",
+                "invalid config for host1: max_size lots for /srv is not a valid rsync size",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_merges_config_dir() {
+        let dir = TempDir::new("config").unwrap();
+        fs::write(
+            dir.path().join("00-main.yaml"),
+            "snapshots: /snapshots\nmin_free_bytes: 1000\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("host1.yaml"),
+            "hosts:\n  host1:\n    user: backup\n    sources: []\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("host2.yaml"),
+            "snapshots: /snapshots\nhosts:\n  host2:\n    user: backup\n    sources: []\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("README"), "not yaml: [").unwrap();
+
+        let config = Config::load(dir.path()).unwrap();
+        assert_eq!(config.snapshots, Path::new("/snapshots"));
+        assert_eq!(config.min_free_bytes, Some(1000));
+        let mut hosts: Vec<_> = config.hosts.keys().collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["host1", "host2"]);
+    }
+
+    #[test]
+    fn dumped_config_loads_back_unchanged() {
+        let dir = TempDir::new("config").unwrap();
+        fs::write(
+            dir.path().join("00-main.yaml"),
+            "snapshots: /snapshots\nmin_free_bytes: 1000\nnotify:\n  mode: webhook\n  target: http://localhost/\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("host1.yaml"),
+            "hosts:\n  host1:\n    user: backup\n    port: 2222\n    inhibit_shutdown: systemctl\n    sources:\n      - path: /etc\n        root: true\n        excludes: [\"*.bak\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("host2.yaml"),
+            "hosts:\n  host2:\n    user: backup\n    sources:\n      - path: /home\n        root: false\n",
+        )
+        .unwrap();
+
+        let dump = Config::load(dir.path()).unwrap().to_yaml();
+        // Defaults that weren't in any file are filled in.
+        assert!(dump.contains("numeric_ids: false"), "{}", dump);
+        assert!(dump.contains("ssh_options: []"), "{}", dump);
+        assert!(dump.find("host1:").unwrap() < dump.find("host2:").unwrap());
+
+        let path = dir.path().join("dump.yml");
+        fs::write(&path, &dump).unwrap();
+        let reloaded = Config::load(&path).unwrap();
+        assert_eq!(reloaded.to_yaml(), dump);
+        assert_eq!(reloaded.hosts["host1"].port, Some(2222));
+        assert_eq!(reloaded.hosts["host1"].sources[0].excludes, vec!["*.bak"]);
+        assert_eq!(reloaded.notify.unwrap().mode, NotifyMode::Webhook);
+    }
+
+    #[test]
+    fn load_rejects_duplicate_hosts_in_config_dir() {
+        let dir = TempDir::new("config").unwrap();
+        let host = "hosts:\n  host1:\n    user: backup\n    sources: []\n";
+        fs::write(
+            dir.path().join("a.yaml"),
+            format!("snapshots: /s\n{}", host),
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.yaml"), host).unwrap();
+
+        match Config::load(dir.path()) {
+            Err(DoppelbackError::InvalidConfig(msg)) => {
+                assert!(msg.contains("host1"), "{}", msg);
+                assert!(msg.contains("a.yaml") && msg.contains("b.yaml"), "{}", msg);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_rejects_conflicting_settings_in_config_dir() {
+        let dir = TempDir::new("config").unwrap();
+        fs::write(dir.path().join("a.yaml"), "snapshots: /snapshots\n").unwrap();
+        fs::write(dir.path().join("b.yaml"), "snapshots: /other\nhosts: {}\n").unwrap();
+
+        match Config::load(dir.path()) {
+            Err(DoppelbackError::InvalidConfig(msg)) => {
+                assert!(msg.contains("snapshots"), "{}", msg);
+                assert!(msg.contains("a.yaml") && msg.contains("b.yaml"), "{}", msg);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_rejects_bad_snapshot_name_format() {
+        let dir = TempDir::new("config").unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            "snapshots: /snapshots\nhosts: {}\nsnapshot_name_format: \"%Y/%m%d\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Config::load(&path),
+            Err(DoppelbackError::InvalidConfig(_))
+        ));
+
+        fs::write(
+            &path,
+            "snapshots: /snapshots\nhosts: {}\nsnapshot_name_format: \"%Y%m%d-%H\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Config::load(&path).unwrap().snapshot_name_format(),
+            "%Y%m%d-%H"
+        );
+    }
+
+    #[test]
+    fn configured_programs_are_used() {
+        let dir = TempDir::new("config").unwrap();
+        let rsync = dir.path().join("rsync");
+        fs::write(&rsync, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&rsync, fs::Permissions::from_mode(0o755)).unwrap();
+        let not_executable = dir.path().join("btrfs");
+        fs::write(&not_executable, "").unwrap();
+
+        let cfg = Config {
+            rsync_bin: Some(rsync.clone()),
+            btrfs_bin: Some(not_executable),
+            ..Config::default()
+        };
+        assert_eq!(cfg.rsync_bin().unwrap(), rsync);
+        let err = cfg.btrfs_bin().unwrap_err().to_string();
+        assert!(
+            err.contains("btrfs_bin") && err.contains("not an executable"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn relative_program_is_rejected() {
+        let cfg = Config {
+            rsync_bin: Some(PathBuf::from("bin/rsync")),
+            ..Config::default()
+        };
+        match cfg.rsync_bin() {
+            Err(DoppelbackError::ConfigError { field, message, .. }) => {
+                assert_eq!(field, "rsync_bin");
+                assert_eq!(message, "bin/rsync is not an absolute path");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn free_space_is_unchecked_by_default() {
+        let cfg = Config {
+            snapshots: PathBuf::from("/nonexistent"),
+            ..Config::default()
+        };
+        assert!(cfg.check_free_space("host1").is_ok());
+    }
+
+    #[test]
+    fn free_space_below_minimum_fails() {
+        assert_eq!(
+            check_available_space(4096, 1000, 4096 * 1000).unwrap(),
+            4096000
+        );
+        match check_available_space(4096, 999, 4096 * 1000) {
+            Err(DoppelbackError::InsufficientSpace {
+                available,
+                required,
+            }) => {
+                assert_eq!(available, 4096 * 999);
+                assert_eq!(required, 4096 * 1000);
+            }
+            other => panic!("expected InsufficientSpace, got {:?}", other),
+        }
+        assert!(check_available_space(u64::MAX, 2, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn free_space_checks_real_filesystem() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let mut cfg = Config {
+            snapshots: dir.path().to_path_buf(),
+            min_free_bytes: Some(1),
+            ..Config::default()
+        };
+        assert!(cfg.check_free_space("host1").is_ok());
+
+        cfg.min_free_bytes = Some(u64::MAX);
+        assert!(matches!(
+            cfg.check_free_space("host1"),
+            Err(DoppelbackError::InsufficientSpace { .. })
+        ));
+    }
+
+    #[test]
+    fn backuphost_user_is_nonempty() {
+        let cfg = BackupHost {
+            user: String::from(""),
+            ..BackupHost::default()
+        };
+        assert!(!cfg.is_user_valid());
+    }
+
+    #[test]
+    fn backuphost_user_is_nonempty_yaml() {
+        let cfg = BackupHost {
+            user: String::from("~"),
+            ..BackupHost::default()
+        };
+        assert!(!cfg.is_user_valid());
+    }
+
+    #[test]
+    fn backuphost_user_is_not_root() {
+        let cfg = BackupHost {
+            user: String::from("root"),
+            ..BackupHost::default()
+        };
+        assert!(!cfg.is_user_valid());
+    }
+
+    #[test]
+    fn backuphost_user_is_ok() {
+        let cfg = BackupHost {
+            user: String::from("backupuser"),
+            ..BackupHost::default()
+        };
+        assert!(cfg.is_user_valid());
+    }
+
+    #[test]
+    fn find_ssh_key_absolute_path() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        let _ = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&keyfile);
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            ..BackupHost::default()
+        };
+
+        assert_eq!(cfg.find_ssh_key(PathBuf::from("/nosuch")), Some(keyfile));
+    }
+
+    #[test]
+    fn find_ssh_key_in_home() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let ssh_dir = dir.path().join(".ssh");
+        let _ = fs::create_dir(&ssh_dir);
+
+        let keyfile = ssh_dir.join("keyfile");
+        let _ = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&keyfile);
+
+        let cfg = BackupHost {
+            key: PathBuf::from("keyfile"),
+            ..BackupHost::default()
+        };
+
+        assert_eq!(cfg.find_ssh_key(dir.path()), Some(keyfile));
+    }
+
+    #[test]
+    fn keys_fold_legacy_key_first() {
+        let cfg: BackupHost =
+            serde_yaml::from_str("user: backup\nkey: old_key\nkeys: [new_key]\nsources: []\n")
+                .unwrap();
+        assert_eq!(
+            cfg.configured_keys(),
+            vec![Path::new("old_key"), Path::new("new_key")]
+        );
+
+        let cfg: BackupHost =
+            serde_yaml::from_str("user: backup\nkeys: [new_key]\nsources: []\n").unwrap();
+        assert_eq!(cfg.configured_keys(), vec![Path::new("new_key")]);
+    }
+
+    #[test]
+    fn find_ssh_keys_skips_missing() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let second = dir.path().join("second");
+        let third = dir.path().join("third");
+        fs::write(&second, "").unwrap();
+        fs::write(&third, "").unwrap();
+
+        let cfg = BackupHost {
+            key: dir.path().join("first"),
+            keys: vec![second.clone(), third.clone()],
             ..BackupHost::default()
         };
-        assert!(!cfg.is_user_valid());
+
+        assert_eq!(
+            cfg.find_ssh_keys("/nosuch"),
+            vec![second.clone(), third.clone()]
+        );
+        assert_eq!(cfg.find_ssh_key("/nosuch"), Some(second.clone()));
+        let expected = vec![
+            OsString::from("/opt/bin/ssh"),
+            OsString::from("-a"),
+            OsString::from("-x"),
+            OsString::from("-oIdentitiesOnly=true"),
+            OsString::from("-i"),
+            second.into_os_string(),
+            OsString::from("-i"),
+            third.into_os_string(),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn missing_key_error_lists_keys() {
+        let cfg = BackupHost {
+            keys: vec![PathBuf::from("a"), PathBuf::from("/b")],
+            ..BackupHost::default()
+        };
+        assert_eq!(
+            cfg.missing_key_error().to_string(),
+            "invalid config: ssh key a, /b not found"
+        );
+    }
+
+    fn host_with_sources(paths: &[&str]) -> BackupHost {
+        BackupHost {
+            sources: paths
+                .iter()
+                .map(|path| BackupSource {
+                    path: PathBuf::from(path),
+                    ..BackupSource::default()
+                })
+                .collect(),
+            ..BackupHost::default()
+        }
+    }
+
+    #[test]
+    fn overlapping_sources_nested() {
+        let cfg = host_with_sources(&["/etc", "/home/user", "/home"]);
+        assert_eq!(
+            cfg.find_overlapping_sources(),
+            Some((PathBuf::from("/home"), PathBuf::from("/home/user")))
+        );
+    }
+
+    #[test]
+    fn overlapping_sources_identical() {
+        let cfg = host_with_sources(&["/etc", "/etc/"]);
+        assert_eq!(
+            cfg.find_overlapping_sources(),
+            Some((PathBuf::from("/etc"), PathBuf::from("/etc/")))
+        );
+    }
+
+    #[test]
+    fn overlapping_sources_disjoint() {
+        let cfg = host_with_sources(&["/home", "/homework", "/etc"]);
+        assert_eq!(cfg.find_overlapping_sources(), None);
+    }
+
+    #[test]
+    fn destination_defaults_to_host_key() {
+        let cfg = BackupHost {
+            user: String::from("backup"),
+            ..BackupHost::default()
+        };
+        assert_eq!(cfg.ssh_destination("host1"), "backup@host1");
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &BackupSource::default()),
+            "backup@host1"
+        );
+        assert!(cfg.check_options().is_ok());
+    }
+
+    #[test]
+    fn destination_uses_address() {
+        let mut cfg = BackupHost {
+            user: String::from("backup"),
+            address: Some(String::from("10.0.0.5")),
+            ..BackupHost::default()
+        };
+        assert_eq!(cfg.ssh_destination("host1"), "backup@10.0.0.5");
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &BackupSource::default()),
+            "backup@10.0.0.5"
+        );
+        assert!(cfg.check_options().is_ok());
+
+        cfg.address = Some(String::from("2001:db8::5"));
+        assert_eq!(cfg.ssh_destination("host1"), "backup@2001:db8::5");
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &BackupSource::default()),
+            "backup@[2001:db8::5]"
+        );
+        assert!(cfg.check_options().is_ok());
+
+        for bad in [
+            "",
+            "-oProxyCommand=x",
+            "[2001:db8::5]",
+            "host 1",
+            "user@host1",
+        ] {
+            cfg.address = Some(String::from(bad));
+            assert!(cfg.check_options().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn source_destination_overrides_host() {
+        let cfg = BackupHost {
+            user: String::from("backup"),
+            address: Some(String::from("10.0.0.5")),
+            ..BackupHost::default()
+        };
+        let mut source = BackupSource {
+            path: PathBuf::from("/srv/db"),
+            ..BackupSource::default()
+        };
+        assert!(!source.overrides_destination());
+        assert_eq!(
+            cfg.source_ssh_destination("host1", &source),
+            "backup@10.0.0.5"
+        );
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &source),
+            "backup@10.0.0.5"
+        );
+
+        source.source_host = Some(String::from("2001:db8::7"));
+        assert!(source.overrides_destination());
+        assert_eq!(
+            cfg.source_ssh_destination("host1", &source),
+            "backup@2001:db8::7"
+        );
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &source),
+            "backup@[2001:db8::7]"
+        );
+
+        source.source_user = Some(String::from("dbbackup"));
+        source.source_host = None;
+        assert_eq!(
+            cfg.source_rsync_destination("host1", &source),
+            "dbbackup@10.0.0.5"
+        );
+        assert!(source.check_options().is_ok());
+
+        for (host, user) in [
+            (Some("-oProxyCommand=x"), None),
+            (Some("user@db1"), None),
+            (None, Some("root")),
+            (None, Some("-l")),
+            (None, Some("db@db1")),
+        ] {
+            let source = BackupSource {
+                source_host: host.map(String::from),
+                source_user: user.map(String::from),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_err(), "{:?} {:?}", host, user);
+        }
+    }
+
+    #[test]
+    fn ssh_args_no_empty_key() {
+        let cfg = BackupHost::default();
+        assert!(matches!(
+            cfg.ssh_args("/usr/bin/ssh", "/tmp", "host1"),
+            Err(DoppelbackError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn ssh_args_missing_key() {
+        let cfg = BackupHost {
+            key: PathBuf::from("/nosuch"),
+            ..BackupHost::default()
+        };
+        assert_eq!(
+            cfg.ssh_args("/usr/bin/ssh", "/tmp", "host1")
+                .unwrap_err()
+                .to_string(),
+            cfg.missing_key_error().to_string()
+        );
+    }
+
+    #[test]
+    fn ssh_args_no_port() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        let _ = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&keyfile);
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            ..BackupHost::default()
+        };
+        let expected = vec![
+            OsString::from("/opt/bin/ssh"),
+            OsString::from("-a"),
+            OsString::from("-x"),
+            OsString::from("-oIdentitiesOnly=true"),
+            OsString::from("-i"),
+            keyfile.as_os_str().to_os_string(),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ssh_args_zero_port() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        let _ = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&keyfile);
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            port: Some(0),
+            ..BackupHost::default()
+        };
+        let expected = vec![
+            OsString::from("/opt/bin/ssh"),
+            OsString::from("-a"),
+            OsString::from("-x"),
+            OsString::from("-oIdentitiesOnly=true"),
+            OsString::from("-i"),
+            keyfile.as_os_str().to_os_string(),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ssh_args_nonzero_port() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        let _ = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&keyfile);
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            port: Some(2221),
+            ..BackupHost::default()
+        };
+        let expected = vec![
+            OsString::from("/opt/bin/ssh"),
+            OsString::from("-a"),
+            OsString::from("-x"),
+            OsString::from("-oIdentitiesOnly=true"),
+            OsString::from("-i"),
+            keyfile.as_os_str().to_os_string(),
+            OsString::from("-p"),
+            OsString::from("2221"),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ssh_args_custom_options() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            ssh_options: vec![
+                String::from("-oConnectTimeout=30"),
+                String::from("-o StrictHostKeyChecking=accept-new"),
+            ],
+            ..BackupHost::default()
+        };
+        assert!(cfg.check_options().is_ok());
+        let expected = vec![
+            OsString::from("/opt/bin/ssh"),
+            OsString::from("-a"),
+            OsString::from("-x"),
+            OsString::from("-oIdentitiesOnly=true"),
+            OsString::from("-i"),
+            keyfile.as_os_str().to_os_string(),
+            OsString::from("-oConnectTimeout=30"),
+            OsString::from("-oStrictHostKeyChecking=accept-new"),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ssh_args_custom_rsh() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
+
+        let cfg = BackupHost {
+            key: keyfile.clone(),
+            port: Some(2221),
+            rsh: Some(String::from("ssh -J bastion.example.com")),
+            ..BackupHost::default()
+        };
+        assert!(cfg.check_options().is_ok());
+        let expected = vec![
+            OsString::from("ssh"),
+            OsString::from("-J"),
+            OsString::from("bastion.example.com"),
+            OsString::from("-i"),
+            keyfile.as_os_str().to_os_string(),
+            OsString::from("-p"),
+            OsString::from("2221"),
+        ];
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ssh_args_custom_rsh_keeps_own_key_and_port() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
+
+        let cfg = BackupHost {
+            key: keyfile,
+            port: Some(2221),
+            rsh: Some(String::from(
+                "/usr/bin/autossh -M 0 -i /etc/backup.key -p 2022",
+            )),
+            ..BackupHost::default()
+        };
+        let expected: Vec<_> = [
+            "/usr/bin/autossh",
+            "-M",
+            "0",
+            "-i",
+            "/etc/backup.key",
+            "-p",
+            "2022",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rsh_is_validated() {
+        for rsh in [
+            "ssh",
+            "/usr/bin/ssh -J bastion",
+            "autossh -M 0",
+            "ssh -oConnectTimeout=30",
+            "ssh -4q -l Fred -i /home/backup/.ssh/id_F",
+            "ssh -o ConnectTimeout=30",
+        ] {
+            assert!(check_rsh(rsh).is_ok(), "{} rejected", rsh);
+        }
+        for rsh in [
+            "",
+            "nc host 22",
+            "/tmp/ssh.sh",
+            "sh -c ssh",
+            "ssh -J bastion; id",
+            "ssh  -J bastion",
+            "ssh -oProxyCommand=nc",
+            "ssh -o LocalCommand=id",
+            "ssh -qoProxyCommand=nc",
+            "ssh -F /home/backup/evil_config",
+            "ssh -F/home/backup/evil_config",
+            "ssh -qF /home/backup/evil_config",
+            "autossh -M 0 -F /home/backup/evil_config",
+            "ssh -oInclude=/home/backup/evil_config",
+            "ssh -o include=/home/backup/evil_config",
+        ] {
+            assert!(check_rsh(rsh).is_err(), "{} accepted", rsh);
+        }
+
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
+        let cfg = BackupHost {
+            key: keyfile,
+            rsh: Some(String::from("nc host 22")),
+            ..BackupHost::default()
+        };
+        assert!(cfg.check_options().is_err());
+        assert!(matches!(
+            cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1"),
+            Err(DoppelbackError::ConfigError { field, .. }) if field == "rsh"
+        ));
+    }
+
+    #[test]
+    fn ssh_args_known_hosts() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
+
+        let mut cfg = BackupHost {
+            key: keyfile,
+            ..BackupHost::default()
+        };
+        let args = cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap();
+        assert!(!args
+            .iter()
+            .any(|a| a.to_string_lossy().contains("KnownHosts")
+                || a.to_string_lossy().contains("StrictHostKeyChecking")));
+
+        cfg.known_hosts = Some(PathBuf::from("/etc/doppelback/known_hosts"));
+        let args = cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1").unwrap();
+        assert_eq!(
+            args[args.len() - 2..],
+            [
+                OsString::from("-oUserKnownHostsFile=/etc/doppelback/known_hosts"),
+                OsString::from("-oStrictHostKeyChecking=yes"),
+            ]
+        );
+    }
+
+    #[test]
+    fn control_path_is_per_host() {
+        let mut cfg = BackupHost::default();
+        assert_eq!(cfg.control_path("/home/backup", "host1.example.com"), None);
+        assert!(cfg.multiplex_args("/home/backup", "host1").is_empty());
+
+        cfg.ssh_multiplex = true;
+        assert_eq!(
+            cfg.control_path("/home/backup", "host1.example.com"),
+            Some(PathBuf::from(
+                "/home/backup/.ssh/doppelback-host1.example.com.sock"
+            ))
+        );
+        assert_eq!(
+            cfg.control_path("/home/backup", "odd/name"),
+            Some(PathBuf::from("/home/backup/.ssh/doppelback-odd_name.sock"))
+        );
     }
 
     #[test]
-    fn backuphost_user_is_not_root() {
+    fn multiplex_args_share_connection() {
         let cfg = BackupHost {
-            user: String::from("root"),
+            ssh_multiplex: true,
             ..BackupHost::default()
         };
-        assert!(!cfg.is_user_valid());
+        assert_eq!(
+            cfg.multiplex_args("/home/backup", "host1"),
+            vec![
+                OsString::from("-oControlMaster=auto"),
+                OsString::from("-oControlPath=/home/backup/.ssh/doppelback-host1.sock"),
+                OsString::from("-oControlPersist=60"),
+            ]
+        );
     }
 
     #[test]
-    fn backuphost_user_is_ok() {
+    fn ssh_args_share_connection() {
+        let dir = TempDir::new("sshkey").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
         let cfg = BackupHost {
-            user: String::from("backupuser"),
+            key: keyfile,
+            ssh_multiplex: true,
             ..BackupHost::default()
         };
-        assert!(cfg.is_user_valid());
+        let multiplex = cfg.multiplex_args("/home/backup", "host1");
+
+        let args = cfg
+            .ssh_args("/opt/bin/ssh", "/home/backup", "host1")
+            .unwrap();
+        assert!(args.ends_with(&multiplex));
+
+        let source = BackupSource::default();
+        let source_args = cfg
+            .source_ssh_args("/opt/bin/ssh", "/home/backup", "host1", &source)
+            .unwrap();
+        assert_eq!(source_args, args);
+
+        let source = BackupSource {
+            source_host: Some(String::from("nas.example.com")),
+            ..BackupSource::default()
+        };
+        let source_args = cfg
+            .source_ssh_args("/opt/bin/ssh", "/home/backup", "host1", &source)
+            .unwrap();
+        assert!(!source_args.iter().any(|arg| multiplex.contains(arg)));
+        assert_eq!(source_args, args[..args.len() - multiplex.len()]);
     }
 
     #[test]
-    fn find_ssh_key_absolute_path() {
-        let dir = TempDir::new("sshkey").unwrap();
-        let keyfile = dir.path().join("keyfile");
-        let _ = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&keyfile);
+    fn known_hosts_must_have_entry() {
+        let dir = TempDir::new("known_hosts").unwrap();
+        let known_hosts = dir.path().join("known_hosts");
 
-        let cfg = BackupHost {
-            key: keyfile.clone(),
-            ..BackupHost::default()
-        };
+        let mut cfg = BackupHost::default();
+        assert!(cfg.check_known_hosts("host1.example.com").is_ok());
 
-        assert_eq!(cfg.find_ssh_key(PathBuf::from("/nosuch")), Some(keyfile));
+        cfg.known_hosts = Some(known_hosts.clone());
+        assert!(cfg.check_known_hosts("host1.example.com").is_err());
+
+        fs::write(
+            &known_hosts,
+            "host1.example.com ssh-ed25519 \
+             AAAAC3NzaC1lZDI1NTE5AAAAIMnAbjBkLrtNRv/arseNile8NS1zSRPTghy5D3V/TzxH\n",
+        )
+        .unwrap();
+        assert!(cfg.check_known_hosts("host1.example.com").is_ok());
+        assert!(cfg.check_known_hosts("host2.example.com").is_err());
+
+        // A non-default port is looked up as [host]:port.
+        cfg.port = Some(2221);
+        assert!(cfg.check_known_hosts("host1.example.com").is_err());
     }
 
     #[test]
-    fn find_ssh_key_in_home() {
+    fn ssh_options_reject_injection() {
         let dir = TempDir::new("sshkey").unwrap();
-        let ssh_dir = dir.path().join(".ssh");
-        let _ = fs::create_dir(&ssh_dir);
+        let keyfile = dir.path().join("keyfile");
+        fs::write(&keyfile, "").unwrap();
 
-        let keyfile = ssh_dir.join("keyfile");
-        let _ = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&keyfile);
+        for option in [
+            "-oConnectTimeout=30 -v",
+            "-oProxyCommand=nc",
+            "-o LocalCommand=id",
+            "-oInclude=/home/backup/evil_config",
+            "-oConnectTimeout=30; rm",
+            "-v",
+            "ConnectTimeout=30",
+            "-o  ConnectTimeout=30",
+        ] {
+            let cfg = BackupHost {
+                key: keyfile.clone(),
+                ssh_options: vec![option.to_string()],
+                ..BackupHost::default()
+            };
+            assert!(cfg.check_options().is_err(), "{} accepted", option);
+            assert!(matches!(
+                cfg.ssh_args("/opt/bin/ssh", "/tmp", "host1"),
+                Err(DoppelbackError::ConfigError { field, .. }) if field == "ssh_options"
+            ));
+        }
+    }
 
-        let cfg = BackupHost {
-            key: PathBuf::from("keyfile"),
-            ..BackupHost::default()
-        };
+    fn window(start: &str, end: &str, bwlimit: u64) -> BwlimitWindow {
+        BwlimitWindow {
+            start: start.to_string(),
+            end: end.to_string(),
+            bwlimit,
+        }
+    }
 
-        assert_eq!(cfg.find_ssh_key(dir.path()), Some(keyfile));
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
     }
 
     #[test]
-    fn ssh_args_no_empty_key() {
-        let cfg = BackupHost::default();
-        assert!(cfg.ssh_args("/usr/bin/ssh", "/tmp").is_none());
+    fn bwlimit_window_matches_daytime() {
+        let day = window("08:00", "18:30", 500);
+        assert!(!day.contains(time(7, 59)));
+        assert!(day.contains(time(8, 0)));
+        assert!(day.contains(time(18, 29)));
+        assert!(!day.contains(time(18, 30)));
+        assert!(!day.contains(time(0, 0)));
     }
 
     #[test]
-    fn ssh_args_missing_key() {
-        let cfg = BackupHost {
-            key: PathBuf::from("/nosuch"),
-            ..BackupHost::default()
-        };
-        assert!(cfg.ssh_args("/usr/bin/ssh", "/tmp").is_none());
+    fn bwlimit_window_wraps_around_midnight() {
+        let night = window("22:00", "06:00", 0);
+        assert!(night.contains(time(22, 0)));
+        assert!(night.contains(time(23, 59)));
+        assert!(night.contains(time(0, 0)));
+        assert!(night.contains(time(5, 59)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+        assert!(!night.contains(time(21, 59)));
+
+        assert!(window("00:00", "00:00", 0).contains(time(13, 0)));
+        assert!(!window("25:00", "06:00", 0).contains(time(1, 0)));
     }
 
     #[test]
-    fn ssh_args_no_port() {
-        let dir = TempDir::new("sshkey").unwrap();
-        let keyfile = dir.path().join("keyfile");
-        let _ = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&keyfile);
-
-        let cfg = BackupHost {
-            key: keyfile.clone(),
+    fn bwlimit_at_falls_back_to_bwlimit() {
+        let mut host = BackupHost {
+            bwlimit_schedule: vec![
+                window("08:00", "18:00", 500),
+                window("07:00", "20:00", 2000),
+            ],
             ..BackupHost::default()
         };
-        let expected = vec![
-            OsString::from("/opt/bin/ssh"),
-            OsString::from("-a"),
-            OsString::from("-x"),
-            OsString::from("-oIdentitiesOnly=true"),
-            OsString::from("-i"),
-            keyfile.as_os_str().to_os_string(),
-        ];
-        assert_eq!(cfg.ssh_args("/opt/bin/ssh", "/tmp").unwrap(), expected);
+        assert_eq!(host.bwlimit_at(time(9, 0)), Some(500));
+        assert_eq!(host.bwlimit_at(time(19, 0)), Some(2000));
+        assert_eq!(host.bwlimit_at(time(23, 0)), None);
+
+        host.bwlimit = Some(10000);
+        assert_eq!(host.bwlimit_at(time(9, 0)), Some(500));
+        assert_eq!(host.bwlimit_at(time(23, 0)), Some(10000));
     }
 
     #[test]
-    fn ssh_args_zero_port() {
-        let dir = TempDir::new("sshkey").unwrap();
-        let keyfile = dir.path().join("keyfile");
-        let _ = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&keyfile);
-
-        let cfg = BackupHost {
-            key: keyfile.clone(),
-            port: Some(0),
+    fn bwlimit_schedule_is_validated() {
+        let mut host = BackupHost {
+            bwlimit_schedule: vec![window("22:00", "06:00", 0)],
             ..BackupHost::default()
         };
-        let expected = vec![
-            OsString::from("/opt/bin/ssh"),
-            OsString::from("-a"),
-            OsString::from("-x"),
-            OsString::from("-oIdentitiesOnly=true"),
-            OsString::from("-i"),
-            keyfile.as_os_str().to_os_string(),
-        ];
-        assert_eq!(cfg.ssh_args("/opt/bin/ssh", "/tmp").unwrap(), expected);
+        assert!(host.check_options().is_ok());
+
+        host.bwlimit_schedule.push(window("8am", "18:00", 500));
+        let err = host.check_options().unwrap_err().to_string();
+        assert!(err.contains("bwlimit_schedule"), "{}", err);
     }
 
     #[test]
-    fn ssh_args_nonzero_port() {
-        let dir = TempDir::new("sshkey").unwrap();
-        let keyfile = dir.path().join("keyfile");
-        let _ = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&keyfile);
+    fn partial_dir_is_validated() {
+        for partial_dir in [".rsync-partial", "partial", ".~partial~"] {
+            let cfg = BackupHost {
+                partial_dir: Some(partial_dir.to_string()),
+                ..BackupHost::default()
+            };
+            assert!(cfg.check_options().is_ok(), "{} rejected", partial_dir);
+        }
+        for partial_dir in ["", ".", "..", "/tmp/partial", "a/b", "../partial"] {
+            let cfg = BackupHost {
+                partial_dir: Some(partial_dir.to_string()),
+                ..BackupHost::default()
+            };
+            assert!(cfg.check_options().is_err(), "{} accepted", partial_dir);
+        }
+    }
 
-        let cfg = BackupHost {
-            key: keyfile.clone(),
-            port: Some(2221),
-            ..BackupHost::default()
+    #[test]
+    fn compress_is_validated() {
+        for compress in ["none", "zlib", "zstd", "lz4"] {
+            let cfg = BackupHost {
+                compress: Some(compress.to_string()),
+                ..BackupHost::default()
+            };
+            assert!(cfg.check_options().is_ok(), "{} rejected", compress);
+        }
+        for compress in ["", "gzip", "ZSTD", "zstd lz4"] {
+            let cfg = BackupHost {
+                compress: Some(compress.to_string()),
+                ..BackupHost::default()
+            };
+            assert!(cfg.check_options().is_err(), "{} accepted", compress);
+        }
+    }
+
+    #[test]
+    fn backup_dest_missing_is_not_writable() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let source = BackupSource {
+            path: PathBuf::from("/opt/backups"),
+            ..BackupSource::default()
         };
-        let expected = vec![
-            OsString::from("/opt/bin/ssh"),
-            OsString::from("-a"),
-            OsString::from("-x"),
-            OsString::from("-oIdentitiesOnly=true"),
-            OsString::from("-i"),
-            keyfile.as_os_str().to_os_string(),
-            OsString::from("-p"),
-            OsString::from("2221"),
-        ];
-        assert_eq!(cfg.ssh_args("/opt/bin/ssh", "/tmp").unwrap(), expected);
+        let dest = BackupDest::new(dir.path(), "host1.example.com", &source);
+        assert!(matches!(
+            dest.check_writable(),
+            Err(DoppelbackError::MissingDir(_))
+        ));
+
+        fs::create_dir_all(dest.backup_dir()).unwrap();
+        assert!(dest.check_writable().is_ok());
     }
 
     #[test]
@@ -423,6 +2824,302 @@ mod tests {
         );
     }
 
+    #[test]
+    fn max_size_defaults_to_valid() {
+        let source = BackupSource::default();
+        assert!(source.check_options().is_ok());
+    }
+
+    #[test]
+    fn max_size_accepts_suffixes() {
+        for size in ["100", "500K", "20M", "10G", "2T", "3g"] {
+            let source = BackupSource {
+                max_size: Some(size.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_ok(), "{} rejected", size);
+        }
+    }
+
+    #[test]
+    fn max_size_rejects_garbage() {
+        for size in ["", "G", "10X", "10 G", "-5", "10GG"] {
+            let source = BackupSource {
+                max_size: Some(size.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_err(), "{} accepted", size);
+        }
+    }
+
+    #[test]
+    fn option_errors_name_field_and_host() {
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            max_size: Some(String::from("10X")),
+            ..BackupSource::default()
+        };
+        let err = source.check_options().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: max_size 10X for /etc is not a valid rsync size"
+        );
+        assert_eq!(
+            err.for_host("host1").to_string(),
+            "invalid config for host1: max_size 10X for /etc is not a valid rsync size"
+        );
+
+        let host = BackupHost {
+            ssh_options: vec![String::from("-oProxyCommand=nc")],
+            ..BackupHost::default()
+        };
+        match host.check_options().unwrap_err().for_host("host1") {
+            DoppelbackError::ConfigError {
+                host: Some(host),
+                field,
+                ..
+            } => {
+                assert_eq!(host, "host1");
+                assert_eq!(field, "ssh_options");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extra_rsync_args_are_checked() {
+        let mut source = BackupSource {
+            extra_rsync_args: vec![String::from("--checksum")],
+            ..BackupSource::default()
+        };
+        assert!(source.check_options().is_ok());
+
+        source
+            .extra_rsync_args
+            .push(String::from("--remove-source-files"));
+        assert!(source.check_options().is_err());
+    }
+
+    #[test]
+    fn chmod_accepts_rsync_specs() {
+        for chmod in [
+            "D0755,F0644",
+            "755",
+            "Dg+s,ug+w,Fo-w,+X",
+            "a=rX",
+            "u=rwx,go=",
+        ] {
+            let source = BackupSource {
+                chmod: Some(chmod.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_ok(), "{} rejected", chmod);
+        }
+    }
+
+    #[test]
+    fn filter_file_must_be_absolute() {
+        let mut source = BackupSource {
+            filter_file: Some(PathBuf::from("/etc/doppelback/home.filter")),
+            ..BackupSource::default()
+        };
+        assert!(source.check_options().is_ok());
+
+        source.filter_file = Some(PathBuf::from("home.filter"));
+        assert!(source.check_options().is_err());
+    }
+
+    #[test]
+    fn chmod_rejects_malformed_specs() {
+        for chmod in [
+            "", "D0755,", "0888", "D07555", "Fu+q", "x+r", "DF0644", "u+r g+w", "0644;id",
+        ] {
+            let source = BackupSource {
+                chmod: Some(chmod.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_err(), "{} accepted", chmod);
+        }
+    }
+
+    #[test]
+    fn delete_mode_is_validated() {
+        for delete_mode in ["during", "after", "before", "none"] {
+            let source = BackupSource {
+                delete_mode: Some(delete_mode.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_ok(), "{} rejected", delete_mode);
+        }
+        for delete_mode in ["", "After", "delay", "after before"] {
+            let source = BackupSource {
+                delete_mode: Some(delete_mode.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_err(), "{} accepted", delete_mode);
+        }
+    }
+
+    #[test]
+    fn include_only_needs_includes() {
+        let source = BackupSource {
+            path: PathBuf::from("/home"),
+            include_only: true,
+            ..BackupSource::default()
+        };
+        assert_eq!(
+            source.check_options().unwrap_err().to_string(),
+            "invalid config: include_only for /home needs at least one pattern in includes"
+        );
+
+        let source = BackupSource {
+            includes: vec![String::from("/user/***")],
+            ..source
+        };
+        assert!(source.check_options().is_ok());
+    }
+
+    #[test]
+    fn priorities_are_validated() {
+        for (nice, ionice_class) in [(Some(-20), Some(0)), (Some(19), Some(3)), (None, None)] {
+            let source = BackupSource {
+                nice,
+                ionice_class,
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_ok());
+        }
+
+        let source = BackupSource {
+            path: PathBuf::from("/srv"),
+            nice: Some(20),
+            ..BackupSource::default()
+        };
+        assert_eq!(
+            source.check_options().unwrap_err().to_string(),
+            "invalid config: nice 20 for /srv is not between -20 and 19"
+        );
+        let source = BackupSource {
+            path: PathBuf::from("/srv"),
+            ionice_class: Some(4),
+            ..BackupSource::default()
+        };
+        assert_eq!(
+            source.check_options().unwrap_err().to_string(),
+            "invalid config: ionice_class 4 for /srv is not between 0 and 3"
+        );
+    }
+
+    #[test]
+    fn rsync_path_accepts_paths_and_wrappers() {
+        for rsync_path in [
+            "/usr/local/bin/rsync",
+            "sudo doppelback sudo -- rsync",
+            "/usr/bin/sudo /usr/bin/rsync",
+        ] {
+            let source = BackupSource {
+                rsync_path: Some(rsync_path.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_ok(), "{} rejected", rsync_path);
+        }
+    }
+
+    #[test]
+    fn rsync_path_rejects_shell_syntax() {
+        for rsync_path in [
+            "",
+            "rsync",
+            "bash -c rsync",
+            "/usr/bin/rsync; rm -rf /",
+            "/usr/bin/rsync $(id)",
+            "/usr/bin/rsync  --foo",
+            "sudo rsync|tee",
+            "/usr/bin/rsync\t-v",
+        ] {
+            let source = BackupSource {
+                rsync_path: Some(rsync_path.to_string()),
+                ..BackupSource::default()
+            };
+            assert!(source.check_options().is_err(), "{} accepted", rsync_path);
+        }
+    }
+
+    #[test]
+    fn create_backup_dir_makes_parents() {
+        let root = TempDir::new("dest").unwrap();
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        let dest = BackupDest::new(root.path(), "host1", &source);
+
+        dest.create_backup_dir(root.path(), None).unwrap();
+        assert!(dest.backup_dir().is_dir());
+        // Existing directories are fine.
+        dest.create_backup_dir(root.path(), None).unwrap();
+    }
+
+    #[test]
+    fn create_backup_dir_sets_mode() {
+        let root = TempDir::new("dest").unwrap();
+        fs::create_dir(root.path().join("live")).unwrap();
+        fs::set_permissions(root.path().join("live"), fs::Permissions::from_mode(0o755)).unwrap();
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        let dest = BackupDest::new(root.path(), "host1", &source);
+
+        dest.create_backup_dir(root.path(), Some(0o750)).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(dest.backup_dir()), 0o750);
+        assert_eq!(mode(&root.path().join("live/host1")), 0o750);
+        // Only directories created by doppelback are changed.
+        assert_eq!(mode(&root.path().join("live")), 0o755);
+    }
+
+    #[test]
+    fn dest_mode_is_octal() {
+        let dir = TempDir::new("config").unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            "snapshots: /snapshots\nhosts: {}\ndest_mode: 0o750\n",
+        )
+        .unwrap();
+        assert_eq!(Config::load(&path).unwrap().dest_mode, Some(0o750));
+
+        fs::write(
+            &path,
+            "snapshots: /snapshots\nhosts: {}\ndest_mode: 0o17777\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Config::load(&path).unwrap_err().to_string(),
+            "invalid config: dest_mode 17777 is not a valid permission mode"
+        );
+    }
+
+    #[test]
+    fn create_backup_dir_rejects_symlinks() {
+        let root = TempDir::new("dest").unwrap();
+        let elsewhere = TempDir::new("elsewhere").unwrap();
+        let source = BackupSource {
+            path: PathBuf::from("/etc"),
+            ..BackupSource::default()
+        };
+        fs::create_dir(root.path().join("live")).unwrap();
+        std::os::unix::fs::symlink(elsewhere.path(), root.path().join("live/host1")).unwrap();
+
+        let dest = BackupDest::new(root.path(), "host1", &source);
+        assert!(matches!(
+            dest.create_backup_dir(root.path(), None),
+            Err(DoppelbackError::InvalidPath(p)) if p == root.path().join("live/host1")
+        ));
+        assert!(!elsewhere.path().join("etc").exists());
+    }
+
     #[test]
     fn backup_dest_main_dir() {
         let source = BackupSource {
@@ -436,6 +3133,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn backup_dest_in_snapshot() {
+        let source = BackupSource {
+            path: PathBuf::from("/opt/backups.dir"),
+            ..BackupSource::default()
+        };
+        let dest = BackupDest::for_snapshot("/snapshots", "20210704.00", "host1", &source);
+        assert_eq!(
+            dest.backup_dir(),
+            Path::new("/snapshots/20210704.00/host1/opt_backups_dir")
+        );
+    }
+
     #[test]
     fn backup_dest_companion_file() {
         let source = BackupSource {
@@ -0,0 +1,709 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::config::{BackupHost, BackupSource, Config};
+use crate::rsync_util;
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Number of entries in a source directory that `check_source_access` stats.
+const SAMPLE_ENTRIES: usize = 20;
+
+/// Results of `config-test --type host`, shared by the text and JSON output.
+#[derive(Debug, Default, Serialize)]
+pub struct HostTestReport {
+    pub snapshots: PathBuf,
+
+    /// Hosts that keep their snapshots somewhere other than `snapshots`.
+    pub snapshot_overrides: BTreeMap<String, PathBuf>,
+
+    pub rsync: PathBuf,
+
+    /// `None` if rsync's version couldn't be parsed from its output.
+    pub rsync_version: Option<(u32, u32, u32)>,
+
+    /// Set if running rsync to get its version failed.
+    pub rsync_error: Option<String>,
+
+    /// Options that the local rsync is too old for.  These don't fail the check.
+    pub rsync_warnings: Vec<String>,
+
+    /// Every host that was checked, sorted by name.
+    pub hosts: Vec<HostResult>,
+
+    /// Problems found by --strict.
+    pub strict_problems: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HostResult {
+    pub host: String,
+    pub user: String,
+    pub port: Option<u16>,
+    pub user_valid: bool,
+
+    /// The configured ssh keys that were found, in the order they will be tried.
+    pub ssh_keys: Vec<PathBuf>,
+
+    pub known_hosts: Option<PathBuf>,
+
+    /// Why the host can't be backed up.  Its sources aren't checked if this is set.
+    pub error: Option<String>,
+
+    pub sources: Vec<SourceResult>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SourceResult {
+    pub path: PathBuf,
+    pub status: SourceStatus,
+
+    /// Why the check failed.
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceStatus {
+    Ok,
+    Disabled,
+    Failed,
+}
+
+impl HostTestReport {
+    /// Returns whether every host and source passed and --strict found nothing.
+    pub fn ok(&self) -> bool {
+        self.rsync_error.is_none()
+            && self.strict_problems.is_empty()
+            && self.hosts.iter().all(|host| {
+                host.error.is_none()
+                    && host
+                        .sources
+                        .iter()
+                        .all(|source| source.status != SourceStatus::Failed)
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::to_value(self).expect("report is always serializable");
+        json["ok"] = serde_json::Value::from(self.ok());
+        json.to_string()
+    }
+
+    pub fn print_text(&self) {
+        println!("Saving snapshots into {}", self.snapshots.display());
+        for (host, root) in &self.snapshot_overrides {
+            println!("  {} saves snapshots into {}", host, root.display());
+        }
+
+        match (&self.rsync_error, self.rsync_version) {
+            (Some(e), _) => println!("Failed to get rsync version: {}", e),
+            (None, Some(version)) => {
+                let (major, minor, patch) = version;
+                println!(
+                    "Using rsync {} version {}.{}.{}",
+                    self.rsync.display(),
+                    major,
+                    minor,
+                    patch
+                );
+                if version < rsync_util::MIN_RSYNC_VERSION {
+                    let (major, minor, patch) = rsync_util::MIN_RSYNC_VERSION;
+                    println!(
+                        "  Warning: options like --preallocate need rsync {}.{}.{}",
+                        major, minor, patch
+                    );
+                }
+            }
+            (None, None) => println!("Using rsync {} of unknown version", self.rsync.display()),
+        }
+        for warning in &self.rsync_warnings {
+            println!("  Warning: {}", warning);
+        }
+
+        for host in &self.hosts {
+            println!("Checking {}", host.host);
+            if !host.user_valid {
+                println!("  Invalid user for {}", host.host);
+                continue;
+            }
+            for sshkey in &host.ssh_keys {
+                println!("  Using ssh key {}", sshkey.display());
+            }
+            if let Some(error) = &host.error {
+                println!("  {}", error);
+                continue;
+            }
+            if let Some(known_hosts) = &host.known_hosts {
+                println!("  Using known_hosts {}", known_hosts.display());
+            }
+
+            let port_str = match host.port {
+                Some(p) => format!(" (port {})", p),
+                None => String::new(),
+            };
+            println!(
+                "  Backup sources for {}@{}{}:",
+                host.user, host.host, port_str
+            );
+            for source in &host.sources {
+                match source.status {
+                    SourceStatus::Ok => println!("    {}: OK", source.path.display()),
+                    SourceStatus::Disabled => println!("    {}: Disabled", source.path.display()),
+                    SourceStatus::Failed => println!(
+                        "    {}: Failed: {}",
+                        source.path.display(),
+                        source.message.as_deref().unwrap_or_default()
+                    ),
+                }
+            }
+        }
+
+        let unusable: Vec<_> = self
+            .hosts
+            .iter()
+            .filter_map(|host| Some((&host.host, host.error.as_ref()?)))
+            .collect();
+        if !unusable.is_empty() {
+            println!("\nUnusable backups:");
+            for (host, reason) in unusable {
+                println!("  {}: {}", host, reason);
+            }
+        }
+        if !self.strict_problems.is_empty() {
+            println!("\nStrict check problems:");
+            for problem in &self.strict_problems {
+                println!("  {}", problem);
+            }
+        }
+    }
+}
+
+/// Returns a warning for each source that sets `crtimes` if `rsync_version` is known to be too
+/// old for `--crtimes`.  Sources are listed by host.
+pub fn crtimes_warnings(config: &Config, rsync_version: Option<(u32, u32, u32)>) -> Vec<String> {
+    let version = match rsync_version {
+        Some(version) if version < rsync_util::MIN_CRTIMES_VERSION => version,
+        _ => return Vec::new(),
+    };
+    let (major, minor, patch) = version;
+    let (min_major, min_minor, min_patch) = rsync_util::MIN_CRTIMES_VERSION;
+
+    let mut hosts: Vec<_> = config.hosts.iter().collect();
+    hosts.sort_by_key(|(host, _)| host.as_str());
+    hosts
+        .into_iter()
+        .flat_map(|(host, host_config)| {
+            host_config
+                .sources
+                .iter()
+                .filter(|source| source.crtimes)
+                .map(move |source| {
+                    format!(
+                        "{}:{} sets crtimes, but rsync {}.{}.{} doesn't support --crtimes; it needs {}.{}.{}",
+                        host,
+                        source.path.display(),
+                        major,
+                        minor,
+                        patch,
+                        min_major,
+                        min_minor,
+                        min_patch
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Checks the hosts in `config`, or only `only_host` if given, and returns the results sorted by
+/// host.
+///
+/// Each host's user, ssh keys, options, sources and known_hosts file are checked locally.  If all
+/// of those pass, `check_remote` is called for every enabled source to check it on the host
+/// itself.
+pub fn check_hosts<F>(
+    config: &Config,
+    only_host: Option<&str>,
+    home_dir: &OsStr,
+    check_remote: F,
+) -> Vec<HostResult>
+where
+    F: Fn(&str, &BackupHost, &BackupSource) -> Result<(), String>,
+{
+    let mut hosts: Vec<_> = config
+        .hosts
+        .iter()
+        .filter(|(host, _)| only_host.is_none() || only_host == Some(host.as_str()))
+        .collect();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    hosts
+        .into_iter()
+        .map(|(host, host_config)| check_host(host, host_config, home_dir, &check_remote))
+        .collect()
+}
+
+fn check_host<F>(
+    host: &str,
+    host_config: &BackupHost,
+    home_dir: &OsStr,
+    check_remote: &F,
+) -> HostResult
+where
+    F: Fn(&str, &BackupHost, &BackupSource) -> Result<(), String>,
+{
+    let mut result = HostResult {
+        host: host.to_string(),
+        user: host_config.user.clone(),
+        port: host_config.port,
+        user_valid: host_config.is_user_valid(),
+        ssh_keys: Vec::new(),
+        known_hosts: None,
+        error: None,
+        sources: Vec::new(),
+    };
+    if !result.user_valid {
+        result.error = Some(format!("Invalid user {}", host_config.user));
+        return result;
+    }
+
+    result.ssh_keys = host_config.find_ssh_keys(home_dir);
+    if result.ssh_keys.is_empty() {
+        result.error = Some(host_config.missing_key_error().to_string());
+        return result;
+    }
+    if let Err(e) = host_config.check_options() {
+        result.error = Some(e.to_string());
+        return result;
+    }
+    if let Some((outer, inner)) = host_config.find_overlapping_sources() {
+        result.error = Some(format!(
+            "source {} overlaps source {}",
+            inner.display(),
+            outer.display()
+        ));
+        return result;
+    }
+    if host_config.known_hosts.is_some() {
+        if let Err(e) = host_config.check_known_hosts(host) {
+            result.error = Some(e.to_string());
+            return result;
+        }
+        result.known_hosts = host_config.known_hosts.clone();
+    }
+
+    result.sources = host_config
+        .sources
+        .iter()
+        .map(|source| {
+            let (status, message) = match check_source(host, host_config, source, check_remote) {
+                Ok(status) => (status, None),
+                Err(message) => (SourceStatus::Failed, Some(message)),
+            };
+            SourceResult {
+                path: source.path.clone(),
+                status,
+                message,
+            }
+        })
+        .collect();
+    result
+}
+
+fn check_source<F>(
+    host: &str,
+    host_config: &BackupHost,
+    source: &BackupSource,
+    check_remote: &F,
+) -> Result<SourceStatus, String>
+where
+    F: Fn(&str, &BackupHost, &BackupSource) -> Result<(), String>,
+{
+    source.check_options().map_err(|e| e.to_string())?;
+    if let Some(filter_file) = &source.filter_file {
+        if !filter_file.is_file() {
+            return Err(format!("filter_file {} not found", filter_file.display()));
+        }
+    }
+    if source.disabled {
+        return Ok(SourceStatus::Disabled);
+    }
+    check_remote(host, host_config, source)?;
+    Ok(SourceStatus::Ok)
+}
+
+/// Checks that `source`'s path is a directory this process can list, and that a sample of its
+/// entries can be examined.  This is the check behind `config-test --type=source`.
+///
+/// Returns a note to show alongside the result, if any.
+pub fn check_source_access(source: &BackupSource) -> Result<Option<String>, String> {
+    let path = &source.path;
+    if !path.is_dir() {
+        return Err(format!("Source path {} is not a directory", path.display()));
+    }
+
+    let entries = fs::read_dir(path).map_err(|e| access_error(source, path, e))?;
+    for entry in entries.take(SAMPLE_ENTRIES) {
+        let entry = entry.map_err(|e| access_error(source, path, e))?;
+        entry
+            .metadata()
+            .map_err(|e| access_error(source, &entry.path(), e))?;
+    }
+
+    Ok(source.root.then(|| {
+        format!(
+            "{} has root: true, so backups read it as root",
+            path.display()
+        )
+    }))
+}
+
+/// Describes a failure to read `path` in `source`, calling out permission problems.
+fn access_error(source: &BackupSource, path: &Path, e: io::Error) -> String {
+    if e.kind() != io::ErrorKind::PermissionDenied {
+        return format!("Failed to read {}: {}", path.display(), e);
+    }
+    if source.root {
+        format!(
+            "Permission denied reading {}; the source has root: true, so it must be read as root",
+            path.display()
+        )
+    } else {
+        format!(
+            "Permission denied reading {}; set root: true if the backup needs root to read it",
+            path.display()
+        )
+    }
+}
+
+/// Runs `doppelback config-test --type=source` on `host` over ssh to check that `source` exists
+/// there and is listed in the host's own config.
+pub fn check_remote_source(
+    ssh: &Path,
+    home_dir: &OsStr,
+    host: &str,
+    host_config: &BackupHost,
+    source: &BackupSource,
+) -> Result<(), String> {
+    let command = remote_source_command(ssh, home_dir, host, host_config, source)?;
+    run_remote_command(&command).map(|_| ())
+}
+
+/// Returns the ssh command line that runs `doppelback config-test --type=source` for `source` on
+/// `host`, or on the source's own `source_host` if it has one.
+pub fn remote_source_command(
+    ssh: &Path,
+    home_dir: &OsStr,
+    host: &str,
+    host_config: &BackupHost,
+    source: &BackupSource,
+) -> Result<Vec<OsString>, String> {
+    let mut remote_cmd = host_config
+        .source_ssh_args(ssh, home_dir, host, source)
+        .map_err(|e| e.for_host(host).to_string())?;
+    remote_cmd.push(OsString::from(
+        host_config.source_ssh_destination(host, source),
+    ));
+    remote_cmd.push(OsString::from("doppelback"));
+    remote_cmd.push(OsString::from("config-test"));
+    remote_cmd.push(OsString::from("--type=source"));
+    remote_cmd.push(OsString::from("--source"));
+    remote_cmd.push(source.path.as_os_str().to_os_string());
+    Ok(remote_cmd)
+}
+
+/// Runs a remote `command` built by `remote_source_command` and returns its stdout.  If it fails,
+/// the error holds everything it printed.
+pub fn run_remote_command(command: &[OsString]) -> Result<String, String> {
+    let output = process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir("/")
+        .output()
+        .map_err(|e| format!("Failed to run ssh: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout).trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn source(path: &str, disabled: bool) -> BackupSource {
+        BackupSource {
+            path: PathBuf::from(path),
+            disabled,
+            ..BackupSource::default()
+        }
+    }
+
+    #[test]
+    fn crtimes_needs_new_rsync() {
+        let mut hosts = HashMap::new();
+        for host in ["host2", "host1"] {
+            hosts.insert(
+                String::from(host),
+                BackupHost {
+                    sources: vec![
+                        BackupSource {
+                            crtimes: true,
+                            ..source("/home", false)
+                        },
+                        source("/etc", false),
+                    ],
+                    ..BackupHost::default()
+                },
+            );
+        }
+        let config = Config {
+            hosts,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            crtimes_warnings(&config, Some((3, 1, 3))),
+            vec![
+                "host1:/home sets crtimes, but rsync 3.1.3 doesn't support --crtimes; it needs 3.2.0",
+                "host2:/home sets crtimes, but rsync 3.1.3 doesn't support --crtimes; it needs 3.2.0",
+            ]
+        );
+        assert!(crtimes_warnings(&config, Some((3, 2, 0))).is_empty());
+        assert!(crtimes_warnings(&config, None).is_empty());
+    }
+
+    #[test]
+    fn readable_source_passes_access_check() {
+        let dir = TempDir::new("source").unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        fs::create_dir(dir.path().join("subdir")).unwrap();
+
+        let mut readable = source(dir.path().to_str().unwrap(), false);
+        assert_eq!(check_source_access(&readable), Ok(None));
+
+        readable.root = true;
+        assert_eq!(
+            check_source_access(&readable),
+            Ok(Some(format!(
+                "{} has root: true, so backups read it as root",
+                dir.path().display()
+            )))
+        );
+
+        let file = source(dir.path().join("file").to_str().unwrap(), false);
+        assert!(check_source_access(&file)
+            .unwrap_err()
+            .ends_with("is not a directory"));
+    }
+
+    #[test]
+    fn unreadable_source_fails_access_check() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("source").unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let result = check_source_access(&source(locked.to_str().unwrap(), false));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // root can read the directory anyway.
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!(result, Ok(None));
+        } else {
+            assert_eq!(
+                result.unwrap_err(),
+                format!(
+                    "Permission denied reading {}; set root: true if the backup needs root to \
+                     read it",
+                    locked.display()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn permission_errors_are_described() {
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+        let mut srv = source("/srv", false);
+        assert_eq!(
+            access_error(&srv, Path::new("/srv"), denied()),
+            "Permission denied reading /srv; set root: true if the backup needs root to read it"
+        );
+
+        srv.root = true;
+        assert_eq!(
+            access_error(&srv, Path::new("/srv/data"), denied()),
+            "Permission denied reading /srv/data; the source has root: true, so it must be read \
+             as root"
+        );
+
+        assert_eq!(
+            access_error(&srv, Path::new("/srv"), io::Error::other("I/O error")),
+            "Failed to read /srv: I/O error"
+        );
+    }
+
+    #[test]
+    fn remote_source_command_runs_config_test() {
+        let home = TempDir::new("home").unwrap();
+        fs::create_dir(home.path().join(".ssh")).unwrap();
+        fs::write(home.path().join(".ssh/id_host1"), "").unwrap();
+        let host_config = BackupHost {
+            user: String::from("backup"),
+            address: Some(String::from("192.0.2.7")),
+            key: PathBuf::from("id_host1"),
+            ..BackupHost::default()
+        };
+
+        let command = remote_source_command(
+            Path::new("/usr/bin/ssh"),
+            home.path().as_os_str(),
+            "host1",
+            &host_config,
+            &source("/etc", false),
+        )
+        .unwrap();
+        assert_eq!(command[0], "/usr/bin/ssh");
+        let tail: Vec<_> = command[command.len() - 6..].to_vec();
+        assert_eq!(
+            tail,
+            [
+                "backup@192.0.2.7",
+                "doppelback",
+                "config-test",
+                "--type=source",
+                "--source",
+                "/etc"
+            ]
+        );
+    }
+
+    #[test]
+    fn hosts_are_checked_with_stubbed_remote() {
+        let home = TempDir::new("home").unwrap();
+        fs::create_dir(home.path().join(".ssh")).unwrap();
+        fs::write(home.path().join(".ssh/id_host1"), "").unwrap();
+
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            String::from("host1"),
+            BackupHost {
+                user: String::from("backup"),
+                port: Some(2234),
+                key: PathBuf::from("id_host1"),
+                sources: vec![
+                    source("/etc", false),
+                    source("/srv", true),
+                    source("/var", false),
+                ],
+                ..BackupHost::default()
+            },
+        );
+        hosts.insert(
+            String::from("host2"),
+            BackupHost {
+                user: String::from("backup"),
+                key: PathBuf::from("id_missing"),
+                sources: vec![source("/etc", false)],
+                ..BackupHost::default()
+            },
+        );
+        hosts.insert(
+            String::from("host3"),
+            BackupHost {
+                user: String::from("root"),
+                ..BackupHost::default()
+            },
+        );
+        let config = Config {
+            hosts,
+            ..Config::default()
+        };
+
+        let results = check_hosts(&config, None, home.path().as_os_str(), |host, _, source| {
+            assert_eq!(host, "host1");
+            if source.path == Path::new("/var") {
+                Err(String::from("Source path /var is not a directory"))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(
+            results[0],
+            HostResult {
+                host: String::from("host1"),
+                user: String::from("backup"),
+                port: Some(2234),
+                user_valid: true,
+                ssh_keys: vec![home.path().join(".ssh/id_host1")],
+                known_hosts: None,
+                error: None,
+                sources: vec![
+                    SourceResult {
+                        path: PathBuf::from("/etc"),
+                        status: SourceStatus::Ok,
+                        message: None,
+                    },
+                    SourceResult {
+                        path: PathBuf::from("/srv"),
+                        status: SourceStatus::Disabled,
+                        message: None,
+                    },
+                    SourceResult {
+                        path: PathBuf::from("/var"),
+                        status: SourceStatus::Failed,
+                        message: Some(String::from("Source path /var is not a directory")),
+                    },
+                ],
+            }
+        );
+        assert_eq!(results[1].host, "host2");
+        assert!(results[1].user_valid);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("invalid config: ssh key id_missing not found")
+        );
+        assert!(results[1].sources.is_empty());
+        assert_eq!(results[2].host, "host3");
+        assert!(!results[2].user_valid);
+
+        let only_host1 = check_hosts(
+            &config,
+            Some("host1"),
+            home.path().as_os_str(),
+            |_, _, _| Ok(()),
+        );
+        assert_eq!(only_host1.len(), 1);
+
+        let mut report = HostTestReport {
+            hosts: only_host1,
+            ..HostTestReport::default()
+        };
+        assert!(report.ok());
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["ok"], true);
+        assert_eq!(json["hosts"][0]["sources"][1]["status"], "disabled");
+
+        report.hosts = results;
+        assert!(!report.ok());
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["hosts"][0]["sources"][2]["status"], "failed");
+        assert_eq!(json["hosts"][2]["user_valid"], false);
+    }
+}
@@ -1,20 +1,44 @@
 // Copyright 2021 Benjamin Gordon
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::rsync_util;
 use std::error;
 use std::fmt::{self, Display};
 use std::io;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum DoppelbackError {
     IoError(io::Error),
     ParseError(serde_yaml::Error),
     InvalidConfig(String),
+    /// A problem with one field of the config, and the host it belongs to if known.
+    ConfigError {
+        host: Option<String>,
+        field: String,
+        message: String,
+    },
     MissingDir(PathBuf),
+    NotSubvolume(PathBuf),
     InvalidPath(PathBuf),
     CommandFailed(PathBuf, process::ExitStatus),
+    Locked(PathBuf),
+    RsyncFailed(process::ExitStatus),
+    /// ssh couldn't connect to the named host.
+    HostUnreachable(String),
+    InsufficientSpace {
+        available: u64,
+        required: u64,
+    },
+    Timeout(String, Duration),
+    /// A transfer stayed below the minimum rate in bytes per second for the given time.
+    Stalled {
+        what: String,
+        min_rate: u64,
+        duration: Duration,
+    },
 }
 
 impl Display for DoppelbackError {
@@ -23,7 +47,22 @@ impl Display for DoppelbackError {
             DoppelbackError::IoError(e) => write!(f, "{}", e),
             DoppelbackError::ParseError(e) => write!(f, "failed to parse config file: {}", e),
             DoppelbackError::InvalidConfig(s) => write!(f, "invalid config: {}", s),
+            DoppelbackError::ConfigError {
+                host,
+                field,
+                message,
+            } => match host {
+                Some(host) => write!(f, "invalid config for {}: {} {}", host, field, message),
+                None => write!(f, "invalid config: {} {}", field, message),
+            },
             DoppelbackError::MissingDir(d) => write!(f, "{} is not a directory", d.display()),
+            DoppelbackError::NotSubvolume(d) => write!(
+                f,
+                "{} is not a btrfs subvolume; move its contents aside and create it with \
+                 `btrfs subvolume create {}`",
+                d.display(),
+                d.display()
+            ),
             DoppelbackError::InvalidPath(d) => write!(f, "{} is not a valid path", d.display()),
             DoppelbackError::CommandFailed(c, s) => write!(
                 f,
@@ -31,6 +70,43 @@ impl Display for DoppelbackError {
                 c.display(),
                 s.code().unwrap_or(-1)
             ),
+            DoppelbackError::Locked(p) => {
+                write!(f, "{} is locked by another doppelback process", p.display())
+            }
+            DoppelbackError::RsyncFailed(s) => match s.code() {
+                Some(code) => write!(
+                    f,
+                    "rsync failed with exit status {} ({})",
+                    code,
+                    rsync_util::describe_exit_code(code)
+                ),
+                None => write!(f, "rsync was killed by a signal"),
+            },
+            DoppelbackError::HostUnreachable(host) => {
+                write!(f, "{} is unreachable: ssh connection failed", host)
+            }
+            DoppelbackError::InsufficientSpace {
+                available,
+                required,
+            } => write!(
+                f,
+                "only {} bytes free in snapshots filesystem, {} required",
+                available, required
+            ),
+            DoppelbackError::Timeout(what, t) => {
+                write!(f, "{} timed out after {}s", what, t.as_secs())
+            }
+            DoppelbackError::Stalled {
+                what,
+                min_rate,
+                duration,
+            } => write!(
+                f,
+                "{} stalled below {} bytes/s for {}s",
+                what,
+                min_rate,
+                duration.as_secs()
+            ),
         }
     }
 }
@@ -41,9 +117,45 @@ impl error::Error for DoppelbackError {
             DoppelbackError::IoError(e) => Some(e),
             DoppelbackError::ParseError(e) => Some(e),
             DoppelbackError::InvalidConfig(_) => None,
+            DoppelbackError::ConfigError { .. } => None,
             DoppelbackError::MissingDir(_) => None,
+            DoppelbackError::NotSubvolume(_) => None,
             DoppelbackError::InvalidPath(_) => None,
             DoppelbackError::CommandFailed(_, _) => None,
+            DoppelbackError::Locked(_) => None,
+            DoppelbackError::RsyncFailed(_) => None,
+            DoppelbackError::HostUnreachable(_) => None,
+            DoppelbackError::InsufficientSpace { .. } => None,
+            DoppelbackError::Timeout(_, _) => None,
+            DoppelbackError::Stalled { .. } => None,
+        }
+    }
+}
+
+impl DoppelbackError {
+    /// Creates a `ConfigError` for `field` that isn't tied to a host yet.
+    pub fn config_field<S: Into<String>>(field: &str, message: S) -> Self {
+        DoppelbackError::ConfigError {
+            host: None,
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Attaches `host` to a `ConfigError` that doesn't name one yet.  Other errors are returned
+    /// unchanged.
+    pub fn for_host(self, host: &str) -> Self {
+        match self {
+            DoppelbackError::ConfigError {
+                host: None,
+                field,
+                message,
+            } => DoppelbackError::ConfigError {
+                host: Some(host.to_string()),
+                field,
+                message,
+            },
+            other => other,
         }
     }
 }
@@ -0,0 +1,124 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::doppelback_error::DoppelbackError;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// An exclusive advisory lock held on a file for as long as the guard is alive.
+///
+/// The lock is released when the guard is dropped, including during a panic.  If the process dies
+/// without unwinding, the kernel releases the lock when the file descriptor is closed.
+#[derive(Debug)]
+pub struct LockFile {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Takes an exclusive lock on `path`, creating the file if needed.
+    ///
+    /// Returns `DoppelbackError::Locked` immediately instead of waiting if another process already
+    /// holds the lock.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self, DoppelbackError> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => DoppelbackError::Locked(path),
+                _ => DoppelbackError::IoError(err),
+            });
+        }
+        debug!("Acquired lock on {}", path.display());
+
+        Ok(LockFile { file, path })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+        debug!("Released lock on {}", self.path.display());
+    }
+}
+
+/// Locks on several files, each taken the first time it is needed and held until the set is
+/// dropped.
+///
+/// Hosts backed up in parallel can share a lock, so a lock this set already holds counts as
+/// acquired instead of failing with `DoppelbackError::Locked`.
+#[derive(Debug, Default)]
+pub struct LockSet {
+    locks: Mutex<HashMap<PathBuf, LockFile>>,
+}
+
+impl LockSet {
+    /// Takes an exclusive lock on `path` unless this set already holds it.
+    pub fn acquire<P: AsRef<Path>>(&self, path: P) -> Result<(), DoppelbackError> {
+        let path = path.as_ref();
+        let mut locks = self.locks.lock().expect("lock set poisoned");
+        if !locks.contains_key(path) {
+            locks.insert(path.to_path_buf(), LockFile::acquire(path)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn second_lock_fails() {
+        let dir = TempDir::new("lock").unwrap();
+        let path = dir.path().join(".doppelback.lock");
+
+        let _first = LockFile::acquire(&path).unwrap();
+        match LockFile::acquire(&path) {
+            Err(DoppelbackError::Locked(p)) => assert_eq!(p, path),
+            other => panic!("expected Locked, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lock_is_released_on_drop() {
+        let dir = TempDir::new("lock").unwrap();
+        let path = dir.path().join(".doppelback.lock");
+
+        let first = LockFile::acquire(&path).unwrap();
+        drop(first);
+        assert!(LockFile::acquire(&path).is_ok());
+    }
+
+    #[test]
+    fn lock_set_shares_its_locks() {
+        let dir = TempDir::new("lock").unwrap();
+        let path = dir.path().join(".doppelback.lock");
+
+        let locks = LockSet::default();
+        locks.acquire(&path).unwrap();
+        locks.acquire(&path).unwrap();
+        assert!(matches!(
+            LockFile::acquire(&path),
+            Err(DoppelbackError::Locked(_))
+        ));
+        drop(locks);
+        assert!(LockFile::acquire(&path).is_ok());
+    }
+}
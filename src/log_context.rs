@@ -0,0 +1,61 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use std::cell::RefCell;
+
+thread_local! {
+    /// Host that log lines from this thread belong to, when several hosts run at once.
+    static HOST: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous host of the thread's log context when dropped.
+pub struct HostGuard {
+    previous: Option<String>,
+}
+
+/// Tags log lines from the current thread with `host` until the returned guard is dropped.
+pub fn set_host(host: Option<&str>) -> HostGuard {
+    let previous = HOST.with(|h| h.replace(host.map(String::from)));
+    HostGuard { previous }
+}
+
+/// Returns the host that log lines from the current thread belong to, if any.
+pub fn host() -> Option<String> {
+    HOST.with(|h| h.borrow().clone())
+}
+
+/// Returns `[host] ` for the current thread's host, or an empty string if there isn't one.
+pub fn prefix() -> String {
+    host().map_or_else(String::new, |host| format!("[{}] ", host))
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        HOST.with(|h| *h.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn guard_restores_previous_host() {
+        assert_eq!(prefix(), "");
+        {
+            let _outer = set_host(Some("host1"));
+            assert_eq!(prefix(), "[host1] ");
+            {
+                let _inner = set_host(Some("host2"));
+                assert_eq!(host().as_deref(), Some("host2"));
+            }
+            assert_eq!(host().as_deref(), Some("host1"));
+
+            // Other threads have their own context.
+            thread::spawn(|| assert!(host().is_none())).join().unwrap();
+        }
+        assert!(host().is_none());
+    }
+}
@@ -2,47 +2,126 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 mod args;
+mod atomic_file;
 mod commands;
 mod config;
+mod config_test;
 mod doppelback_error;
+mod lock_file;
+mod log_context;
+mod metrics;
+mod notify;
+mod process_util;
 mod rsync_util;
+mod signals;
+mod status_file;
+mod syslog;
+#[cfg(test)]
+mod test_util;
 
 #[cfg(test)]
 #[macro_use(lazy_static)]
 extern crate lazy_static;
 extern crate utime;
 
-use args::Command;
-use config::{BackupHost, Config, ConfigTestType};
+use args::{Command, LogFormat};
+use commands::{backup, check, snapshots};
+use config::{BackupDest, BackupHost, Config, ConfigTestType};
+use lock_file::LockSet;
 use log::{error, info};
 use pathsearch::find_executable_in_path;
-use std::collections::HashMap;
+use status_file::StatusFile;
 use std::env;
-use std::ffi::OsString;
 use std::fs;
 use std::io;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-fn init_logging(verbose: bool, log: Option<PathBuf>, cmd: &Command) -> Result<(), fern::InitError> {
+/// Formats a log record as a single-line JSON object for `--log-format json`.
+///
+/// `host` is added when the record was logged while backing up hosts in parallel.
+fn json_log_record<Tz: chrono::TimeZone>(
+    time: &chrono::DateTime<Tz>,
+    level: log::Level,
+    target: &str,
+    host: Option<&str>,
+    message: &str,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let mut record = serde_json::json!({
+        "timestamp": time.to_rfc3339(),
+        "level": level.as_str(),
+        "target": target,
+        "message": message,
+    });
+    if let Some(host) = host {
+        record["host"] = serde_json::Value::from(host);
+    }
+    record.to_string()
+}
+
+/// Returns the levels for the log file and the console, in that order.
+///
+/// --quiet only affects the console, so a cron job can stay silent unless something goes wrong
+/// while --log still records what happened.
+fn log_levels(verbose: bool, quiet: bool) -> (log::LevelFilter, log::LevelFilter) {
     let file_level = if verbose {
         log::LevelFilter::Debug
     } else {
         log::LevelFilter::Info
     };
+    let console_level = if quiet {
+        log::LevelFilter::Warn
+    } else {
+        file_level
+    };
+    (file_level, console_level)
+}
+
+fn init_logging(
+    verbose: bool,
+    quiet: bool,
+    syslog: bool,
+    log: Option<PathBuf>,
+    max_log_bytes: Option<u64>,
+    log_format: &LogFormat,
+    cmd: &Command,
+) -> Result<(), fern::InitError> {
+    let (logging, _) = build_logging(verbose, quiet, syslog, log, max_log_bytes, log_format, cmd)?;
+    logging.apply()?;
+    Ok(())
+}
+
+/// Builds the log dispatch for `init_logging` and returns it with the names of the outputs it
+/// writes to.
+fn build_logging(
+    verbose: bool,
+    quiet: bool,
+    syslog: bool,
+    log: Option<PathBuf>,
+    max_log_bytes: Option<u64>,
+    log_format: &LogFormat,
+    cmd: &Command,
+) -> Result<(fern::Dispatch, Vec<&'static str>), fern::InitError> {
+    let (file_level, console_level) = log_levels(verbose, quiet);
     let console_level = match cmd {
         Command::Ssh(_) | Command::Sudo(_) => log::LevelFilter::Off,
-        _ => file_level,
+        _ => console_level,
     };
-    let logging = fern::Dispatch::new().level(file_level);
+    let mut logging = fern::Dispatch::new().level(file_level);
+    let mut outputs = vec!["stdout"];
 
     let stdout_log = fern::Dispatch::new()
         .format(|out, message, _| {
             out.finish(format_args!(
-                "{} {}",
+                "{} {}{}",
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                log_context::prefix(),
                 message
             ))
         })
@@ -58,27 +137,107 @@ fn init_logging(verbose: bool, log: Option<PathBuf>, cmd: &Command) -> Result<()
             )
             .into());
         }
+        if let Some(max_log_bytes) = max_log_bytes {
+            rotate_log_if_needed(&log, max_log_bytes)?;
+        }
         let file = fs::OpenOptions::new()
             .create(true)
             .append(true)
             .custom_flags(libc::O_NOFOLLOW)
             .open(log)?;
-        file_log = file_log
-            .format(|out, message, record| {
+        outputs.push("file");
+        file_log = match log_format {
+            LogFormat::Text => file_log.format(|out, message, record| {
                 out.finish(format_args!(
-                    "[{}] [{}] [{}] {}",
+                    "[{}] [{}] [{}] {}{}",
                     chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                     record.target(),
                     record.level(),
+                    log_context::prefix(),
                     message
                 ))
-            })
-            .chain(file);
+            }),
+            LogFormat::Json => file_log.format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}",
+                    json_log_record(
+                        &chrono::Local::now(),
+                        record.level(),
+                        record.target(),
+                        log_context::host().as_deref(),
+                        &message.to_string()
+                    )
+                ))
+            }),
+        }
+        .chain(file);
     }
 
-    logging.chain(file_log).chain(stdout_log).apply()?;
+    logging = logging.chain(file_log).chain(stdout_log);
 
-    Ok(())
+    // Even with --verbose, syslog only gets a summary of the run.
+    if syslog {
+        let syslog_log = fern::Dispatch::new()
+            .level(log::LevelFilter::Info)
+            .filter(syslog::is_summary)
+            .chain(Box::new(syslog::Syslog::open()) as Box<dyn log::Log>);
+        logging = logging.chain(syslog_log);
+        outputs.push("syslog");
+    }
+
+    Ok((logging, outputs))
+}
+
+/// Number of rotated logs kept by `--max-log-bytes`, as `<log>.1` (newest) to `<log>.5`.
+const KEPT_LOGS: usize = 5;
+
+/// Returns whether a log of `size` bytes has grown past `max_bytes` and should be rotated.
+fn log_needs_rotation(size: u64, max_bytes: u64) -> bool {
+    size > max_bytes
+}
+
+/// Rotates `log` if it is a regular file larger than `max_bytes`.
+///
+/// A symlink is never rotated, so opening it with `O_NOFOLLOW` still fails afterwards.
+fn rotate_log_if_needed(log: &Path, max_bytes: u64) -> io::Result<()> {
+    match fs::symlink_metadata(log) {
+        Ok(metadata) if metadata.is_file() && log_needs_rotation(metadata.len(), max_bytes) => {
+            rotate_log(log, KEPT_LOGS)
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Renames `log` to `<log>.1`, shifting each older `<log>.N` to `<log>.N+1`.  Whatever was in
+/// `<log>.<keep>` is dropped.
+fn rotate_log(log: &Path, keep: usize) -> io::Result<()> {
+    let rotated = |n: usize| {
+        let mut name = log.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    for n in (1..keep).rev() {
+        match fs::rename(rotated(n), rotated(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(log, rotated(1))
+}
+
+/// Prints the hosts that keep their snapshots somewhere other than the global directory.
+fn print_snapshot_overrides(config: &Config) {
+    let mut hosts: Vec<_> = config
+        .hosts
+        .iter()
+        .filter_map(|(host, host_config)| Some((host, host_config.snapshots.as_ref()?)))
+        .collect();
+    hosts.sort();
+    for (host, root) in hosts {
+        println!("  {} saves snapshots into {}", host, root.display());
+    }
 }
 
 fn main() {
@@ -86,11 +245,38 @@ fn main() {
     let args = full_args.args;
     let cmd = full_args.cmd;
 
-    init_logging(args.verbose, args.log.clone(), &cmd).unwrap_or_else(|e| {
+    init_logging(
+        args.verbose,
+        args.quiet,
+        args.syslog,
+        args.log.clone(),
+        args.max_log_bytes,
+        &args.log_format,
+        &cmd,
+    )
+    .unwrap_or_else(|e| {
         eprintln!("Failed to set up logging: {}", e);
         process::exit(1);
     });
 
+    // Everything that needs the home directory looks it up through HOME, including after
+    // re-running itself through ssh or sudo.
+    if let Some(home) = &args.home {
+        env::set_var("HOME", home);
+    }
+
+    if args.print_commands && !args.dry_run {
+        error!("--print-commands only works with --dry-run");
+        process::exit(1);
+    }
+    process_util::set_print_commands(args.print_commands);
+
+    // The generated config is meant for users who don't have a config yet.
+    if let Command::GenerateConfig(generate) = &cmd {
+        generate.print_sample();
+        return;
+    }
+
     // Parse the config before worrying about which parts are needed.  This ensures that the config
     // is valid YAML.  Each specific subcommand will do further checks on the contents as needed.
     let config = Config::load(&args.config).unwrap_or_else(|e| {
@@ -111,7 +297,13 @@ fn main() {
         }),
 
         None => match &cmd {
-            Command::Ssh(_) | Command::Sudo(_) => {
+            Command::Ssh(_)
+            | Command::Sudo(_)
+            | Command::Restore(_)
+            | Command::Sizes(_)
+            | Command::Orphans(_)
+            | Command::TestSsh(_)
+            | Command::Check(_) => {
                 error!("--host is required for {}", cmd);
                 process::exit(1);
             }
@@ -143,99 +335,133 @@ fn main() {
         // time we parse the config file because not every subcommand cares about every section.
         Command::ConfigTest(test) => match test.test_type {
             ConfigTestType::Host => {
+                // With --json, stdout only holds the JSON result, even when a check can't start.
+                let fail = |message: String| -> ! {
+                    if test.json {
+                        println!("{}", serde_json::json!({ "ok": false, "error": message }));
+                    } else {
+                        println!("{}", message);
+                    }
+                    process::exit(1);
+                };
+                if let Err(e) = config.snapshot_dir_valid() {
+                    fail(format!("Snapshot dir is invalid: {}", e));
+                }
+
+                let home_dir = process_util::home_dir().unwrap_or_else(|e| fail(e.to_string()));
+                let ssh = find_executable_in_path("ssh")
+                    .unwrap_or_else(|| fail(String::from("ssh not found in PATH")));
+                let rsync = config.rsync_bin().unwrap_or_else(|e| fail(e.to_string()));
+
+                let mut report = config_test::HostTestReport {
+                    snapshots: config.snapshots.clone(),
+                    snapshot_overrides: config
+                        .hosts
+                        .iter()
+                        .filter_map(|(host, host_config)| {
+                            Some((host.clone(), host_config.snapshots.clone()?))
+                        })
+                        .collect(),
+                    ..config_test::HostTestReport::default()
+                };
+                match rsync_util::rsync_version(&rsync) {
+                    Ok(version) => report.rsync_version = version,
+                    Err(e) => report.rsync_error = Some(e.to_string()),
+                }
+                report.rsync_warnings =
+                    config_test::crtimes_warnings(&config, report.rsync_version);
+                report.rsync = rsync;
+                report.hosts = config_test::check_hosts(
+                    &config,
+                    args.host.as_deref(),
+                    &home_dir,
+                    |host, host_config, source| {
+                        config_test::check_remote_source(&ssh, &home_dir, host, host_config, source)
+                    },
+                );
+                if test.strict {
+                    report.strict_problems = config
+                        .strict_problems()
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect();
+                }
+
+                if test.json {
+                    println!("{}", report.to_json());
+                } else {
+                    report.print_text();
+                }
+                if !report.strict_problems.is_empty() {
+                    process::exit(1);
+                }
+            }
+
+            ConfigTestType::Remote => {
+                let mut ok = true;
                 if let Err(e) = config.snapshot_dir_valid() {
                     println!("Snapshot dir is invalid: {}", e);
                     process::exit(1);
                 }
                 println!("Saving snapshots into {}", config.snapshots.display());
+                print_snapshot_overrides(&config);
 
-                let home_dir = env::var_os("HOME").expect("HOME missing in environment");
-                let ssh = find_executable_in_path("ssh").unwrap_or_else(|| {
-                    println!("ssh not found in PATH");
-                    process::exit(1);
-                });
-                let mut failed = HashMap::new();
-                let only_host = args.host.unwrap_or("".into());
-                for (host, host_config) in &config.hosts {
-                    if !only_host.is_empty() && &only_host != host {
-                        continue;
+                match config.btrfs_bin() {
+                    Ok(btrfs) => println!("Using btrfs {}", btrfs.display()),
+                    Err(e) => {
+                        println!("Can't use btrfs: {}", e);
+                        ok = false;
                     }
+                }
 
-                    println!("Checking {}", host);
-                    if !host_config.is_user_valid() {
-                        println!("  Invalid user for {}", host);
-                        failed.insert(host, format!("Invalid user {}", host_config.user));
-                        continue;
+                for root in config.snapshot_roots() {
+                    let live_dir = root.join("live");
+                    match snapshots::check_live_subvolume(&live_dir) {
+                        Ok(()) => println!("{} is a btrfs subvolume", live_dir.display()),
+                        Err(e) => {
+                            println!("{}", e);
+                            ok = false;
+                        }
                     }
+                }
 
-                    if let Some(sshkey) = host_config.find_ssh_key(&home_dir) {
-                        println!("  Using ssh key {}", sshkey.display());
-                    } else {
-                        let reason = format!("ssh key {} not found", host_config.key.display());
-                        println!("  {}", reason);
-                        failed.insert(host, reason);
+                let only_host = args.host.unwrap_or("".into());
+                for (host, host_config) in &config.hosts {
+                    if !only_host.is_empty() && &only_host != host {
                         continue;
                     }
-                    let port_str = if let Some(p) = host_config.port {
-                        format!(" (port {})", p)
-                    } else {
-                        "".to_string()
-                    };
-                    println!(
-                        "  Backup sources for {}@{}{}:",
-                        host_config.user, host, port_str,
-                    );
-                    for source in &host_config.sources {
-                        print!("    {}: ", source.path.display());
-
-                        let mut remote_cmd = match host_config.ssh_args(&ssh, &home_dir) {
-                            Some(cmd) => cmd,
-
-                            None => {
-                                println!(" Failed to get ssh arguments");
-                                continue;
-                            }
-                        };
-                        remote_cmd.push(OsString::from(format!("{}@{}", &host_config.user, &host)));
-                        remote_cmd.push(OsString::from("doppelback"));
-                        remote_cmd.push(OsString::from("config-test"));
-                        remote_cmd.push(OsString::from("--type=source"));
-                        remote_cmd.push(OsString::from("--source"));
-                        remote_cmd.push(source.path.as_os_str().to_os_string());
-
-                        let output = match process::Command::new(&remote_cmd[0])
-                            .args(&remote_cmd[1..])
-                            .current_dir("/")
-                            .output()
-                        {
-                            Ok(output) => output,
 
+                    println!("Checking destinations for {}", host);
+                    for source in &host_config.sources {
+                        let dest = BackupDest::new(config.snapshot_root(host), host, source);
+                        print!("    {}: ", dest.backup_dir().display());
+                        match dest.check_writable() {
+                            Ok(()) => println!("OK"),
                             Err(e) => {
-                                println!("Failed to run ssh: {}", e);
-                                continue;
+                                println!("Failed: {}", e);
+                                ok = false;
                             }
-                        };
-                        if output.status.success() {
-                            println!("OK");
-                        } else {
-                            println!(
-                                "Failed: {}{} ",
-                                String::from_utf8_lossy(&output.stdout),
-                                String::from_utf8_lossy(&output.stderr)
-                            );
                         }
                     }
                 }
-                if !failed.is_empty() {
-                    println!("\nUnusable backups:");
-                    for (host, reason) in failed.iter() {
-                        println!("  {}: {}", host, reason);
-                    }
+
+                if !ok {
+                    process::exit(1);
                 }
             }
 
-            ConfigTestType::Remote => {
-                unimplemented!();
+            // Only the loaded config is checked, so this is quick and safe to run anywhere.
+            ConfigTestType::Parse => {
+                let problems = config.parse_problems();
+                if problems.is_empty() {
+                    println!("OK");
+                }
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                if !problems.is_empty() {
+                    process::exit(1);
+                }
             }
 
             ConfigTestType::Source => {
@@ -249,15 +475,18 @@ fn main() {
                     process::exit(1);
                 });
 
-                if !source_config.path.is_dir() {
-                    eprintln!(
-                        "Source path {} is not a directory",
-                        source_config.path.display()
-                    );
-                    process::exit(1);
+                match config_test::check_source_access(source_config) {
+                    Ok(note) => {
+                        println!("OK");
+                        if let Some(note) = note {
+                            println!("{}", note);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
                 }
-
-                println!("OK");
             }
         },
 
@@ -273,7 +502,35 @@ fn main() {
                 error!("Snapshot dir is invalid: {}", e);
                 process::exit(1);
             }
-            match snapshot.make_snapshot(&config.snapshots, args.dry_run) {
+            let root = args
+                .host
+                .as_deref()
+                .map_or(config.snapshots.as_path(), |host| {
+                    config.snapshot_root(host)
+                });
+            // With --host, make_snapshot checks the host's own subvolume instead.
+            if args.host.is_none() {
+                if let Err(e) = snapshots::check_live_subvolume(root.join("live")) {
+                    error!("Can't take snapshots: {}", e);
+                    process::exit(1);
+                }
+            }
+            let btrfs = config.btrfs_bin().unwrap_or_else(|e| {
+                error!("Can't take snapshots: {}", e);
+                process::exit(1);
+            });
+            let sudo = config.snapshot_sudo().unwrap_or_else(|e| {
+                error!("Can't take snapshots: {}", e);
+                process::exit(1);
+            });
+            match snapshot.make_snapshot(
+                root,
+                sudo.as_deref(),
+                &btrfs,
+                args.host.as_deref(),
+                config.snapshot_name_format(),
+                args.dry_run,
+            ) {
                 Ok(name) => info!("New snapshot dir: {}", name),
                 Err(e) => {
                     error!("failed to create snapshot: {}", e);
@@ -282,30 +539,335 @@ fn main() {
             }
         }
 
-        Command::PullBackup(pull) => {
+        Command::ListSnapshots(list) => {
             if let Err(e) = config.snapshot_dir_valid() {
                 error!("Snapshot dir is invalid: {}", e);
                 process::exit(1);
             }
+            if let Err(e) = list.list_snapshots(&config, args.host.as_deref()) {
+                error!("failed to list snapshots: {}", e);
+                process::exit(1);
+            }
+        }
+
+        Command::PullBackup(pull) => {
             if pull.all == args.host.is_some() {
                 error!("Exactly one of --all or --host must be supplied");
                 process::exit(1);
             }
-            let home_dir = env::var_os("HOME").expect("HOME missing in environment");
-
-            let hosts = if pull.all {
-                config.hosts.keys()
+            let hosts: Vec<&str> = if pull.all {
+                pull.selected_hosts(&config).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    process::exit(1);
+                })
             } else {
-                let b = Box::<HashMap<String, BackupHost>>::default();
-                let map = Box::leak(b);
-                map.insert(args.host.unwrap(), host_config);
-                map.keys()
+                vec![args.host.as_deref().expect("host already checked")]
             };
-            for host in hosts {
-                if let Err(e) = pull.backup_host(host, &config, args.dry_run, &home_dir) {
-                    error!("Backup failed for {}: {}", host, e);
+            let home_dir = process_util::home_dir().unwrap_or_else(|e| {
+                error!("Unable to start backup: {}", e);
+                process::exit(1);
+            });
+
+            // Each host locks its snapshots directory, and the locks are held until the end of
+            // this arm.
+            let locks = LockSet::default();
+
+            // If the run is interrupted before a new snapshot is used, delete it again.
+            let btrfs = config.btrfs_bin().unwrap_or_else(|e| {
+                error!("Can't take snapshots: {}", e);
+                process::exit(1);
+            });
+            let sudo = config.snapshot_sudo().unwrap_or_else(|e| {
+                error!("Can't take snapshots: {}", e);
+                process::exit(1);
+            });
+            if let Err(e) = signals::install_handler(args.dry_run, sudo, btrfs) {
+                error!("Failed to set up signal handling: {}", e);
+                process::exit(1);
+            }
+
+            let status = StatusFile::new(config.status_file.clone().filter(|_| !args.dry_run));
+            let run_start = Instant::now();
+            let results = pull.for_each_host(&hosts, |host| {
+                let result = pull.run_host(host, &config, args.dry_run, &home_dir, &status, &locks);
+                if let Err(e) = &result {
+                    error!(target: syslog::SUMMARY_TARGET, "Backup failed for {}: {}", host, e);
+                }
+                result
+            });
+
+            let mut summary = notify::RunSummary::default();
+            let mut reports = Vec::new();
+            let mut host_errors = 0;
+            for (host, result) in hosts.into_iter().zip(results) {
+                let host_summary = match result {
+                    Ok(report) => {
+                        let host_summary = notify::HostSummary {
+                            host: host.to_string(),
+                            succeeded: report.succeeded(),
+                            failed: report.failed(),
+                            error: report.error.clone(),
+                        };
+                        reports.push(report);
+                        host_summary
+                    }
+                    Err(e) => {
+                        host_errors += 1;
+                        notify::HostSummary {
+                            host: host.to_string(),
+                            succeeded: 0,
+                            failed: 0,
+                            error: Some(e.to_string()),
+                        }
+                    }
+                };
+                summary.hosts.push(host_summary);
+            }
+
+            if let (Some(metrics_file), false) = (&config.metrics_file, args.dry_run) {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                if let Err(e) = metrics::write_metrics(metrics_file, &reports, now) {
+                    error!("Failed to write {}: {}", metrics_file.display(), e);
+                }
+            }
+
+            if let Some(notify_config) = &config.notify {
+                if let Err(e) = notify::send(notify_config, &summary, args.dry_run) {
+                    error!("Failed to send notification: {}", e);
+                }
+            }
+
+            if pull.json_summary {
+                let totals = backup::RunTotals::new(&reports, host_errors, run_start.elapsed());
+                println!(
+                    "{}",
+                    serde_json::to_string(&totals).expect("run totals can't be serialized")
+                );
+            }
+        }
+
+        Command::Prune(prune) => {
+            if let Err(e) = config.snapshot_dir_valid() {
+                error!("Snapshot dir is invalid: {}", e);
+                process::exit(1);
+            }
+            let btrfs = config.btrfs_bin().unwrap_or_else(|e| {
+                error!("Can't delete snapshots: {}", e);
+                process::exit(1);
+            });
+            for root in config.snapshot_roots() {
+                match prune.prune(root, config.snapshot_name_format(), &btrfs, args.dry_run) {
+                    Ok(count) => info!("Pruned {} snapshots from {}", count, root.display()),
+                    Err(e) => {
+                        error!("failed to prune snapshots in {}: {}", root.display(), e);
+                        process::exit(1);
+                    }
                 }
             }
         }
+
+        Command::Restore(restore) => {
+            if let Err(e) = config.snapshot_dir_valid() {
+                error!("Snapshot dir is invalid: {}", e);
+                process::exit(1);
+            }
+            let host = args.host.as_deref().expect("host already checked");
+            if let Err(e) = restore.restore(&config, host, args.dry_run) {
+                error!("Restore failed for {}: {}", host, e);
+                process::exit(1);
+            }
+        }
+
+        Command::Check(check) => {
+            let host = args.host.as_deref().expect("host already checked");
+            let (status, message) = check
+                .check(&config, host)
+                .unwrap_or_else(|e| (check::CheckStatus::Unknown, e.to_string()));
+            println!("{}: {}", status.label(), message);
+            process::exit(status.exit_code());
+        }
+
+        Command::GenerateConfig(_) => unreachable!("handled before loading the config"),
+
+        Command::ShowConfig => print!("{}", config.to_yaml()),
+
+        Command::TestSsh(test) => {
+            let host = args.host.as_deref().expect("host already checked");
+            let home_dir = process_util::home_dir().unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1);
+            });
+            let ssh = find_executable_in_path("ssh").unwrap_or_else(|| {
+                error!("ssh not found in PATH");
+                process::exit(1);
+            });
+            match test.test_ssh(&config, host, &ssh, &home_dir) {
+                Ok(0) => {}
+                Ok(failed) => {
+                    error!("{} sources failed for {}", failed, host);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error!("Failed to test ssh for {}: {}", host, e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        Command::Sizes(sizes) => {
+            if let Err(e) = config.snapshot_dir_valid() {
+                error!("Snapshot dir is invalid: {}", e);
+                process::exit(1);
+            }
+            let host = args.host.as_deref().expect("host already checked");
+            if let Err(e) = sizes.print_sizes(&config, host) {
+                error!("Failed to get sizes for {}: {}", host, e);
+                process::exit(1);
+            }
+        }
+
+        Command::Orphans(orphans) => {
+            if let Err(e) = config.snapshot_dir_valid() {
+                error!("Snapshot dir is invalid: {}", e);
+                process::exit(1);
+            }
+            let host = args.host.as_deref().expect("host already checked");
+            match orphans.find_orphans(&config, host, args.dry_run) {
+                Ok(count) => info!("Found {} orphaned sources for {}", count, host),
+                Err(e) => {
+                    error!("Failed to check orphans for {}: {}", host, e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn quiet_only_raises_console_level() {
+        use log::LevelFilter;
+
+        assert_eq!(
+            log_levels(false, false),
+            (LevelFilter::Info, LevelFilter::Info)
+        );
+        assert_eq!(
+            log_levels(true, false),
+            (LevelFilter::Debug, LevelFilter::Debug)
+        );
+        assert_eq!(
+            log_levels(false, true),
+            (LevelFilter::Info, LevelFilter::Warn)
+        );
+    }
+
+    #[test]
+    fn syslog_is_only_added_when_requested() {
+        let cmd = Command::ShowConfig;
+        let (_, outputs) =
+            build_logging(false, false, false, None, None, &LogFormat::Text, &cmd).unwrap();
+        assert_eq!(outputs, vec!["stdout"]);
+
+        let (_, outputs) =
+            build_logging(false, false, true, None, None, &LogFormat::Text, &cmd).unwrap();
+        assert_eq!(outputs, vec!["stdout", "syslog"]);
+
+        let dir = tempdir::TempDir::new("main").unwrap();
+        let log = dir.path().join("doppelback.log");
+        let (_, outputs) =
+            build_logging(true, false, true, Some(log), None, &LogFormat::Json, &cmd).unwrap();
+        assert_eq!(outputs, vec!["stdout", "file", "syslog"]);
+    }
+
+    #[test]
+    fn log_rotates_past_limit() {
+        assert!(!log_needs_rotation(0, 1024));
+        assert!(!log_needs_rotation(1024, 1024));
+        assert!(log_needs_rotation(1025, 1024));
+        assert!(log_needs_rotation(1, 0));
+    }
+
+    #[test]
+    fn rotate_log_shifts_old_logs() {
+        let dir = tempdir::TempDir::new("main").unwrap();
+        let log = dir.path().join("doppelback.log");
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).ok();
+
+        fs::write(&log, "current").unwrap();
+        fs::write(dir.path().join("doppelback.log.1"), "previous").unwrap();
+        rotate_log(&log, 3).unwrap();
+        assert_eq!(read("doppelback.log"), None);
+        assert_eq!(read("doppelback.log.1").as_deref(), Some("current"));
+        assert_eq!(read("doppelback.log.2").as_deref(), Some("previous"));
+        assert_eq!(read("doppelback.log.3"), None);
+
+        fs::write(&log, "next").unwrap();
+        rotate_log(&log, 3).unwrap();
+        fs::write(&log, "last").unwrap();
+        rotate_log(&log, 3).unwrap();
+        assert_eq!(read("doppelback.log.1").as_deref(), Some("last"));
+        assert_eq!(read("doppelback.log.2").as_deref(), Some("next"));
+        assert_eq!(read("doppelback.log.3").as_deref(), Some("current"));
+        // "previous" was the oldest and is dropped instead of going to .4.
+        assert_eq!(read("doppelback.log.4"), None);
+    }
+
+    #[test]
+    fn rotate_log_if_needed_checks_size() {
+        let dir = tempdir::TempDir::new("main").unwrap();
+        let log = dir.path().join("doppelback.log");
+        rotate_log_if_needed(&log, 4).unwrap();
+
+        fs::write(&log, "1234").unwrap();
+        rotate_log_if_needed(&log, 4).unwrap();
+        assert!(log.exists());
+
+        fs::write(&log, "12345").unwrap();
+        rotate_log_if_needed(&log, 4).unwrap();
+        assert!(!log.exists());
+        assert!(dir.path().join("doppelback.log.1").exists());
+
+        // A symlink is left for O_NOFOLLOW to reject.
+        std::os::unix::fs::symlink(dir.path().join("doppelback.log.1"), &log).unwrap();
+        rotate_log_if_needed(&log, 4).unwrap();
+        assert!(fs::symlink_metadata(&log).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn json_log_record_is_valid_json() {
+        let naive = chrono::NaiveDate::from_ymd_opt(2021, 7, 4)
+            .and_then(|d| d.and_hms_opt(12, 30, 0))
+            .unwrap();
+        let time = chrono::Utc.from_utc_datetime(&naive);
+        let line = json_log_record(
+            &time,
+            log::Level::Warn,
+            "doppelback::commands::rsync",
+            None,
+            "rsync said \"hi\"\nand left",
+        );
+        assert!(!line.contains('\n'));
+
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["timestamp"], "2021-07-04T12:30:00+00:00");
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["target"], "doppelback::commands::rsync");
+        assert_eq!(record["message"], "rsync said \"hi\"\nand left");
+        assert!(record.get("host").is_none());
+    }
+
+    #[test]
+    fn json_log_record_includes_host() {
+        let time = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let line = json_log_record(&time, log::Level::Info, "doppelback", Some("host1"), "hi");
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["host"], "host1");
     }
 }
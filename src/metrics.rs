@@ -0,0 +1,183 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::atomic_file;
+use crate::commands::backup::{BackupReport, SourceOutcome};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const LAST_SUCCESS: &str = "doppelback_last_success_timestamp";
+
+/// Writes Prometheus textfile metrics for `reports` to `path`.
+///
+/// The file is written to a temporary name and renamed into place so that node_exporter's textfile
+/// collector never reads a partial file.  Success timestamps for sources that weren't backed up
+/// successfully in this run are carried over from the existing file, so a failed run or a
+/// single-host run doesn't reset the freshness of everything else.
+pub fn write_metrics<P: AsRef<Path>>(
+    path: P,
+    reports: &[BackupReport],
+    now: u64,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let previous = fs::read_to_string(path).unwrap_or_default();
+    atomic_file::write_atomic(path, render(reports, now, &previous).as_bytes())
+}
+
+fn render(reports: &[BackupReport], now: u64, previous: &str) -> String {
+    // Keyed by the label string so the output is stable between runs.
+    let mut last_success: BTreeMap<String, String> = previous
+        .lines()
+        .filter_map(|line| line.strip_prefix(LAST_SUCCESS))
+        .filter_map(|rest| rest.rsplit_once(' '))
+        .map(|(labels, value)| (labels.to_string(), value.to_string()))
+        .collect();
+    let mut duration = String::new();
+    let mut success = String::new();
+    let mut bytes = String::new();
+
+    for report in reports {
+        for source in &report.sources {
+            let labels = format!(
+                "{{host=\"{}\",source=\"{}\"}}",
+                escape_label(&report.host),
+                escape_label(&source.path.to_string_lossy())
+            );
+            let succeeded = source.outcome == SourceOutcome::Succeeded;
+            if succeeded {
+                last_success.insert(labels.clone(), now.to_string());
+            }
+            let _ = writeln!(
+                duration,
+                "doppelback_backup_duration_seconds{} {:.3}",
+                labels,
+                source.duration.as_secs_f64()
+            );
+            let _ = writeln!(
+                success,
+                "doppelback_backup_success{} {}",
+                labels, succeeded as u8
+            );
+            if let Some(b) = source.bytes {
+                let _ = writeln!(bytes, "doppelback_backup_transferred_bytes{} {}", labels, b);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    out.push_str(
+        "# HELP doppelback_last_success_timestamp Unix time of the last successful backup.\n\
+         # TYPE doppelback_last_success_timestamp gauge\n",
+    );
+    for (labels, value) in &last_success {
+        let _ = writeln!(out, "{}{} {}", LAST_SUCCESS, labels, value);
+    }
+    out.push_str(
+        "# HELP doppelback_backup_duration_seconds Time taken by the most recent backup.\n\
+         # TYPE doppelback_backup_duration_seconds gauge\n",
+    );
+    out.push_str(&duration);
+    out.push_str(
+        "# HELP doppelback_backup_success Whether the most recent backup succeeded.\n\
+         # TYPE doppelback_backup_success gauge\n",
+    );
+    out.push_str(&success);
+    out.push_str(
+        "# HELP doppelback_backup_transferred_bytes Bytes transferred by the most recent backup.\n\
+         # TYPE doppelback_backup_transferred_bytes gauge\n",
+    );
+    out.push_str(&bytes);
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::backup::SourceResult;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn report() -> BackupReport {
+        BackupReport {
+            host: String::from("host1"),
+            snapshot: String::from("20210704.00"),
+            sources: vec![
+                SourceResult {
+                    path: PathBuf::from("/etc"),
+                    outcome: SourceOutcome::Succeeded,
+                    duration: Duration::from_millis(12500),
+                    bytes: Some(4096),
+                },
+                SourceResult {
+                    path: PathBuf::from("/home"),
+                    outcome: SourceOutcome::Failed(String::from("rsync failed")),
+                    duration: Duration::from_secs(3),
+                    bytes: None,
+                },
+            ],
+            error: None,
+        }
+    }
+
+    #[test]
+    fn render_report() {
+        assert_eq!(
+            render(&[report()], 1625400000, ""),
+            "# HELP doppelback_last_success_timestamp Unix time of the last successful backup.\n\
+             # TYPE doppelback_last_success_timestamp gauge\n\
+             doppelback_last_success_timestamp{host=\"host1\",source=\"/etc\"} 1625400000\n\
+             # HELP doppelback_backup_duration_seconds Time taken by the most recent backup.\n\
+             # TYPE doppelback_backup_duration_seconds gauge\n\
+             doppelback_backup_duration_seconds{host=\"host1\",source=\"/etc\"} 12.500\n\
+             doppelback_backup_duration_seconds{host=\"host1\",source=\"/home\"} 3.000\n\
+             # HELP doppelback_backup_success Whether the most recent backup succeeded.\n\
+             # TYPE doppelback_backup_success gauge\n\
+             doppelback_backup_success{host=\"host1\",source=\"/etc\"} 1\n\
+             doppelback_backup_success{host=\"host1\",source=\"/home\"} 0\n\
+             # HELP doppelback_backup_transferred_bytes Bytes transferred by the most recent backup.\n\
+             # TYPE doppelback_backup_transferred_bytes gauge\n\
+             doppelback_backup_transferred_bytes{host=\"host1\",source=\"/etc\"} 4096\n"
+        );
+    }
+
+    #[test]
+    fn render_keeps_previous_success() {
+        let previous = render(&[report()], 1000, "")
+            + "doppelback_last_success_timestamp{host=\"host1\",source=\"/home\"} 900\n";
+        let out = render(&[report()], 2000, &previous);
+        assert!(out
+            .contains("doppelback_last_success_timestamp{host=\"host1\",source=\"/etc\"} 2000\n"));
+        assert!(out
+            .contains("doppelback_last_success_timestamp{host=\"host1\",source=\"/home\"} 900\n"));
+        assert_eq!(out.matches(LAST_SUCCESS).count(), 4);
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn write_replaces_file() {
+        let dir = TempDir::new("metrics").unwrap();
+        let path = dir.path().join("doppelback.prom");
+        fs::write(&path, "old contents\n").unwrap();
+
+        write_metrics(&path, &[report()], 1625400000).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("source=\"/etc\"} 1625400000\n"));
+        assert!(!contents.contains("old contents"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::config::{Notify, NotifyMode};
+use crate::doppelback_error::DoppelbackError;
+use log::{debug, info};
+use pathsearch::find_executable_in_path;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+
+/// Per-host results of a pull-backup run, used to build notifications.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub hosts: Vec<HostSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostSummary {
+    pub host: String,
+    pub succeeded: usize,
+    pub failed: usize,
+
+    /// Set if the host's backup couldn't run at all.
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    pub fn has_failures(&self) -> bool {
+        self.hosts.iter().any(|h| h.failed > 0 || h.error.is_some())
+    }
+
+    fn to_text(&self) -> String {
+        let mut out = if self.has_failures() {
+            String::from("doppelback backup failed\n")
+        } else {
+            String::from("doppelback backup succeeded\n")
+        };
+        for host in &self.hosts {
+            match &host.error {
+                Some(e) => out.push_str(&format!("{}: backup failed: {}\n", host.host, e)),
+                None => out.push_str(&format!(
+                    "{}: {} succeeded, {} failed\n",
+                    host.host, host.succeeded, host.failed
+                )),
+            }
+        }
+        out
+    }
+}
+
+/// Sends `summary` as configured by `notify`.
+///
+/// Nothing is sent for a successful run unless `notify_on_success` is set.  For a dry run, the
+/// notification is only logged.
+pub fn send(notify: &Notify, summary: &RunSummary, dry_run: bool) -> Result<(), DoppelbackError> {
+    if !summary.has_failures() && !notify.notify_on_success {
+        return Ok(());
+    }
+
+    let (command, payload) = match notify.mode {
+        NotifyMode::Command => (vec![OsString::from(&notify.target)], summary.to_text()),
+        NotifyMode::Webhook => {
+            let curl = find_executable_in_path("curl").ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Couldn't find curl in PATH")
+            })?;
+            let payload = serde_json::to_string(summary).map_err(io::Error::from)?;
+            (get_webhook_command(curl, &notify.target), payload)
+        }
+    };
+
+    debug!("Notification command: {:?}", &command);
+    if dry_run {
+        info!("Would send notification: {}", payload);
+        return Ok(());
+    }
+
+    run_with_stdin(&command, &payload)
+}
+
+fn get_webhook_command(curl: PathBuf, url: &str) -> Vec<OsString> {
+    vec![
+        curl.into_os_string(),
+        OsString::from("--silent"),
+        OsString::from("--show-error"),
+        OsString::from("--fail"),
+        OsString::from("--request"),
+        OsString::from("POST"),
+        OsString::from("--header"),
+        OsString::from("Content-Type: application/json"),
+        OsString::from("--data-binary"),
+        OsString::from("@-"),
+        OsString::from(url),
+    ]
+}
+
+fn run_with_stdin(command: &[OsString], payload: &str) -> Result<(), DoppelbackError> {
+    let mut child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir("/")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(payload.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DoppelbackError::CommandFailed(
+            PathBuf::from(&command[0]),
+            status,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    fn summary(failed: usize) -> RunSummary {
+        RunSummary {
+            hosts: vec![
+                HostSummary {
+                    host: String::from("host1"),
+                    succeeded: 2,
+                    failed,
+                    error: None,
+                },
+                HostSummary {
+                    host: String::from("host2"),
+                    succeeded: 0,
+                    failed: 0,
+                    error: None,
+                },
+            ],
+        }
+    }
+
+    /// Creates a notifier script that copies its stdin to `out`.
+    fn notifier(dir: &TempDir, out: &std::path::Path) -> String {
+        let script = dir.path().join("notify.sh");
+        fs::write(&script, format!("#!/bin/sh\ncat > {}\n", out.display())).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn command_receives_summary() {
+        let dir = TempDir::new("notify").unwrap();
+        let out = dir.path().join("out.txt");
+        let notify = Notify {
+            mode: NotifyMode::Command,
+            target: notifier(&dir, &out),
+            notify_on_success: false,
+        };
+
+        send(&notify, &summary(1), false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "doppelback backup failed\n\
+             host1: 2 succeeded, 1 failed\n\
+             host2: 0 succeeded, 0 failed\n"
+        );
+    }
+
+    #[test]
+    fn success_is_quiet_by_default() {
+        let dir = TempDir::new("notify").unwrap();
+        let out = dir.path().join("out.txt");
+        let mut notify = Notify {
+            mode: NotifyMode::Command,
+            target: notifier(&dir, &out),
+            notify_on_success: false,
+        };
+
+        send(&notify, &summary(0), false).unwrap();
+        assert!(!out.exists());
+
+        notify.notify_on_success = true;
+        send(&notify, &summary(0), false).unwrap();
+        assert!(fs::read_to_string(&out)
+            .unwrap()
+            .starts_with("doppelback backup succeeded\n"));
+    }
+
+    #[test]
+    fn host_errors_are_failures() {
+        let summary = RunSummary {
+            hosts: vec![HostSummary {
+                host: String::from("host1"),
+                succeeded: 0,
+                failed: 0,
+                error: Some(String::from("ssh key not found")),
+            }],
+        };
+        assert!(summary.has_failures());
+        assert_eq!(
+            summary.to_text(),
+            "doppelback backup failed\nhost1: backup failed: ssh key not found\n"
+        );
+    }
+
+    #[test]
+    fn webhook_posts_json() {
+        let command = get_webhook_command(PathBuf::from("/usr/bin/curl"), "https://example.com/");
+        assert_eq!(command.last().unwrap(), "https://example.com/");
+        assert!(command.contains(&OsString::from("@-")));
+        assert_eq!(
+            serde_json::to_string(&summary(1)).unwrap(),
+            r#"{"hosts":[{"host":"host1","succeeded":2,"failed":1,"error":null},{"host":"host2","succeeded":0,"failed":0,"error":null}]}"#
+        );
+    }
+}
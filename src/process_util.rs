@@ -0,0 +1,217 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::doppelback_error::DoppelbackError;
+use log::warn;
+use std::env;
+use std::ffi::{CStr, OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{Child, ExitStatus};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check whether a child with a timeout has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether `print_dry_run` writes to stdout.  Set once at startup from `--print-commands`.
+static PRINT_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_print_commands(enabled: bool) {
+    PRINT_COMMANDS.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a command that a dry run skipped to stdout if `--print-commands` was passed.
+pub fn print_dry_run(command: &[OsString]) {
+    if PRINT_COMMANDS.load(Ordering::Relaxed) {
+        println!("{}", shell_quote(command));
+    }
+}
+
+/// Returns the home directory that ssh keys are looked up in.
+///
+/// `HOME` is used if it is set.  Services started by systemd may not have it, so the passwd entry
+/// of the user running doppelback is the fallback.  `--home` works by setting `HOME` at startup.
+pub fn home_dir() -> Result<OsString, DoppelbackError> {
+    resolve_home_dir(env::var_os("HOME"), passwd_home_dir)
+}
+
+fn resolve_home_dir<F>(home: Option<OsString>, passwd: F) -> Result<OsString, DoppelbackError>
+where
+    F: FnOnce() -> Option<OsString>,
+{
+    match home.filter(|home| !home.is_empty()) {
+        Some(home) => Ok(home),
+        None => passwd().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Couldn't find the home directory; set HOME or pass --home",
+            )
+            .into()
+        }),
+    }
+}
+
+/// Looks up the home directory of the current user in the passwd database.
+fn passwd_home_dir() -> Option<OsString> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let rc = unsafe {
+        libc::getpwuid_r(
+            libc::getuid(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    Some(OsStr::from_bytes(dir.to_bytes()).to_os_string()).filter(|dir| !dir.is_empty())
+}
+
+/// Joins `args` into a string that a POSIX shell would split back into the same arguments.
+///
+/// Arguments made only of characters that are never special to the shell are left alone.
+/// Anything else is wrapped in single quotes, with embedded single quotes written as `'\''`.
+pub fn shell_quote(args: &[OsString]) -> String {
+    args.iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let safe = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+            if safe {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Waits for `child` to exit, killing it if it runs longer than `timeout`.
+///
+/// Returns `Ok(None)` if the child was killed.  A killed child is always reaped before returning
+/// so that it doesn't linger as a zombie.  With no timeout, this is the same as `child.wait()`.
+pub fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Some),
+    };
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    warn!(
+        "Killing pid {} after {}s",
+        child.id(),
+        timeout.as_secs_f64()
+    );
+    // The child may have exited between the last poll and now, in which case kill fails but wait
+    // still reaps it.
+    let _ = child.kill();
+    child.wait()?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn quote(args: &[&str]) -> String {
+        shell_quote(&args.iter().map(OsString::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn home_dir_falls_back_to_passwd() {
+        let from_env = resolve_home_dir(Some(OsString::from("/home/backup")), || {
+            panic!("passwd looked up with HOME set")
+        });
+        assert_eq!(from_env.unwrap(), "/home/backup");
+
+        for home in [None, Some(OsString::new())] {
+            let from_passwd = resolve_home_dir(home, || Some(OsString::from("/var/lib/backup")));
+            assert_eq!(from_passwd.unwrap(), "/var/lib/backup");
+        }
+
+        let err = resolve_home_dir(None, || None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Couldn't find the home directory; set HOME or pass --home"
+        );
+    }
+
+    #[test]
+    fn passwd_has_current_user() {
+        let home = passwd_home_dir().expect("no passwd entry for the current user");
+        assert!(Path::new(&home).is_absolute());
+    }
+
+    #[test]
+    fn shell_quote_plain_args() {
+        assert_eq!(
+            quote(&["/usr/bin/rsync", "--max-size=10G", "user@host:/etc/"]),
+            "/usr/bin/rsync --max-size=10G user@host:/etc/"
+        );
+    }
+
+    #[test]
+    fn shell_quote_spaces_and_specials() {
+        assert_eq!(
+            quote(&["--rsh=/usr/bin/ssh -i /key", "--exclude=*.swp", "$HOME"]),
+            "'--rsh=/usr/bin/ssh -i /key' '--exclude=*.swp' '$HOME'"
+        );
+    }
+
+    #[test]
+    fn shell_quote_quotes_and_empty() {
+        assert_eq!(quote(&["it's", ""]), r"'it'\''s' ''");
+    }
+
+    #[test]
+    fn fast_child_is_not_killed() {
+        let mut child = Command::new("true").spawn().unwrap();
+        let status = wait_with_timeout(&mut child, Some(Duration::from_secs(10))).unwrap();
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn slow_child_is_killed_and_reaped() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start = Instant::now();
+        let status = wait_with_timeout(&mut child, Some(Duration::from_millis(200))).unwrap();
+        assert!(status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // Already reaped, so there's nothing left to wait for.
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn no_timeout_waits() {
+        let mut child = Command::new("false").spawn().unwrap();
+        let status = wait_with_timeout(&mut child, None).unwrap();
+        assert!(!status.unwrap().success());
+    }
+}
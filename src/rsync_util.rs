@@ -2,58 +2,207 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 
 use log::{error, warn};
+use regex::Regex;
 use std::ffi::OsString;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-pub fn filter_args<S: AsRef<str>>(args: &[S]) -> Result<Vec<OsString>, Error> {
-    let mut filtered = Vec::new();
+/// Delay before the first retry of a failed rsync.  Each later retry waits twice as long.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
 
-    if args.len() < 5 {
-        error!("Need at least 6 arguments to rsync");
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Not enough rsync arguments",
-        ));
+/// Upper limit on the delay between retries.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Server options that are silently dropped.  They make the sender delete files after sending
+/// them.
+const STRIPPED_SERVER_ARGS: &[&str] = &["--remove-sent-files", "--remove-source-files"];
+
+/// Server options that make the whole command fail.  A client can't legitimately send any of these
+/// when pulling from the host, so seeing one means the command was crafted by hand.  These run
+/// other programs, delete files, or write files on the host.
+const DENIED_SERVER_ARGS: &[&str] = &[
+    "--rsync-path",
+    "--remote-option",
+    "-M",
+    "--rsh",
+    "--daemon",
+    "--config",
+    "--log-file",
+    "--write-batch",
+    "--only-write-batch",
+    "--backup-dir",
+    "--temp-dir",
+];
+
+/// Returns whether `arg` is one of the `DENIED_SERVER_ARGS` or a deletion option, in either the
+/// `--opt=value` or the `--opt value` form.
+fn is_denied_server_arg(arg: &str) -> bool {
+    if arg.starts_with("--delete") {
+        return true;
     }
+    DENIED_SERVER_ARGS.iter().any(|denied| {
+        arg == *denied
+            || (denied.starts_with("--") && arg.starts_with(&format!("{}=", denied)))
+            || (*denied == "-M" && arg.starts_with("-M"))
+    })
+}
 
-    if args[0].as_ref() != "--server" {
-        error!("First rsync argument must be --server");
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Unexpected rsync argument",
-        ));
+/// Checks that a `--files-from` list is either read from the client or lives inside `source`.
+fn check_files_from(files_from: &str, source: &str) -> Result<(), Error> {
+    if files_from == "-" {
+        return Ok(());
     }
-    if args[1].as_ref() != "--sender" {
-        error!("Second rsync argument must be --sender");
+    let path = Path::new(files_from);
+    let escapes = path.components().any(|c| c == Component::ParentDir);
+    if escapes || !path.starts_with(source) {
+        error!("--files-from {} is outside {}", files_from, source);
         return Err(Error::new(
             ErrorKind::InvalidInput,
-            "Unexpected rsync argument",
+            format!("--files-from {} is outside the source path", files_from),
         ));
     }
-    for arg in args.iter() {
-        if arg.as_ref() == "--remove-sent-files" || arg.as_ref() == "--remove-source-files" {
-            warn!("Removed unsafe rsync argument {}", arg.as_ref());
-            continue;
+    Ok(())
+}
+
+/// An rsync server command line sent by a client pulling from this host, such as
+/// `--server --sender -logDtprze.iLsfxC . /etc/`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RsyncServerInvocation {
+    /// Every option in the order it was sent, starting with `--server --sender`.  Values sent as a
+    /// separate argument follow their option.
+    pub options: Vec<String>,
+    /// The one path being backed up.
+    pub source: PathBuf,
+    /// The `.` placeholder the client sends in place of the destination.
+    pub dest: String,
+}
+
+impl RsyncServerInvocation {
+    /// Splits `args` into options, the destination placeholder, and the source path.
+    ///
+    /// The client sends every option first, then `.`, then the source.  Anything else, including
+    /// more than one source, is rejected.
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Self, Error> {
+        let invalid = |message: String| {
+            error!("{}", message);
+            Error::new(ErrorKind::InvalidInput, message)
+        };
+
+        let mut iter = args.iter().map(|arg| arg.as_ref());
+        let mut options = Vec::new();
+        let dest = loop {
+            match iter.next() {
+                None => return Err(invalid(String::from("No source path found in arguments"))),
+                Some(arg) if SEPARATE_VALUE_SERVER_ARGS.contains(&arg) => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| invalid(format!("Missing value for {}", arg)))?;
+                    options.push(arg.to_string());
+                    options.push(value.to_string());
+                }
+                Some(arg) if arg.starts_with('-') => options.push(arg.to_string()),
+                Some(arg) => break arg.to_string(),
+            }
+        };
+
+        if options.first().map(String::as_str) != Some("--server") {
+            return Err(invalid(String::from(
+                "First rsync argument must be --server",
+            )));
+        }
+        if options.get(1).map(String::as_str) != Some("--sender") {
+            return Err(invalid(String::from(
+                "Second rsync argument must be --sender",
+            )));
+        }
+        if dest != "." {
+            return Err(invalid(format!(
+                "Expected . before the source path, not {}",
+                dest
+            )));
         }
-        filtered.push(arg.as_ref().into());
+
+        let source = match iter.collect::<Vec<_>>()[..] {
+            [source] => PathBuf::from(source),
+            [] => return Err(invalid(String::from("No source path found in arguments"))),
+            _ => return Err(invalid(String::from("Only one source path is allowed"))),
+        };
+        if !source.is_absolute() {
+            return Err(invalid(format!(
+                "rsync path is not absolute: {}",
+                source.display()
+            )));
+        }
+
+        Ok(RsyncServerInvocation {
+            options,
+            source,
+            dest,
+        })
     }
 
-    Ok(filtered)
+    /// Returns the command line to pass to the server rsync, without the options that make the
+    /// sender delete files.  Options that run other programs, delete files, or write files on this
+    /// host are rejected, and a `--files-from` list has to be inside the source.
+    pub fn filtered_args(&self) -> Result<Vec<OsString>, Error> {
+        let source = self.source.to_string_lossy();
+        let mut filtered = Vec::new();
+        let mut iter = self.options.iter().map(String::as_str).peekable();
+        while let Some(arg) = iter.next() {
+            if STRIPPED_SERVER_ARGS.contains(&arg) {
+                warn!("Removed unsafe rsync argument {}", arg);
+                continue;
+            }
+            if is_denied_server_arg(arg) {
+                error!("Rejected unsafe rsync argument {}", arg);
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Rejected rsync argument {}", arg),
+                ));
+            }
+            if let Some(files_from) = arg.strip_prefix("--files-from=") {
+                check_files_from(files_from, &source)?;
+            } else if arg == "--files-from" {
+                let files_from = iter.peek().copied().unwrap_or_default();
+                check_files_from(files_from, &source)?;
+            }
+            filtered.push(arg.into());
+        }
+        filtered.push(OsString::from(&self.dest));
+        filtered.push(self.source.clone().into_os_string());
+        Ok(filtered)
+    }
 }
 
-pub fn check_source_path<S: AsRef<str>>(args: &[S]) -> Result<(), Error> {
-    let path_arg = args
-        .iter()
-        .find(|&arg| arg.as_ref().starts_with('/'))
-        .ok_or(Error::new(
-            ErrorKind::InvalidInput,
-            "No source path found in arguments",
-        ))?;
+/// Server options that rsync sends with their value as a separate argument.
+const SEPARATE_VALUE_SERVER_ARGS: &[&str] = &[
+    "--files-from",
+    "--partial-dir",
+    "--temp-dir",
+    "--backup-dir",
+    "--compare-dest",
+    "--copy-dest",
+    "--link-dest",
+];
 
-    let path = PathBuf::from(path_arg.as_ref());
+/// Checks that `path`, the source of a server command line, is absolute and already canonical, so
+/// that it can't reach outside the path it names through `..` or symlinks.
+pub fn check_source_path(path: &Path) -> Result<(), Error> {
+    if !path.is_absolute() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Source path {} is not absolute", path.display()),
+        ));
+    }
     let canon_path = path.canonicalize().map_err(|e| {
-        error!("Failed to canonicalize path {:?}: {}", path_arg.as_ref(), e);
+        error!(
+            "Failed to canonicalize source path {}: {}",
+            path.display(),
+            e
+        );
         e
     })?;
     if path != canon_path {
@@ -61,7 +210,7 @@ pub fn check_source_path<S: AsRef<str>>(args: &[S]) -> Result<(), Error> {
             ErrorKind::InvalidInput,
             format!(
                 "Source path {} does not match canonical path {}",
-                path_arg.as_ref(),
+                path.display(),
                 canon_path.display(),
             ),
         ));
@@ -69,9 +218,439 @@ pub fn check_source_path<S: AsRef<str>>(args: &[S]) -> Result<(), Error> {
     Ok(())
 }
 
+/// Options that can't be added through a source's `extra_rsync_args`.  These delete files on the
+/// host, change how or where the remote rsync runs, or write files outside the destination.
+const DENIED_CLIENT_ARGS: &[&str] = &[
+    "--remove-sent-files",
+    "--remove-source-files",
+    "--rsh",
+    "--rsync-path",
+    "--server",
+    "--sender",
+    "--daemon",
+    "--config",
+    "--log-file",
+    "--write-batch",
+    "--only-write-batch",
+    "--files-from",
+    "--temp-dir",
+    "--backup-dir",
+];
+
+/// Returns whether `arg` is allowed as an extra option on the rsync client command line.
+///
+/// Only long options are accepted so that denied options can't be hidden in a short option
+/// cluster such as `-ve`, and so that extra positional arguments can't add sources.
+pub fn is_allowed_client_arg(arg: &str) -> bool {
+    if !arg.starts_with("--") || arg.len() == 2 {
+        return false;
+    }
+    let name = arg.split_once('=').map_or(arg, |(name, _)| name);
+    !DENIED_CLIENT_ARGS.contains(&name)
+}
+
+/// rsync exit code for a partial transfer because source files vanished during the transfer.
+pub const EXIT_VANISHED: i32 = 24;
+
+/// rsync exit code when the remote shell failed, which for ssh means it couldn't connect.
+pub const EXIT_SSH_FAILED: i32 = 255;
+
+/// Returns a human-readable explanation of an rsync exit code.
+pub fn describe_exit_code(code: i32) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "syntax or usage error",
+        2 => "protocol incompatibility",
+        3 => "errors selecting input/output files or directories",
+        4 => "requested action not supported",
+        5 => "error starting client-server protocol",
+        6 => "daemon unable to append to log file",
+        10 => "error in socket I/O",
+        11 => "error in file I/O",
+        12 => "error in rsync protocol data stream",
+        13 => "errors with program diagnostics",
+        14 => "error in IPC code",
+        20 => "received SIGUSR1 or SIGINT",
+        21 => "some error returned by waitpid()",
+        22 => "error allocating core memory buffers",
+        23 => "partial transfer due to error",
+        24 => "partial transfer due to vanished source files",
+        25 => "the --max-delete limit stopped deletions",
+        30 => "timeout in data send/receive",
+        35 => "timeout waiting for daemon connection",
+        255 => "ssh connection failed",
+        _ => "unknown error",
+    }
+}
+
+/// Returns whether an rsync exit code indicates a transient failure worth retrying.
+///
+/// These are the codes for timeouts and dropped connections (255 is ssh's own exit code when the
+/// connection fails).  Anything else, such as 23 for a partial transfer due to permissions, will
+/// fail the same way again.
+pub fn is_retryable_exit_code(code: i32) -> bool {
+    matches!(code, 10 | 12 | 30 | 35 | 255)
+}
+
+/// Returns how long to wait before retry number `attempt` (starting from 0).
+pub fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(RETRY_MAX_DELAY, |d| d.min(RETRY_MAX_DELAY))
+}
+
+/// Transfer totals parsed from the output of `rsync --stats`.
+///
+/// Either value is `None` if its line wasn't found, e.g. because rsync exited before printing the
+/// summary or a different version changed the wording.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RsyncStats {
+    pub files_transferred: Option<u64>,
+    pub bytes_transferred: Option<u64>,
+}
+
+impl RsyncStats {
+    /// Picks the `--stats` summary values out of a line of rsync's stdout.  Other lines are
+    /// ignored.
+    ///
+    /// rsync 3.0 prints "Number of files transferred" while 3.1 and later print "Number of regular
+    /// files transferred".  Depending on the version and locale, numbers may contain thousands
+    /// separators such as `,`, `.`, or spaces, so only the digits are kept.
+    pub fn add_line(&mut self, line: &str) {
+        let (label, value) = match line.split_once(':') {
+            Some((label, value)) => (label.trim().to_lowercase(), value),
+            None => return,
+        };
+        match label.as_str() {
+            "number of files transferred" | "number of regular files transferred" => {
+                self.files_transferred = parse_stats_number(value);
+            }
+            "total transferred file size" => {
+                self.bytes_transferred = parse_stats_number(value);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the transfer rate in bytes per second from an `--info=progress2` line such as
+/// `1,048,576  50%    2.00MB/s    0:00:02`, or `None` for any other line.
+///
+/// rsync scales the rate by powers of 1024 and uses `.` or `,` as the decimal separator depending
+/// on the locale.
+pub fn parse_progress_rate(line: &str) -> Option<u64> {
+    let rate = line.split_whitespace().find(|word| word.ends_with("B/s"))?;
+    let number = rate.trim_end_matches("B/s");
+    let (number, multiplier) = match number.chars().last()? {
+        'k' | 'K' => (&number[..number.len() - 1], 1u64 << 10),
+        'M' => (&number[..number.len() - 1], 1 << 20),
+        'G' => (&number[..number.len() - 1], 1 << 30),
+        'T' => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    let value: f64 = number.replace(',', ".").parse().ok()?;
+    Some((value * multiplier as f64) as u64)
+}
+
+/// Decides when a transfer has been slower than `min_rate` bytes per second for too long.
+///
+/// Progress samples are fed in as rsync prints them.  The transfer counts as stalled once every
+/// sample for `window` has been below the minimum.  A single faster sample starts the wait over.
+#[derive(Debug)]
+pub struct StallWatchdog {
+    min_rate: u64,
+    window: Duration,
+    slow_since: Option<Instant>,
+}
+
+impl StallWatchdog {
+    pub fn new(min_rate: u64, window: Duration) -> Self {
+        StallWatchdog {
+            min_rate,
+            window,
+            slow_since: None,
+        }
+    }
+
+    /// Records a `rate` seen at `now` and returns whether the transfer is stalled.
+    pub fn sample(&mut self, rate: u64, now: Instant) -> bool {
+        if rate >= self.min_rate {
+            self.slow_since = None;
+            return false;
+        }
+        let slow_since = *self.slow_since.get_or_insert(now);
+        now.duration_since(slow_since) >= self.window
+    }
+}
+
+/// Returns whether a line of rsync's stdout is an `--itemize-changes` line rather than stats or
+/// progress output.
+///
+/// Each itemized line starts with an 11 character change summary such as `>f.st......` (9
+/// characters before rsync 3.1), or with `*deleting` for removed files.
+pub fn is_itemized_change(line: &str) -> bool {
+    static ITEM_RE: OnceLock<Regex> = OnceLock::new();
+    ITEM_RE
+        .get_or_init(|| {
+            Regex::new(r"^(\*deleting|[<>ch.][fdLDS][.+ ?a-zA-Z]{7,9}) ")
+                .expect("invalid itemize regex")
+        })
+        .is_match(line)
+}
+
+/// Returns the directory holding a `CACHEDIR.TAG` file if `line` of rsync `--list-only` output
+/// lists one, relative to the top of the transfer.  A tag at the top itself is ignored.
+pub fn cache_dir(line: &str) -> Option<String> {
+    // Permissions, size, date and time, then the name.
+    static ENTRY_RE: OnceLock<Regex> = OnceLock::new();
+    ENTRY_RE
+        .get_or_init(|| {
+            Regex::new(r"^-\S{9}\s+[0-9,.']+\s+\S+\s+\S+\s(.*)$").expect("invalid list-only regex")
+        })
+        .captures(line)?
+        .get(1)?
+        .as_str()
+        .strip_suffix("/CACHEDIR.TAG")
+        .map(String::from)
+}
+
+/// Escapes the wildcard characters in `path` so that an rsync filter pattern matches it literally.
+pub fn escape_pattern(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Extracts a number like "1,234,567 bytes" from a stats value.  Returns `None` if the value has
+/// a unit suffix (from `--human-readable`) or no digits at all.
+fn parse_stats_number(value: &str) -> Option<u64> {
+    let number = value.trim().trim_end_matches("bytes").trim_end();
+    if number.is_empty()
+        || !number
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ',' || c == '.' || c == ' ' || c == '\'')
+    {
+        return None;
+    }
+    number
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// Oldest rsync that supports every option doppelback passes, such as `--info=progress2` and
+/// `--preallocate`.
+pub const MIN_RSYNC_VERSION: (u32, u32, u32) = (3, 1, 0);
+
+/// First rsync release with `--crtimes`.  Whether it works also depends on the platform rsync was
+/// built for.
+pub const MIN_CRTIMES_VERSION: (u32, u32, u32) = (3, 2, 0);
+
+/// Parses the version out of the first line of `rsync --version`.
+///
+/// Newer releases print the version as `v3.2.7` and older ones as `3.1.3`.  A missing patch level
+/// counts as 0, and anything after the digits (e.g. `3.2.0dev`) is ignored.
+pub fn parse_rsync_version(banner: &str) -> Option<(u32, u32, u32)> {
+    let mut words = banner.lines().next()?.split_whitespace();
+    words.find(|&word| word == "version")?;
+    let version = words.next()?.trim_start_matches('v');
+
+    let mut parts = version.split('.').map(|part| {
+        let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse::<u32>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    Some((major, minor, patch))
+}
+
+/// Runs `rsync --version` and returns the parsed version, or `None` if it couldn't be parsed.
+pub fn rsync_version(rsync: &Path) -> Result<Option<(u32, u32, u32)>, Error> {
+    let output = process::Command::new(rsync)
+        .arg("--version")
+        .current_dir("/")
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "{} --version exited with {}",
+            rsync.display(),
+            output.status
+        )));
+    }
+    Ok(parse_rsync_version(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn client_args_denylist() {
+        for arg in [
+            "--checksum",
+            "--compress",
+            "--info=progress2",
+            "--no-inc-recursive",
+        ] {
+            assert!(is_allowed_client_arg(arg), "{} rejected", arg);
+        }
+        for arg in [
+            "--remove-source-files",
+            "--rsh=sh -c id",
+            "--rsync-path=rm -rf /",
+            "--log-file=/etc/passwd",
+            "-e",
+            "-ve",
+            "--",
+            "host:/other",
+            "",
+        ] {
+            assert!(!is_allowed_client_arg(arg), "{} accepted", arg);
+        }
+    }
+
+    const STATS_3_2: &str = "
+Number of files: 52,013 (reg: 45,011, dir: 6,120, link: 882)
+Number of created files: 12 (reg: 12)
+Number of deleted files: 0
+Number of regular files transferred: 342
+Total file size: 10,215,447,552 bytes
+Total transferred file size: 1,288,490,189 bytes
+Literal data: 1,024,356 bytes
+Matched data: 1,287,465,833 bytes
+File list size: 1,441,699
+File list generation time: 0.001 seconds
+File list transfer time: 0.000 seconds
+Total bytes sent: 43,901
+Total bytes received: 2,905,771
+
+sent 43,901 bytes  received 2,905,771 bytes  31,542.48 bytes/sec
+total size is 10,215,447,552  speedup is 3,463.25
+";
+
+    const STATS_3_0: &str = "
+Number of files: 1203
+Number of files transferred: 17
+Total file size: 5443210 bytes
+Total transferred file size: 120394 bytes
+Literal data: 120394 bytes
+Matched data: 0 bytes
+File list size: 27042
+";
+
+    #[test]
+    fn parse_rsync_version_3_2() {
+        let banner = "rsync  version v3.2.7  protocol version 31\n\
+                      Copyright (C) 1996-2022 by Andrew Tridgell, Wayne Davison, and others.\n\
+                      Web site: https://rsync.samba.org/\n";
+        assert_eq!(parse_rsync_version(banner), Some((3, 2, 7)));
+    }
+
+    #[test]
+    fn parse_rsync_version_3_1() {
+        let banner = "rsync  version 3.1.3  protocol version 31\n\
+                      Copyright (C) 1996-2018 by Andrew Tridgell, Wayne Davison, and others.\n";
+        assert_eq!(parse_rsync_version(banner), Some((3, 1, 3)));
+    }
+
+    #[test]
+    fn parse_rsync_version_old_and_odd() {
+        assert_eq!(
+            parse_rsync_version("rsync  version 3.0.9  protocol version 30\n"),
+            Some((3, 0, 9))
+        );
+        assert_eq!(
+            parse_rsync_version("rsync  version 3.2.0dev  protocol version 31\n"),
+            Some((3, 2, 0))
+        );
+        assert_eq!(
+            parse_rsync_version("rsync version 3.1 protocol version 31"),
+            Some((3, 1, 0))
+        );
+        assert!((3, 0, 9) < MIN_RSYNC_VERSION);
+        assert!((3, 1, 0) >= MIN_RSYNC_VERSION);
+    }
+
+    #[test]
+    fn parse_rsync_version_garbage() {
+        assert_eq!(parse_rsync_version(""), None);
+        assert_eq!(parse_rsync_version("openrsync: protocol version 29"), None);
+        assert_eq!(parse_rsync_version("rsync version"), None);
+        assert_eq!(parse_rsync_version("rsync version x.y.z"), None);
+    }
+
+    fn parse_stats(output: &str) -> RsyncStats {
+        let mut stats = RsyncStats::default();
+        output.lines().for_each(|line| stats.add_line(line));
+        stats
+    }
+
+    #[test]
+    fn parse_stats_3_2() {
+        assert_eq!(
+            parse_stats(STATS_3_2),
+            RsyncStats {
+                files_transferred: Some(342),
+                bytes_transferred: Some(1_288_490_189),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_stats_3_0() {
+        assert_eq!(
+            parse_stats(STATS_3_0),
+            RsyncStats {
+                files_transferred: Some(17),
+                bytes_transferred: Some(120_394),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_stats_locale_separators() {
+        let output = "Number of regular files transferred: 1.342\n\
+                      Total transferred file size: 1.288.490.189 bytes\n";
+        assert_eq!(
+            parse_stats(output),
+            RsyncStats {
+                files_transferred: Some(1342),
+                bytes_transferred: Some(1_288_490_189),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_stats_missing_lines() {
+        assert_eq!(parse_stats(""), RsyncStats::default());
+        assert_eq!(
+            parse_stats("Total transferred file size: 1.20G bytes\n"),
+            RsyncStats::default()
+        );
+        assert_eq!(
+            parse_stats("Number of files transferred: 5\n"),
+            RsyncStats {
+                files_transferred: Some(5),
+                bytes_transferred: None,
+            }
+        );
+    }
+
+    fn filter_args<S: AsRef<str>>(args: &[S]) -> Result<Vec<OsString>, Error> {
+        RsyncServerInvocation::parse(args)?.filtered_args()
+    }
 
     #[test]
     fn filter_args_removes_dangerous() {
@@ -94,38 +673,271 @@ mod tests {
         );
     }
 
+    fn server_cmd(extra: &[&'static str]) -> Vec<&'static str> {
+        let mut cmd = vec!["--server", "--sender", "-logDtprze.iLsfxC"];
+        cmd.extend_from_slice(extra);
+        cmd.extend_from_slice(&[".", "/etc/"]);
+        cmd
+    }
+
     #[test]
-    fn check_source_path_fails_without_path() {
-        let cmd = vec![
-            "--server",
-            "--sender",
-            "--remove-sent-files",
-            "--remove-source-files",
-        ];
-        assert!(check_source_path(&cmd).is_err());
+    fn filter_args_rejects_rsync_path() {
+        assert!(filter_args(&server_cmd(&["--rsync-path=/bin/sh"])).is_err());
+        assert!(filter_args(&server_cmd(&["--rsync-path", "/bin/sh"])).is_err());
+    }
+
+    #[test]
+    fn filter_args_rejects_remote_option() {
+        assert!(filter_args(&server_cmd(&["--remote-option=--log-file=/x"])).is_err());
+        assert!(filter_args(&server_cmd(&["-M--log-file=/x"])).is_err());
+        assert!(filter_args(&server_cmd(&["-M", "--log-file=/x"])).is_err());
+    }
+
+    #[test]
+    fn filter_args_rejects_delete() {
+        assert!(filter_args(&server_cmd(&["--delete"])).is_err());
+        assert!(filter_args(&server_cmd(&["--delete-excluded"])).is_err());
+    }
+
+    #[test]
+    fn filter_args_rejects_other_denied_options() {
+        for arg in [
+            "--log-file=/tmp/log",
+            "--write-batch=/tmp/b",
+            "--temp-dir=/tmp",
+        ] {
+            assert!(
+                filter_args(&server_cmd(&[arg])).is_err(),
+                "{} accepted",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn filter_args_checks_files_from() {
+        assert!(filter_args(&server_cmd(&["--files-from=-"])).is_ok());
+        assert!(filter_args(&server_cmd(&["--files-from", "/etc/backup.list"])).is_ok());
+        assert!(filter_args(&server_cmd(&["--files-from=/etc/backup.list"])).is_ok());
+        assert!(filter_args(&server_cmd(&["--files-from", "/root/list"])).is_err());
+        assert!(filter_args(&server_cmd(&["--files-from=/etc/../root/list"])).is_err());
+    }
+
+    #[test]
+    fn filter_args_keeps_normal_options() {
+        let cmd = server_cmd(&["--numeric-ids", "--max-size=10G"]);
+        let expected: Vec<OsString> = cmd.iter().map(OsString::from).collect();
+        assert_eq!(filter_args(&cmd).unwrap(), expected);
+    }
+
+    #[test]
+    fn server_invocation_parses_real_command_lines() {
+        let parse = |cmd: &str| {
+            let args: Vec<&str> = cmd.split(' ').collect();
+            RsyncServerInvocation::parse(&args)
+        };
+
+        let parsed = parse("--server --sender -logDtprze.iLsfxCIvu . /etc/").unwrap();
+        assert_eq!(
+            parsed,
+            RsyncServerInvocation {
+                options: vec![
+                    String::from("--server"),
+                    String::from("--sender"),
+                    String::from("-logDtprze.iLsfxCIvu"),
+                ],
+                source: PathBuf::from("/etc/"),
+                dest: String::from("."),
+            }
+        );
+
+        let parsed = parse(
+            "--server --sender -vlHogDtpAXrxe.iLsfxCIvu --numeric-ids --files-from /home/list --from0 . /home/",
+        )
+        .unwrap();
+        assert_eq!(parsed.source, PathBuf::from("/home/"));
+        assert_eq!(
+            parsed.options[3..],
+            ["--numeric-ids", "--files-from", "/home/list", "--from0"]
+        );
+
+        // A separate option value that looks like a path isn't the source.
+        let parsed = parse("--server --sender -lr --partial-dir .rsync-partial . /srv/").unwrap();
+        assert_eq!(parsed.source, PathBuf::from("/srv/"));
+    }
+
+    #[test]
+    fn server_invocation_rejects_bad_structure() {
+        let parse = |cmd: &str| {
+            let args: Vec<&str> = cmd.split(' ').collect();
+            RsyncServerInvocation::parse(&args)
+        };
+        for cmd in [
+            "--sender --server -lr . /etc/",
+            "--server -lr . /etc/",
+            "--server --sender -lr /etc/",
+            "--server --sender -lr x /etc/",
+            "--server --sender -lr . /etc/ /root/",
+            "--server --sender -lr . etc/",
+            "--server --sender -lr . --delete",
+            "--server --sender -lr .",
+            "--server --sender -lr --files-from",
+        ] {
+            assert!(parse(cmd).is_err(), "{} accepted", cmd);
+        }
     }
 
     #[test]
     fn check_source_path_fails_for_non_canonical_path() {
-        let cmd = vec![
-            "--server",
-            "--sender",
-            "--remove-sent-files",
-            "--remove-source-files",
-            "/tmp/../",
-        ];
-        assert!(check_source_path(&cmd).is_err());
+        assert!(check_source_path(Path::new("/tmp/../")).is_err());
+        assert!(check_source_path(Path::new("tmp")).is_err());
     }
 
     #[test]
     fn check_source_path_succeeds_for_real_path() {
-        let cmd = vec![
-            "--server",
-            "--sender",
-            "--remove-sent-files",
-            "--remove-source-files",
-            "/tmp",
-        ];
-        assert!(check_source_path(&cmd).is_ok());
+        assert!(check_source_path(Path::new("/tmp")).is_ok());
+        assert!(check_source_path(Path::new("/tmp/")).is_ok());
+    }
+
+    #[test]
+    fn check_source_path_fails_through_symlink() {
+        let dir = TempDir::new("rsync_util").unwrap();
+        let real = dir.path().canonicalize().unwrap();
+        fs::create_dir(real.join("a")).unwrap();
+        std::os::unix::fs::symlink(real.join("a"), real.join("link")).unwrap();
+
+        assert!(check_source_path(&real.join("a")).is_ok());
+        let err = check_source_path(&real.join("link"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("canonical path"), "{}", err);
+    }
+
+    #[test]
+    fn progress_rate_is_parsed() {
+        assert_eq!(
+            parse_progress_rate("      1,048,576  50%    2.00MB/s    0:00:02"),
+            Some(2 << 20)
+        );
+        assert_eq!(
+            parse_progress_rate("  32,768   0%   12,50kB/s    0:00:01 (xfr#1, to-chk=0/1)"),
+            Some(12_800)
+        );
+        assert_eq!(
+            parse_progress_rate("  0   0%    0.00kB/s    0:00:00"),
+            Some(0)
+        );
+        assert_eq!(parse_progress_rate("Number of files: 12"), None);
+        assert_eq!(parse_progress_rate("  0   0%    fastB/s    0:00:00"), None);
+    }
+
+    #[test]
+    fn watchdog_needs_slow_samples_for_whole_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = StallWatchdog::new(1000, Duration::from_secs(60));
+
+        assert!(!watchdog.sample(5000, at(0)));
+        assert!(!watchdog.sample(10, at(10)));
+        assert!(!watchdog.sample(10, at(69)));
+        assert!(watchdog.sample(10, at(70)));
+        assert!(watchdog.sample(0, at(80)));
+    }
+
+    #[test]
+    fn watchdog_resets_on_fast_sample() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = StallWatchdog::new(1000, Duration::from_secs(60));
+
+        assert!(!watchdog.sample(10, at(0)));
+        assert!(!watchdog.sample(10, at(50)));
+        // Exactly the minimum is fast enough.
+        assert!(!watchdog.sample(1000, at(55)));
+        assert!(!watchdog.sample(10, at(61)));
+        assert!(!watchdog.sample(10, at(120)));
+        assert!(watchdog.sample(10, at(121)));
+    }
+
+    #[test]
+    fn itemized_changes_skip_other_output() {
+        let output = ">f+++++++++ etc/new.conf\n\
+                      .d..t...... etc/\n\
+                      >f.st...... etc/hosts\n\
+                      cL+++++++++ etc/localtime -> /usr/share/zoneinfo/UTC\n\
+                      *deleting   etc/old.conf\n\
+                      \n\
+                      Number of files: 3\n\
+                      Total transferred file size: 1,024 bytes\n";
+        assert_eq!(
+            output
+                .lines()
+                .filter(|line| is_itemized_change(line))
+                .collect::<Vec<_>>(),
+            vec![
+                ">f+++++++++ etc/new.conf",
+                ".d..t...... etc/",
+                ">f.st...... etc/hosts",
+                "cL+++++++++ etc/localtime -> /usr/share/zoneinfo/UTC",
+                "*deleting   etc/old.conf",
+            ]
+        );
+    }
+
+    #[test]
+    fn cache_dirs_are_found_in_listing() {
+        let output = "drwxr-xr-x          4,096 2021/07/04 12:00:00 .\n\
+                      -rw-r--r--             43 2021/07/04 12:00:00 CACHEDIR.TAG\n\
+                      drwxr-xr-x          4,096 2021/07/04 12:00:00 user\n\
+                      drwxr-xr-x          4,096 2021/07/04 12:00:00 user/.cache\n\
+                      -rw-r--r--             43 2021/07/04 12:00:00 user/.cache/CACHEDIR.TAG\n\
+                      -rw-r--r--             43 2021/07/04 12:00:00 user/my  build/CACHEDIR.TAG\n\
+                      -rw-r--r--             43 2021/07/04 12:00:00 user/NOTCACHEDIR.TAG\n\
+                      lrwxrwxrwx             12 2021/07/04 12:00:00 link/CACHEDIR.TAG -> x\n";
+        assert_eq!(
+            output.lines().filter_map(cache_dir).collect::<Vec<_>>(),
+            vec!["user/.cache", "user/my  build"]
+        );
+    }
+
+    #[test]
+    fn patterns_are_escaped() {
+        assert_eq!(escape_pattern("user/.cache"), "user/.cache");
+        assert_eq!(escape_pattern("a*b?c[d]\\e"), "a\\*b\\?c\\[d]\\\\e");
+    }
+
+    #[test]
+    fn exit_codes_are_described() {
+        assert_eq!(describe_exit_code(23), "partial transfer due to error");
+        assert_eq!(
+            describe_exit_code(EXIT_VANISHED),
+            "partial transfer due to vanished source files"
+        );
+        assert_eq!(describe_exit_code(30), "timeout in data send/receive");
+        assert_eq!(describe_exit_code(255), "ssh connection failed");
+        assert_eq!(describe_exit_code(99), "unknown error");
+    }
+
+    #[test]
+    fn connection_failures_are_retryable() {
+        for code in [10, 12, 30, 35, 255] {
+            assert!(is_retryable_exit_code(code), "{} not retryable", code);
+        }
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retryable() {
+        for code in [0, 1, 2, 3, 11, 23, 24, 127] {
+            assert!(!is_retryable_exit_code(code), "{} retryable", code);
+        }
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        assert_eq!(retry_delay(0), Duration::from_secs(30));
+        assert_eq!(retry_delay(1), Duration::from_secs(60));
+        assert_eq!(retry_delay(2), Duration::from_secs(120));
+        assert_eq!(retry_delay(5), Duration::from_secs(600));
+        assert_eq!(retry_delay(40), Duration::from_secs(600));
     }
 }
@@ -0,0 +1,192 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::commands::prune;
+use crate::doppelback_error::DoppelbackError;
+use log::{error, info, warn};
+use std::ffi::OsString;
+use std::io::{self, Error};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+
+/// Snapshots that can still be deleted if the run is interrupted.  Hosts backed up in parallel
+/// each have their own.
+static PENDING_SNAPSHOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Records a snapshot that was just created and is safe to delete if the run is interrupted.
+///
+/// A new snapshot holds the live tree as the previous backup left it.  Until the live tree is
+/// changed, the snapshot is an exact duplicate and can be deleted.  Once anything is written to the
+/// live tree the snapshot is the only copy of that state, so `clear_pending_snapshot` must be
+/// called first.
+pub fn set_pending_snapshot(snapshot: PathBuf) {
+    PENDING_SNAPSHOTS
+        .lock()
+        .expect("pending snapshot lock poisoned")
+        .push(snapshot);
+}
+
+pub fn clear_pending_snapshot(snapshot: &Path) {
+    PENDING_SNAPSHOTS
+        .lock()
+        .expect("pending snapshot lock poisoned")
+        .retain(|pending| pending != snapshot);
+}
+
+/// Deletes every pending snapshot with `btrfs`, run through `sudo --` if `sudo` is given.  All of
+/// them are tried even if one fails, and the first error is returned.
+pub fn cleanup_pending_snapshots(sudo: Option<&Path>, btrfs: &Path) -> Result<(), DoppelbackError> {
+    let pending = mem::take(
+        &mut *PENDING_SNAPSHOTS
+            .lock()
+            .expect("pending snapshot lock poisoned"),
+    );
+    let mut result = Ok(());
+    for snapshot in pending {
+        if let Err(e) = delete_snapshot(sudo, btrfs, &snapshot) {
+            error!("Failed to delete {}: {}", snapshot.display(), e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+fn delete_snapshot(
+    sudo: Option<&Path>,
+    btrfs: &Path,
+    snapshot: &Path,
+) -> Result<(), DoppelbackError> {
+    let sudo = sudo.map(|sudo| [sudo.as_os_str().to_os_string(), OsString::from("--")]);
+    let command: Vec<OsString> = sudo
+        .into_iter()
+        .flatten()
+        .chain(prune::get_delete_command(btrfs, snapshot))
+        .collect();
+    info!("Deleting interrupted snapshot {}", snapshot.display());
+    let status = process::Command::new(&command[0])
+        .args(&command[1..])
+        .current_dir("/")
+        .status()?;
+    if !status.success() {
+        return Err(DoppelbackError::CommandFailed(btrfs.to_path_buf(), status));
+    }
+    Ok(())
+}
+
+/// Handles SIGINT and SIGTERM on a dedicated thread.
+///
+/// On either signal, the pending snapshots are deleted with `btrfs` and `sudo` the same way they
+/// were taken (unless this is a dry run) and the process exits with 128 plus the signal number.
+/// The signals are blocked in every other thread, so this must be called before any other threads
+/// are started.  Child processes start with an empty signal mask and still receive the signals
+/// normally.
+pub fn install_handler(dry_run: bool, sudo: Option<PathBuf>, btrfs: PathBuf) -> io::Result<()> {
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+    }
+    let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) };
+    if rc != 0 {
+        return Err(Error::from_raw_os_error(rc));
+    }
+
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            error!("Failed to wait for signals");
+            return;
+        }
+        warn!("Received signal {}, stopping", signal);
+        if !dry_run && cleanup_pending_snapshots(sudo.as_deref(), &btrfs).is_err() {
+            error!("Failed to delete interrupted snapshots");
+        }
+        process::exit(128 + signal);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{FakeCommand, ENV_LOCK};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn cleanup_deletes_pending_snapshot() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("signals").unwrap();
+        let snapshot = dir.path().join("20210704.00");
+        fs::create_dir(&snapshot).unwrap();
+        let btrfs = FakeCommand::script("btrfs", "rm -r \"$3\"").unwrap();
+
+        set_pending_snapshot(snapshot.clone());
+        cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+        assert!(!snapshot.exists());
+
+        // Nothing is left to delete the second time.
+        cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+    }
+
+    #[test]
+    fn cleanup_deletes_every_pending_snapshot() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("signals").unwrap();
+        let first = dir.path().join("20210704.00");
+        let second = dir.path().join("20210704.01");
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        let btrfs = FakeCommand::script("btrfs", "rm -r \"$3\"").unwrap();
+
+        set_pending_snapshot(first.clone());
+        set_pending_snapshot(second.clone());
+        cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+        assert!(!first.exists());
+        assert!(!second.exists());
+    }
+
+    #[test]
+    fn cleanup_uses_sudo() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("signals").unwrap();
+        let snapshot = dir.path().join("20210704.00");
+        fs::create_dir(&snapshot).unwrap();
+        // Only sudo can delete it, and only when it is given the btrfs command.
+        let sudo =
+            FakeCommand::script("sudo", "[ \"$1 $4\" = '-- delete' ] && rm -r \"$5\"").unwrap();
+        let btrfs = FakeCommand::new("btrfs").unwrap();
+
+        set_pending_snapshot(snapshot.clone());
+        cleanup_pending_snapshots(Some(&sudo.cmd), &btrfs.cmd).unwrap();
+        assert!(!snapshot.exists());
+    }
+
+    #[test]
+    fn cleared_snapshot_is_kept() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let dir = TempDir::new("signals").unwrap();
+        let snapshot = dir.path().join("20210704.00");
+        fs::create_dir(&snapshot).unwrap();
+        let btrfs = FakeCommand::script("btrfs", "rm -r \"$3\"").unwrap();
+
+        let other = dir.path().join("20210704.01");
+        fs::create_dir(&other).unwrap();
+
+        set_pending_snapshot(snapshot.clone());
+        set_pending_snapshot(other.clone());
+        clear_pending_snapshot(&snapshot);
+        cleanup_pending_snapshots(None, &btrfs.cmd).unwrap();
+        assert!(snapshot.exists());
+        assert!(!other.exists());
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::atomic_file;
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a pull-backup run is currently doing, as written to the status file.
+#[derive(Debug, Serialize)]
+pub struct BackupStatus {
+    pub pid: u32,
+
+    /// Unix time when the run started.
+    pub started: u64,
+
+    /// Most recently started host.  Other hosts can still be running with `--parallel-hosts`.
+    pub host: Option<String>,
+
+    /// Snapshot taken of the live tree before the most recent host's backup started.
+    pub snapshot: Option<String>,
+
+    /// Sources that are being transferred.  More than one can be running with `--jobs` or
+    /// `--parallel-hosts`.
+    pub running: Vec<RunningSource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunningSource {
+    pub host: String,
+    pub path: PathBuf,
+    pub started: u64,
+}
+
+/// Keeps a JSON status file up to date during a pull-backup run.
+///
+/// Every update rewrites the whole file atomically.  The file is removed when this is dropped.
+/// Failing to write the status is logged but never fails the backup.
+#[derive(Debug)]
+pub struct StatusFile {
+    path: Option<PathBuf>,
+    status: Mutex<BackupStatus>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl StatusFile {
+    /// Creates a tracker that writes to `path`, or does nothing if `path` is `None`.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let status_file = StatusFile {
+            path,
+            status: Mutex::new(BackupStatus {
+                pid: process::id(),
+                started: now(),
+                host: None,
+                snapshot: None,
+                running: Vec::new(),
+            }),
+        };
+        status_file.update(|_| {});
+        status_file
+    }
+
+    pub fn start_host(&self, host: &str, snapshot: &str) {
+        self.update(|status| {
+            status.host = Some(host.to_string());
+            status.snapshot = Some(snapshot.to_string());
+            status.running.retain(|s| s.host != host);
+        });
+    }
+
+    pub fn start_source(&self, host: &str, path: &Path) {
+        self.update(|status| {
+            status.running.push(RunningSource {
+                host: host.to_string(),
+                path: path.to_path_buf(),
+                started: now(),
+            })
+        });
+    }
+
+    pub fn finish_source(&self, host: &str, path: &Path) {
+        self.update(|status| status.running.retain(|s| s.host != host || s.path != path));
+    }
+
+    fn update<F: FnOnce(&mut BackupStatus)>(&self, f: F) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        // Hold the lock while writing so that updates from parallel sources land in order.
+        let mut status = self.status.lock().expect("status lock poisoned");
+        f(&mut status);
+        let result = serde_json::to_vec_pretty(&*status)
+            .map_err(std::io::Error::from)
+            .and_then(|json| atomic_file::write_atomic(path, &json));
+        if let Err(e) = result {
+            warn!("Failed to write status file {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove status file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn read_status(path: &Path) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn status_tracks_running_sources() {
+        let dir = TempDir::new("status").unwrap();
+        let path = dir.path().join("status.json");
+
+        let status_file = StatusFile::new(Some(path.clone()));
+        let status = read_status(&path);
+        assert_eq!(status["pid"], process::id());
+        assert!(status["host"].is_null());
+
+        status_file.start_host("host1", "20210704.00");
+        status_file.start_source("host1", Path::new("/etc"));
+        status_file.start_source("host1", Path::new("/home"));
+        status_file.finish_source("host1", Path::new("/etc"));
+
+        let status = read_status(&path);
+        assert_eq!(status["host"], "host1");
+        assert_eq!(status["snapshot"], "20210704.00");
+        assert_eq!(status["running"].as_array().unwrap().len(), 1);
+        assert_eq!(status["running"][0]["host"], "host1");
+        assert_eq!(status["running"][0]["path"], "/home");
+
+        drop(status_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn parallel_hosts_keep_their_sources() {
+        let dir = TempDir::new("status").unwrap();
+        let path = dir.path().join("status.json");
+
+        let status_file = StatusFile::new(Some(path.clone()));
+        status_file.start_host("host1", "20210704.00");
+        status_file.start_source("host1", Path::new("/etc"));
+        status_file.start_host("host2", "20210704.01");
+        status_file.start_source("host2", Path::new("/etc"));
+        status_file.finish_source("host2", Path::new("/etc"));
+
+        let status = read_status(&path);
+        assert_eq!(status["host"], "host2");
+        assert_eq!(status["running"].as_array().unwrap().len(), 1);
+        assert_eq!(status["running"][0]["host"], "host1");
+        assert_eq!(status["running"][0]["path"], "/etc");
+    }
+
+    #[test]
+    fn no_path_writes_nothing() {
+        let status_file = StatusFile::new(None);
+        status_file.start_host("host1", "20210704.00");
+        status_file.start_source("host1", Path::new("/etc"));
+    }
+}
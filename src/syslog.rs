@@ -0,0 +1,83 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_context;
+use std::ffi::{CStr, CString};
+
+/// Name that messages are tagged with in syslog.
+const IDENT: &CStr = c"doppelback";
+
+/// Log target of the records that also go to syslog: one line with the outcome of each host's
+/// backup.
+pub const SUMMARY_TARGET: &str = "doppelback::summary";
+
+/// Returns whether a record is part of the run summary that is sent to syslog.
+pub fn is_summary(metadata: &log::Metadata) -> bool {
+    metadata.target() == SUMMARY_TARGET
+}
+
+/// Sends log records to the local syslog daemon through `syslog(3)` with the daemon facility.
+pub struct Syslog;
+
+impl Syslog {
+    /// Opens the connection to syslog.  Messages include the pid so that concurrent runs can be
+    /// told apart.
+    pub fn open() -> Self {
+        // openlog keeps the pointer, so the ident has to live for the whole process.
+        unsafe {
+            libc::openlog(IDENT.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON);
+        }
+        Syslog
+    }
+}
+
+/// Returns the syslog priority for a log level.
+fn priority(level: log::Level) -> libc::c_int {
+    match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+impl log::Log for Syslog {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = format!("{}{}", log_context::prefix(), record.args()).replace('\0', "");
+        let message = CString::new(message).expect("NUL bytes already removed");
+        // Pass the message as an argument so that a % in it isn't taken as a format directive.
+        unsafe {
+            libc::syslog(
+                libc::LOG_DAEMON | priority(record.level()),
+                c"%s".as_ptr(),
+                message.as_ptr(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_summary_records_are_sent() {
+        let metadata = |target| log::MetadataBuilder::new().target(target).build();
+        assert!(is_summary(&metadata(SUMMARY_TARGET)));
+        assert!(!is_summary(&metadata("doppelback::commands::rsync")));
+    }
+
+    #[test]
+    fn levels_map_to_priorities() {
+        assert_eq!(priority(log::Level::Error), libc::LOG_ERR);
+        assert_eq!(priority(log::Level::Warn), libc::LOG_WARNING);
+        assert_eq!(priority(log::Level::Info), libc::LOG_INFO);
+        assert_eq!(priority(log::Level::Trace), libc::LOG_DEBUG);
+    }
+}
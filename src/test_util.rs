@@ -0,0 +1,108 @@
+// Copyright 2021 Benjamin Gordon
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use log::error;
+use std::env;
+use std::fs;
+use std::io::{Error, Result};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempdir::TempDir;
+
+lazy_static! {
+    // Tests should lock this before manipulating PATH.  Otherwise FakeCommand
+    // instances in separate threads can end up overwriting each other's changes.
+    pub static ref ENV_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A command added to the front of PATH for the lifetime of the value.
+pub struct FakeCommand {
+    pub dir: TempDir,
+    pub cmd: PathBuf,
+}
+
+impl FakeCommand {
+    /// Adds `command` to PATH as a link to /bin/false.
+    pub fn new<P: AsRef<Path>>(command: P) -> Result<FakeCommand> {
+        let dir = TempDir::new("test")?;
+
+        let file_path = dir.path().join(command);
+        symlink("/bin/false", &file_path)?;
+
+        FakeCommand::install(dir, file_path)
+    }
+
+    /// Adds `command` to PATH as a shell script that runs `body`.
+    pub fn script<P: AsRef<Path>>(command: P, body: &str) -> Result<FakeCommand> {
+        let dir = TempDir::new("test")?;
+
+        let file_path = dir.path().join(command);
+        fs::write(&file_path, format!("#!/bin/sh\n{}\n", body))?;
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o755))?;
+
+        FakeCommand::install(dir, file_path)
+    }
+
+    fn install(dir: TempDir, cmd: PathBuf) -> Result<FakeCommand> {
+        if let Some(path) = env::var_os("PATH") {
+            let mut paths = env::split_paths(&path).collect::<Vec<_>>();
+            paths.insert(0, dir.path().to_path_buf());
+            let new_path =
+                env::join_paths(paths).map_err(|_| Error::other("Failed to join paths"))?;
+            env::set_var("PATH", new_path);
+        }
+
+        Ok(FakeCommand { dir, cmd })
+    }
+}
+
+impl Drop for FakeCommand {
+    fn drop(&mut self) {
+        if let Some(path) = env::var_os("PATH") {
+            let paths = env::split_paths(&path)
+                .filter(|p| p != self.dir.path())
+                .collect::<Vec<_>>();
+            if let Ok(new_path) = env::join_paths(paths) {
+                env::set_var("PATH", new_path);
+            } else {
+                error!("Failed to remove {} from PATH", self.dir.path().display());
+            }
+        }
+    }
+}
+
+#[test]
+fn fakecommand_cleans_path() {
+    let _lock = ENV_LOCK.lock().unwrap();
+
+    let mytest = FakeCommand::new("mytest").unwrap();
+    let path = env::var_os("PATH").unwrap();
+    let dir = mytest.dir.path().to_str().unwrap().to_string();
+    assert!(path.to_str().unwrap().contains(&dir));
+    drop(mytest);
+    let path = env::var_os("PATH").unwrap();
+    assert!(!path.to_str().unwrap().contains(&dir));
+}
+
+#[test]
+fn fakecommand_is_found() {
+    let _lock = ENV_LOCK.lock().unwrap();
+
+    let mytest = FakeCommand::new("mytest").unwrap();
+
+    assert_eq!(mytest.cmd.file_name().unwrap(), "mytest");
+    assert!(mytest.cmd.exists());
+
+    let found = pathsearch::find_executable_in_path("mytest").unwrap();
+    assert_eq!(found, mytest.cmd);
+}
+
+#[test]
+fn fakecommand_script_runs() {
+    let _lock = ENV_LOCK.lock().unwrap();
+
+    let mytest = FakeCommand::script("mytest", "exit 3").unwrap();
+    let status = std::process::Command::new(&mytest.cmd).status().unwrap();
+    assert_eq!(status.code(), Some(3));
+}